@@ -1,32 +1,44 @@
 use std::io::{self, BufRead};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 
-use irc_server::client::{IRCClient, SessionMonitor};
+use irc_server::client::{ConnectionState, IRCClient, ServerMessage, SessionMonitor};
+use irc_server::utils::format_duration;
 use log::{info, warn, error};
 
-// Function to read messages from the server and print them
-fn message_reader(client: &mut IRCClient, activity_handle: Arc<Mutex<Instant>>) {
+// Function to read messages from the server and print them. The client is shared
+// with the command loop, so what read_parsed records (such as the server's
+// SESSION_REMAINING) is what /status reports.
+fn message_reader(client: Arc<Mutex<IRCClient>>, activity_handle: Arc<Mutex<Instant>>) {
 	let mut consecutive_errors = 0;
 	
-	while let Some(stream) = &mut client.stream {
-		match client.read_message() {
-			Ok(message) => {
+	loop {
+		// read_parsed answers the server's PINGs, so the connection isn't dropped as dead,
+		// and leaves the Connected state once the server closes the connection. The lock
+		// is only held for one read, which the short read timeout keeps brief.
+		let result = {
+			let mut client = client.lock().unwrap();
+			if client.state() != ConnectionState::Connected {
+				break;
+			}
+			client.read_parsed()
+		};
+		
+		match result {
+			Ok(messages) => {
 				// Reset error counter on successful reads
 				consecutive_errors = 0;
 				
 				// Update last activity time when receiving messages
 				{
 					let mut guard = activity_handle.lock().unwrap();
-					*guard = std::time::Instant::now();
+					*guard = Instant::now();
 				}
 				
-				println!("{}", message);
-				
-				// If the message contains a deletion notice, acknowledge it
-				if message.contains("[DELETED]") || message.contains("deleted") {
-					println!("[SECURITY] Some messages have been automatically deleted for security");
+				for message in messages {
+					print_message(&message);
 				}
 			}
 			Err(e) => {
@@ -53,6 +65,18 @@ fn message_reader(client: &mut IRCClient, activity_handle: Arc<Mutex<Instant>>)
 	info!("[SECURITY] Message receiver stopped. Session ended.");
 }
 
+// Print a message from the server; security notices are called out separately
+fn print_message(message: &ServerMessage) {
+	match message {
+		ServerMessage::Privmsg { target, text, .. } => println!("{}: {}", target, text),
+		ServerMessage::Notice { text } | ServerMessage::Announcement(text) => println!("-server- {}", text),
+		ServerMessage::SecurityNotice { kind, detail } => println!("[SECURITY] {:?}: {}", kind, detail),
+		ServerMessage::Numeric { text, .. } if !text.is_empty() => println!("{}", text),
+		ServerMessage::Fail { text, .. } | ServerMessage::Error(text) => println!("[ERROR] {}", text),
+		_ => {}
+	}
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 	env_logger::init();
 	
@@ -60,8 +84,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	// after user registration/authentication
 	let token = "your_actual_token_here"; // Replace with a real token
 	
-	// Create client
+	// Create client; a short read timeout keeps the reader thread from holding
+	// the shared client for long
 	let mut client = IRCClient::new("127.0.0.1", 6667, token);
+	client.set_read_timeout(Some(Duration::from_millis(200)))?;
 	
 	// Create session monitor
 	let monitor = SessionMonitor::new(
@@ -71,16 +97,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	
 	// Set up session monitoring
 	let activity_handle = monitor.activity_handle();
+	let monitor_stop = monitor.stop_handle();
 	
-	// Set expiration callback
-	let monitor = monitor.on_expire(|| {
-		println!("\n[SECURITY] Session expired or inactive for too long.");
-		println!("[SECURITY] Disconnecting and securely deleting all messages...");
-		std::process::exit(0);
-	});
+	// Set warning and expiration callbacks
+	let monitor = monitor
+		.on_warn(|remaining| {
+			println!("\n[SECURITY] Session will expire in {} seconds.", remaining.as_secs());
+		})
+		.on_expire(|| {
+			println!("\n[SECURITY] Session expired or inactive for too long.");
+			println!("[SECURITY] Disconnecting and securely deleting all messages...");
+			std::process::exit(0);
+		});
 	
 	// Start monitor in background thread
-	let _monitor_handle = monitor.start_monitoring();
+	let monitor_handle = monitor.start_monitoring();
 	
 	// Connect to server
 	match client.connect() {
@@ -100,11 +131,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 		Err(e) => error!("Failed to join channel: {}", e),
 	}
 	
-	// Spawn a thread to listen for messages
-	let mut receive_client = client.clone();
+	// Spawn a thread to listen for messages on the same client
+	let shared_client = Arc::new(Mutex::new(client));
+	let receive_client = shared_client.clone();
 	let receive_activity = activity_handle.clone();
 	thread::spawn(move || {
-		message_reader(&mut receive_client, receive_activity);
+		message_reader(receive_client, receive_activity);
 	});
 	
 	// Main loop for sending messages
@@ -125,6 +157,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			break;
 		}
 		
+		let mut client = shared_client.lock().unwrap();
+		
 		if line.trim() == "secure-clear" {
 			println!("[SECURITY] Requesting secure deletion of all messages...");
 			match client.secure_clear() {
@@ -182,14 +216,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 					}
 				}
 				"status" => {
-					let session_duration = client.session_duration();
-					let hours = session_duration.as_secs() / 3600;
-					let minutes = (session_duration.as_secs() % 3600) / 60;
-					let seconds = session_duration.as_secs() % 60;
+					let status = client.session_status();
 					
-					println!("[SESSION] Active for {}:{:02}:{:02}", hours, minutes, seconds);
-					println!("[SESSION] Session will end in {} minutes", 
-							 60 - (session_duration.as_secs() / 60));
+					println!("[SESSION] Active for {}", status.formatted);
+					match status.remaining {
+						Some(remaining) => println!("[SESSION] Session will end in {}", format_duration(remaining)),
+						None => println!("[SESSION] The server has not reported when the session ends yet"),
+					}
+					if let Some(message) = client.away_message() {
+						println!("[SESSION] Away: {}", message);
+					}
 				}
 				"help" => {
 					println!("Available commands:");
@@ -209,8 +245,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			}
 		} else {
 			// Send message to current channel
-			if let Some(channel) = &client.current_channel {
-				match client.send_message(channel, &line) {
+			if let Some(channel) = client.current_channel.clone() {
+				match client.send_message(&channel, &line) {
 					Ok(_) => {}
 					Err(e) => error!("Failed to send message: {}", e),
 				}
@@ -223,10 +259,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	println!("[SECURITY] Disconnecting and securely deleting all messages...");
 	
 	// Disconnect from server
-	match client.disconnect() {
+	match shared_client.lock().unwrap().disconnect() {
 		Ok(_) => println!("[SECURITY] Successfully disconnected and cleared all data"),
 		Err(e) => error!("[ERROR] Error disconnecting: {}", e),
 	}
 	
+	// Stop the session monitor so its thread exits without firing the expiry callback
+	monitor_stop.store(true, Ordering::SeqCst);
+	let _ = monitor_handle.join();
+	
 	Ok(())
 }
\ No newline at end of file
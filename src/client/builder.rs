@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use crate::client::client::IRCClient;
+use crate::error::IrcError;
+
+/// Port used when the builder is not given one
+pub const DEFAULT_PORT: u16 = 6667;
+
+/// Step-by-step configuration for an `IRCClient`
+///
+/// `server` and `token` are required; everything else keeps the same default
+/// as `IRCClient::new`. Nothing connects until `IRCClient::connect` is called.
+///
+/// There is no `tls` option: the client only speaks plain TCP, so put a TLS
+/// tunnel in front of it when the connection needs encrypting. Like `IRCClient`,
+/// the builder has no `Debug` impl, since it holds the token and channel keys.
+#[derive(Clone, Default)]
+pub struct IRCClientBuilder {
+	server: Option<String>,
+	port: Option<u16>,
+	token: Option<String>,
+	read_timeout: Option<Option<Duration>>, // Outer None keeps the client's default
+	channel_keys: Vec<(String, [u8; 32])>,
+}
+
+impl IRCClientBuilder {
+	/// Start with nothing configured
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Set the server host name or address
+	pub fn server(mut self, server: &str) -> Self {
+		self.server = Some(server.to_string());
+		self
+	}
+
+	/// Set the server port (6667 by default)
+	pub fn port(mut self, port: u16) -> Self {
+		self.port = Some(port);
+		self
+	}
+
+	/// Set the JWT sent to authenticate
+	pub fn token(mut self, token: &str) -> Self {
+		self.token = Some(token.to_string());
+		self
+	}
+
+	/// Set the socket read timeout; `None` makes reads block (see `IRCClient::set_read_timeout`)
+	pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+		self.read_timeout = Some(timeout);
+		self
+	}
+
+	/// Set an end-to-end key for a channel or nick (see `IRCClient::set_channel_key`)
+	pub fn channel_key(mut self, channel: &str, key: [u8; 32]) -> Self {
+		self.channel_keys.push((channel.to_string(), key));
+		self
+	}
+
+	/// Check the configuration and create the client
+	pub fn build(self) -> Result<IRCClient, IrcError> {
+		let server = self
+			.server
+			.filter(|server| !server.trim().is_empty())
+			.ok_or_else(|| IrcError::InvalidInput("A server is required".to_string()))?;
+		let token = self
+			.token
+			.filter(|token| !token.trim().is_empty())
+			.ok_or_else(|| IrcError::InvalidInput("A token is required".to_string()))?;
+		let port = self.port.unwrap_or(DEFAULT_PORT);
+		if port == 0 {
+			return Err(IrcError::InvalidInput("Port must not be 0".to_string()));
+		}
+
+		let mut client = IRCClient::new(&server, port, &token);
+		if let Some(timeout) = self.read_timeout {
+			client.set_read_timeout(timeout)?;
+		}
+		for (channel, key) in self.channel_keys {
+			client.set_channel_key(&channel, key);
+		}
+
+		Ok(client)
+	}
+}
@@ -1,8 +1,175 @@
 use log::{debug, error, info, warn};
-use std::collections::HashSet;
-use std::io::{self, Error, ErrorKind, Read, Write};
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::builder::IRCClientBuilder;
+use crate::client::message::{FileEvent, SecurityNoticeKind, ServerMessage};
+use crate::error::IrcError;
+use crate::server::crypto::Encryptor;
+use crate::utils::duration::format_duration;
+
+/// Marker that starts an end-to-end encrypted message body.
+///
+/// The full envelope is `+E2E:<nonce>:<ciphertext>`, where `<nonce>` is the
+/// 16-digit hex counter passed to `Encryptor` and `<ciphertext>` is the
+/// base64-encoded AES-256-GCM output (ciphertext followed by the tag).
+pub const E2E_PREFIX: &str = "+E2E:";
+
+/// How long to wait for the server to finish a multi-line reply (PROFILE, MYCHANNELS, LIST, WHO, WHOIS)
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default read timeout on the socket, so read loops can check for other work
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most security notices `security_notices` keeps; the oldest are dropped first
+const MAX_SECURITY_NOTICES: usize = 100;
+
+/// How long `send_file` waits for the recipient to accept
+const FILE_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Encrypted bytes per FILEDATA line (800 base64 characters)
+const FILE_CHUNK_SIZE: usize = 600;
+
+/// Decides where a file offered by a nick is saved: a path accepts it, None declines it
+type FileOfferHandler = Box<dyn FnMut(&str, &str, u64) -> Option<PathBuf> + Send>;
+
+/// A file we accepted, buffered encrypted on disk until the sender finishes
+struct IncomingFile {
+	sender: String,
+	path: PathBuf,      // Where the decrypted file is saved
+	part_path: PathBuf, // Encrypted stream received so far
+	part: File,
+}
+
+/// Sends an encrypted stream as FILEDATA lines of FILE_CHUNK_SIZE bytes
+struct FileDataWriter<'a> {
+	stream: &'a mut TcpStream,
+	id: &'a str,
+	buffer: Vec<u8>,
+}
+
+impl FileDataWriter<'_> {
+	fn send_chunk(&mut self, len: usize) -> io::Result<()> {
+		let chunk: Vec<u8> = self.buffer.drain(..len).collect();
+		let line = format!("FILEDATA {} :{}\r\n", self.id, base64::encode(chunk));
+		self.stream.write_all(line.as_bytes())
+	}
+}
+
+impl Write for FileDataWriter<'_> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		self.buffer.extend_from_slice(data);
+		while self.buffer.len() >= FILE_CHUNK_SIZE {
+			self.send_chunk(FILE_CHUNK_SIZE)?;
+		}
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		if !self.buffer.is_empty() {
+			self.send_chunk(self.buffer.len())?;
+		}
+		self.stream.flush()
+	}
+}
+
+/// How a line read while awaiting a reply relates to it
+enum ReplyLine<T> {
+	Unrelated, // Kept for the next read_parsed call
+	Consumed,  // Part of the reply; keep reading
+	Done(Result<T, IrcError>),
+}
+
+/// A channel from a LIST reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+	pub name: String,
+	pub users: usize,
+	pub topic: String,
+}
+
+/// A user from a WHO reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoEntry {
+	pub channel: String, // "*" when listing every connected user
+	pub nick: String,
+	pub away: bool,
+}
+
+/// A stored channel message matched by SEARCH
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResult {
+	pub sender: String,
+	pub sent_at: SystemTime,
+	pub text: String,
+	pub encrypted: bool, // Whether the text was decrypted from an end-to-end envelope
+}
+
+/// The server's clock as reported by TIME
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerTime {
+	pub time: SystemTime,   // Server wall-clock time when it answered
+	pub offset_millis: i64, // Server clock minus ours, estimated at the middle of the round trip
+}
+
+impl ServerTime {
+	/// Shift a local timestamp onto the server's clock
+	pub fn to_server_clock(&self, local: SystemTime) -> SystemTime {
+		let offset = Duration::from_millis(self.offset_millis.unsigned_abs());
+		if self.offset_millis >= 0 {
+			local + offset
+		} else {
+			local - offset
+		}
+	}
+}
+
+/// A user's details from a WHOIS reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisInfo {
+	pub nick: String,
+	pub username: String,
+	pub channels: Vec<String>, // Only channels visible to us
+	pub away: Option<String>,
+	pub messages_sent: Option<u64>, // Messages sent in the user's current session, if reported
+}
+
+/// How long the session has run and, once the server has said, how long it has left
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStatus {
+	pub duration: Duration,          // Time since the client connected
+	pub formatted: String,           // `duration` in the shared "1h 2m 3s" form
+	pub remaining: Option<Duration>, // From the server's last SESSION_REMAINING notice, if any
+}
+
+impl SessionStatus {
+	/// Build a status for a session that has run for `duration`
+	pub fn new(duration: Duration, remaining: Option<Duration>) -> Self {
+		SessionStatus {
+			duration,
+			formatted: format_duration(duration),
+			remaining,
+		}
+	}
+}
+
+/// Where an `IRCClient` is in its connection lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+	/// `connect` has not been called yet
+	NeverConnected,
+	/// Connected and authenticated with the token
+	Connected,
+	/// Closed by `disconnect`
+	Disconnected,
+	/// The connection failed, or the server closed it; call `connect` to retry
+	Lost,
+}
 
 /// IRC Client implementation with security features
 pub struct IRCClient {
@@ -13,10 +180,27 @@ pub struct IRCClient {
 	pub channels: HashSet<String>,
 	pub current_channel: Option<String>,
 	pub session_start: Instant,
+	channel_keys: HashMap<String, [u8; 32]>, // End-to-end keys by channel or nick
+	read_buffer: String,                     // Partial line left over from the last read
+	pending: Vec<ServerMessage>,             // Lines read while waiting for a reply, returned by the next read_parsed
+	state: ConnectionState,
+	file_offer_handler: Option<FileOfferHandler>,
+	incoming_files: HashMap<String, IncomingFile>, // Accepted transfers still arriving, by ID
+	away: Option<String>,                          // Away message confirmed by the server, if away
+	server_remaining: Option<(Duration, Instant)>, // Last SESSION_REMAINING value and when it arrived
+	security_notices: VecDeque<(SecurityNoticeKind, String)>, // Recent SECURITY: notices, oldest first
+	read_timeout: Option<Duration>,                // Applied to every socket opened; None blocks
 }
 
 impl IRCClient {
+	/// Start configuring a client with `IRCClientBuilder`
+	pub fn builder() -> IRCClientBuilder {
+		IRCClientBuilder::new()
+	}
+
 	/// Create a new IRC client
+	///
+	/// Shortcut for `IRCClient::builder()` with only the server, port and token set.
 	pub fn new(server: &str, port: u16, token: &str) -> Self {
 		IRCClient {
 			server: server.to_string(),
@@ -26,48 +210,121 @@ impl IRCClient {
 			channels: HashSet::new(),
 			current_channel: None,
 			session_start: Instant::now(),
+			channel_keys: HashMap::new(),
+			read_buffer: String::new(),
+			pending: Vec::new(),
+			state: ConnectionState::NeverConnected,
+			file_offer_handler: None,
+			incoming_files: HashMap::new(),
+			away: None,
+			server_remaining: None,
+			security_notices: VecDeque::new(),
+			read_timeout: Some(DEFAULT_READ_TIMEOUT),
 		}
 	}
 
-	/// Connect to the IRC server
-	pub fn connect(&mut self) -> Result<(), String> {
-		let addr = format!("{}:{}", self.server, self.port);
+	/// Set the end-to-end encryption key for a channel (or a nick, for private messages)
+	///
+	/// Messages sent to the target are encrypted before they leave the client,
+	/// so the server only relays ciphertext. Keys are exchanged out of band;
+	/// every participant must set the same key to read the conversation.
+	pub fn set_channel_key(&mut self, channel: &str, key: [u8; 32]) {
+		self.channel_keys.insert(channel.to_lowercase(), key);
+	}
 
-		match TcpStream::connect(addr) {
-			Ok(stream) => {
-				// Set read timeout to allow for periodic checking
-				if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(1))) {
-					return Err(format!("Failed to set read timeout: {}", e));
-				}
+	/// Stop encrypting messages for a channel or nick
+	pub fn remove_channel_key(&mut self, channel: &str) {
+		self.channel_keys.remove(&channel.to_lowercase());
+	}
 
-				// Enable TCP keepalive
-				if let Err(e) = stream.set_keepalive(Some(Duration::from_secs(60))) {
-					warn!("Failed to set TCP keepalive: {}", e);
-				}
+	/// Set how long a read waits for data before returning `WouldBlock` (1 second by default)
+	///
+	/// `None` makes reads block until data arrives. The timeout applies to the
+	/// current connection, if any, and to every later `connect`.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), IrcError> {
+		if timeout == Some(Duration::ZERO) {
+			return Err(IrcError::InvalidInput("Read timeout must be longer than zero".to_string()));
+		}
 
-				// Send token for authentication
-				if let Err(e) = stream.write_all(self.token.as_bytes()) {
-					return Err(format!("Failed to send authentication token: {}", e));
-				}
+		if let Some(stream) = &self.stream {
+			stream.set_read_timeout(timeout)?;
+		}
+		self.read_timeout = timeout;
+		Ok(())
+	}
+
+	/// Get the read timeout applied to the connection
+	pub fn read_timeout(&self) -> Option<Duration> {
+		self.read_timeout
+	}
 
-				self.stream = Some(stream);
-				self.session_start = Instant::now();
+	/// Get where the client is in its connection lifecycle
+	pub fn state(&self) -> ConnectionState {
+		self.state
+	}
 
-				info!("Connected to IRC server {}:{}", self.server, self.port);
-				Ok(())
+	/// Connect to the IRC server and wait for it to accept the token
+	///
+	/// Fails with `IrcError::AuthFailed` when the server rejects the token. The
+	/// welcome (001) and the lines after it are still returned by `read_parsed`.
+	pub fn connect(&mut self) -> Result<(), IrcError> {
+		let result = self.open_connection().and_then(|()| self.await_welcome());
+		self.state = match result {
+			Ok(()) => ConnectionState::Connected,
+			Err(_) => {
+				self.stream = None;
+				ConnectionState::Lost
 			}
-			Err(e) => Err(format!("Failed to connect: {}", e)),
+		};
+		result
+	}
+
+	// Wait for the welcome or the server's refusal, leaving the welcome first in line
+	fn await_welcome(&mut self) -> Result<(), IrcError> {
+		let welcome = self.await_reply("the welcome", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Numeric { code: 1, .. } => ReplyLine::Done(Ok(message.clone())),
+			ServerMessage::Error(text) => ReplyLine::Done(Err(match text.strip_prefix("Authentication failed: ") {
+				Some(reason) => IrcError::AuthFailed(reason.to_string()),
+				None => IrcError::Other(text.clone()),
+			})),
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		self.pending.insert(0, welcome);
+		Ok(())
+	}
+
+	// Open the socket and send the token
+	fn open_connection(&mut self) -> Result<(), IrcError> {
+		let addr = format!("{}:{}", self.server, self.port);
+
+		let mut stream = TcpStream::connect(addr)?;
+
+		// Set read timeout to allow for periodic checking
+		stream.set_read_timeout(self.read_timeout)?;
+
+		// Enable TCP keepalive
+		if let Err(e) = stream.set_keepalive(Some(Duration::from_secs(60))) {
+			warn!("Failed to set TCP keepalive: {}", e);
 		}
+
+		// Send token for authentication, terminated by a newline
+		stream.write_all(format!("{}\r\n", self.token).as_bytes())?;
+
+		self.stream = Some(stream);
+		self.session_start = Instant::now();
+		self.server_remaining = None;
+
+		info!("Connected to IRC server {}:{}", self.server, self.port);
+		Ok(())
 	}
 
 	/// Join a channel
-	pub fn join_channel(&mut self, channel: &str) -> Result<(), String> {
+	pub fn join_channel(&mut self, channel: &str) -> Result<(), IrcError> {
 		if let Some(stream) = &mut self.stream {
 			let command = format!("JOIN {}\r\n", channel);
 
-			if let Err(e) = stream.write_all(command.as_bytes()) {
-				return Err(format!("Failed to join channel: {}", e));
-			}
+			stream.write_all(command.as_bytes())?;
 
 			// Set as current channel if this is the first one
 			if self.current_channel.is_none() {
@@ -79,39 +336,67 @@ impl IRCClient {
 			info!("Joined channel: {}", channel);
 			Ok(())
 		} else {
-			Err("Not connected to server".to_string())
+			Err(IrcError::NotConnected)
 		}
 	}
 
 	/// Send a message to a channel or user
-	pub fn send_message(&mut self, target: &str, message: &str) -> Result<(), String> {
+	pub fn send_message(&mut self, target: &str, message: &str) -> Result<(), IrcError> {
+		self.send_privmsg(target, message, None)
+	}
+
+	/// Send a message that the server deletes after `ttl` instead of its usual message TTL
+	///
+	/// `ttl` is rounded down to whole seconds and must be at least one second and
+	/// no longer than the server's message TTL; otherwise the server answers
+	/// `FAIL PRIVMSG INVALID_TTL` and drops the message.
+	pub fn send_ephemeral(&mut self, target: &str, message: &str, ttl: Duration) -> Result<(), IrcError> {
+		if ttl.as_secs() == 0 {
+			return Err(IrcError::InvalidInput("TTL must be at least one second".to_string()));
+		}
+
+		self.send_privmsg(target, message, Some(ttl))
+	}
+
+	// Send a PRIVMSG, end-to-end encrypted when a key is set for the target
+	fn send_privmsg(&mut self, target: &str, message: &str, ttl: Option<Duration>) -> Result<(), IrcError> {
 		if let Some(stream) = &mut self.stream {
 			// Don't allow empty messages
 			if message.trim().is_empty() {
 				return Ok(());
 			}
 
-			let command = format!("PRIVMSG {} :{}\r\n", target, message);
-
-			if let Err(e) = stream.write_all(command.as_bytes()) {
-				return Err(format!("Failed to send message: {}", e));
+			// Line breaks would let the message smuggle in extra commands
+			if target.contains(['\r', '\n']) || message.contains(['\r', '\n']) {
+				return Err(IrcError::InvalidInput("Message must not contain line breaks".to_string()));
 			}
 
+			// Encrypt the body if an end-to-end key is set for the target
+			let body = match self.channel_keys.get(&target.to_lowercase()) {
+				Some(key) => Self::encrypt_body(key, message)?,
+				None => message.to_string(),
+			};
+
+			let command = match ttl {
+				Some(ttl) => format!("PRIVMSG {} +ttl={} :{}\r\n", target, ttl.as_secs(), body),
+				None => format!("PRIVMSG {} :{}\r\n", target, body),
+			};
+
+			stream.write_all(command.as_bytes())?;
+
 			debug!("Sent message to {}: {}", target, message);
 			Ok(())
 		} else {
-			Err("Not connected to server".to_string())
+			Err(IrcError::NotConnected)
 		}
 	}
 
 	/// Leave a channel
-	pub fn leave_channel(&mut self, channel: &str) -> Result<(), String> {
+	pub fn leave_channel(&mut self, channel: &str) -> Result<(), IrcError> {
 		if let Some(stream) = &mut self.stream {
 			let command = format!("PART {}\r\n", channel);
 
-			if let Err(e) = stream.write_all(command.as_bytes()) {
-				return Err(format!("Failed to leave channel: {}", e));
-			}
+			stream.write_all(command.as_bytes())?;
 
 			self.channels.remove(channel);
 
@@ -125,47 +410,665 @@ impl IRCClient {
 			info!("Left channel: {}", channel);
 			Ok(())
 		} else {
-			Err("Not connected to server".to_string())
+			Err(IrcError::NotConnected)
+		}
+	}
+
+	/// Leave every channel at once, e.g. before logging off
+	pub fn leave_all_channels(&mut self) -> Result<(), IrcError> {
+		self.send_raw("PARTALL")?;
+
+		self.channels.clear();
+		self.current_channel = None;
+
+		info!("Left all channels");
+		Ok(())
+	}
+
+	/// Mark ourselves away with a message, or back when `message` is `None`
+	///
+	/// Waits for the server to confirm with 306 (away) or 305 (back); unrelated
+	/// lines received meanwhile are kept for the next `read_parsed` call.
+	pub fn set_away(&mut self, message: Option<&str>) -> Result<(), IrcError> {
+		let message = message.map(str::trim).filter(|m| !m.is_empty());
+		let (command, expected) = match message {
+			Some(text) => (format!("AWAY :{}", text), 306),
+			None => ("AWAY".to_string(), 305),
+		};
+
+		self.send_raw(&command)?;
+
+		let mut reply = None;
+		let mut unrelated = Vec::new();
+		for message in self.read_until(&[305, 306], REPLY_TIMEOUT)? {
+			match message {
+				ServerMessage::Numeric { code: code @ (305 | 306), .. } => reply = Some(code),
+				other => unrelated.push(other),
+			}
 		}
+		self.unread(unrelated);
+
+		if reply != Some(expected) {
+			return Err(IrcError::Other(format!("Unexpected reply to {}", command)));
+		}
+
+		self.away = message.map(String::from);
+		info!("Away status: {}", self.away.as_deref().unwrap_or("back"));
+		Ok(())
+	}
+
+	/// The away message the server last confirmed, or `None` when not away
+	pub fn away_message(&self) -> Option<&str> {
+		self.away.as_deref()
 	}
 
 	/// Request secure deletion of all messages
-	pub fn secure_clear(&mut self) -> Result<(), String> {
+	pub fn secure_clear(&mut self) -> Result<(), IrcError> {
 		if let Some(stream) = &mut self.stream {
 			let command = "SECURECLEAR\r\n";
 
-			if let Err(e) = stream.write_all(command.as_bytes()) {
-				return Err(format!("Failed to send secure clear command: {}", e));
-			}
+			stream.write_all(command.as_bytes())?;
 
 			info!("Requested secure deletion of all messages");
 			Ok(())
 		} else {
-			Err("Not connected to server".to_string())
+			Err(IrcError::NotConnected)
+		}
+	}
+
+	/// Ask the server to rotate this session's encryption key
+	///
+	/// The server starts a fresh key and nonce counter and securely deletes the
+	/// private history it kept for this user. The other side of each conversation
+	/// keeps its own copy, and channel history is untouched. Returns how many
+	/// stored messages were discarded.
+	pub fn rekey(&mut self) -> Result<usize, IrcError> {
+		self.send_raw("REKEY")?;
+
+		let discarded = self.await_reply("the rekey confirmation", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Notice { text } => match text.strip_prefix("REKEYED ") {
+				Some(count) => ReplyLine::Done(count.trim().parse().map_err(|_| {
+					IrcError::Other(format!("Invalid rekey confirmation: {}", text))
+				})),
+				None => ReplyLine::Unrelated,
+			},
+			ServerMessage::Fail { command, text, .. } if command == "REKEY" => {
+				ReplyLine::Done(Err(IrcError::Other(text.clone())))
+			}
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		info!("Session key rotated");
+		Ok(discarded)
+	}
+
+	/// Ask the server for its current time, to correct displayed timestamps for clock drift
+	pub fn server_time(&mut self) -> Result<ServerTime, IrcError> {
+		let sent = SystemTime::now();
+		self.send_raw("TIME")?;
+
+		let time = self.await_reply("the server time", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Numeric { code: 391, params, text } => match params.get(2).map(|p| p.parse::<u64>()) {
+				Some(Ok(millis)) => ReplyLine::Done(Ok(UNIX_EPOCH + Duration::from_millis(millis))),
+				_ => ReplyLine::Done(Err(IrcError::Other(format!("Invalid TIME reply: {}", text)))),
+			},
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		// Assume the server answered halfway between sending and receiving
+		let received = SystemTime::now();
+		let round_trip = received.duration_since(sent).unwrap_or_default();
+		let local = sent + round_trip / 2;
+		let offset_millis = match time.duration_since(local) {
+			Ok(ahead) => ahead.as_millis() as i64,
+			Err(behind) => -(behind.duration().as_millis() as i64),
+		};
+
+		Ok(ServerTime { time, offset_millis })
+	}
+
+	/// Tell a channel or user that we are typing
+	///
+	/// The server relays this without storing it and drops notifications sent
+	/// more often than every few seconds, so it is safe to call on each keystroke.
+	pub fn send_typing(&mut self, target: &str) -> Result<(), IrcError> {
+		if target.is_empty() || target.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid typing target".to_string()));
+		}
+
+		self.send_raw(&format!("TYPING {}", target))
+	}
+
+	/// Fetch a user's profile picture
+	///
+	/// Sends `PROFILE nick` and reassembles the base64 chunks the server
+	/// replies with (numeric 910, ending with 911). Returns an empty vector
+	/// if the user has no avatar. Unrelated lines received while waiting are
+	/// kept and returned by the next `read_parsed` call.
+	pub fn request_profile(&mut self, nick: &str) -> Result<Vec<u8>, IrcError> {
+		if nick.is_empty() || nick.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid nick".to_string()));
+		}
+
+		self.send_raw(&format!("PROFILE {}", nick))?;
+
+		let mut encoded = String::new();
+		self.await_reply(&format!("the profile of {}", nick), REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Numeric { code, params, text }
+				if params.get(1).map_or(false, |p| p.eq_ignore_ascii_case(nick)) =>
+			{
+				match code {
+					910 => {
+						encoded.push_str(text);
+						ReplyLine::Consumed
+					}
+					911 => ReplyLine::Done(
+						base64::decode(&encoded)
+							.map_err(|e| IrcError::Other(format!("Invalid profile picture data: {}", e))),
+					),
+					401 => ReplyLine::Done(Err(IrcError::NickNotFound(nick.to_string()))),
+					_ => ReplyLine::Unrelated,
+				}
+			}
+			_ => ReplyLine::Unrelated,
+		})
+	}
+
+	/// Fetch a user's profile picture and write it to a file
+	///
+	/// Returns the number of bytes written; fails if the user has no avatar.
+	pub fn save_profile<P: AsRef<Path>>(&mut self, nick: &str, path: P) -> Result<usize, IrcError> {
+		let picture = self.request_profile(nick)?;
+
+		if picture.is_empty() {
+			return Err(IrcError::Other(format!("{} has no profile picture", nick)));
+		}
+
+		std::fs::write(path.as_ref(), &picture)?;
+
+		info!("Saved profile picture of {} to {}", nick, path.as_ref().display());
+		Ok(picture.len())
+	}
+
+	/// Ask the server which channels we are in and replace `channels` with the answer
+	///
+	/// Use this after reconnecting (or resuming a session) when the local set
+	/// may be stale. `current_channel` moves to another channel if it was left.
+	pub fn refresh_channels(&mut self) -> Result<(), IrcError> {
+		self.send_raw("MYCHANNELS")?;
+
+		let mut channels = HashSet::new();
+		let channels = self.await_reply("the channel list", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Numeric { code: 319, text, .. } => {
+				channels.extend(text.split_whitespace().map(String::from));
+				ReplyLine::Consumed
+			}
+			ServerMessage::Numeric { code: 318, .. } => ReplyLine::Done(Ok(std::mem::take(&mut channels))),
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		if let Some(current) = &self.current_channel {
+			if !channels.contains(current) {
+				self.current_channel = channels.iter().next().cloned();
+			}
+		}
+		self.channels = channels;
+
+		debug!("Refreshed channel list: {} channels", self.channels.len());
+		Ok(())
+	}
+
+	/// Send a file to `nick`, relayed through the server
+	///
+	/// This is not a peer-to-peer transfer: the file travels over our
+	/// connection to the server, which passes it on to the recipient without
+	/// storing it. The contents are encrypted end to end with the key set for
+	/// `nick` by `set_channel_key`, so the recipient needs the same key set for
+	/// us. Blocks until the recipient accepts (for up to two minutes) and the
+	/// whole file has been sent. Returns the transfer ID.
+	pub fn send_file<P: AsRef<Path>>(&mut self, path: P, nick: &str) -> Result<String, IrcError> {
+		if nick.is_empty() || nick.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid nick".to_string()));
+		}
+
+		let key = match self.channel_keys.get(&nick.to_lowercase()) {
+			Some(key) => *key,
+			None => {
+				return Err(IrcError::InvalidInput(format!(
+					"No end-to-end key set for {}",
+					nick
+				)))
+			}
+		};
+
+		let path = path.as_ref();
+		let file = File::open(path)?;
+		let size = file.metadata()?.len();
+
+		// The name travels as a single IRC parameter
+		let name: String = path
+			.file_name()
+			.map(|name| {
+				name.to_string_lossy()
+					.replace(|c: char| c.is_whitespace() || c.is_control(), "_")
+			})
+			.map(|name| name.trim_start_matches(':').to_string())
+			.filter(|name| !name.is_empty())
+			.unwrap_or_else(|| "file".to_string());
+
+		self.send_raw(&format!("SENDFILE {} {} {}", nick, name, size))?;
+
+		let id = self.await_reply("the file transfer ID", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::File {
+				event: FileEvent::Pending { id, nick: recipient },
+				..
+			} if recipient.eq_ignore_ascii_case(nick) => ReplyLine::Done(Ok(id.clone())),
+			ServerMessage::Numeric { code: 401, params, .. }
+				if params.get(1).map_or(false, |p| p.eq_ignore_ascii_case(nick)) =>
+			{
+				ReplyLine::Done(Err(IrcError::NickNotFound(nick.to_string())))
+			}
+			ServerMessage::Fail { command, text, .. } if command == "SENDFILE" => {
+				ReplyLine::Done(Err(IrcError::Other(text.clone())))
+			}
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		let accepted = self.await_reply("the file to be accepted", FILE_ACCEPT_TIMEOUT, |message| {
+			match message {
+				ServerMessage::File {
+					event: FileEvent::Accept { id: accepted },
+					..
+				} if *accepted == id => ReplyLine::Done(Ok(true)),
+				ServerMessage::File {
+					event: FileEvent::Cancel { id: cancelled },
+					..
+				} if *cancelled == id => ReplyLine::Done(Ok(false)),
+				_ => ReplyLine::Unrelated,
+			}
+		});
+		match accepted {
+			Ok(true) => {}
+			Ok(false) => return Err(IrcError::Other(format!("{} declined the file", nick))),
+			Err(e) => {
+				let _ = self.send_raw(&format!("CANCELFILE {}", id));
+				return Err(e);
+			}
+		}
+
+		// Frames are numbered from a random counter sent ahead of the stream; its
+		// top bit is left clear so the count can't wrap around
+		let counter = thread_rng().gen::<u64>() >> 1;
+		let stream = self.stream.as_mut().ok_or(IrcError::NotConnected)?;
+		let mut writer = FileDataWriter {
+			stream,
+			id: &id,
+			buffer: counter.to_be_bytes().to_vec(),
+		};
+		if let Err(e) = Encryptor::new(key).encrypt_stream(counter, BufReader::new(file), &mut writer) {
+			let _ = self.send_raw(&format!("CANCELFILE {}", id));
+			return Err(IrcError::Other(e));
+		}
+		self.send_raw(&format!("FILEDONE {}", id))?;
+
+		info!("Sent file {} ({} bytes) to {}", name, size, nick);
+		Ok(id)
+	}
+
+	/// Choose what happens to files other users send us
+	///
+	/// `handler` is called with the sender's nick, the file name and its size
+	/// while `read_parsed` processes the offer. Returning a path accepts the
+	/// file: once it has arrived it is decrypted with the key set for the
+	/// sender by `set_channel_key` and saved there, and `read_parsed` returns a
+	/// `FileEvent::Saved` (or `FileEvent::Failed`). Returning None declines it.
+	/// Offers are declined without asking when no handler is set or no key is
+	/// set for the sender. The file name comes from the sender, so check it
+	/// before using it in a path.
+	pub fn on_file_offer<F>(&mut self, handler: F)
+	where
+		F: FnMut(&str, &str, u64) -> Option<PathBuf> + Send + 'static,
+	{
+		self.file_offer_handler = Some(Box::new(handler));
+	}
+
+	/// Cancel a file transfer we are receiving (or one that is still waiting for us to accept)
+	pub fn cancel_file(&mut self, id: &str) -> Result<(), IrcError> {
+		if id.is_empty() || id.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid transfer ID".to_string()));
+		}
+
+		self.send_raw(&format!("CANCELFILE {}", id))?;
+		self.discard_incoming_file(id);
+		Ok(())
+	}
+
+	/// List the channels visible to us, optionally filtered by a glob such as `#rust*`
+	///
+	/// Unrelated lines received while waiting are kept and returned by the
+	/// next `read_parsed` call.
+	pub fn list_channels(&mut self, pattern: Option<&str>) -> Result<Vec<ChannelInfo>, IrcError> {
+		match pattern {
+			Some(pattern) if pattern.is_empty() || pattern.contains([' ', '\r', '\n']) => {
+				return Err(IrcError::InvalidInput("Invalid channel pattern".to_string()));
+			}
+			Some(pattern) => self.send_raw(&format!("LIST {}", pattern))?,
+			None => self.send_raw("LIST")?,
+		}
+
+		let mut channels = Vec::new();
+		let mut unrelated = Vec::new();
+		for message in self.read_until(&[323], REPLY_TIMEOUT)? {
+			match message {
+				ServerMessage::Numeric { code: 322, params, text } if params.len() >= 3 => {
+					channels.push(ChannelInfo {
+						name: params[1].clone(),
+						users: params[2].parse().unwrap_or(0),
+						topic: text,
+					});
+				}
+				ServerMessage::Numeric { code: 323, .. } => {}
+				other => unrelated.push(other),
+			}
+		}
+		self.unread(unrelated);
+
+		Ok(channels)
+	}
+
+	/// List the users in a channel, or every connected user when `channel` is None
+	///
+	/// Unrelated lines received while waiting are kept and returned by the
+	/// next `read_parsed` call.
+	pub fn who(&mut self, channel: Option<&str>) -> Result<Vec<WhoEntry>, IrcError> {
+		match channel {
+			Some(channel) if !channel.starts_with('#') || channel.contains([' ', '\r', '\n']) => {
+				return Err(IrcError::InvalidInput("Invalid channel name".to_string()));
+			}
+			Some(channel) => self.send_raw(&format!("WHO {}", channel))?,
+			None => self.send_raw("WHO")?,
+		}
+
+		let mut users = Vec::new();
+		let mut unrelated = Vec::new();
+		let mut failure = None;
+		for message in self.read_until(&[315, 403, 479], REPLY_TIMEOUT)? {
+			match message {
+				ServerMessage::Numeric { code: 352, params, .. } if params.len() >= 7 => {
+					users.push(WhoEntry {
+						channel: params[1].clone(),
+						nick: params[5].clone(),
+						away: params[6].starts_with('G'),
+					});
+				}
+				ServerMessage::Numeric { code: 315, .. } => {}
+				ServerMessage::Numeric { code: 403, .. } => {
+					failure = Some(IrcError::ChannelNotFound(channel.unwrap_or("*").to_string()));
+				}
+				ServerMessage::Numeric { code: 479, text, .. } => failure = Some(IrcError::InvalidInput(text)),
+				other => unrelated.push(other),
+			}
+		}
+		self.unread(unrelated);
+
+		match failure {
+			Some(e) => Err(e),
+			None => Ok(users),
+		}
+	}
+
+	/// Search a channel's stored history for `query`, ignoring case
+	///
+	/// Only members may search, and the server returns at most the 20 most
+	/// recent unexpired matches, oldest first. Messages sent end-to-end
+	/// encrypted are matched against their ciphertext on the server, so they
+	/// rarely match; those that do are decrypted when the channel key is set.
+	pub fn search(&mut self, channel: &str, query: &str) -> Result<Vec<SearchResult>, IrcError> {
+		if !channel.starts_with('#') || channel.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid channel name".to_string()));
+		}
+		let query = query.trim();
+		if query.is_empty() || query.contains(['\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid search query".to_string()));
+		}
+
+		self.send_raw(&format!("SEARCH {} :{}", channel, query))?;
+
+		let key = self.channel_keys.get(&channel.to_lowercase()).copied();
+		let mut results = Vec::new();
+		self.await_reply("search results", REPLY_TIMEOUT, |message| match message {
+			ServerMessage::Numeric { code: 920, params, text } if params.len() >= 4 => {
+				let sent_at = UNIX_EPOCH + Duration::from_secs(params[3].parse().unwrap_or(0));
+				let plaintext = key
+					.as_ref()
+					.filter(|_| text.starts_with(E2E_PREFIX))
+					.and_then(|key| Self::decrypt_body(key, text));
+				results.push(SearchResult {
+					sender: params[2].clone(),
+					sent_at,
+					encrypted: plaintext.is_some(),
+					text: plaintext.unwrap_or_else(|| text.clone()),
+				});
+				ReplyLine::Consumed
+			}
+			ServerMessage::Numeric { code: 921, .. } => ReplyLine::Done(Ok(())),
+			ServerMessage::Numeric { code: 403, .. } => {
+				ReplyLine::Done(Err(IrcError::ChannelNotFound(channel.to_string())))
+			}
+			ServerMessage::Numeric { code: 442 | 461, text, .. } => {
+				ReplyLine::Done(Err(IrcError::InvalidInput(text.clone())))
+			}
+			_ => ReplyLine::Unrelated,
+		})?;
+
+		Ok(results)
+	}
+
+	/// Look up a connected user by nick
+	///
+	/// Unrelated lines received while waiting are kept and returned by the
+	/// next `read_parsed` call.
+	pub fn whois(&mut self, nick: &str) -> Result<WhoisInfo, IrcError> {
+		if nick.is_empty() || nick.contains([' ', '\r', '\n']) {
+			return Err(IrcError::InvalidInput("Invalid nick".to_string()));
+		}
+
+		self.send_raw(&format!("WHOIS {}", nick))?;
+
+		let mut info = WhoisInfo {
+			nick: nick.to_string(),
+			username: String::new(),
+			channels: Vec::new(),
+			away: None,
+			messages_sent: None,
+		};
+		let mut found = true;
+		let mut unrelated = Vec::new();
+		for message in self.read_until(&[318, 401], REPLY_TIMEOUT)? {
+			match message {
+				ServerMessage::Numeric { code: 311, params, .. } if params.len() >= 3 => {
+					info.nick = params[1].clone();
+					info.username = params[2].clone();
+				}
+				ServerMessage::Numeric { code: 319, text, .. } => {
+					info.channels.extend(text.split_whitespace().map(String::from));
+				}
+				ServerMessage::Numeric { code: 301, text, .. } => info.away = Some(text),
+				ServerMessage::Numeric { code: 320, text, .. } => {
+					info.messages_sent = text
+						.strip_prefix("has sent ")
+						.and_then(|rest| rest.split(' ').next())
+						.and_then(|count| count.parse().ok());
+				}
+				ServerMessage::Numeric { code: 318, .. } => {}
+				ServerMessage::Numeric { code: 401, .. } => found = false,
+				other => unrelated.push(other),
+			}
+		}
+		self.unread(unrelated);
+
+		if found {
+			Ok(info)
+		} else {
+			Err(IrcError::NickNotFound(nick.to_string()))
+		}
+	}
+
+	/// Read lines until one is a numeric reply listed in `terminators`
+	///
+	/// Returns every line received up to and including the terminating one;
+	/// lines that arrived after it are kept for the next `read_parsed` call.
+	/// If `timeout` elapses first, the lines read so far are kept the same way
+	/// and a `TimedOut` error is returned.
+	pub fn read_until(
+		&mut self,
+		terminators: &[u16],
+		timeout: Duration,
+	) -> Result<Vec<ServerMessage>, io::Error> {
+		let deadline = Instant::now() + timeout;
+		let mut received = Vec::new();
+
+		loop {
+			if Instant::now() >= deadline {
+				self.unread(received);
+				return Err(io::Error::new(
+					ErrorKind::TimedOut,
+					"Timed out waiting for a terminating reply",
+				));
+			}
+
+			let mut messages = match self.read_parsed() {
+				Ok(messages) => messages,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+				Err(e) => {
+					self.unread(received);
+					return Err(e);
+				}
+			};
+
+			let end = messages.iter().position(|message| {
+				matches!(message, ServerMessage::Numeric { code, .. } if terminators.contains(code))
+			});
+
+			if let Some(end) = end {
+				let rest = messages.split_off(end + 1);
+				received.append(&mut messages);
+				self.unread(rest);
+				return Ok(received);
+			}
+			received.append(&mut messages);
+		}
+	}
+
+	// Put lines back so the next read_parsed call returns them, ahead of anything read later
+	fn unread(&mut self, mut messages: Vec<ServerMessage>) {
+		messages.append(&mut self.pending);
+		self.pending = messages;
+	}
+
+	// Read lines until `classify` completes the reply, keeping unrelated lines
+	// (in order) for the next read_parsed call
+	fn await_reply<T>(
+		&mut self,
+		what: &str,
+		timeout: Duration,
+		mut classify: impl FnMut(&ServerMessage) -> ReplyLine<T>,
+	) -> Result<T, IrcError> {
+		let deadline = Instant::now() + timeout;
+		let mut unrelated = Vec::new();
+
+		let result = loop {
+			if Instant::now() >= deadline {
+				break Err(IrcError::Io(io::Error::new(
+					ErrorKind::TimedOut,
+					format!("Timed out waiting for {}", what),
+				)));
+			}
+
+			let messages = match self.read_parsed() {
+				Ok(messages) => messages,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+				Err(e) => break Err(IrcError::Io(e)),
+			};
+
+			let mut done = None;
+			for message in messages {
+				if done.is_some() {
+					unrelated.push(message);
+					continue;
+				}
+
+				match classify(&message) {
+					ReplyLine::Unrelated => unrelated.push(message),
+					ReplyLine::Consumed => {}
+					ReplyLine::Done(result) => done = Some(result),
+				}
+			}
+
+			if let Some(result) = done {
+				break result;
+			}
+		};
+
+		// Hand everything else back to the caller, ahead of anything read later
+		self.unread(unrelated);
+
+		result
+	}
+
+	/// Send a raw IRC line for commands without a dedicated method
+	///
+	/// A trailing line ending is optional; embedded CR/LF characters are
+	/// rejected so a single call can never smuggle in extra commands.
+	pub fn send_raw(&mut self, line: &str) -> Result<(), IrcError> {
+		if let Some(stream) = &mut self.stream {
+			let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+
+			if line.is_empty() {
+				return Err(IrcError::InvalidInput("Raw command must not be empty".to_string()));
+			}
+
+			if line.contains('\r') || line.contains('\n') {
+				return Err(IrcError::InvalidInput("Raw command must be a single line".to_string()));
+			}
+
+			let command = format!("{}\r\n", line);
+
+			stream.write_all(command.as_bytes())?;
+
+			debug!("Sent raw command: {}", line);
+			Ok(())
+		} else {
+			Err(IrcError::NotConnected)
 		}
 	}
 
 	/// Disconnect from the server
-	pub fn disconnect(&mut self) -> Result<(), String> {
+	pub fn disconnect(&mut self) -> Result<(), IrcError> {
 		if let Some(stream) = &mut self.stream {
 			// Send special command to request secure deletion of all messages
 			let command = "QUIT :SECURE_DELETE\r\n";
 
-			if let Err(e) = stream.write_all(command.as_bytes()) {
-				return Err(format!("Failed to send secure quit command: {}", e));
-			}
+			stream.write_all(command.as_bytes())?;
 
-			// Wait briefly for the server to process
-			std::thread::sleep(Duration::from_millis(500));
+			// The server answers a processed QUIT with ERROR before closing the connection
+			let acknowledged = self.await_reply("QUIT acknowledgment", REPLY_TIMEOUT, |message| match message {
+				ServerMessage::Error(_) => ReplyLine::Done(Ok(())),
+				_ => ReplyLine::Unrelated,
+			});
+			if let Err(e) = acknowledged {
+				debug!("No QUIT acknowledgment from server: {}", e);
+			}
 
 			self.stream = None;
 			self.channels.clear();
 			self.current_channel = None;
+			self.state = ConnectionState::Disconnected;
 
 			info!("Disconnected from server");
 			Ok(())
 		} else {
-			Err("Not connected to server".to_string())
+			Err(IrcError::NotConnected)
 		}
 	}
 
@@ -177,6 +1080,7 @@ impl IRCClient {
 			match stream.read(&mut buffer) {
 				Ok(size) => {
 					if size == 0 {
+						self.connection_lost();
 						return Err(Error::new(
 							ErrorKind::ConnectionAborted,
 							"Connection closed by server", 
@@ -191,6 +1095,7 @@ impl IRCClient {
 						// Just a timeout, not an error for our purposes
 						Err(Error::new(ErrorKind::WouldBlock, "No data available"))
 					} else {
+						self.connection_lost();
 						Err(e)
 					}
 				} 
@@ -203,8 +1108,272 @@ impl IRCClient {
 		}
 	}
 
+	// Drop the socket after the server closed it or it failed, so loops
+	// reading while connected stop instead of retrying a dead connection
+	fn connection_lost(&mut self) {
+		self.stream = None;
+		self.state = ConnectionState::Lost;
+	}
+
+	/// Read from the server and parse every complete line received
+	///
+	/// End-to-end encrypted messages are decrypted when a matching key has
+	/// been set; otherwise their ciphertext envelope is returned unchanged.
+	pub fn read_parsed(&mut self) -> Result<Vec<ServerMessage>, io::Error> {
+		// Lines set aside while awaiting a reply come first
+		if !self.pending.is_empty() {
+			return Ok(std::mem::take(&mut self.pending));
+		}
+
+		let data = self.read_message()?;
+		self.read_buffer.push_str(&data);
+
+		// Keep any trailing partial line for the next read
+		let mut messages = Vec::new();
+		while let Some(pos) = self.read_buffer.find('\n') {
+			let line: String = self.read_buffer.drain(..=pos).collect();
+			if line.trim().is_empty() {
+				continue;
+			}
+
+			let message = ServerMessage::parse(&line);
+
+			// Answer liveness checks so the server doesn't drop the connection
+			if let ServerMessage::Ping(token) = &message {
+				let _ = self.send_raw(&format!("PONG :{}", token));
+			}
+
+			// Remember the server's view of the session end for session_status
+			if let ServerMessage::SessionRemaining(remaining) = &message {
+				self.server_remaining = Some((*remaining, Instant::now()));
+			}
+
+			if let ServerMessage::SecurityNotice { kind, detail } = &message {
+				if self.security_notices.len() == MAX_SECURITY_NOTICES {
+					self.security_notices.pop_front();
+				}
+				self.security_notices.push_back((*kind, detail.clone()));
+			}
+
+			let message = self.decrypt_message(message);
+			messages.push(self.handle_file_event(message));
+		}
+
+		Ok(messages)
+	}
+
+	// Answer file offers and assemble accepted files as their data arrives. A finished
+	// file is reported as Saved or Failed in place of the sender's Done.
+	fn handle_file_event(&mut self, message: ServerMessage) -> ServerMessage {
+		let (source, event) = match message {
+			ServerMessage::File { source, event } => (source, event),
+			other => return other,
+		};
+
+		let event = match event {
+			FileEvent::Offer { id, name, size } => {
+				self.answer_file_offer(&source, &id, &name, size);
+				FileEvent::Offer { id, name, size }
+			}
+			FileEvent::Data { id, data } => match self.store_file_data(&id, &data) {
+				Ok(()) => FileEvent::Data { id, data },
+				Err(reason) => {
+					let _ = self.cancel_file(&id);
+					FileEvent::Failed { id, reason }
+				}
+			},
+			FileEvent::Done { id } => match self.finish_incoming_file(&id) {
+				Some(Ok(path)) => FileEvent::Saved { id, path },
+				Some(Err(reason)) => FileEvent::Failed { id, reason },
+				None => FileEvent::Done { id },
+			},
+			FileEvent::Cancel { id } => {
+				self.discard_incoming_file(&id);
+				FileEvent::Cancel { id }
+			}
+			other => other,
+		};
+
+		ServerMessage::File { source, event }
+	}
+
+	// Ask the file offer handler where to save an offered file, then accept or decline it
+	fn answer_file_offer(&mut self, sender: &str, id: &str, name: &str, size: u64) {
+		let path = match &mut self.file_offer_handler {
+			Some(handler) if self.channel_keys.contains_key(&sender.to_lowercase()) => {
+				handler(sender, name, size)
+			}
+			_ => None,
+		};
+
+		let path = match path {
+			Some(path) => path,
+			None => {
+				let _ = self.send_raw(&format!("CANCELFILE {}", id));
+				return;
+			}
+		};
+
+		let mut part_path = path.clone().into_os_string();
+		part_path.push(".part");
+		let part_path = PathBuf::from(part_path);
+
+		match File::create(&part_path) {
+			Ok(part) => {
+				self.incoming_files.insert(
+					id.to_string(),
+					IncomingFile {
+						sender: sender.to_string(),
+						path,
+						part_path,
+						part,
+					},
+				);
+				let _ = self.send_raw(&format!("ACCEPTFILE {}", id));
+			}
+			Err(e) => {
+				warn!("Declining file {} from {}: {}", name, sender, e);
+				let _ = self.send_raw(&format!("CANCELFILE {}", id));
+			}
+		}
+	}
+
+	// Append a chunk of an accepted transfer's encrypted stream to its .part file
+	fn store_file_data(&mut self, id: &str, data: &str) -> Result<(), String> {
+		let incoming = match self.incoming_files.get_mut(id) {
+			Some(incoming) => incoming,
+			None => return Ok(()),
+		};
+
+		let chunk = base64::decode(data.trim()).map_err(|e| format!("Invalid file data: {}", e))?;
+		incoming
+			.part
+			.write_all(&chunk)
+			.map_err(|e| format!("Failed to write {}: {}", incoming.part_path.display(), e))
+	}
+
+	// Decrypt a completed transfer into its destination, removing the encrypted copy.
+	// Returns None if the transfer isn't one we accepted.
+	fn finish_incoming_file(&mut self, id: &str) -> Option<Result<PathBuf, String>> {
+		let incoming = self.incoming_files.remove(id)?;
+		let key = self.channel_keys.get(&incoming.sender.to_lowercase()).copied();
+
+		let result = Self::decrypt_incoming_file(key, &incoming);
+		let _ = fs::remove_file(&incoming.part_path);
+
+		match result {
+			Ok(size) => {
+				info!("Received file from {} ({} bytes)", incoming.sender, size);
+				Some(Ok(incoming.path))
+			}
+			Err(e) => {
+				// Don't leave a partly decrypted file behind
+				let _ = fs::remove_file(&incoming.path);
+				Some(Err(e))
+			}
+		}
+	}
+
+	// Decrypt the stream in an incoming file's .part file: the 8-byte starting counter, then the frames
+	fn decrypt_incoming_file(key: Option<[u8; 32]>, incoming: &IncomingFile) -> Result<u64, String> {
+		let key = key.ok_or_else(|| format!("No end-to-end key set for {}", incoming.sender))?;
+
+		let mut part = File::open(&incoming.part_path)
+			.map_err(|e| format!("Failed to open {}: {}", incoming.part_path.display(), e))?;
+		let mut counter = [0u8; 8];
+		part.read_exact(&mut counter)
+			.map_err(|_| "Encrypted stream is truncated".to_string())?;
+
+		let output = File::create(&incoming.path)
+			.map_err(|e| format!("Failed to create {}: {}", incoming.path.display(), e))?;
+
+		Encryptor::new(key).decrypt_stream(
+			u64::from_be_bytes(counter),
+			BufReader::new(part),
+			BufWriter::new(output),
+		)
+	}
+
+	// Forget a transfer we were receiving and delete what arrived of it
+	fn discard_incoming_file(&mut self, id: &str) {
+		if let Some(incoming) = self.incoming_files.remove(id) {
+			drop(incoming.part);
+			let _ = fs::remove_file(&incoming.part_path);
+		}
+	}
+
+	// Encrypt a message body into an end-to-end envelope
+	fn encrypt_body(key: &[u8; 32], message: &str) -> Result<String, String> {
+		// A random counter keeps nonces unique across every sender sharing the key
+		let nonce: u64 = thread_rng().gen();
+		let ciphertext = Encryptor::new(*key).encrypt(nonce, message.as_bytes())?;
+
+		Ok(format!("{}{:016x}:{}", E2E_PREFIX, nonce, base64::encode(ciphertext)))
+	}
+
+	// Decrypt an end-to-end envelope, returning None if it is malformed or the key is wrong
+	fn decrypt_body(key: &[u8; 32], envelope: &str) -> Option<String> {
+		let (nonce, ciphertext) = envelope.strip_prefix(E2E_PREFIX)?.split_once(':')?;
+		let nonce = u64::from_str_radix(nonce, 16).ok()?;
+		let ciphertext = base64::decode(ciphertext.trim()).ok()?;
+		let plaintext = Encryptor::new(*key).decrypt(nonce, &ciphertext).ok()?;
+
+		String::from_utf8(plaintext).ok()
+	}
+
+	// Replace an encrypted message's envelope with its plaintext when we hold the key
+	fn decrypt_message(&self, message: ServerMessage) -> ServerMessage {
+		if let ServerMessage::Privmsg {
+			source,
+			target,
+			text,
+			encrypted: false,
+		} = &message
+		{
+			// Channel messages arrive as ":sender PRIVMSG #channel :<sender> body" and
+			// private ones as "PRIVMSG sender :body", so the target names the key
+			if let (Some(key), Some(start)) = (
+				self.channel_keys.get(&target.to_lowercase()),
+				text.find(E2E_PREFIX),
+			) {
+				if let Some(plaintext) = Self::decrypt_body(key, &text[start..]) {
+					// Keep any "<sender> " label the server put before the body
+					let label = text[..start].trim_end_matches(':');
+					return ServerMessage::Privmsg {
+						source: source.clone(),
+						target: target.clone(),
+						text: format!("{}{}", label, plaintext),
+						encrypted: true,
+					};
+				}
+			}
+		}
+
+		message
+	}
+
 	/// Get session duration
 	pub fn session_duration(&self) -> Duration {
 		Instant::now().duration_since(self.session_start)
 	}
+
+	/// Security notices seen by `read_parsed` (message expiry, inactivity warnings,
+	/// session expiry, ...), oldest first
+	///
+	/// Only the most recent 100 are kept.
+	pub fn security_notices(&self) -> impl Iterator<Item = (SecurityNoticeKind, &str)> {
+		self.security_notices.iter().map(|(kind, detail)| (*kind, detail.as_str()))
+	}
+
+	/// How long the session has run, and how long it has left
+	///
+	/// `remaining` is counted down from the last `SESSION_REMAINING` notice seen
+	/// by `read_parsed`, and is `None` until the server has sent one.
+	pub fn session_status(&self) -> SessionStatus {
+		let remaining = self
+			.server_remaining
+			.map(|(remaining, received_at)| remaining.saturating_sub(received_at.elapsed()));
+
+		SessionStatus::new(self.session_duration(), remaining)
+	}
 }
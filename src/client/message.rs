@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A line received from the server, parsed into the parts clients care about
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+	/// Numeric reply such as 001 (welcome) or 322 (LIST entry)
+	Numeric {
+		code: u16,
+		params: Vec<String>,
+		text: String,
+	},
+	/// Channel or private message
+	Privmsg {
+		source: Option<String>, // Line prefix (the channel for channel messages)
+		target: String,         // First parameter (the sender's nick for private messages)
+		text: String,
+		encrypted: bool, // Whether the text was decrypted from an end-to-end envelope
+	},
+	/// Server notice
+	Notice { text: String },
+	/// Authoritative time left before the server ends the session, from
+	/// `NOTICE :SESSION_REMAINING <seconds>`; feed it to `SessionHandle::sync_remaining`
+	SessionRemaining(Duration),
+	/// Server-wide announcement from an operator, from `NOTICE :ANNOUNCEMENT: <text>`
+	Announcement(String),
+	/// Security notice from `NOTICE :SECURITY: <detail>`, classified by `kind`
+	SecurityNotice {
+		kind: SecurityNoticeKind,
+		detail: String,
+	},
+	/// Transient "is typing" notification from `source` in a channel or private chat
+	Typing { source: String, target: String },
+	/// Liveness check; the client must answer with `PONG :<token>`
+	Ping(String),
+	/// File transfer event from `source` (see `IRCClient::send_file`)
+	File { source: String, event: FileEvent },
+	/// IRCv3 standard reply: `command` failed for the reason named by `code`
+	Fail {
+		command: String,
+		code: String,
+		text: String,
+	},
+	/// Fatal error sent before the server closes the connection
+	Error(String),
+	/// Any other line, kept verbatim
+	Other(String),
+}
+
+/// What a `SECURITY:` notice from the server reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecurityNoticeKind {
+	/// How long messages are kept, sent on connect ("All messages will be deleted after 1h")
+	Retention,
+	/// Channel or private messages reached their TTL and were deleted
+	MessageExpiry,
+	/// The session will end soon unless there is some activity
+	InactivityWarning,
+	/// The session ended for inactivity and its messages were deleted
+	SessionExpired,
+	/// Any other security notice, e.g. from a custom connect template
+	Other,
+}
+
+impl SecurityNoticeKind {
+	/// Classify the text of a notice after its `SECURITY: ` prefix
+	pub fn classify(detail: &str) -> SecurityNoticeKind {
+		if detail.starts_with("All messages will be deleted after") {
+			SecurityNoticeKind::Retention
+		} else if detail.contains("have been automatically deleted") {
+			SecurityNoticeKind::MessageExpiry
+		} else if detail.starts_with("You will be disconnected for inactivity") {
+			SecurityNoticeKind::InactivityWarning
+		} else if detail.starts_with("You have been disconnected due to inactivity") {
+			SecurityNoticeKind::SessionExpired
+		} else {
+			SecurityNoticeKind::Other
+		}
+	}
+}
+
+/// Step of a relayed file transfer, from a `:source FILE <event> <id> ...` line
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileEvent {
+	/// Our SENDFILE was registered as transfer `id`; waiting for `nick` to accept
+	Pending { id: String, nick: String },
+	/// `source` offers us a file
+	Offer { id: String, name: String, size: u64 },
+	/// The recipient accepted our offer
+	Accept { id: String },
+	/// One base64 chunk of the encrypted stream
+	Data { id: String, data: String },
+	/// The sender has relayed the whole stream
+	Done { id: String },
+	/// The other side (or the server, when it disconnected) ended the transfer
+	Cancel { id: String },
+	/// A file we accepted was received, decrypted and saved; reported by the client itself
+	Saved { id: String, path: PathBuf },
+	/// A file we accepted could not be decrypted or saved; reported by the client itself
+	Failed { id: String, reason: String },
+}
+
+impl ServerMessage {
+	/// Parse a single line received from the server
+	pub fn parse(line: &str) -> ServerMessage {
+		let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+
+		// IRCv3 message tags ("@time=...;msgid=... ") are skipped
+		let line = match line.strip_prefix('@') {
+			Some(tagged) => tagged.split_once(' ').map(|(_, rest)| rest).unwrap_or(""),
+			None => line,
+		};
+
+		// Optional ":prefix " at the start of the line
+		let (prefix, rest) = match line.strip_prefix(':') {
+			Some(stripped) => match stripped.split_once(' ') {
+				Some((prefix, rest)) => (Some(prefix.to_string()), rest),
+				None => (Some(stripped.to_string()), ""),
+			},
+			None => (None, line),
+		};
+
+		// Everything after " :" is the trailing text parameter
+		let (head, text) = match rest.split_once(" :") {
+			Some((head, text)) => (head, text.to_string()),
+			None => (rest, String::new()),
+		};
+
+		let mut words = head.split_whitespace();
+		let command = words.next().unwrap_or("").to_uppercase();
+		let params: Vec<String> = words.map(String::from).collect();
+
+		if command.len() == 3 {
+			if let Ok(code) = command.parse::<u16>() {
+				return ServerMessage::Numeric { code, params, text };
+			}
+		}
+
+		match command.as_str() {
+			"PRIVMSG" => ServerMessage::Privmsg {
+				source: prefix,
+				target: params.first().cloned().unwrap_or_default(),
+				text,
+				encrypted: false,
+			},
+			"NOTICE" => match text
+				.strip_prefix("SESSION_REMAINING ")
+				.and_then(|seconds| seconds.trim().parse::<u64>().ok())
+			{
+				Some(seconds) => ServerMessage::SessionRemaining(Duration::from_secs(seconds)),
+				None => match text.strip_prefix("ANNOUNCEMENT: ") {
+					Some(announcement) => ServerMessage::Announcement(announcement.to_string()),
+					None => match text.strip_prefix("SECURITY: ") {
+						Some(detail) => ServerMessage::SecurityNotice {
+							kind: SecurityNoticeKind::classify(detail),
+							detail: detail.to_string(),
+						},
+						None => ServerMessage::Notice { text },
+					},
+				},
+			},
+			"TYPING" => ServerMessage::Typing {
+				source: prefix.unwrap_or_default(),
+				target: params.first().cloned().unwrap_or_default(),
+			},
+			"FILE" => match Self::parse_file_event(&params, text) {
+				Some(event) => ServerMessage::File {
+					source: prefix.unwrap_or_default(),
+					event,
+				},
+				None => ServerMessage::Other(line.to_string()),
+			},
+			"FAIL" => ServerMessage::Fail {
+				command: params.first().cloned().unwrap_or_default(),
+				code: params.get(1).cloned().unwrap_or_default(),
+				text,
+			},
+			"ERROR" => ServerMessage::Error(text),
+			"PING" => ServerMessage::Ping(if text.is_empty() {
+				params.first().cloned().unwrap_or_default()
+			} else {
+				text
+			}),
+			_ => ServerMessage::Other(line.to_string()),
+		}
+	}
+
+	// Parse the parameters of a FILE line; None if they are malformed
+	fn parse_file_event(params: &[String], text: String) -> Option<FileEvent> {
+		let id = params.get(1)?.clone();
+
+		match params.first()?.as_str() {
+			"PENDING" => Some(FileEvent::Pending {
+				id,
+				nick: params.get(2)?.clone(),
+			}),
+			"OFFER" => Some(FileEvent::Offer {
+				id,
+				name: params.get(2)?.clone(),
+				size: params.get(3)?.parse().ok()?,
+			}),
+			"ACCEPT" => Some(FileEvent::Accept { id }),
+			"DATA" => Some(FileEvent::Data { id, data: text }),
+			"DONE" => Some(FileEvent::Done { id }),
+			"CANCEL" => Some(FileEvent::Cancel { id }),
+			_ => None,
+		}
+	}
+}
@@ -1,7 +1,11 @@
 // Export client modules
+pub mod builder;
 pub mod client;
+pub mod message;
 pub mod monitor;
 
 // Re-export main types
-pub use client::IRCClient;
-pub use monitor::SessionMonitor;
+pub use builder::IRCClientBuilder;
+pub use client::{ChannelInfo, ConnectionState, IRCClient, SearchResult, ServerTime, SessionStatus, WhoEntry, WhoisInfo};
+pub use message::{FileEvent, SecurityNoticeKind, ServerMessage};
+pub use monitor::{SessionHandle, SessionMonitor};
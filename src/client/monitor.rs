@@ -1,12 +1,56 @@
 use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::utils::duration::format_duration;
+use crate::utils::sync::lock_or_recover;
+
+/// Cloneable handle for updating a running `SessionMonitor`
+#[derive(Clone)]
+pub struct SessionHandle {
+	start_time: Arc<Mutex<Instant>>,
+	last_activity: Arc<Mutex<Instant>>,
+	inactivity_timeout: Duration,
+}
+
+impl SessionHandle {
+	/// Update last activity time
+	pub fn update_activity(&self) {
+		let mut guard = lock_or_recover(&self.last_activity);
+		*guard = Instant::now();
+	}
+
+	/// Restart the session lifetime and activity clock, re-arming all warnings
+	///
+	/// Both timestamps are updated while holding both locks, so the monitor
+	/// never observes a reset start time with a stale activity time.
+	pub fn reset_session(&self) {
+		let mut start = lock_or_recover(&self.start_time);
+		let mut activity = lock_or_recover(&self.last_activity);
+		let now = Instant::now();
+		*start = now;
+		*activity = now;
+	}
+
+	/// Align the inactivity countdown with the time the server says is left
+	///
+	/// Call this with each `ServerMessage::SessionRemaining` so warnings and
+	/// expiry follow the server's clock rather than drifting local time.
+	/// Remaining times beyond the local inactivity timeout count as fully active.
+	pub fn sync_remaining(&self, remaining: Duration) {
+		let now = Instant::now();
+		let idle = self.inactivity_timeout.saturating_sub(remaining);
+		let mut guard = lock_or_recover(&self.last_activity);
+		*guard = now.checked_sub(idle).unwrap_or(now);
+	}
+}
+
 /// Session monitor to track session lifetime and inactivity
 pub struct SessionMonitor {
-	/// When the session started
-	start_time: Instant,
+	/// When the session started (shared so the session can be extended while monitoring)
+	start_time: Arc<Mutex<Instant>>,
 	/// Maximum session duration
 	max_duration: Duration,
 	/// Time of last activity
@@ -17,18 +61,24 @@ pub struct SessionMonitor {
 	warning_threshold: Duration,
 	/// Callback for session expiration
 	on_expire: Option<Box<dyn Fn() + Send>>,
+	/// Callback for expiry warnings, given the time remaining
+	on_warn: Option<Box<dyn Fn(Duration) + Send>>,
+	/// Set to stop the monitoring thread without firing callbacks
+	stopped: Arc<AtomicBool>,
 }
 
 impl SessionMonitor {
 	/// Create a new session monitor
 	pub fn new(max_duration: Duration, inactivity_timeout: Duration) -> Self {
 		SessionMonitor {
-			start_time: Instant::now(),
+			start_time: Arc::new(Mutex::new(Instant::now())),
 			max_duration,
 			last_activity: Arc::new(Mutex::new(Instant::now())),
 			inactivity_timeout,
 			warning_threshold: inactivity_timeout.saturating_sub(Duration::from_secs(300)), // 5 minutes before timeout
 			on_expire: None,
+			on_warn: None,
+			stopped: Arc::new(AtomicBool::new(false)),
 		}
 	}
 
@@ -41,6 +91,19 @@ impl SessionMonitor {
 		self
 	}
 
+	/// Set a callback to be called when the session is about to expire
+	///
+	/// Fires once when the inactivity warning threshold is crossed (again after
+	/// activity resumes) and once when nearing the maximum session duration,
+	/// receiving the time remaining in each case.
+	pub fn on_warn<F>(mut self, callback: F) -> Self
+	where
+		F: Fn(Duration) + Send + 'static,
+	{
+		self.on_warn = Some(Box::new(callback));
+		self
+	}
+
 	/// Get a handle to update activity
 	pub fn activity_handle(&self) -> Arc<Mutex<Instant>> {
 		self.last_activity.clone()
@@ -48,10 +111,41 @@ impl SessionMonitor {
 
 	/// Update last activity time
 	pub fn update_activity(&self) {
-		let mut guard = self.last_activity.lock().unwrap();
+		let mut guard = lock_or_recover(&self.last_activity);
 		*guard = Instant::now();
 	}
 
+	/// Get a handle that can update activity or reset the session after monitoring starts
+	pub fn session_handle(&self) -> SessionHandle {
+		SessionHandle {
+			start_time: self.start_time.clone(),
+			last_activity: self.last_activity.clone(),
+			inactivity_timeout: self.inactivity_timeout,
+		}
+	}
+
+	/// Restart the session lifetime and activity clock, re-arming all warnings
+	pub fn reset_session(&self) {
+		self.session_handle().reset_session();
+	}
+
+	/// Align the inactivity countdown with the time the server says is left
+	pub fn sync_remaining(&self, remaining: Duration) {
+		self.session_handle().sync_remaining(remaining);
+	}
+
+	/// Get a handle that stops monitoring when set to `true`
+	///
+	/// Take this before calling `start_monitoring`, which consumes the monitor.
+	pub fn stop_handle(&self) -> Arc<AtomicBool> {
+		self.stopped.clone()
+	}
+
+	/// Stop monitoring without firing the expiration callback
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::SeqCst);
+	}
+
 	/// Start monitoring session in a background thread
 	pub fn start_monitoring(self) -> thread::JoinHandle<()> {
 		thread::spawn(move || {
@@ -63,18 +157,38 @@ impl SessionMonitor {
 	fn monitor_session(self) {
 		let mut warned_about_inactivity = false;
 		let mut warned_about_duration = false;
+		let mut observed_start = *lock_or_recover(&self.start_time);
 
 		loop {
-			thread::sleep(Duration::from_secs(30)); // Check every 30 seconds
+			// Check every 30 seconds, waking early if stopped
+			for _ in 0..30 {
+				if self.stopped.load(Ordering::SeqCst) {
+					break;
+				}
+				thread::sleep(Duration::from_secs(1));
+			}
+
+			if self.stopped.load(Ordering::SeqCst) {
+				info!("Session monitor stopped");
+				break;
+			}
+
+			// Re-arm warnings if the session was reset since the last check
+			let start_time = *lock_or_recover(&self.start_time);
+			if start_time != observed_start {
+				observed_start = start_time;
+				warned_about_duration = false;
+				warned_about_inactivity = false;
+			}
 
 			let now = Instant::now();
-			let session_duration = now.duration_since(self.start_time);
+			let session_duration = now.duration_since(start_time);
 
 			// Check if we've exceeded the absolute session limit
 			if session_duration >= self.max_duration {
 				info!(
-					"Session maximum duration reached ({:?}).",
-					self.max_duration
+					"Session maximum duration reached ({}).",
+					format_duration(self.max_duration)
 				);
 
 				if let Some(callback) = &self.on_expire {
@@ -90,18 +204,25 @@ impl SessionMonitor {
 			{
 				warn!("Session will expire in less than 5 minutes");
 				warned_about_duration = true;
+
+				if let Some(callback) = &self.on_warn {
+					callback(self.max_duration.saturating_sub(session_duration));
+				}
 			}
 
 			// Check if there's been no activity for too long
 			let last_active = {
-				let guard = self.last_activity.lock().unwrap();
+				let guard = lock_or_recover(&self.last_activity);
 				*guard
 			};
 
 			let idle_time = now.duration_since(last_active);
 
 			if idle_time >= self.inactivity_timeout {
-				info!("Session inactive for {:?}, exceeding timeout", idle_time);
+				info!(
+					"Session inactive for {}, exceeding timeout",
+					format_duration(idle_time)
+				);
 
 				if let Some(callback) = &self.on_expire {
 					callback();
@@ -118,6 +239,10 @@ impl SessionMonitor {
 					idle_time, remaining
 				);
 				warned_about_inactivity = true;
+
+				if let Some(callback) = &self.on_warn {
+					callback(remaining);
+				}
 			} else if warned_about_inactivity && idle_time < self.warning_threshold {
 				// Reset warning if activity resumed
 				warned_about_inactivity = false;
@@ -127,13 +252,14 @@ impl SessionMonitor {
 
 	/// Get session duration
 	pub fn duration(&self) -> Duration {
-		Instant::now().duration_since(self.start_time)
+		let start_time = *lock_or_recover(&self.start_time);
+		Instant::now().duration_since(start_time)
 	}
 
 	/// Get idle time
 	pub fn idle_time(&self) -> Duration {
 		let last_active = {
-			let guard = self.last_activity.lock().unwrap();
+			let guard = lock_or_recover(&self.last_activity);
 			*guard
 		};
 		Instant::now().duration_since(last_active)
@@ -150,18 +276,8 @@ impl SessionMonitor {
 	}
 
 	/// Format duration in a human-readable way
+	#[deprecated(note = "use irc_server::utils::format_duration")]
 	pub fn format_duration(duration: Duration) -> String {
-		let total_seconds = duration.as_secs();
-		let hours = total_seconds / 3600;
-		let minutes = (total_seconds % 3600) / 60;
-		let seconds = total_seconds % 60;
-
-		if hours > 0 {
-			format!("{}h {}m {}s", hours, minutes, seconds)
-		} else if minutes > 0 {
-			format!("{}m {}s", minutes, seconds)
-		} else {
-			format!("{}s", seconds)
-		}
+		format_duration(duration)
 	}
 }
@@ -0,0 +1,54 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Errors returned by the public client, server and token APIs
+///
+/// Internal helpers still report failures as `String`; those convert into
+/// `IrcError::Other`, so `?` works across layers.
+#[derive(Debug, Error)]
+pub enum IrcError {
+	/// The client has no open connection to the server
+	#[error("Not connected to server")]
+	NotConnected,
+
+	/// The server rejected the presented credentials
+	#[error("Authentication failed: {0}")]
+	AuthFailed(String),
+
+	/// A token or signing key could not be created or verified
+	#[error("Invalid token: {0}")]
+	TokenInvalid(String),
+
+	/// A network or file operation failed
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+
+	/// The named channel does not exist
+	#[error("No such channel: {0}")]
+	ChannelNotFound(String),
+
+	/// No connected user has the given nick
+	#[error("No such nick: {0}")]
+	NickNotFound(String),
+
+	/// An argument was malformed or out of range
+	#[error("{0}")]
+	InvalidInput(String),
+
+	/// Any other failure, described by its message
+	#[error("{0}")]
+	Other(String),
+}
+
+impl From<String> for IrcError {
+	fn from(message: String) -> Self {
+		IrcError::Other(message)
+	}
+}
+
+impl From<jsonwebtoken::errors::Error> for IrcError {
+	fn from(e: jsonwebtoken::errors::Error) -> Self {
+		IrcError::TokenInvalid(e.to_string())
+	}
+}
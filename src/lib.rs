@@ -1,9 +1,11 @@
 // Export modules
 pub mod client;
+pub mod error;
 pub mod server;
 pub mod utils;
 
 // Re-export main types
 pub use client::client::IRCClient;
+pub use error::IrcError;
 pub use server::facade::IRCServerFacade;
 pub use utils::token::TokenGenerator;
@@ -1,8 +1,38 @@
 use base64::encode;
 use irc_server::server::facade::IRCServerFacade;
-use log::{error, info};
+use log::{error, info, warn};
 use ring::rand::SystemRandom;
 use std::env;
+use std::fs;
+use std::thread;
+
+// Environment variable holding the JWT secret shared with the token generator
+const JWT_SECRET_ENV: &str = "IRC_JWT_SECRET";
+
+// Read the JWT secret from `secret_file` if given, otherwise from IRC_JWT_SECRET.
+// Returns None when neither is set, so the caller can fall back to a random secret.
+fn configured_jwt_secret(secret_file: Option<&str>) -> Result<Option<String>, String> {
+	let (secret, source) = match secret_file {
+		Some(path) => {
+			let contents = fs::read_to_string(path)
+				.map_err(|e| format!("Failed to read JWT secret file {}: {}", path, e))?;
+			(contents, path.to_string())
+		}
+		None => match env::var(JWT_SECRET_ENV) {
+			Ok(value) => (value, JWT_SECRET_ENV.to_string()),
+			Err(_) => return Ok(None),
+		},
+	};
+
+	// Files usually end with a newline that isn't part of the secret
+	let secret = secret.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
+	if secret.is_empty() {
+		return Err(format!("JWT secret from {} is empty", source));
+	}
+
+	info!("Using JWT secret from {}", source);
+	Ok(Some(secret))
+}
 
 fn main() {
 	env_logger::init();
@@ -25,17 +55,34 @@ fn main() {
 		.unwrap_or_else(|| "1".to_string())
 		.parse::<u64>()
 		.unwrap_or(1);
+	let jwt_secret_file = args.get(4).map(String::as_str);
 
-	// Generate a secure random JWT secret
-	let mut jwt_secret = [0u8; 32];
-	let rng = SystemRandom::new();
-	if let Err(e) = rng.fill(&mut jwt_secret) {
-		error!("Failed to generate secure JWT secret: {:?}", e);
-		return;
-	}
+	// Use the configured secret so tokens from the token generator stay valid across restarts
+	let jwt_secret_str = match configured_jwt_secret(jwt_secret_file) {
+		Ok(Some(secret)) => secret,
+		Ok(None) => {
+			// Generate a secure random JWT secret, for development use
+			let mut jwt_secret = [0u8; 32];
+			let rng = SystemRandom::new();
+			if let Err(e) = rng.fill(&mut jwt_secret) {
+				error!("Failed to generate secure JWT secret: {:?}", e);
+				return;
+			}
 
-	let jwt_secret_str = encode(&jwt_secret);
-	info!("Generated secure JWT secret: {}", jwt_secret_str);
+			let jwt_secret_str = encode(&jwt_secret);
+			warn!(
+				"No JWT secret configured (set {} or pass a secret file); using a random one. \
+				 Tokens will stop working when the server restarts.",
+				JWT_SECRET_ENV
+			);
+			info!("Generated secure JWT secret: {}", jwt_secret_str);
+			jwt_secret_str
+		}
+		Err(e) => {
+			error!("{}", e);
+			return;
+		}
+	};
 
 	// Create and configure the server
 	let server = IRCServerFacade::new(&jwt_secret_str);
@@ -55,6 +102,22 @@ fn main() {
 		"Starting secure IRC server on {} with message auto-deletion after {} hour(s)",
 		bind_address, message_ttl_hours
 	);
+
+	// A comma-separated list (e.g. "0.0.0.0:6667,[::]:6667") serves every address
+	let addresses: Vec<&str> = bind_address.split(',').map(str::trim).collect();
+	if addresses.len() > 1 {
+		let results = server.start_multi(&addresses);
+		if results.iter().all(|r| r.is_err()) {
+			error!("Failed to start server: no address could be bound");
+			return;
+		}
+
+		// The accept loops run on background threads
+		loop {
+			thread::park();
+		}
+	}
+
 	match server.start(&bind_address) {
 		Ok(_) => info!("Server started successfully"),
 		Err(e) => error!("Failed to start server: {}", e),
@@ -0,0 +1,126 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde_json::{json, Value};
+
+// Connection events recorded in the audit log. None of them carry message
+// contents or profile pictures, which must stay ephemeral.
+pub enum AuditEvent<'a> {
+	// A user authenticated, or reclaimed a dropped session with RESUME
+	Connect {
+		user_id: &'a str,
+		username: &'a str,
+		ip: Option<IpAddr>,
+		jti: Option<&'a str>,
+		resumed: bool,
+	},
+	// A token or resume token was rejected
+	AuthFailure {
+		ip: Option<IpAddr>,
+		reason: &'a str,
+	},
+	// An authenticated connection closed
+	Disconnect {
+		user_id: &'a str,
+		ip: Option<IpAddr>,
+		reason: &'a str,
+	},
+	// An admin forcibly disconnected a user
+	Kill {
+		user_id: &'a str,
+		by: &'a str,
+		reason: &'a str,
+	},
+}
+
+impl AuditEvent<'_> {
+	// The event as a JSON object stamped with the current time
+	fn to_json(&self) -> Value {
+		let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+		let ip_text = |ip: &Option<IpAddr>| ip.map(|ip| ip.to_string());
+
+		match self {
+			AuditEvent::Connect {
+				user_id,
+				username,
+				ip,
+				jti,
+				resumed,
+			} => json!({
+				"time": time,
+				"event": "connect",
+				"user_id": user_id,
+				"username": username,
+				"ip": ip_text(ip),
+				"jti": jti,
+				"resumed": resumed,
+			}),
+			AuditEvent::AuthFailure { ip, reason } => json!({
+				"time": time,
+				"event": "auth_failure",
+				"ip": ip_text(ip),
+				"reason": reason,
+			}),
+			AuditEvent::Disconnect {
+				user_id,
+				ip,
+				reason,
+			} => json!({
+				"time": time,
+				"event": "disconnect",
+				"user_id": user_id,
+				"ip": ip_text(ip),
+				"reason": reason,
+			}),
+			AuditEvent::Kill {
+				user_id,
+				by,
+				reason,
+			} => json!({
+				"time": time,
+				"event": "kill",
+				"user_id": user_id,
+				"by": by,
+				"reason": reason,
+			}),
+		}
+	}
+}
+
+// Append-only audit log with one JSON object per line. Lines are written by a
+// dedicated thread, so recording an event never waits on the disk while the
+// server lock is held.
+pub struct AuditLog {
+	sender: Sender<String>,
+}
+
+impl AuditLog {
+	// Open (or create) the log file for appending and start its writer thread
+	pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+		let (sender, receiver) = mpsc::channel::<String>();
+
+		// Runs until the log is replaced or the server is dropped
+		thread::spawn(move || {
+			for line in receiver {
+				if let Err(e) = file.write_all(line.as_bytes()) {
+					warn!("Failed to write audit log: {}", e);
+				}
+			}
+		});
+
+		Ok(AuditLog { sender })
+	}
+
+	// Queue an event for writing
+	pub fn record(&self, event: &AuditEvent) {
+		let line = format!("{}\n", event.to_json());
+		let _ = self.sender.send(line);
+	}
+}
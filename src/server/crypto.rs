@@ -3,6 +3,13 @@ use ring::aead::{
 };
 use ring::error::Unspecified;
 use ring::rand::SecureRandom;
+use std::io::{self, Read, Write};
+
+// Plaintext bytes carried by each frame of an encrypted stream
+pub const FRAME_SIZE: usize = 16 * 1024;
+
+// Bytes each frame adds to its plaintext: the 4-byte length prefix and the 16-byte GCM tag
+pub const FRAME_OVERHEAD: usize = 4 + 16;
 
 // Custom nonce sequence for AES-GCM
 pub struct CounterNonceSequence {
@@ -38,6 +45,8 @@ impl Encryptor {
 		}
 	}
 
+	// Seal `message` with nonce counter `counter`, which must never be reused with this
+	// key; session data should be encrypted through Session::encrypt, which allocates it
 	pub fn encrypt(&self, counter: u64, message: &[u8]) -> Result<Vec<u8>, String> {
 		let unbound_key = match UnboundKey::new(&AES_256_GCM, &self.key) {
 			Ok(k) => k,
@@ -78,6 +87,101 @@ impl Encryptor {
 		}
 	}
 
+	// Encrypt everything `reader` yields into `writer` as a series of frames, without
+	// holding more than one frame in memory. Each frame is a 4-byte big-endian length
+	// followed by the ciphertext of up to FRAME_SIZE bytes, sealed with nonce counter
+	// `counter + index`; an empty frame marks the end, so a truncated stream is detected.
+	// The stream uses one counter per frame plus one, none of which may be reused with this key.
+	// Returns the number of plaintext bytes encrypted.
+	pub fn encrypt_stream<R: Read, W: Write>(
+		&self,
+		counter: u64,
+		mut reader: R,
+		mut writer: W,
+	) -> Result<u64, String> {
+		let mut buffer = vec![0u8; FRAME_SIZE];
+		let mut frame_counter = counter;
+		let mut total = 0u64;
+
+		loop {
+			let len = read_full(&mut reader, &mut buffer)
+				.map_err(|e| format!("Failed to read plaintext: {}", e))?;
+			let frame = self.encrypt(frame_counter, &buffer[..len])?;
+
+			writer
+				.write_all(&(frame.len() as u32).to_be_bytes())
+				.and_then(|_| writer.write_all(&frame))
+				.map_err(|e| format!("Failed to write frame: {}", e))?;
+
+			if len == 0 {
+				break;
+			}
+			total += len as u64;
+			frame_counter = frame_counter
+				.checked_add(1)
+				.ok_or("Nonce counter exhausted")?;
+		}
+
+		writer
+			.flush()
+			.map_err(|e| format!("Failed to write frame: {}", e))?;
+		Ok(total)
+	}
+
+	// Decrypt a stream written by encrypt_stream with the same starting counter, writing
+	// the plaintext to `writer` frame by frame. Fails if a frame is tampered with, out of
+	// order or missing, including when the stream ends before its closing empty frame.
+	// Nothing after the closing frame is read. Returns the number of plaintext bytes written.
+	pub fn decrypt_stream<R: Read, W: Write>(
+		&self,
+		counter: u64,
+		mut reader: R,
+		mut writer: W,
+	) -> Result<u64, String> {
+		let max_frame_len = FRAME_SIZE + AES_256_GCM.tag_len();
+		let mut frame = vec![0u8; max_frame_len];
+		let mut frame_counter = counter;
+		let mut total = 0u64;
+
+		loop {
+			let mut len_bytes = [0u8; 4];
+			match read_full(&mut reader, &mut len_bytes) {
+				Ok(4) => {}
+				Ok(_) => return Err("Encrypted stream is truncated".to_string()),
+				Err(e) => return Err(format!("Failed to read frame: {}", e)),
+			}
+
+			let len = u32::from_be_bytes(len_bytes) as usize;
+			if len > max_frame_len {
+				return Err("Frame too large".to_string());
+			}
+
+			match read_full(&mut reader, &mut frame[..len]) {
+				Ok(n) if n == len => {}
+				Ok(_) => return Err("Encrypted stream is truncated".to_string()),
+				Err(e) => return Err(format!("Failed to read frame: {}", e)),
+			}
+
+			let plaintext = self.decrypt(frame_counter, &frame[..len])?;
+			if plaintext.is_empty() {
+				break;
+			}
+
+			writer
+				.write_all(&plaintext)
+				.map_err(|e| format!("Failed to write plaintext: {}", e))?;
+			total += plaintext.len() as u64;
+			frame_counter = frame_counter
+				.checked_add(1)
+				.ok_or("Nonce counter exhausted")?;
+		}
+
+		writer
+			.flush()
+			.map_err(|e| format!("Failed to write plaintext: {}", e))?;
+		Ok(total)
+	}
+
 	pub fn generate_random_key() -> Result<[u8; 32], String> {
 		let mut key = [0u8; 32];
 		let rng = ring::rand::SystemRandom::new();
@@ -88,3 +192,19 @@ impl Encryptor {
 		}
 	}
 }
+
+// Fill `buffer` from `reader`, stopping early only at end of input; returns the bytes read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+
+	while filled < buffer.len() {
+		match reader.read(&mut buffer[filled..]) {
+			Ok(0) => break,
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(filled)
+}
@@ -1,20 +1,62 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
-use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
+use jsonwebtoken::{decode, DecodingKey, Algorithm};
 use log::{info, error, debug, warn};
 use base64::decode as base64_decode;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use zeroize::Zeroizing;
 
-use crate::server::models::{User, Channel, TokenClaims, ChatMessage};
+use crate::error::IrcError;
+use crate::server::audit::{AuditEvent, AuditLog};
+use crate::server::models::{User, Channel, ChannelCreationPolicy, ChannelInfo, ChannelStats, ChatMessage, ServerStats, TokenClaims, UserInfo};
 use crate::server::session::Session;
 use crate::server::crypto::Encryptor;
-use crate::server::handler::{MessageHandler, ServerState};
+use crate::server::handler::{
+	broadcast_announcement, constant_time_eq, is_hmac_algorithm, is_rsa_algorithm, negotiate_cap, new_message_id, render_template,
+	tag_line, valid_avatar_ref, validate_username, MessageHandler, ServerState, DEFAULT_SECURITY_NOTICE_TEMPLATE,
+	DEFAULT_WELCOME_TEMPLATE, MAX_CHANNEL_NAME_LEN, MAX_NICK_LENGTH_LIMIT, SASL_CHUNK_SIZE, TOPIC_LEN_LIMIT,
+};
+use crate::server::transport::{
+	websocket_writer, JsonLineReader, JsonSink, LineReader, QueuedSink, SharedSink, TcpLineReader, WebSocketLineReader,
+	WebSocketReadStream, WebSocketSink,
+};
+use crate::utils::clock::Clock;
+use crate::utils::duration::format_duration;
+use crate::utils::sync::lock_or_recover;
+
+// What a plain TCP listener's clients speak
+#[derive(Clone, Copy)]
+enum LineProtocol {
+	Irc,  // IRC text lines
+	Json, // One JSON object per line (see json_protocol)
+}
+
+// One of an IP address's connection slots, released when the connection ends
+struct IpSlot {
+	server: Arc<Mutex<ServerState>>,
+	ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+	fn drop(&mut self) {
+		let mut server = lock_or_recover(&self.server);
+		if let Some(count) = server.connections_per_ip.get_mut(&self.ip) {
+			*count = count.saturating_sub(1);
+			if *count == 0 {
+				server.connections_per_ip.remove(&self.ip);
+			}
+		}
+	}
+}
 
 // IRC Server Facade - The main interface to the IRC server
 pub struct IRCServerFacade {
@@ -23,13 +65,7 @@ pub struct IRCServerFacade {
 
 impl IRCServerFacade {
 	pub fn new(jwt_secret: &str) -> Self {
-		let server_state = ServerState {
-			users: HashMap::new(),
-			channels: HashMap::new(),
-			jwt_secret: jwt_secret.to_string(),
-			message_ttl: Duration::from_secs(3600), // 1 hour default
-			session_timeout: Duration::from_secs(3600), // 1 hour default
-		};
+		let server_state = ServerState::new(jwt_secret);
 		
 		let server = Arc::new(Mutex::new(server_state));
 		
@@ -43,11 +79,8 @@ impl IRCServerFacade {
 	}
 	
 	// Set the message time-to-live (how long before messages auto-delete)
-	pub fn set_message_ttl(&self, hours: u64) -> Result<(), String> {
-		let mut server = match self.server.lock() {
-			Ok(s) => s,
-			Err(_) => return Err("Failed to lock server for TTL update".to_string()),
-		};
+	pub fn set_message_ttl(&self, hours: u64) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
 		
 		server.message_ttl = Duration::from_secs(hours * 3600);
 		info!("Message TTL set to {} hours", hours);
@@ -55,36 +88,622 @@ impl IRCServerFacade {
 	}
 	
 	// Set the session timeout duration
-	pub fn set_session_timeout(&self, hours: u64) -> Result<(), String> {
-		let mut server = match self.server.lock() {
-			Ok(s) => s,
-			Err(_) => return Err("Failed to lock server for timeout update".to_string()),
-		};
+	pub fn set_session_timeout(&self, hours: u64) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
 		
 		server.session_timeout = Duration::from_secs(hours * 3600);
 		info!("Session timeout set to {} hours", hours);
 		Ok(())
 	}
 	
-	// Start the server
-	pub fn start(&self, address: &str) -> Result<(), String> {
-		let listener = match TcpListener::bind(address) {
-			Ok(l) => l,
-			Err(e) => return Err(format!("Failed to bind to address: {}", e)),
+	// Set how long before the inactivity timeout users are warned
+	pub fn set_inactivity_warning(&self, minutes: u64) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.inactivity_warning = Duration::from_secs(minutes * 60);
+		info!("Inactivity warning set to {} minutes before timeout", minutes);
+		Ok(())
+	}
+	
+	// Set how long a PING may go unanswered before the connection is treated as dead
+	pub fn set_ping_timeout(&self, seconds: u64) -> Result<(), IrcError> {
+		if seconds == 0 {
+			return Err(IrcError::InvalidInput("Ping timeout must be greater than zero".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.ping_timeout = Duration::from_secs(seconds);
+		info!("Ping timeout set to {} seconds", seconds);
+		Ok(())
+	}
+	
+	// Set how long a session survives a dropped connection, waiting for the client
+	// to RESUME it. Zero turns resumption off: sessions end when the socket closes.
+	pub fn set_resume_grace(&self, seconds: u64) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.resume_grace = Duration::from_secs(seconds);
+		info!("Resume grace period set to {} seconds", seconds);
+		Ok(())
+	}
+	
+	// Set how long a channel may sit empty before cleanup removes it.
+	// Zero removes empty channels on the next cleanup pass; channels made with CREATE are kept.
+	pub fn set_empty_channel_ttl(&self, ttl: Duration) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.empty_channel_ttl = ttl;
+		info!("Empty channel retention set to {:?}", ttl);
+		Ok(())
+	}
+	
+	// Register a callback run with each user as they are disconnected: on QUIT, KILL,
+	// KILLTOKEN or disconnect_by_jti, when their last connection closes, when the
+	// cleanup thread ends them (inactivity, ping timeout, expired token or resume
+	// window), or when a new sign-in replaces a session awaiting RESUME. A connection
+	// dropped while RESUME is enabled runs it only once the resume window passes.
+	// It runs after their messages and session key have been overwritten, with the
+	// server locked, so it must not call back into the facade.
+	pub fn on_session_scrubbed<F>(&self, callback: F)
+	where
+		F: Fn(&User) + Send + 'static,
+	{
+		let mut server = lock_or_recover(&self.server);
+		server.scrub_hook = Some(Box::new(callback));
+	}
+	
+	// Replace the time source the cleanup pass uses for expiry, e.g. with a
+	// ManualClock so tests can move past a TTL without sleeping
+	pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.clock = clock;
+	}
+	
+	// Run a cleanup pass now instead of waiting for the background thread's next
+	// minutely pass: expire messages, ping clients, end idle sessions and drop empty channels
+	pub fn run_cleanup(&self) {
+		Self::cleanup_pass(&self.server);
+	}
+	
+	// Set the largest accepted token and the longest accepted command line, in bytes
+	pub fn set_buffer_sizes(&self, max_token_size: usize, command_buffer_size: usize) -> Result<(), IrcError> {
+		if max_token_size == 0 || command_buffer_size == 0 {
+			return Err(IrcError::InvalidInput("Buffer sizes must be greater than zero".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_token_size = max_token_size;
+		server.command_buffer_size = command_buffer_size;
+		info!("Buffer sizes set: token {} bytes, command {} bytes", max_token_size, command_buffer_size);
+		Ok(())
+	}
+	
+	// Set the largest profile picture a token may carry, in decoded bytes
+	pub fn set_max_avatar_size(&self, max_bytes: usize) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_avatar_size = max_bytes;
+		info!("Max profile picture size set to {} bytes", max_bytes);
+		Ok(())
+	}
+	
+	// Keep an avatar that tokens refer to with a `profile_pic_ref` claim, replacing
+	// any avatar already stored under that reference
+	pub fn store_avatar(&self, reference: &str, data: Vec<u8>) -> Result<(), IrcError> {
+		if !valid_avatar_ref(reference) {
+			return Err(IrcError::InvalidInput(format!("Invalid avatar reference: {:?}", reference)));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		if data.len() > server.max_avatar_size {
+			return Err(IrcError::InvalidInput(format!(
+				"Avatar of {} bytes exceeds the limit of {} bytes",
+				data.len(),
+				server.max_avatar_size
+			)));
+		}
+		
+		// Wipe whatever this replaces, including an avatar fetched for the same reference
+		if let Some(mut old) = server.avatar_store.insert(reference.to_string(), data) {
+			old.fill(0);
+		}
+		if let Some(Some(mut fetched)) = server.fetched_avatars.remove(reference) {
+			fetched.fill(0);
+		}
+		Ok(())
+	}
+	
+	// Remove and wipe the avatar stored under `reference`. Returns whether there was one.
+	pub fn remove_avatar(&self, reference: &str) -> bool {
+		let mut server = lock_or_recover(&self.server);
+		match server.avatar_store.remove(reference) {
+			Some(mut data) => {
+				data.fill(0);
+				true
+			}
+			None => false,
+		}
+	}
+	
+	// Set how PROFILE loads a referenced avatar the store doesn't hold yet, e.g.
+	// from a file or an image service. Results over the avatar size limit are dropped.
+	// Each result, failures included, is reused until no connected user refers to it.
+	pub fn set_avatar_fetcher<F>(&self, fetcher: F)
+	where
+		F: Fn(&str) -> Option<Vec<u8>> + Send + Sync + 'static,
+	{
+		let mut server = lock_or_recover(&self.server);
+		server.avatar_fetcher = Some(Arc::new(fetcher));
+	}
+	
+	// Set the largest file a user may send with SENDFILE, in bytes
+	pub fn set_max_file_size(&self, max_bytes: u64) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_file_size = max_bytes;
+		info!("Max file transfer size set to {} bytes", max_bytes);
+		Ok(())
+	}
+	
+	// Set how many simultaneous connections a single IP address may hold
+	pub fn set_max_connections_per_ip(&self, max: usize) -> Result<(), IrcError> {
+		if max == 0 {
+			return Err(IrcError::InvalidInput("Connection limit must be greater than zero".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_connections_per_ip = max;
+		info!("Max connections per IP set to {}", max);
+		Ok(())
+	}
+	
+	// Lock an IP out after `max_failures` failed authentications within `window_seconds`.
+	// The first lockout lasts one window and each further one twice as long as the last.
+	pub fn set_auth_failure_limit(&self, max_failures: usize, window_seconds: u64) -> Result<(), IrcError> {
+		if max_failures == 0 || window_seconds == 0 {
+			return Err(IrcError::InvalidInput("Failure limit and window must be greater than zero".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_auth_failures = max_failures;
+		server.auth_failure_window = Duration::from_secs(window_seconds);
+		info!("Auth failure limit set to {} within {} seconds", max_failures, window_seconds);
+		Ok(())
+	}
+	
+	// Choose whether JOIN creates missing channels or only admins may create them with CREATE
+	pub fn set_channel_creation_policy(&self, policy: ChannelCreationPolicy) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.channel_creation_policy = policy;
+		info!("Channel creation policy set to {:?}", policy);
+		Ok(())
+	}
+	
+	// Append connection events (connects, authentication failures, disconnects and
+	// kills) to a file as JSON lines. Message contents and avatars are never recorded.
+	pub fn set_audit_log<P: AsRef<Path>>(&self, path: P) -> Result<(), IrcError> {
+		let audit_log = AuditLog::open(&path)?;
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.audit_log = Some(audit_log);
+		info!("Audit log set to {}", path.as_ref().display());
+		Ok(())
+	}
+	
+	// Set the longest nickname accepted, in characters (advertised as NICKLEN)
+	pub fn set_max_nick_length(&self, max_nick_length: usize) -> Result<(), IrcError> {
+		if !(1..=MAX_NICK_LENGTH_LIMIT).contains(&max_nick_length) {
+			return Err(IrcError::InvalidInput(format!(
+				"Maximum nickname length must be between 1 and {}",
+				MAX_NICK_LENGTH_LIMIT
+			)));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_nick_length = max_nick_length;
+		info!("Maximum nickname length set to {} characters", max_nick_length);
+		Ok(())
+	}
+	
+	// Set the longest name JOIN may create a channel with, including the '#'
+	// (advertised as CHANNELLEN); channels that already exist stay joinable
+	pub fn set_max_channel_name_length(&self, max_length: usize) -> Result<(), IrcError> {
+		if !(2..=MAX_CHANNEL_NAME_LEN).contains(&max_length) {
+			return Err(IrcError::InvalidInput(format!(
+				"Maximum channel name length must be between 2 and {}",
+				MAX_CHANNEL_NAME_LEN
+			)));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_channel_name_length = max_length;
+		info!("Maximum channel name length set to {} characters", max_length);
+		Ok(())
+	}
+	
+	// Set the longest topic accepted, in bytes (advertised as TOPICLEN); longer
+	// topics are refused with FAIL TOPIC TOPIC_TOO_LONG
+	pub fn set_max_topic_length(&self, max_length: usize) -> Result<(), IrcError> {
+		if !(1..=TOPIC_LEN_LIMIT).contains(&max_length) {
+			return Err(IrcError::InvalidInput(format!(
+				"Maximum topic length must be between 1 and {} bytes",
+				TOPIC_LEN_LIMIT
+			)));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_topic_length = max_length;
+		info!("Maximum topic length set to {} bytes", max_length);
+		Ok(())
+	}
+	
+	// Choose how registration handles a nickname already in use: answer 433 so the
+	// client retries with another NICK (default), or append '_' until it is free
+	pub fn set_nick_auto_suffix(&self, enabled: bool) {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.nick_auto_suffix = enabled;
+		info!("Nickname auto-suffixing {}", if enabled { "enabled" } else { "disabled" });
+	}
+	
+	// Set how many channels one user may be in at once (advertised as CHANLIMIT)
+	pub fn set_max_channels_per_user(&self, max_channels: usize) -> Result<(), IrcError> {
+		if max_channels == 0 {
+			return Err(IrcError::InvalidInput("Maximum channels per user must be at least 1".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_channels_per_user = max_channels;
+		info!("Maximum channels per user set to {}", max_channels);
+		Ok(())
+	}
+	
+	// Set the longest PRIVMSG line relayed, in bytes; longer message bodies are truncated
+	pub fn set_max_line_length(&self, max_line_length: usize) -> Result<(), IrcError> {
+		if max_line_length < 64 {
+			return Err(IrcError::InvalidInput("Maximum line length must be at least 64 bytes".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.max_line_length = max_line_length;
+		info!("Maximum line length set to {} bytes", max_line_length);
+		Ok(())
+	}
+	
+	// Grant operator privileges (e.g. KILL) to a user ID
+	pub fn add_admin(&self, user_id: &str) -> Result<(), IrcError> {
+		let mut server = lock_or_recover(&self.server);
+		
+		server.admins.insert(user_id.to_string());
+		info!("Added admin: {}", user_id);
+		Ok(())
+	}
+	
+	// Set the text of the 001 welcome. `{username}`, `{ttl}` (the message TTL, such as
+	// "1h 0m 0s") and `{ttl_minutes}` are filled in for each user; the template must fit on one line.
+	pub fn set_welcome_template(&self, template: &str) -> Result<(), IrcError> {
+		Self::check_template(template)?;
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.welcome_template = Some(template.to_string());
+		info!("Welcome template set");
+		Ok(())
+	}
+	
+	// Set the text of the security notice sent after the welcome, with the same
+	// placeholders as the welcome template
+	pub fn set_security_notice_template(&self, template: &str) -> Result<(), IrcError> {
+		Self::check_template(template)?;
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.security_notice_template = Some(template.to_string());
+		info!("Security notice template set");
+		Ok(())
+	}
+	
+	// A template is sent as the trailing text of a single line
+	fn check_template(template: &str) -> Result<(), IrcError> {
+		if template.contains(['\r', '\n']) {
+			return Err(IrcError::InvalidInput("Template must not contain line breaks".to_string()));
+		}
+		Ok(())
+	}
+	
+	// Send a NOTICE to every connected user, as an admin's ANNOUNCE would
+	pub fn announce(&self, message: &str) -> Result<(), IrcError> {
+		if message.trim().is_empty() {
+			return Err(IrcError::InvalidInput("Announcement must not be empty".to_string()));
+		}
+		
+		let reached = broadcast_announcement(&self.server, message);
+		info!("Sent announcement to {} users", reached);
+		Ok(())
+	}
+	
+	// Disconnect every user with a session authenticated by the token `jti`, e.g. one
+	// reported stolen, and refuse the token from then on. Each is told why, channel peers
+	// see a QUIT, and their messages and session keys are scrubbed. A multi-device user is
+	// dropped from all devices. Returns how many users were disconnected.
+	pub fn disconnect_by_jti(&self, jti: &str) -> usize {
+		let disconnected = lock_or_recover(&self.server).revoke_token(jti, "server", "Token revoked");
+		info!("Disconnected {} users using token {}", disconnected, jti);
+		disconnected
+	}
+	
+	// Verify tokens with an RS256 public key instead of the shared HS256 secret
+	pub fn set_rsa_public_key(&self, public_key_pem: &str) -> Result<(), IrcError> {
+		if let Err(e) = DecodingKey::from_rsa_pem(public_key_pem.as_bytes()) {
+			return Err(IrcError::InvalidInput(format!("Invalid RSA public key: {}", e)));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		server.jwt_algorithms = vec![Algorithm::RS256];
+		server.jwt_public_key = Some(public_key_pem.to_string());
+		info!("Token verification switched to RS256");
+		Ok(())
+	}
+	
+	// Set the signing algorithms a token may use (HS256 only by default, or RS256 after
+	// set_rsa_public_key). Tokens naming any other algorithm, or none, are rejected.
+	pub fn set_accepted_algorithms(&self, algorithms: &[Algorithm]) -> Result<(), IrcError> {
+		if algorithms.is_empty() {
+			return Err(IrcError::InvalidInput("At least one algorithm must be accepted".to_string()));
+		}
+		
+		let mut server = lock_or_recover(&self.server);
+		
+		for algorithm in algorithms {
+			if is_rsa_algorithm(*algorithm) {
+				if server.jwt_public_key.is_none() {
+					return Err(IrcError::InvalidInput(format!(
+						"{:?} requires an RSA public key; call set_rsa_public_key first",
+						algorithm
+					)));
+				}
+			} else if !is_hmac_algorithm(*algorithm) {
+				return Err(IrcError::InvalidInput(format!("Unsupported token algorithm: {:?}", algorithm)));
+			}
+		}
+		
+		let mut accepted = Vec::new();
+		for algorithm in algorithms {
+			if !accepted.contains(algorithm) {
+				accepted.push(*algorithm);
+			}
+		}
+		info!("Accepted token algorithms set to {:?}", accepted);
+		server.jwt_algorithms = accepted;
+		Ok(())
+	}
+	
+	// Export all unexpired channel and private message history as pretty-printed JSON.
+	// This is an admin API: message contents are included as stored.
+	pub fn export_history(&self) -> String {
+		let server = lock_or_recover(&self.server);
+		let now = server.clock.now();
+		let message_ttl = server.message_ttl;
+		
+		let export_messages = |messages: &VecDeque<ChatMessage>| -> Vec<serde_json::Value> {
+			messages
+				.iter()
+				.filter(|msg| !msg.is_expired(now, message_ttl))
+				.map(|msg| {
+					json!({
+						"sender": msg.sender,
+						"content": msg.content,
+						"timestamp": DateTime::<Utc>::from(msg.sent_at).to_rfc3339(),
+					})
+				})
+				.collect()
 		};
 		
+		let mut channels = serde_json::Map::new();
+		for (name, channel) in &server.channels {
+			channels.insert(name.clone(), json!(export_messages(&channel.messages)));
+		}
+		
+		let mut users = serde_json::Map::new();
+		for (user_id, user) in &server.users {
+			users.insert(
+				user_id.clone(),
+				json!({
+					"username": user.username,
+					"messages": export_messages(&user.messages),
+				}),
+			);
+		}
+		
+		let history = json!({
+			"channels": channels,
+			"users": users,
+		});
+		
+		serde_json::to_string_pretty(&history).unwrap_or_else(|_| "{}".to_string())
+	}
+	
+	// Snapshot of runtime statistics: connected users and per-channel message totals.
+	// Counters live in memory only and start from zero for each channel and session.
+	pub fn stats(&self) -> ServerStats {
+		let server = lock_or_recover(&self.server);
+		
+		let mut channels: Vec<ChannelStats> = server
+			.channels
+			.values()
+			.map(|channel| ChannelStats {
+				name: channel.name.clone(),
+				users: channel.users.len(),
+				message_count: channel.message_count,
+			})
+			.collect();
+		channels.sort_by(|a, b| a.name.cmp(&b.name));
+		
+		ServerStats {
+			users: server.users.len(),
+			channels,
+		}
+	}
+	
+	// Snapshot of every channel, sorted by name: the programmatic counterpart to LIST.
+	// Unlike LIST it includes secret channels, since the caller is the server itself.
+	pub fn channels(&self) -> Vec<ChannelInfo> {
+		let server = lock_or_recover(&self.server);
+		let (now, wall_now) = (Instant::now(), SystemTime::now());
+		
+		let mut channels: Vec<ChannelInfo> = server
+			.channels
+			.values()
+			.map(|channel| ChannelInfo {
+				name: channel.name.clone(),
+				topic: channel.topic.clone(),
+				users: channel.users.len(),
+				created_at: wall_now - now.duration_since(channel.created_at),
+				modes: channel.modes(),
+			})
+			.collect();
+		channels.sort_by(|a, b| a.name.cmp(&b.name));
+		
+		channels
+	}
+	
+	// Snapshot of every connected user, sorted by username. Sessions awaiting RESUME
+	// are left out, and so are avatars and message contents.
+	pub fn users(&self) -> Vec<UserInfo> {
+		let server = lock_or_recover(&self.server);
+		
+		let mut users: Vec<UserInfo> = server
+			.users
+			.values()
+			.filter(|user| user.stream.is_some())
+			.map(|user| UserInfo {
+				id: user.id.clone(),
+				username: user.username.clone(),
+				channels: user.channels.len(),
+				idle: user.session.as_ref().map_or(Duration::ZERO, |session| session.idle_time()),
+				away: user.away.clone(),
+			})
+			.collect();
+		users.sort_by(|a, b| a.username.cmp(&b.username));
+		
+		users
+	}
+	
+	// Start the server
+	pub fn start(&self, address: &str) -> Result<(), IrcError> {
+		let listener = TcpListener::bind(address)?;
+		
 		info!("IRC Server started on {}", address);
 		
+		Self::accept_connections(self.server.clone(), listener, LineProtocol::Irc);
+		
+		Ok(())
+	}
+	
+	// Start the server on a background thread and return the bound address.
+	// Binding to port 0 picks a free port, which is what tests use.
+	pub fn start_background(&self, address: &str) -> Result<SocketAddr, IrcError> {
+		let listener = TcpListener::bind(address)?;
+		
+		let local_addr = listener.local_addr()?;
+		
+		info!("IRC Server started on {}", local_addr);
+		
 		let server = self.server.clone();
+		thread::spawn(move || {
+			Self::accept_connections(server, listener, LineProtocol::Irc);
+		});
+		
+		Ok(local_addr)
+	}
+	
+	// Start serving several addresses at once (e.g. IPv4 and IPv6), each with its own
+	// accept thread. A failed bind doesn't stop the others; the result for each address
+	// is returned in the same order, with the bound address or the bind error.
+	pub fn start_multi(&self, addresses: &[&str]) -> Vec<Result<SocketAddr, IrcError>> {
+		addresses
+			.iter()
+			.map(|address| {
+				let result = self.start_background(address);
+				if let Err(e) = &result {
+					error!("Failed to start listener on {}: {}", address, e);
+				}
+				result
+			})
+			.collect()
+	}
+	
+	// Start accepting JSON line protocol connections on a background thread and return
+	// the bound address. Each line is a JSON command such as {"cmd":"JOIN","channel":"#rust"},
+	// the first being {"cmd":"AUTH","token":"..."}, and every server line comes back as a
+	// JSON event; the commands themselves run through the same handlers as IRC clients.
+	pub fn start_json(&self, address: &str) -> Result<SocketAddr, IrcError> {
+		let listener = TcpListener::bind(address)?;
+		
+		let local_addr = listener.local_addr()?;
+		
+		info!("IRC JSON server started on {}", local_addr);
 		
-		// Handle incoming connections
+		let server = self.server.clone();
+		thread::spawn(move || {
+			Self::accept_connections(server, listener, LineProtocol::Json);
+		});
+		
+		Ok(local_addr)
+	}
+	
+	// Handle incoming connections until the listener fails
+	fn accept_connections(server: Arc<Mutex<ServerState>>, listener: TcpListener, protocol: LineProtocol) {
 		for stream in listener.incoming() {
 			match stream {
 				Ok(stream) => {
 					let server_clone = server.clone();
+					let conn_id = Self::new_connection_id();
 					thread::spawn(move || {
-						if let Err(e) = Self::handle_connection(server_clone, stream) {
-							error!("Connection handling error: {}", e);
+						if let Err(e) = Self::handle_connection(server_clone, stream, &conn_id, protocol) {
+							error!("[conn {}] Connection handling error: {}", conn_id, e);
+						}
+					});
+				}
+				Err(e) => {
+					warn!("Connection failed: {}", e);
+				}
+			}
+		}
+	}
+	
+	// Start accepting WebSocket connections so browser clients can connect.
+	// The first text message must be the authentication token; after that each
+	// text frame carries one IRC command and each server line is sent back as a frame.
+	pub fn start_websocket(&self, address: &str) -> Result<(), IrcError> {
+		let listener = TcpListener::bind(address)?;
+		
+		info!("IRC WebSocket server started on {}", address);
+		
+		let server = self.server.clone();
+		
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					let server_clone = server.clone();
+					let conn_id = Self::new_connection_id();
+					thread::spawn(move || {
+						if let Err(e) = Self::handle_websocket_connection(server_clone, stream, &conn_id) {
+							error!("[conn {}] WebSocket connection handling error: {}", conn_id, e);
 						}
 					});
 				}
@@ -97,8 +716,83 @@ impl IRCServerFacade {
 		Ok(())
 	}
 	
+	// Client IP address, if the socket still knows it
+	fn peer_ip(stream: &TcpStream) -> Option<IpAddr> {
+		stream.peer_addr().ok().map(|addr| addr.ip())
+	}
+	
+	// Client IP address as shown in logs and hostmasks
+	fn host_name(peer_ip: Option<IpAddr>) -> String {
+		peer_ip
+			.map(|ip| ip.to_string())
+			.unwrap_or_else(|| "unknown".to_string())
+	}
+	
+	// The reply to a liveness probe sent as a connection's first line, if it is one:
+	// a bare `PING [:text]`, or an HTTP `GET /health` from a load balancer
+	fn health_probe_reply(first_line: &str) -> Option<String> {
+		let line = first_line.trim();
+		
+		if line == "PING" || line.starts_with("PING ") {
+			let text = line[4..].trim().trim_start_matches(':');
+			return Some(format!(":{} PONG {} :{}\r\n", "server", "server", text));
+		}
+		
+		let mut request = line.split_whitespace();
+		if request.next() == Some("GET") && request.next() == Some("/health") {
+			return Some("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nOK\n".to_string());
+		}
+		
+		None
+	}
+	
+	// Take one of the IP's connection slots, or None if it is at the per-IP limit
+	fn claim_ip_slot(server: &Arc<Mutex<ServerState>>, ip: IpAddr) -> Option<IpSlot> {
+		let mut server_lock = lock_or_recover(server);
+		let max = server_lock.max_connections_per_ip;
+		
+		let count = server_lock.connections_per_ip.entry(ip).or_insert(0);
+		if *count >= max {
+			return None;
+		}
+		*count += 1;
+		
+		Some(IpSlot {
+			server: server.clone(),
+			ip,
+		})
+	}
+	
+	// Count a failed authentication against the client's IP, logging any lockout it starts
+	fn note_auth_failure(server_lock: &mut ServerState, peer_ip: Option<IpAddr>, conn_id: &str, reason: &str) {
+		server_lock.audit(AuditEvent::AuthFailure { ip: peer_ip, reason });
+		
+		if let Some(ip) = peer_ip {
+			if let Some(cooldown) = server_lock.record_auth_failure(ip, Instant::now()) {
+				warn!("[conn {}] Locking out {} for {:?} after repeated authentication failures", conn_id, ip, cooldown);
+			}
+		}
+	}
+	
+	// Short random ID used to correlate the log lines of one connection
+	fn new_connection_id() -> String {
+		thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(8)
+			.map(char::from)
+			.collect()
+	}
+	
 	// Handle client connection
-	fn handle_connection(server: Arc<Mutex<ServerState>>, mut stream: TcpStream) -> Result<(), String> {
+	fn handle_connection(
+		server: Arc<Mutex<ServerState>>,
+		stream: TcpStream,
+		conn_id: &str,
+		protocol: LineProtocol,
+	) -> Result<(), String> {
+		let peer_ip = Self::peer_ip(&stream);
+		info!("[conn {}] Accepted connection from {}", conn_id, Self::host_name(peer_ip));
+		
 		// Set read timeout
 		if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(300))) {
 			return Err(format!("Failed to set read timeout: {}", e));
@@ -106,183 +800,256 @@ impl IRCServerFacade {
 		
 		// Set TCP keepalive to detect dead connections
 		if let Err(e) = stream.set_keepalive(Some(Duration::from_secs(60))) {
-			warn!("Failed to set TCP keepalive: {}", e);
+			warn!("[conn {}] Failed to set TCP keepalive: {}", conn_id, e);
 		}
 		
-		// Read authentication token
-		let mut buffer = [0; 4096]; // Larger buffer for tokens with images
-		let mut token = String::new();
+		// Writes that stall this long mean the client has stopped reading
+		if let Err(e) = stream.set_write_timeout(Some(Duration::from_secs(30))) {
+			return Err(format!("Failed to set write timeout: {}", e));
+		}
 		
-		match stream.read(&mut buffer) {
-			Ok(size) => {
-				if size > 0 {
-					token = String::from_utf8_lossy(&buffer[0..size]).to_string();
-					token = token.trim().to_string();
-				} else {
-					return Err("Empty read from socket".to_string());
-				}
+		let (write_stream, closer) = match (stream.try_clone(), stream.try_clone()) {
+			(Ok(w), Ok(c)) => (w, c),
+			(Err(e), _) | (_, Err(e)) => return Err(format!("Failed to clone stream: {}", e)),
+		};
+		
+		match protocol {
+			LineProtocol::Irc => {
+				let sink: SharedSink = Arc::new(Mutex::new(QueuedSink::spawn(Box::new(write_stream), closer)));
+				Self::serve_client(server, TcpLineReader::new(stream), sink, conn_id, peer_ip)
 			}
-			Err(e) => {
-				return Err(format!("Failed to read from socket: {}", e));
+			LineProtocol::Json => {
+				let sink: SharedSink = Arc::new(Mutex::new(QueuedSink::spawn(
+					Box::new(JsonSink::new(write_stream)),
+					closer,
+				)));
+				let reader = JsonLineReader::new(TcpLineReader::new(stream), sink.clone());
+				Self::serve_client(server, reader, sink, conn_id, peer_ip)
 			}
 		}
+	}
+	
+	// Complete the WebSocket handshake, then serve the client like any other connection
+	fn handle_websocket_connection(server: Arc<Mutex<ServerState>>, stream: TcpStream, conn_id: &str) -> Result<(), String> {
+		let peer_ip = Self::peer_ip(&stream);
+		info!("[conn {}] Accepted WebSocket connection from {}", conn_id, Self::host_name(peer_ip));
 		
-		// Validate token and create user
-		let user_id = {
-			let mut server_lock = match server.lock() {
-				Ok(s) => s,
-				Err(_) => return Err("Failed to lock server for token validation".to_string()),
-			};
-			
-			// Validate token
-			let validation = Validation::new(Algorithm::HS256);
-			let key = DecodingKey::from_secret(server_lock.jwt_secret.as_bytes());
-			
-			let token_data = match jsonwebtoken::decode::<TokenClaims>(&token, &key, &validation) {
-				Ok(t) => t,
-				Err(e) => {
-					let _ = stream.write_all(format!("ERROR :Authentication failed: {}\r\n", e).as_bytes());
-					return Err(format!("Token validation failed: {}", e));
-				}
-			};
-			
-			let claims = token_data.claims;
-			
-			// Extract profile picture
-			let profile_pic = match base64_decode(&claims.profile_pic) {
-				Ok(data) => data,
-				Err(e) => {
-					let _ = stream.write_all(b"ERROR :Invalid profile picture data\r\n");
-					return Err(format!("Failed to decode profile picture: {}", e));
+		if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(300))) {
+			return Err(format!("Failed to set read timeout: {}", e));
+		}
+		
+		if let Err(e) = stream.set_write_timeout(Some(Duration::from_secs(30))) {
+			return Err(format!("Failed to set write timeout: {}", e));
+		}
+		
+		let (write_stream, closer) = match (stream.try_clone(), stream.try_clone()) {
+			(Ok(w), Ok(c)) => (w, c),
+			(Err(e), _) | (_, Err(e)) => return Err(format!("Failed to clone stream: {}", e)),
+		};
+		
+		let socket = match tungstenite::accept(WebSocketReadStream::new(stream)) {
+			Ok(socket) => socket,
+			Err(e) => return Err(format!("WebSocket handshake failed: {}", e)),
+		};
+		
+		// Replies to pings share the sink's socket, so frames never interleave on the wire
+		let writer = websocket_writer(write_stream);
+		let sink: SharedSink = Arc::new(Mutex::new(QueuedSink::spawn(
+			Box::new(WebSocketSink::new(writer.clone())),
+			closer,
+		)));
+		
+		Self::serve_client(server, WebSocketLineReader::new(socket, writer), sink, conn_id, peer_ip)
+	}
+	
+	// Authenticate a connected client and process its commands until it disconnects.
+	// `reader` yields incoming command lines and `sink` carries everything sent back.
+	// Log lines are prefixed with the connection ID, and the user once authenticated;
+	// message contents are never logged.
+	fn serve_client<R: LineReader>(
+		server: Arc<Mutex<ServerState>>,
+		mut reader: R,
+		sink: SharedSink,
+		conn_id: &str,
+		peer_ip: Option<IpAddr>,
+	) -> Result<(), String> {
+		// Refuse hosts locked out after too many failed authentications
+		if let Some(ip) = peer_ip {
+			if lock_or_recover(&server).auth_locked_out(ip, Instant::now()) {
+				warn!("[conn {}] Refusing connection: {} is locked out after failed authentications", conn_id, ip);
+				let _ = lock_or_recover(&sink).write_all(b"ERROR :Too many failed attempts\r\n");
+				return Err(format!("Too many failed attempts from {}", ip));
+			}
+		}
+		
+		// Limit how many connections a single host can hold open; the slot is
+		// released when this function returns, however the connection ends
+		let _ip_slot = match peer_ip {
+			Some(ip) => match Self::claim_ip_slot(&server, ip) {
+				Some(slot) => Some(slot),
+				None => {
+					warn!("[conn {}] Refusing connection: too many connections from {}", conn_id, ip);
+					let _ = lock_or_recover(&sink).write_all(b"ERROR :Too many connections from your IP\r\n");
+					return Err(format!("Too many connections from {}", ip));
 				}
-			};
-			
-			// Generate secure random session ID
-			let session_id: String = thread_rng()
-				.sample_iter(&Alphanumeric)
-				.take(32)
-				.map(char::from)
-				.collect();
-			
-			// Generate encryption key
-			let encryption_key = match Encryptor::generate_random_key() {
-				Ok(key) => key,
-				Err(e) => {
-					let _ = stream.write_all(b"ERROR :Failed to generate encryption key\r\n");
-					return Err(e);
+			},
+			None => None,
+		};
+		
+		// Read authentication token up to the end of the first line, however large it is
+		let (max_token_size, command_buffer_size) = {
+			let server_lock = lock_or_recover(&server);
+			(server_lock.max_token_size, server_lock.command_buffer_size)
+		};
+		
+		let first_line = match reader.read_line(max_token_size) {
+			Ok(Some(line)) => line,
+			Ok(None) => return Err("Empty read from socket".to_string()),
+			Err(e) => {
+				if e.kind() == io::ErrorKind::InvalidData {
+					let _ = lock_or_recover(&sink).write_all(b"ERROR :Authentication token too large\r\n");
 				}
-			};
-			
-			// Create session
-			let session = Session::new(session_id, claims.sub.clone(), encryption_key);
-			
-			// Create user
-			let user = User {
-				id: claims.sub.clone(),
-				username: claims.username.clone(),
-				profile_pic,
-				channels: HashSet::new(),
-				stream: Some(Arc::new(Mutex::new(stream.try_clone().unwrap()))),
-				session: Some(session),
-				messages: VecDeque::new(),
-			};
-			
-			// Add user to server
-			let user_id = claims.sub.clone();
-			server_lock.users.insert(user_id.clone(), user);
-			
-			info!("User authenticated: {} ({})", claims.username, user_id);
-			
-			user_id
+				return Err(format!("Failed to read token: {}", e));
+			}
+		};
+		
+		// Answer health probes and close without authenticating; no User is created,
+		// so nothing else can be done on this connection
+		if let Some(reply) = Self::health_probe_reply(&first_line) {
+			debug!("[conn {}] Answered health probe", conn_id);
+			let _ = lock_or_recover(&sink).write_all(reply.as_bytes());
+			return Ok(());
+		}
+		
+		// A client whose connection dropped may reclaim its session instead of authenticating again
+		let (user_id, username, resumed) = match first_line.strip_prefix("RESUME ") {
+			Some(resume_token) => {
+				let (user_id, username) = Self::resume_session(&server, resume_token.trim(), &sink, conn_id, peer_ip)?;
+				(user_id, username, true)
+			}
+			None => {
+				let (user_id, username) =
+					Self::register_user(&server, &mut reader, &sink, first_line, max_token_size, conn_id, peer_ip)?;
+				(user_id, username, false)
+			}
 		};
+		let log_prefix = format!("[conn {} user {}/{}]", conn_id, username, user_id);
 		
 		// Send welcome message
 		{
-			let server_lock = match server.lock() {
-				Ok(s) => s,
-				Err(_) => return Err("Failed to lock server for welcome message".to_string()),
-			};
+			let server_lock = lock_or_recover(&server);
 			
 			if let Some(user) = server_lock.users.get(&user_id) {
-				if let Some(stream) = &user.stream {
-					let welcome_message = format!(
-						":{} 001 {} :Welcome to the Secure IRC Server, {}\r\n", 
-						"server", 
-						user_id, 
-						user.username
-					);
-					
-					let security_notice = format!(
-						":{} NOTICE {} :SECURITY: All messages will be deleted after {} minutes\r\n",
-						"server",
-						user_id,
-						server_lock.message_ttl.as_secs() / 60
-					);
-					
-					if let Ok(mut s) = stream.lock() {
-						let _ = s.write_all(welcome_message.as_bytes());
-						let _ = s.write_all(security_notice.as_bytes());
-					}
-				}
+				// Whether this connection is another device of an already connected user
+				let device = user.devices.iter().any(|device| Arc::ptr_eq(&device.sink, &sink));
+				// Clients take their nickname from the 001 target, so every line names the
+				// user by nick rather than by their internal ID
+				let welcome_target = &user.username;
+				let welcome_template = server_lock.welcome_template.as_deref().unwrap_or(DEFAULT_WELCOME_TEMPLATE);
+				let welcome_message = format!(
+					":{} 001 {} :{}\r\n", 
+					"server", 
+					welcome_target, 
+					render_template(welcome_template, &user.username, server_lock.message_ttl)
+				);
+				let isupport = format!(
+					":{} 005 {} {} :are supported by this server\r\n",
+					"server",
+					welcome_target,
+					server_lock.isupport_tokens().join(" ")
+				);
+				
+				let notice_template = server_lock
+					.security_notice_template
+					.as_deref()
+					.unwrap_or(DEFAULT_SECURITY_NOTICE_TEMPLATE);
+				let security_notice = format!(
+					":{} NOTICE {} :{}\r\n",
+					"server",
+					user.username,
+					render_template(notice_template, &user.username, server_lock.message_ttl)
+				);
+				
+				// The token lets the client RESUME this session if its connection drops;
+				// only the first device's connection can be resumed
+				let resume_notice = match &user.session {
+					Some(session) if !device && !server_lock.resume_grace.is_zero() => format!(
+						":{} NOTICE {} :RESUME_TOKEN {}\r\n",
+						"server", user.username, session.resume_token
+					),
+					_ => String::new(),
+				};
+				
+				// A resumed session or another device is told the channels the user is in
+				let rejoined: String = if resumed || device {
+					user.channels
+						.iter()
+						.map(|channel| format!(":{} JOIN {}\r\n", user.username, channel))
+						.collect()
+				} else {
+					String::new()
+				};
+				
+				// Written to this connection only, not to the user's other devices
+				let mut s = lock_or_recover(&sink);
+				let _ = s.write_all(welcome_message.as_bytes());
+				let _ = s.write_all(isupport.as_bytes());
+				let _ = s.write_all(
+					tag_line(&user.caps, &new_message_id(), SystemTime::now(), &security_notice).as_bytes(),
+				);
+				let _ = s.write_all(resume_notice.as_bytes());
+				let _ = s.write_all(rejoined.as_bytes());
 			}
 		}
 		
-		// Get a new copy of the stream for the message handler
-		let stream_arc = {
-			let server_lock = match server.lock() {
-				Ok(s) => s,
-				Err(_) => return Err("Failed to lock server for stream access".to_string()),
-			};
-			
-			match server_lock.users.get(&user_id) {
-				Some(user) => match &user.stream {
-					Some(s) => s.clone(),
-					None => return Err("User has no stream".to_string()),
-				},
-				None => return Err("User not found".to_string()),
-			}
-		};
-		
 		// Set up message handler
-		let mut handler = MessageHandler::new(user_id.clone(), stream_arc.clone(), server.clone());
+		let mut handler = MessageHandler::new(user_id.clone(), sink.clone(), server.clone(), log_prefix.clone());
 		
 		// Main client loop
+		let mut quit = false;
 		loop {
-			let mut buffer = [0; 1024];
-			let mut command = String::new();
-			
-			// Read command
-			let size = match stream.read(&mut buffer) {
-				Ok(s) => {
-					if s == 0 {
-						debug!("Connection closed by client");
-						break;  // Connection closed
+			// Read command (lines sent in the same packet as the token are handled first)
+			let command = match reader.read_line(command_buffer_size) {
+				Ok(Some(line)) => line,
+				Ok(None) => {
+					debug!("{} Connection closed by client", log_prefix);
+					break;  // Connection closed
+				}
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+					// An idle client is not an error: inactivity is enforced by the cleanup thread
+					// and dead connections by the PING timeout. Stop only once the user is gone.
+					if !lock_or_recover(&server).users.contains_key(&user_id) {
+						debug!("{} Session ended while idle", log_prefix);
+						break;
 					}
-					s
+					continue;
+				}
+				Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+					// The reader dropped the line; the connection itself is fine
+					let reply = format!(":{} 417 {} :Input line was too long\r\n", "server", username);
+					let _ = lock_or_recover(&sink).write_all(reply.as_bytes());
+					continue;
 				}
 				Err(e) => {
-					warn!("Read error: {}", e);
+					warn!("{} Read error: {}", log_prefix, e);
 					break;
 				}
 			};
 			
-			command = String::from_utf8_lossy(&buffer[0..size]).trim().to_string();
-			
 			if command.is_empty() {
 				continue;
 			}
 			
-			debug!("Received command: {}", command);
+			// Log only the command name; parameters may carry message contents
+			debug!("{} Received {} command", log_prefix, command.split(' ').next().unwrap_or(""));
 			
 			// Handle command
 			if let Err(e) = handler.handle_message(&command) {
-				warn!("Error handling message: {}", e);
+				warn!("{} Error handling message: {}", log_prefix, e);
 				
 				// Try to send error to client
 				let err_msg = format!("ERROR :{}\r\n", e);
-				if let Ok(mut s) = stream_arc.lock() {
-					let _ = s.write_all(err_msg.as_bytes());
-				}
+				let _ = lock_or_recover(&sink).write_all(err_msg.as_bytes());
 				
 				// If it's a critical error, disconnect
 				if e.contains("authentication") || e.contains("token") {
@@ -290,199 +1057,848 @@ impl IRCServerFacade {
 				}
 			}
 			
-			// Check if command was QUIT
-			if command.starts_with("QUIT") {
-				debug!("User quit: {}", user_id);
+			// Check if command was QUIT, in any case, as the dispatcher accepts it
+			if command.split(' ').next().is_some_and(|name| name.eq_ignore_ascii_case("QUIT")) {
+				debug!("{} User quit", log_prefix);
+				quit = true;
 				break;
 			}
 		}
 		
 		// Disconnect user and clean up
 		{
-			let mut server_lock = match server.lock() {
-				Ok(s) => s,
-				Err(_) => return Err("Failed to lock server for disconnection".to_string()),
-			};
-			
-			// Get username for logging
-			let username = server_lock.users.get(&user_id)
-				.map(|u| u.username.clone())
-				.unwrap_or_else(|| "Unknown".to_string());
+			let mut server_lock = lock_or_recover(&server);
 			
-			info!("User disconnected: {} ({})", username, user_id);
+			server_lock.audit(AuditEvent::Disconnect {
+				user_id: &user_id,
+				ip: peer_ip,
+				reason: if quit { "quit" } else { "connection closed" },
+			});
 			
-			// Perform secure deletion of user data
+			// The user stays connected from their other devices; only this connection ends
 			if let Some(user) = server_lock.users.get_mut(&user_id) {
-				// Clear messages
-				user.messages.clear();
-				
-				// Leave all channels
-				for channel_name in &user.channels.clone() {
-					if let Some(channel) = server_lock.channels.get_mut(channel_name) {
-						channel.users.remove(&user_id);
-						
-						// Notify other users
-						let leave_message = format!("* {} has disconnected", username);
-						for other_id in &channel.users {
-							if other_id != &user_id {
-								if let Some(other) = server_lock.users.get(other_id) {
-									if let Some(other_stream) = &other.stream {
-										let msg = format!(":{} PRIVMSG {} :{}\r\n", 
-														channel_name, other.username, leave_message);
-										if let Ok(mut s) = other_stream.lock() {
-											let _ = s.write_all(msg.as_bytes());
-										}
-									}
-								}
-							}
-						}
+				if !user.is_attached(&sink) || user.detach_device(&sink) {
+					info!("{} Device disconnected; the user's other connections remain", log_prefix);
+					return Ok(());
+				}
+			}
+			
+			// File transfers need both ends connected, so they never outlive the connection
+			server_lock.cancel_transfers(&user_id);
+			
+			// A dropped connection keeps the session (and its channels) for the grace
+			// period so the client can RESUME it; the cleanup thread ends it after that
+			if !quit && !server_lock.resume_grace.is_zero() {
+				if let Some(user) = server_lock.users.get_mut(&user_id) {
+					if let Some(session) = &mut user.session {
+						session.disconnected_at = Some(Instant::now());
+						user.stream = None;
+						info!("{} Connection lost; session held for RESUME", log_prefix);
+						return Ok(());
 					}
 				}
 			}
 			
-			// Remove user from server
-			server_lock.users.remove(&user_id);
+			// QUIT has already removed the user; anyone else is scrubbed and removed here
+			if server_lock.users.contains_key(&user_id) {
+				info!("{} User disconnected", log_prefix);
+				server_lock.disconnect_user(&user_id, "Connection closed");
+			}
 		}
 		
 		Ok(())
 	}
 	
-	// Cleanup thread to periodically remove expired messages and sessions
-	fn cleanup_thread(server: Arc<Mutex<ServerState>>) {
-		loop {
-			thread::sleep(Duration::from_secs(60)); // Check every minute
-			
-			let mut server_lock = match server.lock() {
-				Ok(s) => s,
-				Err(_) => {
-					error!("Failed to lock server for cleanup");
-					continue;
+	// Authenticate a new client from its first line (a bare token, or the start of
+	// PASS/NICK/USER registration) and add its user to the server.
+	// Returns the user ID and the username.
+	fn register_user<R: LineReader>(
+		server: &Arc<Mutex<ServerState>>,
+		reader: &mut R,
+		sink: &SharedSink,
+		first_line: String,
+		max_token_size: usize,
+		conn_id: &str,
+		peer_ip: Option<IpAddr>,
+	) -> Result<(String, String), String> {
+		// Standard IRC clients register with PASS/NICK/USER instead of sending a bare token
+		let (token, nick, caps) = if Self::is_registration_command(&first_line) {
+			Self::read_registration(server, reader, sink, first_line, max_token_size, conn_id, peer_ip)?
+		} else {
+			(first_line, None, HashSet::new())
+		};
+		let token = Zeroizing::new(token); // Wiped once authentication is over, however it ends
+		
+		let mut server_lock = lock_or_recover(server);
+		
+		// Validate token
+		let claims = match server_lock.decode_token(&token) {
+			Ok(c) => c,
+			Err(e) => {
+				Self::note_auth_failure(&mut server_lock, peer_ip, conn_id, "invalid token");
+				let _ = lock_or_recover(sink).write_all(format!("ERROR :Authentication failed: {}\r\n", e).as_bytes());
+				return Err(format!("Token validation failed: {}", e));
+			}
+		};
+		
+		// A revoked token is refused for as long as the server runs
+		if claims.jti.as_ref().is_some_and(|jti| server_lock.revoked_jtis.contains(jti)) {
+			Self::note_auth_failure(&mut server_lock, peer_ip, conn_id, "revoked token");
+			let _ = lock_or_recover(sink).write_all(b"ERROR :Authentication failed: Token revoked\r\n");
+			return Err(format!("Token of {} has been revoked", claims.sub));
+		}
+		
+		// Standard clients choose their nickname with NICK
+		let username = nick.unwrap_or_else(|| claims.username.clone());
+		
+		// Reject nicknames that would break IRC parsing or private message routing
+		if let Err(e) = validate_username(&username) {
+			let _ = lock_or_recover(sink).write_all(format!("ERROR :Invalid username: {}\r\n", e).as_bytes());
+			return Err(format!("Invalid username {}: {}", username, e));
+		}
+		
+		if username.chars().count() > server_lock.max_nick_length {
+			let _ = lock_or_recover(sink).write_all(b"ERROR :Invalid username: Username too long\r\n");
+			return Err(format!("Username too long: {}", username));
+		}
+		
+		// Authenticating again replaces a session of the same user left waiting for RESUME
+		let awaiting_resume = server_lock
+			.users
+			.get(&claims.sub)
+			.and_then(|user| user.session.as_ref())
+			.map_or(false, |session| session.disconnected_at.is_some());
+		if awaiting_resume {
+			server_lock.disconnect_user(&claims.sub, "Replaced by a new connection");
+		}
+		
+		// The same user signing in from another device shares the connected user, so
+		// everything sent to them reaches both devices
+		if server_lock.users.get(&claims.sub).map_or(false, |user| user.stream.is_some()) {
+			let session = Self::new_session(&claims, sink)?;
+			let user_id = claims.sub.clone();
+			let username = match server_lock.users.get_mut(&user_id) {
+				Some(user) => {
+					user.attach_device(sink.clone(), session);
+					user.username.clone()
 				}
+				None => return Err(format!("User {} vanished while attaching a device", user_id)),
 			};
 			
-			let now = Instant::now();
-			let message_ttl = server_lock.message_ttl;
-			let session_timeout = server_lock.session_timeout;
+			info!("[conn {}] User authenticated from another device: {} ({})", conn_id, username, user_id);
+			server_lock.audit(AuditEvent::Connect {
+				user_id: &user_id,
+				username: &username,
+				ip: peer_ip,
+				jti: claims.jti.as_deref(),
+				resumed: false,
+			});
 			
-			// Clean up expired messages in channels
-			for (channel_name, channel) in &mut server_lock.channels {
-				let before_count = channel.messages.len();
-				channel.messages.retain(|msg| {
-					now.duration_since(msg.timestamp) < message_ttl
-				});
-				let removed = before_count - channel.messages.len();
+			return Ok((user_id, username));
+		}
+		
+		if server_lock.username_in_use(&username) {
+			let _ = lock_or_recover(sink).write_all(b"ERROR :Nickname in use\r\n");
+			return Err(format!("Username already in use: {}", username));
+		}
+		
+		// A referenced avatar is kept in the avatar store and served from there by PROFILE
+		if let Some(reference) = &claims.profile_pic_ref {
+			if !valid_avatar_ref(reference) {
+				let _ = lock_or_recover(sink).write_all(b"ERROR :Invalid profile picture reference\r\n");
+				return Err(format!("Invalid profile picture reference for {}", claims.sub));
+			}
+		}
+		
+		// Refuse avatars over the limit before decoding them; every 4 base64
+		// characters hold at most 3 bytes
+		let max_avatar_size = server_lock.max_avatar_size;
+		if claims.profile_pic.len() / 4 * 3 > max_avatar_size + 3 {
+			let _ = lock_or_recover(sink).write_all(b"ERROR :Profile picture too large\r\n");
+			return Err(format!("Profile picture of {} exceeds {} bytes", claims.sub, max_avatar_size));
+		}
+		
+		// Extract profile picture (an empty claim means the user has no avatar,
+		// and a reference takes the place of inline data)
+		let profile_pic = if claims.profile_pic.is_empty() || claims.profile_pic_ref.is_some() {
+			Vec::new()
+		} else {
+			match base64_decode(&claims.profile_pic) {
+				Ok(data) => data,
+				Err(e) => {
+					let _ = lock_or_recover(sink).write_all(b"ERROR :Invalid profile picture data\r\n");
+					return Err(format!("Failed to decode profile picture: {}", e));
+				}
+			}
+		};
+		if profile_pic.len() > max_avatar_size {
+			let _ = lock_or_recover(sink).write_all(b"ERROR :Profile picture too large\r\n");
+			return Err(format!("Profile picture of {} exceeds {} bytes", claims.sub, max_avatar_size));
+		}
+		
+		let session = Self::new_session(&claims, sink)?;
+		
+		// Create user
+		let user = User {
+			id: claims.sub.clone(),
+			username: username.clone(),
+			profile_pic,
+			profile_pic_ref: claims.profile_pic_ref.clone(),
+			channels: HashSet::new(),
+			stream: Some(sink.clone()),
+			session: Some(session),
+			devices: Vec::new(),
+			fan_out: None,
+			messages: VecDeque::new(),
+			away: None,
+			caps,
+			host: Self::host_name(peer_ip),
+			silenced: false,
+		};
+		
+		// Add user to server
+		let user_id = claims.sub.clone();
+		server_lock.add_user(user);
+		
+		info!("[conn {}] User authenticated: {} ({})", conn_id, username, user_id);
+		server_lock.audit(AuditEvent::Connect {
+			user_id: &user_id,
+			username: &username,
+			ip: peer_ip,
+			jti: claims.jti.as_deref(),
+			resumed: false,
+		});
+		
+		Ok((user_id, username))
+	}
+	
+	// Start a session for a newly authenticated connection, with its own ID and key
+	fn new_session(claims: &TokenClaims, sink: &SharedSink) -> Result<Session, String> {
+		// Generate secure random session ID
+		let session_id: String = thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(32)
+			.map(char::from)
+			.collect();
+		
+		// Generate encryption key
+		let encryption_key = match Encryptor::generate_random_key() {
+			Ok(key) => key,
+			Err(e) => {
+				let _ = lock_or_recover(sink).write_all(b"ERROR :Failed to generate encryption key\r\n");
+				return Err(e);
+			}
+		};
+		
+		let mut session = Session::new(session_id, claims.sub.clone(), encryption_key);
+		session.resume_token = Self::new_resume_token();
+		session.token_expires_at = Some(UNIX_EPOCH + Duration::from_secs(claims.exp as u64));
+		session.jti = claims.jti.clone();
+		Ok(session)
+	}
+	
+	// Reattach a dropped session to a new connection. The token only works while the
+	// session is waiting within its grace period, and is replaced once used.
+	fn resume_session(
+		server: &Arc<Mutex<ServerState>>,
+		resume_token: &str,
+		sink: &SharedSink,
+		conn_id: &str,
+		peer_ip: Option<IpAddr>,
+	) -> Result<(String, String), String> {
+		let mut server_lock = lock_or_recover(server);
+		
+		let user = server_lock.users.values_mut().find(|user| {
+			user.session.as_ref().map_or(false, |session| {
+				session.disconnected_at.is_some()
+					&& !resume_token.is_empty()
+					&& constant_time_eq(session.resume_token.as_bytes(), resume_token.as_bytes())
+			})
+		});
+		
+		let user = match user {
+			Some(user) => user,
+			None => {
+				Self::note_auth_failure(&mut server_lock, peer_ip, conn_id, "invalid resume token");
+				let _ = lock_or_recover(sink).write_all(b"ERROR :Invalid or expired resume token\r\n");
+				return Err("Invalid resume token".to_string());
+			}
+		};
+		
+		if let Some(session) = &mut user.session {
+			session.disconnected_at = None;
+			session.resume_token = Self::new_resume_token();
+			session.pending_ping = None;
+			session.update_activity();
+		}
+		user.stream = Some(sink.clone());
+		
+		info!("[conn {}] Session resumed: {} ({})", conn_id, user.username, user.id);
+		
+		// The audit entry names the token the resumed session was authenticated by
+		let (user_id, username) = (user.id.clone(), user.username.clone());
+		let jti = user.session.as_ref().and_then(|session| session.jti.clone());
+		server_lock.audit(AuditEvent::Connect {
+			user_id: &user_id,
+			username: &username,
+			ip: peer_ip,
+			jti: jti.as_deref(),
+			resumed: true,
+		});
+		
+		Ok((user_id, username))
+	}
+	
+	// Random secret handed to a client so it can RESUME its session
+	fn new_resume_token() -> String {
+		thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(32)
+			.map(char::from)
+			.collect()
+	}
+	
+	// Whether the first line starts an IRC registration rather than being a bare token
+	fn is_registration_command(line: &str) -> bool {
+		let command = line.split(' ').next().unwrap_or("").to_uppercase();
+		matches!(command.as_str(), "PASS" | "NICK" | "USER" | "CAP")
+	}
+	
+	// Read PASS/NICK/USER registration lines until the client has sent NICK and USER
+	// (and CAP END, if it started capability negotiation).
+	// Returns the token from PASS (or SASL PLAIN), the requested nickname and the
+	// negotiated capabilities.
+	fn read_registration<R: LineReader>(
+		server: &Arc<Mutex<ServerState>>,
+		reader: &mut R,
+		sink: &SharedSink,
+		first_line: String,
+		max_len: usize,
+		conn_id: &str,
+		peer_ip: Option<IpAddr>,
+	) -> Result<(String, Option<String>, HashSet<String>), String> {
+		let mut token = None;
+		let mut nick = None;
+		let mut user_seen = false;
+		let mut caps = HashSet::new();
+		let mut cap_negotiating = false;
+		let mut sasl: Option<String> = None; // SASL PLAIN payload received so far, once started
+		let mut line = first_line;
+		
+		loop {
+			if line.split(' ').next().unwrap_or("").eq_ignore_ascii_case("CAP") {
+				let cap_parts: Vec<&str> = line.splitn(3, ' ').collect();
+				let subcommand = cap_parts.get(1).copied().unwrap_or("");
 				
-				if removed > 0 {
-					debug!("Removed {} expired messages from channel {}", removed, channel_name);
-					
-					// Notify users in channel about message expiration
-					let notice = format!("NOTICE :SECURITY: {} messages have been automatically deleted", removed);
-					for user_id in &channel.users {
-						if let Some(user) = server_lock.users.get(user_id) {
-							if let Some(stream) = &user.stream {
-								if let Ok(mut s) = stream.lock() {
-									let _ = s.write_all(notice.as_bytes());
-								}
-							}
-						}
-					}
+				// Registration is held until CAP END once negotiation has started
+				cap_negotiating = !subcommand.eq_ignore_ascii_case("END");
+				
+				let target = nick.as_deref().unwrap_or("*");
+				let args = cap_parts.get(2).copied().unwrap_or("");
+				if let Some(reply) = negotiate_cap(target, subcommand, args, &mut caps) {
+					let _ = lock_or_recover(sink).write_all(reply.as_bytes());
 				}
 			}
 			
-			// Clean up expired messages in users' private message history
-			for (user_id, user) in &mut server_lock.users {
-				let before_count = user.messages.len();
-				user.messages.retain(|msg| {
-					now.duration_since(msg.timestamp) < message_ttl
-				});
-				let removed = before_count - user.messages.len();
-				
-				if removed > 0 {
-					debug!("Removed {} expired private messages for user {}", removed, user_id);
-					
-					// Notify user about message expiration
-					if let Some(stream) = &user.stream {
-						let notice = format!("NOTICE :SECURITY: {} private messages have been automatically deleted\r\n", removed);
-						if let Ok(mut s) = stream.lock() {
-							let _ = s.write_all(notice.as_bytes());
+			let mut parts = line.splitn(2, ' ');
+			let command = parts.next().unwrap_or("").to_uppercase();
+			let param = parts
+				.next()
+				.unwrap_or("")
+				.trim()
+				.split(' ')
+				.next()
+				.unwrap_or("")
+				.trim_start_matches(':')
+				.to_string();
+			
+			match command.as_str() {
+				"PASS" => token = Some(param),
+				"NICK" => {
+					let target = nick.as_deref().unwrap_or("*").to_string();
+					match Self::available_nick(server, &param, token.as_deref()) {
+						Ok(available) => nick = Some(available),
+						Err((numeric, reason)) => {
+							// The client may retry with another NICK before registration completes
+							let reply = if param.is_empty() {
+								format!(":{} {} {} :{}\r\n", "server", numeric, target, reason)
+							} else {
+								format!(":{} {} {} {} :{}\r\n", "server", numeric, target, param, reason)
+							};
+							let _ = lock_or_recover(sink).write_all(reply.as_bytes());
 						}
 					}
 				}
+				"USER" => user_seen = true,
+				"AUTHENTICATE" => {
+					let target = nick.as_deref().unwrap_or("*");
+					let reply = if sasl.is_none() {
+						// Choose the mechanism; PLAIN is the only one offered
+						if !caps.contains("sasl") {
+							format!(":{} 904 {} :SASL authentication failed\r\n", "server", target)
+						} else if param.eq_ignore_ascii_case("PLAIN") {
+							sasl = Some(String::new());
+							"AUTHENTICATE +\r\n".to_string()
+						} else {
+							format!(
+								":{} 908 {} PLAIN :are available SASL mechanisms\r\n:{} 904 {} :SASL authentication failed\r\n",
+								"server", target, "server", target
+							)
+						}
+					} else if param == "*" {
+						sasl = None;
+						format!(":{} 906 {} :SASL authentication aborted\r\n", "server", target)
+					} else {
+						// Payloads arrive in 400-byte chunks; "+" ends one that is an exact multiple
+						let buffer = sasl.get_or_insert_with(String::new);
+						if param != "+" {
+							buffer.push_str(&param);
+						}
+						if param.len() == SASL_CHUNK_SIZE && buffer.len() <= max_len {
+							line = Self::read_registration_line(reader, sink, target, max_len)?;
+							continue;
+						}
+						
+						let payload = Zeroizing::new(sasl.take().unwrap_or_default());
+						match Self::sasl_plain_token(server, &payload, conn_id, peer_ip) {
+							Ok((sasl_token, account)) => {
+								token = Some(sasl_token);
+								format!(
+									":{} 900 {} {}!*@* {} :You are now logged in as {}\r\n:{} 903 {} :SASL authentication successful\r\n",
+									"server", target, target, account, account, "server", target
+								)
+							}
+							Err(e) => {
+								debug!("[conn {}] SASL authentication failed: {}", conn_id, e);
+								format!(":{} 904 {} :SASL authentication failed\r\n", "server", target)
+							}
+						}
+					};
+					let _ = lock_or_recover(sink).write_all(reply.as_bytes());
+				}
+				_ => {} // CAP was handled above; anything else is ignored
 			}
 			
-			// Find inactive sessions to disconnect
-			let mut to_disconnect = Vec::new();
-			for (user_id, user) in &server_lock.users {
-				if let Some(session) = &user.session {
-					if now.duration_since(session.last_activity) > session_timeout {
-						to_disconnect.push(user_id.clone());
+			if nick.is_some() && user_seen && !cap_negotiating {
+				return match token {
+					Some(token) => Ok((token, nick, caps)),
+					None => {
+						let _ = lock_or_recover(sink).write_all(b":server 464 * :Password required: send your token with PASS\r\n");
+						Err("Registration without PASS token".to_string())
 					}
+				};
+			}
+			
+			line = Self::read_registration_line(reader, sink, nick.as_deref().unwrap_or("*"), max_len)?;
+		}
+	}
+	
+	// The nickname a registering client gets for NICK `nick`: the nickname itself if it
+	// is free, or with '_' appended until it is when auto-suffixing is on. Otherwise
+	// returns the numeric and reason to answer with. A nickname held by the user the
+	// PASS token (if already sent) belongs to counts as free, for a second device.
+	fn available_nick(
+		server: &Arc<Mutex<ServerState>>,
+		nick: &str,
+		token: Option<&str>,
+	) -> Result<String, (u16, &'static str)> {
+		if nick.is_empty() {
+			return Err((431, "No nickname given"));
+		}
+		
+		let server_lock = lock_or_recover(server);
+		let own_id = token.and_then(|token| server_lock.decode_token(token).ok()).map(|claims| claims.sub);
+		let taken = |candidate: &str| {
+			server_lock
+				.username_to_id
+				.get(candidate)
+				.map_or(false, |owner| Some(owner) != own_id.as_ref())
+		};
+		
+		let mut candidate = nick.to_string();
+		while taken(&candidate) {
+			if !server_lock.nick_auto_suffix || candidate.chars().count() >= server_lock.max_nick_length {
+				return Err((433, "Nickname is already in use"));
+			}
+			candidate.push('_');
+		}
+		
+		Ok(candidate)
+	}
+	
+	// Read the next registration line. Over-long lines are answered with 417 and
+	// skipped, as they are once registered.
+	fn read_registration_line<R: LineReader>(
+		reader: &mut R,
+		sink: &SharedSink,
+		target: &str,
+		max_len: usize,
+	) -> Result<String, String> {
+		loop {
+			match reader.read_line(max_len) {
+				Ok(Some(line)) => return Ok(line),
+				Ok(None) => return Err("Connection closed during registration".to_string()),
+				Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+					let reply = format!(":{} 417 {} :Input line was too long\r\n", "server", target);
+					let _ = lock_or_recover(sink).write_all(reply.as_bytes());
 				}
+				Err(e) => return Err(format!("Failed to read registration: {}", e)),
+			}
+		}
+	}
+	
+	// Take the token from a SASL PLAIN payload (base64 "authzid\0authcid\0password", with the
+	// token as the password) and validate it like a bare token. Returns the token and the
+	// account name. Failures count towards the IP's lockout, which also ends further attempts.
+	fn sasl_plain_token(
+		server: &Arc<Mutex<ServerState>>,
+		payload: &str,
+		conn_id: &str,
+		peer_ip: Option<IpAddr>,
+	) -> Result<(String, String), String> {
+		let mut server_lock = lock_or_recover(server);
+		
+		if let Some(ip) = peer_ip {
+			if server_lock.auth_locked_out(ip, Instant::now()) {
+				return Err("Too many failed attempts".to_string());
 			}
+		}
+		
+		let decoded = Zeroizing::new(base64_decode(payload).unwrap_or_default());
+		let token = std::str::from_utf8(&decoded)
+			.ok()
+			.and_then(|text| text.splitn(3, '\0').nth(2))
+			.map(str::to_string);
+		
+		let result = match token {
+			Some(token) => server_lock.decode_token(&token).map(|claims| (token, claims.username)),
+			None => Err("Malformed SASL PLAIN payload".to_string()),
+		};
+		if result.is_err() {
+			Self::note_auth_failure(&mut server_lock, peer_ip, conn_id, "invalid SASL credentials");
+		}
+		result
+	}
+	
+	// Cleanup thread to periodically remove expired messages and sessions
+	fn cleanup_thread(server: Arc<Mutex<ServerState>>) {
+		loop {
+			thread::sleep(Duration::from_secs(60)); // Check every minute
 			
-			// Disconnect inactive users
-			for user_id in to_disconnect {
-				info!("Auto-disconnecting inactive user: {}", user_id);
-				
-				// Send disconnect notice to user
-				if let Some(user) = server_lock.users.get(&user_id) {
+			Self::cleanup_pass(&server);
+		}
+	}
+	
+	// One cleanup pass: expire messages, check liveness, end idle or abandoned
+	// sessions and drop empty channels past their retention
+	fn cleanup_pass(server: &Arc<Mutex<ServerState>>) {
+		let mut server_lock = lock_or_recover(server);
+		
+		let now = server_lock.clock.now();
+		let message_ttl = server_lock.message_ttl;
+		let session_timeout = server_lock.session_timeout;
+		
+		// Clean up expired messages in channels, noting which members to notify
+		let mut expired_in_channels: Vec<(String, usize, Vec<String>)> = Vec::new();
+		for (channel_name, channel) in &mut server_lock.channels {
+			let before_count = channel.messages.len();
+			channel.messages.retain(|msg| !msg.is_expired(now, message_ttl));
+			let removed = before_count - channel.messages.len();
+			
+			if removed > 0 {
+				debug!("Removed {} expired messages from channel {}", removed, channel_name);
+				expired_in_channels.push((channel_name.clone(), removed, channel.users.iter().cloned().collect()));
+			}
+		}
+		
+		// Notify users in each channel about message expiration
+		for (channel_name, removed, members) in &expired_in_channels {
+			for user_id in members {
+				if let Some(user) = server_lock.users.get(user_id).filter(|u| !u.silenced) {
 					if let Some(stream) = &user.stream {
-						let notice = "NOTICE :SECURITY: You have been disconnected due to inactivity. All messages have been deleted.\r\n";
-						if let Ok(mut s) = stream.lock() {
-							let _ = s.write_all(notice.as_bytes());
-						}
+						let notice = format!(
+							":{} NOTICE {} :SECURITY: {} messages have been automatically deleted from {}\r\n",
+							"server",
+							user.username,
+							removed,
+							channel_name
+						);
+						let notice = tag_line(&user.caps, &new_message_id(), SystemTime::now(), &notice);
+						let _ = lock_or_recover(stream).write_all(notice.as_bytes());
 					}
 				}
+			}
+		}
+		
+		// Clean up expired messages in users' private message history
+		for (user_id, user) in &mut server_lock.users {
+			let before_count = user.messages.len();
+			user.messages.retain(|msg| !msg.is_expired(now, message_ttl));
+			let removed = before_count - user.messages.len();
+			
+			if removed > 0 {
+				debug!("Removed {} expired private messages for user {}", removed, user_id);
 				
-				// Perform user disconnection and cleanup
-				Self::disconnect_user(&mut server_lock, &user_id);
+				// Notify user about message expiration, unless they silenced these notices
+				if let Some(stream) = user.stream.as_ref().filter(|_| !user.silenced) {
+					let notice = tag_line(
+						&user.caps,
+						&new_message_id(),
+						SystemTime::now(),
+						&format!(
+							":{} NOTICE {} :SECURITY: {} private messages have been automatically deleted\r\n",
+							"server",
+							user.username,
+							removed
+						),
+					);
+					let _ = lock_or_recover(stream).write_all(notice.as_bytes());
+				}
+			}
+		}
+		
+		// End sessions whose token has expired since they authenticated. Each device signed
+		// in with its own token, so while another connection's token is still valid only the
+		// expired connections are closed; their connection threads then detach them.
+		let wall_now = SystemTime::now();
+		let mut expired_tokens = Vec::new();
+		let mut expired_connections = Vec::new();
+		for (user_id, user) in &mut server_lock.users {
+			let connections = user.connections_mut();
+			let expired: Vec<Option<SharedSink>> = connections
+				.iter()
+				.filter(|(_, session)| session.token_expired(wall_now))
+				.map(|(sink, _)| sink.clone())
+				.collect();
+			if expired.is_empty() {
+				continue;
+			}
+			if expired.len() == connections.len() {
+				expired_tokens.push(user_id.clone());
+			} else {
+				expired_connections.extend(expired.into_iter().flatten());
 			}
+		}
+		
+		for sink in expired_connections {
+			let mut s = lock_or_recover(&sink);
+			let _ = s.write_all(b"ERROR :Token expired, please re-authenticate\r\n");
+			let _ = s.shutdown();
+		}
+		
+		for user_id in expired_tokens {
+			info!("Disconnecting user whose token expired: {}", user_id);
 			
-			// Clean up empty channels
-			server_lock.channels.retain(|name, channel| {
-				if channel.users.is_empty() && now.duration_since(channel.last_activity) > Duration::from_secs(86400) {
-					debug!("Removing empty channel {}", name);
-					false
-				} else {
-					true
+			if let Some(user) = server_lock.users.get(&user_id) {
+				if let Some(stream) = &user.stream {
+					let mut s = lock_or_recover(stream);
+					let _ = s.write_all(b"ERROR :Token expired, please re-authenticate\r\n");
+					let _ = s.shutdown();
 				}
-			});
+			}
+			
+			server_lock.disconnect_user(&user_id, "Token expired");
 		}
-	}
-	
-	// Disconnect a user and clean up their data
-	fn disconnect_user(server: &mut ServerState, user_id: &str) {
-		let username = server.users.get(user_id)
-			.map(|u| u.username.clone())
-			.unwrap_or_else(|| "Unknown".to_string());
 		
-		// Remove user from all channels
-		if let Some(user) = server.users.get(user_id) {
-			for channel_name in &user.channels.clone() {
-				if let Some(channel) = server.channels.get_mut(channel_name) {
-					channel.users.remove(user_id);
+		// Check liveness: drop connections that left a PING unanswered, then ping the rest.
+		// Each device answers its own PING; a user with live devices left keeps them, and
+		// the dead connections are closed so their threads detach them.
+		let ping_timeout = server_lock.ping_timeout;
+		let mut dead_users = Vec::new();
+		let mut dead_connections = Vec::new();
+		for (user_id, user) in &mut server_lock.users {
+			let connections = user.connections_mut();
+			let total = connections.len();
+			let mut dead = Vec::new();
+			for (sink, session) in connections {
+				if session.disconnected_at.is_some() {
+					continue;
+				}
+				if session.ping_timed_out(now, ping_timeout) {
+					dead.push(sink);
+				} else if session.pending_ping.is_none() {
+					let nonce: String = thread_rng()
+						.sample_iter(&Alphanumeric)
+						.take(16)
+						.map(char::from)
+						.collect();
 					
-					// Notify other users
-					let leave_message = format!("* {} has been disconnected due to inactivity", username);
-					for other_id in &channel.users {
-						if other_id != user_id {
-							if let Some(other) = server.users.get(other_id) {
-								if let Some(other_stream) = &other.stream {
-									let msg = format!(":{} PRIVMSG {} :{}\r\n", 
-													channel_name, other.username, leave_message);
-									if let Ok(mut s) = other_stream.lock() {
-										let _ = s.write_all(msg.as_bytes());
-									}
-								}
-							}
-						}
+					if let Some(sink) = &sink {
+						let _ = lock_or_recover(sink).write_all(format!("PING :{}\r\n", nonce).as_bytes());
+					}
+					session.pending_ping = Some((nonce, now));
+				}
+			}
+			if dead.len() == total && total > 0 {
+				dead_users.push(user_id.clone());
+			} else {
+				dead_connections.extend(dead.into_iter().flatten());
+			}
+		}
+		
+		for sink in dead_connections {
+			let _ = lock_or_recover(&sink).shutdown();
+		}
+		
+		for user_id in dead_users {
+			info!("Disconnecting dead connection (no PONG within {:?}): {}", ping_timeout, user_id);
+			
+			// Close the connection so its thread stops waiting on a vanished client
+			if let Some(user) = server_lock.users.get(&user_id) {
+				if let Some(stream) = &user.stream {
+					let _ = lock_or_recover(stream).shutdown();
+				}
+			}
+			
+			server_lock.disconnect_user(&user_id, "Ping timeout");
+		}
+		
+		// Warn connections approaching the inactivity timeout (once per idle period);
+		// each device goes idle on its own
+		let warning_threshold = session_timeout.saturating_sub(server_lock.inactivity_warning);
+		for user in server_lock.users.values_mut() {
+			let (username, caps) = (user.username.clone(), user.caps.clone());
+			for (sink, session) in user.connections_mut() {
+				let idle = now.duration_since(session.last_activity);
+				if !session.inactivity_warned && idle >= warning_threshold && idle <= session_timeout {
+					session.inactivity_warned = true;
+					
+					if let Some(sink) = sink {
+						let remaining = session_timeout.saturating_sub(idle);
+						let notice = format!(
+							":{} NOTICE {} :SECURITY: You will be disconnected for inactivity in {}\r\n",
+							"server",
+							username,
+							format_duration(remaining)
+						);
+						let notice = tag_line(&caps, &new_message_id(), SystemTime::now(), &notice);
+						let _ = lock_or_recover(&sink).write_all(notice.as_bytes());
 					}
 				}
 			}
 		}
 		
-		// Remove user from server
-		server.users.remove(user_id);
-		info!("User disconnected and data cleared: {}", username);
+		// Report each connection's remaining time so clients can keep their countdown in sync
+		for user in server_lock.users.values_mut() {
+			let (username, caps) = (user.username.clone(), user.caps.clone());
+			for (sink, session) in user.connections_mut() {
+				if let Some(sink) = sink {
+					let remaining = session_timeout.saturating_sub(now.duration_since(session.last_activity));
+					let notice = format!(
+						":{} NOTICE {} :SESSION_REMAINING {}\r\n",
+						"server",
+						username,
+						remaining.as_secs()
+					);
+					let notice = tag_line(&caps, &new_message_id(), SystemTime::now(), &notice);
+					let _ = lock_or_recover(&sink).write_all(notice.as_bytes());
+				}
+			}
+		}
+		
+		// End sessions whose connection dropped and were not resumed in time
+		let resume_grace = server_lock.resume_grace;
+		let mut abandoned = Vec::new();
+		for (user_id, user) in &server_lock.users {
+			if let Some(disconnected_at) = user.session.as_ref().and_then(|s| s.disconnected_at) {
+				if now.duration_since(disconnected_at) >= resume_grace {
+					abandoned.push(user_id.clone());
+				}
+			}
+		}
+		
+		for user_id in abandoned {
+			info!("Ending session not resumed within {:?}: {}", resume_grace, user_id);
+			server_lock.disconnect_user(&user_id, "Session not resumed");
+		}
+		
+		// Find inactive sessions to disconnect. A user idle on only some devices keeps the
+		// others; the idle connections are closed and their threads detach them.
+		let mut to_disconnect = Vec::new();
+		let mut idle_connections = Vec::new();
+		for (user_id, user) in &mut server_lock.users {
+			let caps = user.caps.clone();
+			let connections = user.connections_mut();
+			let idle: Vec<Option<SharedSink>> = connections
+				.iter()
+				.filter(|(_, session)| now.duration_since(session.last_activity) > session_timeout)
+				.map(|(sink, _)| sink.clone())
+				.collect();
+			if idle.is_empty() {
+				continue;
+			}
+			if idle.len() == connections.len() {
+				to_disconnect.push(user_id.clone());
+			} else {
+				idle_connections.extend(idle.into_iter().flatten().map(|sink| (sink, caps.clone())));
+			}
+		}
+		
+		for (sink, caps) in idle_connections {
+			let notice = tag_line(
+				&caps,
+				&new_message_id(),
+				SystemTime::now(),
+				"NOTICE :SECURITY: This connection has been closed due to inactivity.\r\n",
+			);
+			let mut s = lock_or_recover(&sink);
+			let _ = s.write_all(notice.as_bytes());
+			let _ = s.shutdown();
+		}
+		
+		// Disconnect inactive users
+		for user_id in to_disconnect {
+			info!("Auto-disconnecting inactive user: {}", user_id);
+			
+			// Send disconnect notice to user
+			if let Some(user) = server_lock.users.get(&user_id) {
+				if let Some(stream) = &user.stream {
+					let notice = tag_line(
+						&user.caps,
+						&new_message_id(),
+						SystemTime::now(),
+						"NOTICE :SECURITY: You have been disconnected due to inactivity. All messages have been deleted.\r\n",
+					);
+					let _ = lock_or_recover(stream).write_all(notice.as_bytes());
+				}
+			}
+			
+			// Perform user disconnection and cleanup
+			server_lock.disconnect_user(&user_id, "Inactivity timeout");
+		}
+		
+		// Forget authentication failures that no longer count towards a lockout
+		server_lock.prune_auth_failures(now);
+		
+		// Wipe fetched avatars that no connected user refers to any more
+		let referenced: HashSet<String> = server_lock
+			.users
+			.values()
+			.filter_map(|user| user.profile_pic_ref.clone())
+			.collect();
+		server_lock.fetched_avatars.retain(|reference, data| {
+			if referenced.contains(reference) {
+				return true;
+			}
+			if let Some(data) = data {
+				data.fill(0);
+			}
+			false
+		});
+		
+		// Clean up channels left empty for longer than the retention period
+		let empty_channel_ttl = server_lock.empty_channel_ttl;
+		let channel_count = server_lock.channels.len();
+		server_lock.channels.retain(|name, channel| {
+			if channel.users.is_empty() && !channel.persistent && now.duration_since(channel.last_activity) >= empty_channel_ttl {
+				debug!("Removing empty channel {}", name);
+				false
+			} else {
+				true
+			}
+		});
+		if server_lock.channels.len() != channel_count {
+			server_lock.invalidate_channel_list();
+		}
 	}
 }
\ No newline at end of file
@@ -1,37 +1,856 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use log::{debug, error, info, warn};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::server::audit::{AuditEvent, AuditLog};
 use crate::server::crypto::Encryptor;
-use crate::server::models::{Channel, ChatMessage, MessageType, TokenClaims, User};
+use crate::server::crypto::{FRAME_OVERHEAD, FRAME_SIZE};
+use crate::server::models::{
+	AuthFailures, Channel, ChannelCreationPolicy, ChannelListing, ChatMessage, FileTransfer, MessageType,
+	TokenClaims, User,
+};
 use crate::server::session::Session;
+use crate::server::transport::SharedSink;
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::duration::format_duration;
+use crate::utils::sync::lock_or_recover;
 
 pub struct MessageHandler {
 	user_id: String,
-	stream: Arc<Mutex<TcpStream>>,
+	stream: SharedSink,
 	server: Arc<Mutex<ServerState>>,
+	log_prefix: String, // Connection ID and user, prepended to this connection's log lines
 }
 
 pub struct ServerState {
 	pub users: std::collections::HashMap<String, User>,
 	pub channels: std::collections::HashMap<String, Channel>,
 	pub jwt_secret: String,
+	pub jwt_algorithms: Vec<Algorithm>, // Signing algorithms accepted in a token header; nothing else verifies
+	pub jwt_public_key: Option<String>, // RSA public key (PEM) used for the RS* and PS* algorithms
 	pub message_ttl: Duration,
 	pub session_timeout: Duration,
+	pub max_token_size: usize,      // Largest authentication token accepted, in bytes
+	pub command_buffer_size: usize, // Longest command line accepted, in bytes
+	pub admins: HashSet<String>,    // User IDs allowed to run operator commands
+	pub inactivity_warning: Duration, // How long before the session timeout to warn idle users
+	pub ping_timeout: Duration,       // How long a PING may go unanswered before the connection is dropped
+	pub username_to_id: std::collections::HashMap<String, String>, // Username index over `users`
+	pub connections_per_ip: std::collections::HashMap<IpAddr, usize>, // Open connections per source IP
+	pub max_connections_per_ip: usize,
+	pub channel_creation_policy: ChannelCreationPolicy,
+	pub max_line_length: usize, // Longest PRIVMSG line relayed, in bytes; longer bodies are truncated
+	pub resume_grace: Duration, // How long a dropped connection's session waits for RESUME (zero disables it)
+	pub empty_channel_ttl: Duration, // How long an empty channel is kept before cleanup removes it
+	pub scrub_hook: Option<Box<dyn Fn(&User) + Send>>, // Called with each user scrubbed by `disconnect_user`, on every disconnect
+	pub auth_failures: std::collections::HashMap<IpAddr, AuthFailures>, // Recent failed logins per source IP
+	pub max_auth_failures: usize,      // Failures within the window that lock an IP out
+	pub auth_failure_window: Duration, // Also the length of an IP's first lockout
+	pub max_avatar_size: usize,        // Largest decoded profile picture accepted in a token, in bytes
+	pub transfers: std::collections::HashMap<String, FileTransfer>, // File transfers in progress, by ID
+	pub max_file_size: u64, // Largest file SENDFILE may offer, in bytes
+	pub welcome_template: Option<String>, // Text of the 001 welcome; DEFAULT_WELCOME_TEMPLATE when unset
+	pub security_notice_template: Option<String>, // Text of the connect notice; DEFAULT_SECURITY_NOTICE_TEMPLATE when unset
+	pub max_nick_length: usize, // Longest nickname accepted, in characters
+	pub max_channel_name_length: usize, // Longest name JOIN may create a channel with, including the '#'
+	pub max_topic_length: usize, // Longest topic TOPIC accepts, in bytes
+	pub max_channels_per_user: usize, // Most channels one user may be in at once
+	pub audit_log: Option<AuditLog>, // Where connection events are recorded, if anywhere
+	pub channel_list: Option<Arc<Vec<ChannelListing>>>, // Cached LIST snapshot; None (dirty) after any channel change
+	pub drained_channels: HashMap<String, Instant>, // Channels closed by DRAIN, refusing JOIN until then
+	pub clock: Arc<dyn Clock>, // Time source for message, session and channel expiry
+	pub nick_auto_suffix: bool, // Registration appends '_' to a nickname in use instead of answering 433
+	pub avatar_store: HashMap<String, Vec<u8>>, // Avatars tokens refer to with `profile_pic_ref`
+	pub fetched_avatars: HashMap<String, Option<Vec<u8>>>, // Fetcher results (None when it failed), kept while a user refers to them
+	pub avatar_fetcher: Option<Arc<dyn Fn(&str) -> Option<Vec<u8>> + Send + Sync>>, // Loads a reference missing from the store
+	pub revoked_jtis: HashSet<String>, // IDs of tokens revoked by KILLTOKEN or disconnect_by_jti, refused at registration
+}
+
+/// Welcome text sent with 001 unless the operator sets a template; `{username}`,
+/// `{ttl}` (the message TTL, e.g. `1h 0m 0s` or `30s`) and `{ttl_minutes}` are
+/// replaced when a user connects
+pub const DEFAULT_WELCOME_TEMPLATE: &str = "Welcome to the Secure IRC Server, {username}";
+
+/// Security notice sent after the welcome unless the operator sets a template
+pub const DEFAULT_SECURITY_NOTICE_TEMPLATE: &str =
+	"SECURITY: All messages will be deleted after {ttl}";
+
+/// Fill in a welcome or security notice template for a connecting user
+pub fn render_template(template: &str, username: &str, message_ttl: Duration) -> String {
+	// The username goes in last so a name containing a placeholder stays as typed
+	template
+		.replace("{ttl}", &format_duration(message_ttl))
+		.replace("{ttl_minutes}", &(message_ttl.as_secs() / 60).to_string())
+		.replace("{username}", username)
+}
+
+/// Longest an IP address is locked out after repeated authentication failures
+pub const MAX_AUTH_LOCKOUT: Duration = Duration::from_secs(86400);
+
+/// Longest `profile_pic_ref` claim accepted, in bytes
+pub const MAX_AVATAR_REF_LEN: usize = 256;
+
+/// Whether an avatar reference is acceptable: a printable key of at most
+/// `MAX_AVATAR_REF_LEN` bytes with no whitespace
+pub fn valid_avatar_ref(reference: &str) -> bool {
+	!reference.is_empty()
+		&& reference.len() <= MAX_AVATAR_REF_LEN
+		&& !reference.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Maximum length of a channel name, including the leading '#'
+///
+/// This is also the default and the ceiling for `ServerState::max_channel_name_length`.
+pub const MAX_CHANNEL_NAME_LEN: usize = 50;
+
+/// Ceiling for `ServerState::max_nick_length`, in characters
+pub const MAX_NICK_LENGTH_LIMIT: usize = 64;
+
+/// Network name advertised to clients in ISUPPORT (005)
+pub const NETWORK_NAME: &str = "SecureIRC";
+
+/// Validate a channel name and return its normalized (lowercase) form
+///
+/// On failure, returns the numeric reply to send (403 when the name is not a
+/// channel at all, 479 when it is malformed) along with its message.
+pub fn normalize_channel_name(name: &str) -> Result<String, (u16, &'static str)> {
+	if !name.starts_with('#') || name.len() < 2 {
+		return Err((403, "No such channel"));
+	}
+
+	if name.len() > MAX_CHANNEL_NAME_LEN {
+		return Err((479, "Channel name too long"));
+	}
+
+	if name
+		.chars()
+		.any(|c| c == ' ' || c == ',' || c == ':' || c.is_control())
+	{
+		return Err((479, "Illegal channel name"));
+	}
+
+	Ok(name.to_lowercase())
+}
+
+/// Check that a username can be used as an IRC nickname
+pub fn validate_username(username: &str) -> Result<(), &'static str> {
+	if username.is_empty() {
+		return Err("Username must not be empty");
+	}
+
+	if username.starts_with('#') {
+		return Err("Username must not start with '#'");
+	}
+
+	if username
+		.chars()
+		.any(|c| c == ' ' || c == ',' || c == ':' || c.is_control())
+	{
+		return Err("Username contains illegal characters");
+	}
+
+	Ok(())
+}
+
+/// Match text against a simple glob pattern where `*` matches any run of
+/// characters and `?` matches exactly one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let text: Vec<char> = text.chars().collect();
+
+	// Iterative matcher that backtracks to the most recent '*'
+	let (mut p, mut t) = (0, 0);
+	let mut star: Option<(usize, usize)> = None;
+
+	while t < text.len() {
+		if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+			p += 1;
+			t += 1;
+		} else if p < pattern.len() && pattern[p] == '*' {
+			star = Some((p, t));
+			p += 1;
+		} else if let Some((star_p, star_t)) = star {
+			p = star_p + 1;
+			t = star_t + 1;
+			star = Some((star_p, star_t + 1));
+		} else {
+			return false;
+		}
+	}
+
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Base64 characters per 910 line, keeping PROFILE replies well under 512 bytes
+pub const PROFILE_CHUNK_SIZE: usize = 400;
+
+/// Channels per 319 line in a MYCHANNELS reply; with MAX_CHANNEL_NAME_LEN this stays under 512 bytes
+pub const CHANNELS_PER_LINE: usize = 8;
+
+/// Cut text to at most `max_bytes` bytes without splitting a UTF-8 character
+pub fn truncate_utf8(text: &str, max_bytes: usize) -> &str {
+	if text.len() <= max_bytes {
+		return text;
+	}
+
+	let mut end = max_bytes;
+	while !text.is_char_boundary(end) {
+		end -= 1;
+	}
+	&text[..end]
+}
+
+/// Overwrite a message's plaintext and ciphertext with zeros in place
+pub fn secure_delete_message(message: &mut ChatMessage) {
+	// into_bytes keeps the same allocation, so the original bytes are overwritten;
+	// zeros are valid UTF-8, so the buffer goes straight back into the String
+	let mut content = std::mem::take(&mut message.content).into_bytes();
+	content.fill(0);
+	message.content = String::from_utf8(content).unwrap_or_default();
+
+	message.encrypted.fill(0);
+}
+
+/// Scrub a user's stored messages and session key before the user is dropped
+pub fn scrub_user(user: &mut User) {
+	for message in user.messages.iter_mut() {
+		secure_delete_message(message);
+	}
+
+	if let Some(session) = &mut user.session {
+		session.encryption_key.fill(0);
+	}
+	for device in user.devices.iter_mut() {
+		device.session.encryption_key.fill(0);
+	}
+}
+
+/// Compare two secrets in a time that depends only on their lengths, not on
+/// where they first differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+
+	let diff = a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y));
+	std::hint::black_box(diff) == 0
+}
+
+/// Replace CR/LF with spaces so user text can't inject extra IRC lines
+pub fn strip_line_breaks(text: &str) -> String {
+	text.replace(['\r', '\n'], " ")
+}
+
+/// Default for the longest channel topic accepted, in bytes
+pub const MAX_TOPIC_LEN: usize = 300;
+
+/// Ceiling for `ServerState::max_topic_length`, keeping 332 replies under 512 bytes
+pub const TOPIC_LEN_LIMIT: usize = 390;
+
+/// Most matches one SEARCH returns; the most recent are kept
+pub const MAX_SEARCH_RESULTS: usize = 20;
+
+/// Minimum time between typing notifications relayed for one user
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Most file transfers one user may have offered and not yet finished
+pub const MAX_TRANSFERS_PER_USER: usize = 4;
+
+/// Most encrypted bytes relayed for a file of `size` bytes: the 8-byte starting
+/// nonce counter, then the framed ciphertext with its closing empty frame
+pub fn max_file_transfer_len(size: u64) -> u64 {
+	let frames = size / FRAME_SIZE as u64 + 2;
+	8 + size + frames * FRAME_OVERHEAD as u64
+}
+
+/// IRCv3 capabilities the server can enable with CAP REQ
+pub const SUPPORTED_CAPS: &[&str] = &["server-time", "message-tags", "sasl", "away-notify"];
+
+/// Longest AUTHENTICATE argument; a chunk of exactly this length means more follow
+pub const SASL_CHUNK_SIZE: usize = 400;
+
+/// Apply a CAP subcommand to the client's capability set, returning the reply to send.
+/// `target` is the client's nickname, or "*" before registration completes.
+pub fn negotiate_cap(
+	target: &str,
+	subcommand: &str,
+	args: &str,
+	caps: &mut HashSet<String>,
+) -> Option<String> {
+	match subcommand.to_uppercase().as_str() {
+		"LS" => Some(format!(
+			":{} CAP {} LS :{}\r\n",
+			"server",
+			target,
+			SUPPORTED_CAPS.join(" ")
+		)),
+		"LIST" => {
+			let mut enabled: Vec<&str> = caps.iter().map(|c| c.as_str()).collect();
+			enabled.sort();
+			Some(format!(
+				":{} CAP {} LIST :{}\r\n",
+				"server",
+				target,
+				enabled.join(" ")
+			))
+		}
+		"REQ" => {
+			// A request is applied all-or-nothing; "-cap" disables a capability
+			let requested: Vec<&str> = args.trim_start_matches(':').split_whitespace().collect();
+			let supported = !requested.is_empty()
+				&& requested
+					.iter()
+					.all(|cap| SUPPORTED_CAPS.contains(&cap.trim_start_matches('-')));
+
+			if !supported {
+				return Some(format!(
+					":{} CAP {} NAK :{}\r\n",
+					"server",
+					target,
+					requested.join(" ")
+				));
+			}
+
+			for cap in &requested {
+				match cap.strip_prefix('-') {
+					Some(name) => caps.remove(name),
+					None => caps.insert(cap.to_string()),
+				};
+			}
+
+			Some(format!(
+				":{} CAP {} ACK :{}\r\n",
+				"server",
+				target,
+				requested.join(" ")
+			))
+		}
+		"END" => None,
+		_ => Some(format!(
+			":{} 410 {} {} :Invalid CAP command\r\n",
+			"server", target, subcommand
+		)),
+	}
+}
+
+/// Send an announcement NOTICE to every connected user, returning how many it reached
+///
+/// The text is prefixed with `ANNOUNCEMENT: ` so clients can highlight it. The
+/// server lock is released before anything is written, since every stream is touched.
+pub fn broadcast_announcement(server: &Arc<Mutex<ServerState>>, message: &str) -> usize {
+	let message = strip_line_breaks(message);
+
+	let recipients: Vec<(String, HashSet<String>, SharedSink)> = {
+		let server = lock_or_recover(server);
+		server
+			.users
+			.values()
+			.filter_map(|user| {
+				let stream = user.stream.clone()?;
+				Some((user.username.clone(), user.caps.clone(), stream))
+			})
+			.collect()
+	};
+
+	for (username, caps, stream) in &recipients {
+		let notice = format!(":{} NOTICE {} :ANNOUNCEMENT: {}\r\n", "server", username, message);
+		let notice = tag_line(caps, &new_message_id(), SystemTime::now(), &notice);
+		let _ = lock_or_recover(stream).write_all(notice.as_bytes());
+	}
+
+	recipients.len()
+}
+
+/// Random ID for a message, sent to clients as the msgid tag
+pub fn new_message_id() -> String {
+	thread_rng()
+		.sample_iter(&Alphanumeric)
+		.take(16)
+		.map(char::from)
+		.collect()
+}
+
+/// Prefix an outgoing PRIVMSG/NOTICE line with the IRCv3 tags the recipient negotiated.
+/// Clients that didn't negotiate CAP get the line unchanged.
+pub fn tag_line(caps: &HashSet<String>, msgid: &str, sent_at: SystemTime, line: &str) -> String {
+	let mut tags = Vec::new();
+
+	if caps.contains("server-time") {
+		let time = DateTime::<Utc>::from(sent_at).to_rfc3339_opts(SecondsFormat::Millis, true);
+		tags.push(format!("time={}", time));
+	}
+	if caps.contains("message-tags") {
+		tags.push(format!("msgid={}", msgid));
+	}
+
+	if tags.is_empty() {
+		line.to_string()
+	} else {
+		format!("@{} {}", tags.join(";"), line)
+	}
+}
+
+/// Which of the four possible tag prefixes `tag_line` adds for these capabilities
+/// (0 when it adds none), so lines can be tagged once per variant
+pub fn tag_variant(caps: &HashSet<String>) -> usize {
+	usize::from(caps.contains("server-time")) | usize::from(caps.contains("message-tags")) << 1
+}
+
+/// The nick!user@host form of a user, as matched by ban masks (user is the token subject)
+pub fn hostmask(user: &User) -> String {
+	format!("{}!{}@{}", user.username, user.id, user.host)
+}
+
+/// Whether a ban mask matches a user. A mask without '!' or '@' is a bare nickname.
+pub fn ban_matches(mask: &str, user: &User) -> bool {
+	let mask = mask.to_lowercase();
+	let mask = if mask.contains('!') || mask.contains('@') {
+		mask
+	} else {
+		format!("{}!*@*", mask)
+	};
+
+	glob_match(&mask, &hostmask(user).to_lowercase())
+}
+
+/// Split a CTCP message ("\x01COMMAND params\x01") into its command and parameters.
+/// Returns None for ordinary text.
+pub fn parse_ctcp(text: &str) -> Option<(&str, &str)> {
+	let inner = text.strip_prefix('\x01')?;
+	let inner = inner.strip_suffix('\x01').unwrap_or(inner);
+
+	match inner.split_once(' ') {
+		Some((command, params)) => Some((command, params)),
+		None => Some((inner, "")),
+	}
+}
+
+/// Decode and validate an HS256 token signed with `jwt_secret`, returning its claims
+pub fn decode_token(jwt_secret: &str, token: &str) -> Result<TokenClaims, String> {
+	let key = DecodingKey::from_secret(jwt_secret.as_bytes());
+	decode_token_with_key(token, &key, &[Algorithm::HS256])
+}
+
+/// Whether `algorithm` verifies with the shared secret rather than an RSA public key
+pub fn is_hmac_algorithm(algorithm: Algorithm) -> bool {
+	matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/// Whether `algorithm` verifies with an RSA public key
+pub fn is_rsa_algorithm(algorithm: Algorithm) -> bool {
+	matches!(
+		algorithm,
+		Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512
+	)
+}
+
+fn decode_token_with_key(
+	token: &str,
+	key: &DecodingKey,
+	algorithms: &[Algorithm],
+) -> Result<TokenClaims, String> {
+	// Pin the accepted set explicitly so the header's alg can't pick another one
+	let mut validation = Validation::default();
+	validation.algorithms = algorithms.to_vec();
+
+	match jsonwebtoken::decode::<TokenClaims>(token, key, &validation) {
+		Ok(token_data) => Ok(token_data.claims),
+		Err(e) => Err(format!("Invalid token: {}", e)),
+	}
+}
+
+impl ServerState {
+	/// Server state with default settings and no users or channels
+	pub fn new(jwt_secret: &str) -> Self {
+		ServerState {
+			users: HashMap::new(),
+			channels: HashMap::new(),
+			jwt_secret: jwt_secret.to_string(),
+			jwt_algorithms: vec![Algorithm::HS256],
+			jwt_public_key: None,
+			message_ttl: Duration::from_secs(3600), // 1 hour default
+			session_timeout: Duration::from_secs(3600), // 1 hour default
+			max_token_size: 65536, // Room for tokens with large avatars
+			command_buffer_size: 1024,
+			admins: HashSet::new(),
+			inactivity_warning: Duration::from_secs(300), // Warn 5 minutes before timeout
+			ping_timeout: Duration::from_secs(120),
+			username_to_id: HashMap::new(),
+			connections_per_ip: HashMap::new(),
+			max_connections_per_ip: 10,
+			channel_creation_policy: ChannelCreationPolicy::AllowAutoCreate,
+			max_line_length: 512, // RFC 1459 line limit
+			resume_grace: Duration::from_secs(60),
+			empty_channel_ttl: Duration::from_secs(86400), // 24 hours
+			scrub_hook: None,
+			auth_failures: HashMap::new(),
+			max_auth_failures: 5,
+			auth_failure_window: Duration::from_secs(60),
+			max_avatar_size: 256 * 1024,
+			transfers: HashMap::new(),
+			max_file_size: 10 * 1024 * 1024, // 10 MB
+			welcome_template: None,
+			security_notice_template: None,
+			max_nick_length: 30,
+			max_channel_name_length: MAX_CHANNEL_NAME_LEN,
+			max_topic_length: MAX_TOPIC_LEN,
+			max_channels_per_user: 50,
+			audit_log: None,
+			channel_list: None,
+			drained_channels: HashMap::new(),
+			clock: Arc::new(SystemClock),
+			nick_auto_suffix: false,
+			avatar_store: HashMap::new(),
+			fetched_avatars: HashMap::new(),
+			avatar_fetcher: None,
+			revoked_jtis: HashSet::new(),
+		}
+	}
+
+	/// Every channel as listed by LIST, sorted by name. The snapshot is cached and
+	/// only rebuilt after `invalidate_channel_list`, so repeated LISTs are cheap.
+	pub fn channel_listing(&mut self) -> Arc<Vec<ChannelListing>> {
+		if let Some(list) = &self.channel_list {
+			return list.clone();
+		}
+
+		let mut list: Vec<ChannelListing> = self
+			.channels
+			.values()
+			.map(|channel| ChannelListing {
+				name: channel.name.clone(),
+				users: channel.users.len(),
+				topic: channel.topic.clone(),
+				secret: channel.secret,
+			})
+			.collect();
+		list.sort_by(|a, b| a.name.cmp(&b.name));
+
+		let list = Arc::new(list);
+		self.channel_list = Some(list.clone());
+		list
+	}
+
+	/// Mark the cached LIST snapshot stale; call after creating or removing a channel
+	/// or changing its members, topic or modes
+	pub fn invalidate_channel_list(&mut self) {
+		self.channel_list = None;
+	}
+
+	/// Record a connection event in the audit log, if one is configured
+	pub fn audit(&self, event: AuditEvent) {
+		if let Some(log) = &self.audit_log {
+			log.record(&event);
+		}
+	}
+
+	/// The ISUPPORT (005) tokens advertising this server's limits and channel modes
+	pub fn isupport_tokens(&self) -> Vec<String> {
+		vec![
+			format!("NETWORK={}", NETWORK_NAME),
+			"CHANTYPES=#".to_string(),
+			"CHANMODES=b,,,st".to_string(), // Ban list; secret and topic lock take no parameter
+			format!("CHANNELLEN={}", self.max_channel_name_length),
+			format!("NICKLEN={}", self.max_nick_length),
+			format!("CHANLIMIT=#:{}", self.max_channels_per_user),
+			format!("TOPICLEN={}", self.max_topic_length),
+			format!("LINELEN={}", self.max_line_length),
+		]
+	}
+
+	/// Decode and validate a token signed with one of `jwt_algorithms`, returning its claims
+	///
+	/// The header's alg only selects between the secret and the RSA key; validation is
+	/// then pinned to the accepted algorithms of that key's family. Headers naming any
+	/// other algorithm, including `none`, are rejected before any key is used.
+	pub fn decode_token(&self, token: &str) -> Result<TokenClaims, String> {
+		let header = jsonwebtoken::decode_header(token).map_err(|e| format!("Invalid token: {}", e))?;
+		if !self.jwt_algorithms.contains(&header.alg) {
+			return Err(format!("Invalid token: algorithm {:?} is not accepted", header.alg));
+		}
+
+		let hmac = is_hmac_algorithm(header.alg);
+		let key = if hmac {
+			DecodingKey::from_secret(self.jwt_secret.as_bytes())
+		} else {
+			let pem = self.jwt_public_key.as_ref().ok_or("No RSA public key is configured")?;
+			DecodingKey::from_rsa_pem(pem.as_bytes()).map_err(|e| format!("Invalid RSA public key: {}", e))?
+		};
+
+		let algorithms: Vec<Algorithm> = self
+			.jwt_algorithms
+			.iter()
+			.copied()
+			.filter(|algorithm| is_hmac_algorithm(*algorithm) == hmac)
+			.collect();
+		decode_token_with_key(token, &key, &algorithms)
+	}
+
+	/// Whether new connections from `ip` are refused after repeated authentication failures
+	pub fn auth_locked_out(&self, ip: IpAddr, now: Instant) -> bool {
+		self.auth_failures
+			.get(&ip)
+			.and_then(|failures| failures.blocked_until)
+			.map_or(false, |until| now < until)
+	}
+
+	/// Count a failed authentication from `ip`, locking it out once it reaches the limit
+	///
+	/// Returns the lockout length when this failure started one. The first lockout
+	/// lasts one failure window and each later one twice as long as the last, up to
+	/// `MAX_AUTH_LOCKOUT`.
+	pub fn record_auth_failure(&mut self, ip: IpAddr, now: Instant) -> Option<Duration> {
+		let window = self.auth_failure_window;
+		let max_failures = self.max_auth_failures;
+		let failures = self.auth_failures.entry(ip).or_insert_with(|| AuthFailures {
+			recent: VecDeque::new(),
+			cooldown: Duration::ZERO,
+			blocked_until: None,
+		});
+
+		while failures.recent.front().map_or(false, |t| now.duration_since(*t) >= window) {
+			failures.recent.pop_front();
+		}
+		failures.recent.push_back(now);
+		if failures.recent.len() < max_failures {
+			return None;
+		}
+
+		let cooldown = if failures.cooldown.is_zero() {
+			window
+		} else {
+			failures.cooldown.saturating_mul(2)
+		}
+		.min(MAX_AUTH_LOCKOUT);
+		failures.recent.clear();
+		failures.cooldown = cooldown;
+		failures.blocked_until = Some(now + cooldown);
+		Some(cooldown)
+	}
+
+	/// Forget IPs with no recent failures whose last lockout ended at least that long ago
+	pub fn prune_auth_failures(&mut self, now: Instant) {
+		let window = self.auth_failure_window;
+		self.auth_failures.retain(|_, failures| {
+			let recent = failures.recent.back().map_or(false, |t| now.duration_since(*t) < window);
+			let remembered = failures
+				.blocked_until
+				.map_or(false, |until| now < until + failures.cooldown);
+			recent || remembered
+		});
+	}
+
+	/// Add a connected user, keeping the username index in step
+	pub fn add_user(&mut self, user: User) {
+		// A reconnect with the same ID replaces the old user and its username
+		if let Some(old) = self.users.get(&user.id) {
+			self.username_to_id.remove(&old.username);
+		}
+
+		self.username_to_id.insert(user.username.clone(), user.id.clone());
+		self.users.insert(user.id.clone(), user);
+		self.debug_check_username_index();
+	}
+
+	/// Remove a user, keeping the username index in step
+	pub fn remove_user(&mut self, user_id: &str) -> Option<User> {
+		let user = self.users.remove(user_id)?;
+		if self.username_to_id.get(&user.username).map(String::as_str) == Some(user_id) {
+			self.username_to_id.remove(&user.username);
+		}
+		self.debug_check_username_index();
+		Some(user)
+	}
+
+	/// End every file transfer `user_id` takes part in, telling the other side
+	pub fn cancel_transfers(&mut self, user_id: &str) {
+		let ids: Vec<String> = self
+			.transfers
+			.iter()
+			.filter(|(_, t)| t.sender == user_id || t.recipient == user_id)
+			.map(|(id, _)| id.clone())
+			.collect();
+
+		for id in ids {
+			if let Some(transfer) = self.transfers.remove(&id) {
+				let other = if transfer.sender == user_id {
+					&transfer.recipient
+				} else {
+					&transfer.sender
+				};
+				if let Some(stream) = self.users.get(other).and_then(|u| u.stream.as_ref()) {
+					let _ = lock_or_recover(stream)
+						.write_all(format!(":{} FILE CANCEL {}\r\n", "server", id).as_bytes());
+				}
+			}
+		}
+	}
+
+	/// Write a raw line to every member of a channel, except `exclude_user`
+	pub fn send_to_channel(&self, channel_name: &str, line: &str, exclude_user: Option<&str>) {
+		let channel = match self.channels.get(channel_name) {
+			Some(channel) => channel,
+			None => return,
+		};
+
+		for user_id in &channel.users {
+			if exclude_user == Some(user_id.as_str()) {
+				continue;
+			}
+			if let Some(stream) = self.users.get(user_id).and_then(|u| u.stream.as_ref()) {
+				let _ = lock_or_recover(stream).write_all(line.as_bytes());
+			}
+		}
+	}
+
+	/// Write a raw line once to every other user sharing a channel with `user_id`;
+	/// with `cap`, only to those who negotiated that capability
+	pub fn send_to_channel_peers(&self, user_id: &str, line: &str, cap: Option<&str>) {
+		let channels = match self.users.get(user_id) {
+			Some(user) => &user.channels,
+			None => return,
+		};
+
+		let mut recipients = HashSet::new();
+		for channel_name in channels {
+			if let Some(channel) = self.channels.get(channel_name) {
+				recipients.extend(channel.users.iter().filter(|id| id.as_str() != user_id));
+			}
+		}
+
+		for recipient_id in recipients {
+			if let Some(user) = self.users.get(recipient_id) {
+				if cap.map_or(false, |cap| !user.caps.contains(cap)) {
+					continue;
+				}
+				if let Some(stream) = &user.stream {
+					let _ = lock_or_recover(stream).write_all(line.as_bytes());
+				}
+			}
+		}
+	}
+
+	/// IDs of the users with a session (on any device) authenticated by the token `jti`
+	pub fn users_with_jti(&self, jti: &str) -> Vec<String> {
+		self.users
+			.values()
+			.filter(|user| {
+				user.session
+					.iter()
+					.chain(user.devices.iter().map(|device| &device.session))
+					.any(|session| session.jti.as_deref() == Some(jti))
+			})
+			.map(|user| user.id.clone())
+			.collect()
+	}
+
+	/// Disconnect every user with a session authenticated by the token `jti` and refuse
+	/// the token from then on. Each is sent `ERROR :<reason>`, and `by` is recorded in
+	/// the audit log. Returns how many users were disconnected.
+	pub fn revoke_token(&mut self, jti: &str, by: &str, reason: &str) -> usize {
+		self.revoked_jtis.insert(jti.to_string());
+
+		let user_ids = self.users_with_jti(jti);
+		for user_id in &user_ids {
+			self.audit(AuditEvent::Kill { user_id, by, reason });
+			if let Some(stream) = self.users.get(user_id).and_then(|user| user.stream.as_ref()) {
+				let mut stream = lock_or_recover(stream);
+				let _ = stream.write_all(format!("ERROR :{}\r\n", reason).as_bytes());
+				let _ = stream.shutdown();
+			}
+			self.disconnect_user(user_id, reason);
+		}
+		user_ids.len()
+	}
+
+	/// Disconnect a user and clean up their data; `reason` is shown in the QUIT line their peers see
+	pub fn disconnect_user(&mut self, user_id: &str, reason: &str) {
+		let username = self
+			.users
+			.get(user_id)
+			.map(|u| u.username.clone())
+			.unwrap_or_else(|| "Unknown".to_string());
+
+		self.send_to_channel_peers(user_id, &format!(":{} QUIT :{}\r\n", username, reason), None);
+
+		// Remove user from all channels
+		self.invalidate_channel_list();
+		if let Some(user) = self.users.get(user_id) {
+			for channel_name in &user.channels.clone() {
+				if let Some(channel) = self.channels.get_mut(channel_name) {
+					channel.users.remove(user_id);
+					channel.operators.remove(user_id);
+
+					// Notify other users
+					let leave_message = format!("* {} has disconnected ({})", username, reason);
+					let msg = format!(":{} PRIVMSG {} :{}\r\n", username, channel_name, leave_message);
+					for other_id in &channel.users {
+						if other_id != user_id {
+							if let Some(other) = self.users.get(other_id) {
+								if let Some(other_stream) = &other.stream {
+									let _ = lock_or_recover(other_stream).write_all(msg.as_bytes());
+								}
+							}
+						}
+					}
+				}
+			}
+		}
+
+		self.cancel_transfers(user_id);
+
+		// Overwrite stored messages and the session key before the user is dropped
+		if let Some(user) = self.users.get_mut(user_id) {
+			scrub_user(user);
+
+			if let Some(hook) = &self.scrub_hook {
+				hook(user);
+			}
+		}
+
+		// Remove user from server
+		self.remove_user(user_id);
+		info!("User disconnected and data cleared: {}", username);
+	}
+
+	/// Look up a connected user's ID by username in O(1)
+	pub fn user_id_by_username(&self, username: &str) -> Option<&String> {
+		self.username_to_id.get(username)
+	}
+
+	/// Whether a username is taken by a connected user
+	pub fn username_in_use(&self, username: &str) -> bool {
+		self.username_to_id.contains_key(username)
+	}
+
+	// The index must map exactly the usernames of the users in `users`
+	fn debug_check_username_index(&self) {
+		debug_assert_eq!(self.username_to_id.len(), self.users.len());
+		debug_assert!(self
+			.username_to_id
+			.iter()
+			.all(|(name, id)| self.users.get(id).map(|u| &u.username) == Some(name)));
+	}
 }
 
 impl MessageHandler {
 	pub fn new(
 		user_id: String,
-		stream: Arc<Mutex<TcpStream>>,
+		stream: SharedSink,
 		server: Arc<Mutex<ServerState>>,
+		log_prefix: String,
 	) -> Self {
 		MessageHandler {
 			user_id,
 			stream,
 			server,
+			log_prefix,
 		}
 	}
 
@@ -41,196 +860,1708 @@ impl MessageHandler {
 			return Ok(());
 		}
 
-		// Update user's last activity time
+		// PONG and TYPING are sent automatically by clients, so they must not count as user activity
+		match parts[0].to_uppercase().as_str() {
+			"PONG" => return self.handle_pong(parts),
+			"TYPING" => return self.handle_typing(parts),
+			_ => {}
+		}
+
+		// Update the last activity time of this connection's session
 		{
-			let mut server = self.server.lock().unwrap();
-			if let Some(user) = server.users.get_mut(&self.user_id) {
-				if let Some(session) = &mut user.session {
-					session.update_activity();
-				}
+			let mut server = lock_or_recover(&self.server);
+			if let Some(session) = server.users.get_mut(&self.user_id).and_then(|user| user.session_for(&self.stream)) {
+				session.update_activity();
 			}
 		}
 
 		match parts[0].to_uppercase().as_str() {
 			"JOIN" => self.handle_join(parts),
 			"PART" => self.handle_leave(parts),
+			"PARTALL" => self.handle_part_all(),
 			"PRIVMSG" => self.handle_privmsg(parts),
-			"LIST" => self.handle_list(),
+			"LIST" => self.handle_list(parts),
 			"WHO" => self.handle_who(parts),
+			"WHOIS" => self.handle_whois(parts),
+			"SEARCH" => self.handle_search(parts),
+			"TOPIC" => self.handle_topic(parts),
 			"QUIT" => self.handle_quit(parts),
 			"SECURECLEAR" => self.handle_secure_clear(),
+			"REKEY" => self.handle_rekey(),
+			"TIME" => self.handle_time(),
+			"KILL" => self.handle_kill(parts),
+			"KILLTOKEN" => self.handle_killtoken(parts),
+			"ANNOUNCE" | "WALLOPS" => self.handle_announce(parts),
+			"AWAY" => self.handle_away(parts),
+			"CAP" => self.handle_cap(parts),
+			"CONFIG" => self.handle_config(parts),
+			"MODE" => self.handle_mode(parts),
+			"SILENCE" => self.handle_silence(parts),
+			"CREATE" => self.handle_create(parts),
+			"DRAIN" => self.handle_drain(parts),
+			"PROFILE" => self.handle_profile(parts),
+			"MYCHANNELS" => self.handle_mychannels(),
+			"SENDFILE" => self.handle_sendfile(parts),
+			"ACCEPTFILE" => self.handle_acceptfile(parts),
+			"FILEDATA" => self.handle_filedata(parts),
+			"FILEDONE" => self.handle_filedone(parts),
+			"CANCELFILE" => self.handle_cancelfile(parts),
 			_ => self.handle_unknown(parts[0]),
 		}
 	}
 
-	fn handle_join(&mut self, parts: Vec<&str>) -> Result<(), String> {
+	fn handle_join(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(461, "JOIN", "Not enough parameters");
+		}
+
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+		let channel = channel.as_str();
+
+		let mut server = lock_or_recover(&self.server);
+
+		// A drained channel can't be recreated until its cooldown is over
+		let now = Instant::now();
+		server.drained_channels.retain(|_, until| *until > now);
+		if server.drained_channels.contains_key(channel) {
+			drop(server);
+			return self.send_numeric(437, channel, "Channel is temporarily unavailable");
+		}
+
+		// Banned users can't join
+		if let (Some(ch), Some(user)) = (server.channels.get(channel), server.users.get(&self.user_id)) {
+			if ch.bans.iter().any(|mask| ban_matches(mask, user)) {
+				drop(server);
+				return self.send_numeric(474, channel, "Cannot join channel (+b)");
+			}
+		}
+
+		// Joining a new channel counts against the limit whether or not it creates it
+		if let Some(user) = server.users.get(&self.user_id) {
+			if !user.channels.contains(channel) && user.channels.len() >= server.max_channels_per_user {
+				drop(server);
+				return self.send_numeric(405, channel, "You have joined too many channels");
+			}
+		}
+
+		// Create channel if it doesn't exist and the policy allows it
+		if !server.channels.contains_key(channel) {
+			if server.channel_creation_policy == ChannelCreationPolicy::RequirePreRegistration {
+				drop(server);
+				return self.send_numeric(403, channel, "No such channel");
+			}
+			if channel.len() > server.max_channel_name_length {
+				drop(server);
+				return self.send_numeric(479, channel, "Channel name too long");
+			}
+
+			// Whoever creates a channel by joining it is its operator
+			let mut created = Self::new_channel(channel, false);
+			created.operators.insert(self.user_id.clone());
+			server.channels.insert(channel.to_string(), created);
+		}
+
+		// Add user to channel
+		if let Some(ch) = server.channels.get_mut(channel) {
+			ch.users.insert(self.user_id.clone());
+			ch.last_activity = Instant::now();
+		}
+		server.invalidate_channel_list();
+
+		// Add channel to user's channels
+		if let Some(user) = server.users.get_mut(&self.user_id) {
+			user.channels.insert(channel.to_string());
+
+			// Send join confirmation to user
+			if let Some(stream) = &user.stream {
+				let _ = lock_or_recover(stream)
+					.write_all(format!(":{} JOIN {}\r\n", user.username, channel).as_bytes());
+			}
+		}
+
+		// Get username for broadcast
+		let username = match server.users.get(&self.user_id) {
+			Some(user) => user.username.clone(),
+			None => return Err("User not found".to_string()),
+		};
+
+		// Members keep their member lists current from JOIN, PART and QUIT lines
+		server.send_to_channel(
+			channel,
+			&format!(":{} JOIN {}\r\n", username, channel),
+			Some(&self.user_id),
+		);
+
+		// Broadcast join message to channel
+		let join_message = format!("* {} has joined {}", username, channel);
+		let msgid = new_message_id();
+		let sent_at = SystemTime::now();
+		Self::broadcast_to_channel(
+			&mut server,
+			channel,
+			&username,
+			&join_message,
+			Some(&self.user_id),
+			&msgid,
+			sent_at,
+		);
+
+		// Store join message in channel history
+		Self::store_channel_message(&mut server, channel, "SYSTEM", &join_message, &msgid, sent_at, None);
+
+		Ok(())
+	}
+
+	fn handle_leave(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(461, "PART", "Not enough parameters");
+		}
+
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+
+		let mut server = lock_or_recover(&self.server);
+		if !server.users.contains_key(&self.user_id) {
+			return Err("User not found".to_string());
+		}
+
+		Self::part_channel(&mut server, &self.user_id, &channel, None);
+
+		Ok(())
+	}
+
+	// Take a user out of a channel: remaining members see the PART (with the reason,
+	// if any) and a leave notice, the user gets the same PART as confirmation, and the
+	// channel is removed once empty unless an admin created it
+	fn part_channel(server: &mut ServerState, user_id: &str, channel: &str, reason: Option<&str>) {
+		let username = match server.users.get(user_id) {
+			Some(user) => user.username.clone(),
+			None => return,
+		};
+		let part = match reason {
+			Some(reason) => format!(":{} PART {} :{}\r\n", username, channel, reason),
+			None => format!(":{} PART {}\r\n", username, channel),
+		};
+
+		// Remove user from channel
+		server.invalidate_channel_list();
+		if let Some(ch) = server.channels.get_mut(channel) {
+			ch.users.remove(user_id);
+			ch.operators.remove(user_id);
+			ch.last_activity = Instant::now();
+
+			// Remove empty channels (unless an admin created them)
+			if ch.users.is_empty() && !ch.persistent {
+				server.channels.remove(channel);
+			} else {
+				server.send_to_channel(channel, &part, None);
+
+				// Broadcast leave message to remaining users
+				let leave_message = format!("* {} has left {}", username, channel);
+				let msgid = new_message_id();
+				let sent_at = SystemTime::now();
+				Self::broadcast_to_channel(server, channel, &username, &leave_message, None, &msgid, sent_at);
+
+				// Store leave message in channel history
+				Self::store_channel_message(server, channel, "SYSTEM", &leave_message, &msgid, sent_at, None);
+			}
+		}
+
+		// Remove channel from user's list
+		if let Some(user) = server.users.get_mut(user_id) {
+			user.channels.remove(channel);
+
+			// Send part confirmation to user
+			if let Some(stream) = &user.stream {
+				let _ = lock_or_recover(stream).write_all(part.as_bytes());
+			}
+		}
+	}
+
+	// Leave every channel the user is in, exactly as if they had sent PART for each
+	fn handle_part_all(&mut self) -> Result<(), String> {
+		let mut channels: Vec<String> = {
+			let server = lock_or_recover(&self.server);
+			match server.users.get(&self.user_id) {
+				Some(user) => user.channels.iter().cloned().collect(),
+				None => return Err("User not found".to_string()),
+			}
+		};
+		channels.sort();
+
+		for channel in channels {
+			self.handle_leave(vec!["PART", &channel])?;
+		}
+
+		Ok(())
+	}
+
+	fn handle_privmsg(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 3 {
+			return self.send_numeric(461, "PRIVMSG", "Not enough parameters");
+		}
+
+		// "PRIVMSG target +ttl=<seconds> :body" stores the message for less than the server TTL
+		let (ttl, body) = match parts[2].strip_prefix("+ttl=").and_then(|rest| rest.split_once(' ')) {
+			Some((seconds, body)) => {
+				let max_ttl = lock_or_recover(&self.server).message_ttl;
+				let ttl = seconds
+					.parse::<u64>()
+					.ok()
+					.map(Duration::from_secs)
+					.filter(|ttl| !ttl.is_zero() && *ttl <= max_ttl);
+				match ttl {
+					Some(ttl) => (Some(ttl), body),
+					None => {
+						return self.send_fail(
+							"PRIVMSG",
+							"INVALID_TTL",
+							&format!("TTL must be between 1 and {} seconds", max_ttl.as_secs()),
+						)
+					}
+				}
+			}
+			None => (None, parts[2]),
+		};
+
+		// Never relay line breaks, which would let the sender inject commands into other streams
+		let message = strip_line_breaks(body);
+		let message = message.strip_prefix(':').unwrap_or(&message);
+
+		// Keep "PRIVMSG target :body\r\n" within the maximum line length
+		let max_line_length = lock_or_recover(&self.server).max_line_length;
+		let overhead = "PRIVMSG ".len() + parts[1].len() + " :".len() + "\r\n".len();
+		let max_body = max_line_length.saturating_sub(overhead);
+		let message = if message.len() > max_body {
+			let truncated = truncate_utf8(message, max_body);
+			self.send_notice(&format!(
+				"Message truncated to {} bytes (maximum line length is {})",
+				truncated.len(),
+				max_line_length
+			))?;
+			truncated
+		} else {
+			message
+		};
+
+		// Channel targets are validated and normalized like JOIN
+		let target = if parts[1].starts_with('#') {
+			match normalize_channel_name(parts[1]) {
+				Ok(name) => name,
+				Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+			}
+		} else {
+			parts[1].to_string()
+		};
+		let target = target.as_str();
+
+		let mut server = lock_or_recover(&self.server);
+
+		// Get sender info
+		let (sender_name, in_channel) = match server.users.get(&self.user_id) {
+			Some(user) => (user.username.clone(), user.channels.contains(target)),
+			None => return Err("Sender not found".to_string()),
+		};
+
+		// Update the activity of the sender's session on this connection
+		if let Some(session) = server.users.get_mut(&self.user_id).and_then(|user| user.session_for(&self.stream)) {
+			session.update_activity();
+		}
+
+		// CTCP requests are wrapped in \x01; ACTION (/me) is kept in history as "* nick text"
+		let ctcp = parse_ctcp(message);
+		let action = match ctcp {
+			Some(("ACTION", text)) => Some(format!("* {} {}", sender_name, text)),
+			_ => None,
+		};
+
+		// Check if target is a channel or user
+		if target.starts_with('#') {
+			// Channel message
+			// Check if user is in channel
+			if !in_channel {
+				let exists = server.channels.contains_key(target);
+				drop(server);
+				return if exists {
+					self.send_numeric(404, target, "Cannot send to channel")
+				} else {
+					self.send_numeric(403, target, "No such channel")
+				};
+			}
+
+			// The channel may have been removed while the sender still lists it (e.g. a
+			// message in flight as it emptied); drop the stale membership rather than
+			// storing or relaying the message
+			if !server.channels.contains_key(target) {
+				if let Some(user) = server.users.get_mut(&self.user_id) {
+					user.channels.remove(target);
+				}
+				drop(server);
+				return self.send_numeric(403, target, "No such channel");
+			}
+
+			// Other CTCP requests (VERSION, PING, ...) have no meaning for a channel
+			if ctcp.is_some() && action.is_none() {
+				debug!("{} Ignoring CTCP request to {}", self.log_prefix, target);
+				return Ok(());
+			}
+
+			// Format the message; channel lines carry the sender in the text,
+			// so actions are relayed in their display form rather than as CTCP
+			let (formatted_message, content) = match &action {
+				Some(action) => (action.clone(), action.as_str()),
+				None => (format!("<{}> {}", sender_name, message), message),
+			};
+			let msgid = new_message_id();
+			let sent_at = SystemTime::now();
+
+			// Store in channel history
+			Self::store_channel_message(&mut server, target, &sender_name, content, &msgid, sent_at, ttl);
+
+			// Broadcast message
+			Self::broadcast_to_channel(
+				&mut server,
+				target,
+				&sender_name,
+				&formatted_message,
+				Some(&self.user_id),
+				&msgid,
+				sent_at,
+			);
+		} else {
+			// Private message
+			// Find recipient by username
+			let recipient_id = match Self::find_user_by_username(&server, target) {
+				Some(id) => id,
+				None => {
+					drop(server);
+					return self.send_numeric(401, target, "No such nick");
+				}
+			};
+
+			let msgid = new_message_id();
+			let sent_at = SystemTime::now();
+
+			// Store message in sender's and recipient's history (CTCP requests other than
+			// ACTION are relayed so the recipient can answer, but are not history)
+			match (&action, ctcp) {
+				(Some(action), _) => Self::store_private_message(
+					&mut server,
+					&self.user_id,
+					&recipient_id,
+					action,
+					&msgid,
+					sent_at,
+					ttl,
+				),
+				(None, None) => Self::store_private_message(
+					&mut server,
+					&self.user_id,
+					&recipient_id,
+					message,
+					&msgid,
+					sent_at,
+					ttl,
+				),
+				(None, Some(_)) => {}
+			}
+
+			// Send message to recipient, with any CTCP framing intact
+			if let Some(recipient) = server.users.get(&recipient_id) {
+				if let Some(stream) = &recipient.stream {
+					let pm_message = tag_line(
+						&recipient.caps,
+						&msgid,
+						sent_at,
+						&format!("PRIVMSG {} :{}\r\n", sender_name, message),
+					);
+					if let Err(e) = lock_or_recover(stream).write_all(pm_message.as_bytes()) {
+						return Err(format!("Failed to send message: {}", e));
+					}
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handle_list(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		// Optional glob pattern such as "#rust*" (channel names are stored lowercase)
+		let pattern = parts
+			.get(1)
+			.map(|p| p.trim_start_matches(':').to_lowercase())
+			.filter(|p| !p.is_empty());
+
+		// Take the cached snapshot and filter it after releasing the lock
+		let (listing, my_channels, stream, own_nick) = {
+			let mut server = lock_or_recover(&self.server);
+			let (my_channels, stream, own_nick) = match server.users.get(&self.user_id) {
+				Some(user) => (user.channels.clone(), user.stream.clone(), user.username.clone()),
+				None => return Err("User not found".to_string()),
+			};
+			(server.channel_listing(), my_channels, stream, own_nick)
+		};
+
+		// Send list of matching channels
+		let mut channel_list = String::new();
+		for channel in listing.iter() {
+			if let Some(pattern) = &pattern {
+				if !glob_match(pattern, &channel.name) {
+					continue;
+				}
+			}
+
+			// Secret channels are only listed to their members
+			if channel.secret && !my_channels.contains(&channel.name) {
+				continue;
+			}
+
+			channel_list.push_str(&format!(
+				":{} 322 {} {} {} :{}\r\n",
+				"server", own_nick, channel.name, channel.users, channel.topic
+			));
+		}
+
+		channel_list.push_str(&format!(
+			":{} 323 {} :End of LIST\r\n",
+			"server", own_nick
+		));
+
+		if let Some(stream) = stream {
+			if let Err(e) = lock_or_recover(&stream).write_all(channel_list.as_bytes()) {
+				return Err(format!("Failed to send channel list: {}", e));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handle_who(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		// A bare WHO or "WHO *" lists every connected user instead of one channel
+		let channel = match parts.get(1).copied() {
+			None | Some("*") => None,
+			Some(name) => match normalize_channel_name(name) {
+				Ok(name) => Some(name),
+				Err((numeric, reason)) => return self.send_numeric(numeric, name, reason),
+			},
+		};
+
+		let server = lock_or_recover(&self.server);
+
+		let mut user_ids: Vec<&String> = match &channel {
+			Some(channel) => match server.channels.get(channel) {
+				// Members of a secret channel stay hidden from outsiders
+				Some(ch) if ch.secret && !ch.users.contains(&self.user_id) => Vec::new(),
+				Some(ch) => ch.users.iter().collect(),
+				None => {
+					drop(server);
+					return self.send_numeric(403, channel, "No such channel");
+				}
+			},
+			None => server
+				.users
+				.iter()
+				.filter(|(id, user)| {
+					user.stream.is_some()
+						&& (*id == &self.user_id || !self.in_hidden_channel(&server, user))
+				})
+				.map(|(id, _)| id)
+				.collect(),
+		};
+		let channel = channel.as_deref().unwrap_or("*");
+
+		// List users alphabetically so replies are stable
+		user_ids.sort_by_key(|id| server.users.get(*id).map(|user| user.username.to_lowercase()));
+
+		// Send list of users
+		let own_nick = self.nick(&server);
+		let mut who_list = String::new();
+		let now_unix = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_secs())
+			.unwrap_or(0);
+		for user_id in user_ids {
+			if let Some(user) = server.users.get(user_id) {
+				// H = here, G = gone (away)
+				let status = if user.away.is_some() { "G" } else { "H" };
+
+				// Idle and signon times go in the realname field so the reply stays valid
+				let (idle, signon) = match &user.session {
+					Some(session) => (
+						session.idle_time().as_secs(),
+						now_unix.saturating_sub(session.duration().as_secs()),
+					),
+					None => (0, now_unix),
+				};
+
+				who_list.push_str(&format!(
+					":{} 352 {} {} {} {} {} {} {} :0 {} (idle {}s, signon {})\r\n",
+					"server",
+					own_nick,
+					channel,
+					user.username,
+					"hostname",
+					"server",
+					user.username,
+					status,
+					user.username,
+					idle,
+					signon
+				));
+			}
+		}
+
+		who_list.push_str(&format!(
+			":{} 315 {} {} :End of WHO list\r\n",
+			"server", own_nick, channel
+		));
+
+		if let Some(user) = server.users.get(&self.user_id) {
+			if let Some(stream) = &user.stream {
+				if let Err(e) = lock_or_recover(stream).write_all(who_list.as_bytes()) {
+					return Err(format!("Failed to send WHO list: {}", e));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// Search a channel's stored history for a case-insensitive substring. Each match is a
+	// 920 line (sender and send time in Unix seconds, then the text), oldest first and
+	// tagged like the original message; 921 ends the reply. Only members may search,
+	// and expired messages and join/part notices are never returned.
+	fn handle_search(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 3 {
+			return self.send_numeric(461, "SEARCH", "Not enough parameters");
+		}
+
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+		let query = parts[2..].join(" ");
+		let query = query.trim_start_matches(':').trim().to_lowercase();
+		if query.is_empty() {
+			return self.send_numeric(461, "SEARCH", "Not enough parameters");
+		}
+
+		let server = lock_or_recover(&self.server);
+
+		let ch = match server.channels.get(&channel) {
+			Some(ch) => ch,
+			None => {
+				drop(server);
+				return self.send_numeric(403, &channel, "No such channel");
+			}
+		};
+		if !ch.users.contains(&self.user_id) {
+			drop(server);
+			return self.send_numeric(442, &channel, "You're not on that channel");
+		}
+
+		let now = server.clock.now();
+		let matches: Vec<&ChatMessage> = ch
+			.messages
+			.iter()
+			.filter(|msg| {
+				msg.sender != "SYSTEM"
+					&& !msg.is_expired(now, server.message_ttl)
+					&& msg.content.to_lowercase().contains(&query)
+			})
+			.collect();
+		let skip = matches.len().saturating_sub(MAX_SEARCH_RESULTS);
+
+		let user = match server.users.get(&self.user_id) {
+			Some(user) => user,
+			None => return Err("User not found".to_string()),
+		};
+
+		let own_nick = self.nick(&server);
+		let mut reply = String::new();
+		for msg in &matches[skip..] {
+			let sent_unix = msg
+				.sent_at
+				.duration_since(UNIX_EPOCH)
+				.map(|d| d.as_secs())
+				.unwrap_or(0);
+			let line = format!(
+				":{} 920 {} {} {} {} :{}\r\n",
+				"server", own_nick, channel, msg.sender, sent_unix, msg.content
+			);
+			reply.push_str(&tag_line(&user.caps, &msg.id, msg.sent_at, &line));
+		}
+		reply.push_str(&format!(
+			":{} 921 {} {} :End of search results\r\n",
+			"server", own_nick, channel
+		));
+
+		if let Some(stream) = &user.stream {
+			if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+				return Err(format!("Failed to send search results: {}", e));
+			}
+		}
+
+		Ok(())
+	}
+
+	// Describe one user: 311 (nick, username), 319 (channels the requester may see)
+	// and 301 (away message), terminated by 318
+	fn handle_whois(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(431, "", "No nickname given");
+		}
+
+		let nick = parts[1];
+		let server = lock_or_recover(&self.server);
+
+		// Sessions waiting for RESUME have no connection, so they are not listed
+		let user = match Self::find_user_by_username(&server, nick)
+			.and_then(|id| server.users.get(&id))
+			.filter(|user| user.stream.is_some())
+		{
+			Some(user) => user,
+			None => {
+				drop(server);
+				return self.send_numeric(401, nick, "No such nick");
+			}
+		};
+
+		let own_nick = self.nick(&server);
+		let mut reply = format!(
+			":{} 311 {} {} {} {} * :{}\r\n",
+			"server", own_nick, user.username, user.username, "hostname", user.username
+		);
+
+		// Secret channels are only shown to their members
+		let mut channels: Vec<&str> = user
+			.channels
+			.iter()
+			.filter(|name| {
+				server
+					.channels
+					.get(*name)
+					.map_or(false, |ch| !ch.secret || ch.users.contains(&self.user_id))
+			})
+			.map(String::as_str)
+			.collect();
+		channels.sort();
+		for chunk in channels.chunks(CHANNELS_PER_LINE) {
+			reply.push_str(&format!(
+				":{} 319 {} {} :{}\r\n",
+				"server",
+				own_nick,
+				user.username,
+				chunk.join(" ")
+			));
+		}
+
+		if let Some(away) = &user.away {
+			reply.push_str(&format!(
+				":{} 301 {} {} :{}\r\n",
+				"server", own_nick, user.username, away
+			));
+		}
+
+		// Sent message count for moderation; it restarts with each new session
+		if let Some(session) = &user.session {
+			reply.push_str(&format!(
+				":{} 320 {} {} :has sent {} messages this session\r\n",
+				"server", own_nick, user.username, session.messages_sent
+			));
+		}
+
+		reply.push_str(&format!(
+			":{} 318 {} {} :End of WHOIS list\r\n",
+			"server", own_nick, user.username
+		));
+
+		if let Some(requester) = server.users.get(&self.user_id) {
+			if let Some(stream) = &requester.stream {
+				if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+					return Err(format!("Failed to send WHOIS reply: {}", e));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// TOPIC #chan shows the topic (332, or 331 when none is set); TOPIC #chan :text
+	// lets a member set it, unless the channel is +t and they aren't an operator
+	fn handle_topic(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(461, "TOPIC", "Not enough parameters");
+		}
+
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+		let channel = channel.as_str();
+
+		let mut server = lock_or_recover(&self.server);
+
+		let (topic, locked, member, secret) = match server.channels.get(channel) {
+			Some(ch) => (ch.topic.clone(), ch.topic_locked, ch.users.contains(&self.user_id), ch.secret),
+			None => {
+				drop(server);
+				return self.send_numeric(403, channel, "No such channel");
+			}
+		};
+
+		let new_topic = match parts.get(2) {
+			Some(text) => strip_line_breaks(text.trim_start_matches(':')),
+			None => {
+				drop(server);
+
+				// Secret channels don't reveal their topic to outsiders
+				if secret && !member {
+					return self.send_numeric(403, channel, "No such channel");
+				}
+				if topic.is_empty() {
+					return self.send_numeric(331, channel, "No topic is set");
+				}
+				return self.send_numeric(332, channel, &topic);
+			}
+		};
+
+		if !member {
+			drop(server);
+			return self.send_numeric(442, channel, "You're not on that channel");
+		}
+		if locked && !server.admins.contains(&self.user_id) {
+			drop(server);
+			return self.send_numeric(482, channel, "You're not channel operator");
+		}
+
+		if new_topic.len() > server.max_topic_length {
+			let max_topic_length = server.max_topic_length;
+			drop(server);
+			return self.send_fail(
+				"TOPIC",
+				"TOPIC_TOO_LONG",
+				&format!("Topic exceeds {} bytes", max_topic_length),
+			);
+		}
+
+		let members = match server.channels.get_mut(channel) {
+			Some(ch) => {
+				ch.topic = new_topic.clone();
+				ch.last_activity = Instant::now();
+				ch.users.clone()
+			}
+			None => return Ok(()),
+		};
+		server.invalidate_channel_list();
+
+		let username = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		info!("{} TOPIC {} changed", self.log_prefix, channel);
+
+		let line = format!(":{} TOPIC {} :{}\r\n", username, channel, new_topic);
+		for member_id in &members {
+			self.send_to(&server, member_id, &line);
+		}
+
+		Ok(())
+	}
+
+	fn handle_quit(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		// "QUIT :reason"; a reason containing SECURE_DELETE also wipes the user's messages
+		let reason = parts[1..].join(" ");
+		let reason = strip_line_breaks(reason.trim_start_matches(':').trim());
+		let reason = if reason.is_empty() { "Client Quit".to_string() } else { reason };
+		let secure_delete = reason.contains("SECURE_DELETE");
+
+		let mut server = lock_or_recover(&self.server);
+
+		let (username, channels) = match server.users.get_mut(&self.user_id) {
+			Some(user) => {
+				// With the user's other devices still connected, only this connection closes
+				if user.detach_device(&self.stream) {
+					let line = format!("ERROR :Closing Link: {} ({})\r\n", user.username, reason);
+					drop(server);
+					let _ = lock_or_recover(&self.stream).write_all(line.as_bytes());
+					return Ok(());
+				}
+				(user.username.clone(), user.channels.clone())
+			}
+			None => return Err("User not found".to_string()),
+		};
+
+		if secure_delete {
+			info!("{} Secure deletion requested", self.log_prefix);
+
+			// Perform secure deletion
+			if let Some(user) = server.users.get_mut(&self.user_id) {
+				// Clear and securely delete all messages
+				for mut msg in user.messages.drain(..) {
+					secure_delete_message(&mut msg);
+				}
+			}
+
+			// Remove the user's messages from their channels' history
+			for channel_name in &channels {
+				if let Some(channel) = server.channels.get_mut(channel_name) {
+					channel.messages.retain(|msg| msg.sender != username);
+				}
+			}
+		}
+
+		// Acknowledge the QUIT so the client can tell it from a dropped connection
+		if let Some(stream) = server.users.get(&self.user_id).and_then(|u| u.stream.as_ref()) {
+			let _ = lock_or_recover(stream)
+				.write_all(format!("ERROR :Closing Link: {} ({})\r\n", username, reason).as_bytes());
+		}
+
+		// Tell the user's peers, then scrub and remove the user as every other disconnect does
+		server.disconnect_user(&self.user_id, &reason);
+
+		Ok(())
+	}
+
+	fn handle_secure_clear(&mut self) -> Result<(), String> {
+		let mut server = lock_or_recover(&self.server);
+
+		// Clear all messages for this user
+		if let Some(user) = server.users.get_mut(&self.user_id) {
+			for mut msg in user.messages.drain(..) {
+				secure_delete_message(&mut msg);
+			}
+
+			// Notify the user
+			if let Some(stream) = &user.stream {
+				let notice = tag_line(
+					&user.caps,
+					&new_message_id(),
+					SystemTime::now(),
+					"NOTICE :All your messages have been securely deleted\r\n",
+				);
+				let _ = lock_or_recover(stream).write_all(notice.as_bytes());
+			}
+		}
+
+		Ok(())
+	}
+
+	// Report the server's wall-clock time for client clock sync:
+	// `:server 391 <id> server <unix millis> :<RFC 3339 time>`
+	fn handle_time(&mut self) -> Result<(), String> {
+		let now = SystemTime::now();
+		let millis = now.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+		let time = DateTime::<Utc>::from(now).to_rfc3339_opts(SecondsFormat::Millis, true);
+		self.send_numeric(391, &format!("server {}", millis), &time)
+	}
+
+	// Replace the session's encryption key with a fresh one and restart its nonce counter,
+	// so less data is ever encrypted under a single key. The user's stored private history
+	// is securely deleted as well (recipients' copies and channel history are kept); the
+	// client gets `NOTICE :REKEYED <discarded>` once the new key is in place.
+	fn handle_rekey(&mut self) -> Result<(), String> {
+		let mut new_key = Encryptor::generate_random_key()?;
+
+		let mut server = lock_or_recover(&self.server);
+		let user = match server.users.get_mut(&self.user_id) {
+			Some(user) => user,
+			None => return Err("User not found".to_string()),
+		};
+
+		// Each device has its own session; only this connection's key is rotated
+		let session = match user.session_for(&self.stream) {
+			Some(session) => session,
+			None => {
+				new_key.fill(0);
+				drop(server);
+				return self.send_fail("REKEY", "NO_SESSION", "No active session to rekey");
+			}
+		};
+		session.rekey(new_key);
+		new_key.fill(0);
+
+		let discarded = user.messages.len();
+		for mut msg in user.messages.drain(..) {
+			secure_delete_message(&mut msg);
+		}
+
+		info!("{} Session key rotated; {} stored messages discarded", self.log_prefix, discarded);
+
+		let notice = format!(":{} NOTICE {} :REKEYED {}\r\n", "server", user.username, discarded);
+		drop(server);
+		let _ = lock_or_recover(&self.stream).write_all(notice.as_bytes());
+
+		Ok(())
+	}
+
+	// Send a user's profile picture as base64 in 910 chunks, terminated by 911.
+	// A user without an avatar gets no 910 lines at all. An avatar the token only
+	// referred to is served from the avatar store, or else from the fetcher, whose
+	// result (even a failed one) is kept until no connected user refers to it.
+	fn handle_profile(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(461, "PROFILE", "Not enough parameters");
+		}
+
+		let nick = parts[1];
+		let mut server = lock_or_recover(&self.server);
+
+		let (user_id, username, avatar_ref) = match Self::find_user_by_username(&server, nick)
+			.and_then(|id| server.users.get(&id))
+		{
+			Some(user) => (user.id.clone(), user.username.clone(), user.profile_pic_ref.clone()),
+			None => {
+				drop(server);
+				return self.send_numeric(401, nick, "No such nick");
+			}
+		};
+
+		// The fetcher may be slow (e.g. a network lookup), so it runs without the lock
+		if let Some(reference) = &avatar_ref {
+			if !server.avatar_store.contains_key(reference) && !server.fetched_avatars.contains_key(reference) {
+				if let Some(fetcher) = server.avatar_fetcher.clone() {
+					let max_avatar_size = server.max_avatar_size;
+					drop(server);
+					let fetched = fetcher(reference).filter(|data| data.len() <= max_avatar_size);
+					server = lock_or_recover(&self.server);
+					server.fetched_avatars.insert(reference.clone(), fetched);
+				}
+			}
+		}
+
+		let avatar = match &avatar_ref {
+			Some(reference) => server
+				.avatar_store
+				.get(reference)
+				.or_else(|| server.fetched_avatars.get(reference).and_then(Option::as_ref))
+				.map(Vec::as_slice),
+			None => server.users.get(&user_id).map(|user| user.profile_pic.as_slice()),
+		};
+		let encoded = base64::encode(avatar.unwrap_or_default());
+		let own_nick = self.nick(&server);
+		let mut reply = String::new();
+		for chunk in encoded.as_bytes().chunks(PROFILE_CHUNK_SIZE) {
+			// Base64 is ASCII, so every chunk is valid UTF-8
+			reply.push_str(&format!(
+				":{} 910 {} {} :{}\r\n",
+				"server",
+				own_nick,
+				username,
+				String::from_utf8_lossy(chunk)
+			));
+		}
+		reply.push_str(&format!(
+			":{} 911 {} {} :End of PROFILE\r\n",
+			"server", own_nick, username
+		));
+
+		if let Some(requester) = server.users.get(&self.user_id) {
+			if let Some(stream) = &requester.stream {
+				if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+					return Err(format!("Failed to send profile picture: {}", e));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// List the channels the requesting user is in as 319 lines, terminated by 318,
+	// so a reconnecting client can re-sync its local state
+	fn handle_mychannels(&mut self) -> Result<(), String> {
+		let server = lock_or_recover(&self.server);
+
+		let user = match server.users.get(&self.user_id) {
+			Some(user) => user,
+			None => return Ok(()),
+		};
+
+		let mut channels: Vec<&str> = user.channels.iter().map(String::as_str).collect();
+		channels.sort();
+
+		let own_nick = self.nick(&server);
+		let mut reply = String::new();
+		for chunk in channels.chunks(CHANNELS_PER_LINE) {
+			reply.push_str(&format!(
+				":{} 319 {} {} :{}\r\n",
+				"server",
+				own_nick,
+				user.username,
+				chunk.join(" ")
+			));
+		}
+		reply.push_str(&format!(
+			":{} 318 {} {} :End of MYCHANNELS\r\n",
+			"server", own_nick, user.username
+		));
+
+		if let Some(stream) = &user.stream {
+			if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+				return Err(format!("Failed to send channel list: {}", e));
+			}
+		}
+
+		Ok(())
+	}
+
+	// Offer a file to another user with SENDFILE nick name size. The server only
+	// relays it: once the recipient sends ACCEPTFILE, the sender's encrypted FILEDATA
+	// lines are passed on as they arrive and counted against the declared size.
+	fn handle_sendfile(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let args: Vec<&str> = parts.iter().skip(1).flat_map(|p| p.split_whitespace()).collect();
+		let (nick, name, size) = match args.as_slice() {
+			[nick, name, size] => (*nick, *name, *size),
+			_ => return self.send_numeric(461, "SENDFILE", "Not enough parameters"),
+		};
+
+		let size = match size.parse::<u64>() {
+			Ok(size) => size,
+			Err(_) => return self.send_fail("SENDFILE", "INVALID_SIZE", "File size must be a number of bytes"),
+		};
+		if name.len() > 255 || name.chars().any(|c| c.is_control()) {
+			return self.send_fail("SENDFILE", "INVALID_NAME", "Invalid file name");
+		}
+
+		let mut server = lock_or_recover(&self.server);
+
+		if size > server.max_file_size {
+			let reason = format!("Files may be at most {} bytes", server.max_file_size);
+			drop(server);
+			return self.send_fail("SENDFILE", "TOO_LARGE", &reason);
+		}
+
+		let recipient_id = match Self::find_user_by_username(&server, nick)
+			.filter(|id| server.users.get(id).map_or(false, |u| u.stream.is_some()))
+		{
+			Some(id) if id != self.user_id => id,
+			Some(_) => {
+				drop(server);
+				return self.send_fail("SENDFILE", "INVALID_TARGET", "Cannot send a file to yourself");
+			}
+			None => {
+				drop(server);
+				return self.send_numeric(401, nick, "No such nick");
+			}
+		};
+
+		let offered = server.transfers.values().filter(|t| t.sender == self.user_id).count();
+		if offered >= MAX_TRANSFERS_PER_USER {
+			drop(server);
+			return self.send_fail("SENDFILE", "TOO_MANY_TRANSFERS", "Finish or cancel a file transfer first");
+		}
+
+		let id: String = thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(8)
+			.map(char::from)
+			.collect();
+		server.transfers.insert(
+			id.clone(),
+			FileTransfer {
+				sender: self.user_id.clone(),
+				recipient: recipient_id.clone(),
+				name: name.to_string(),
+				size,
+				accepted: false,
+				relayed: 0,
+			},
+		);
+
+		let sender_name = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		let recipient_name = server.users.get(&recipient_id).map(|u| u.username.clone()).unwrap_or_default();
+		debug!("{} Offered file transfer {} to {} ({} bytes)", self.log_prefix, id, recipient_name, size);
+
+		self.send_to(
+			&server,
+			&self.user_id,
+			&format!(":{} FILE PENDING {} {}\r\n", "server", id, recipient_name),
+		);
+		self.send_to(
+			&server,
+			&recipient_id,
+			&format!(":{} FILE OFFER {} {} {}\r\n", sender_name, id, name, size),
+		);
+
+		Ok(())
+	}
+
+	// Accept a file offered to us, letting the sender start its FILEDATA
+	fn handle_acceptfile(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let id = match parts.get(1) {
+			Some(id) => id.trim(),
+			None => return self.send_numeric(461, "ACCEPTFILE", "Not enough parameters"),
+		};
+
+		let mut server = lock_or_recover(&self.server);
+
+		let sender_id = match server.transfers.get_mut(id) {
+			Some(transfer) if transfer.recipient == self.user_id && !transfer.accepted => {
+				transfer.accepted = true;
+				transfer.sender.clone()
+			}
+			_ => {
+				drop(server);
+				return self.send_fail("ACCEPTFILE", "UNKNOWN_TRANSFER", "No such file offer");
+			}
+		};
+
+		let username = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		self.send_to(&server, &sender_id, &format!(":{} FILE ACCEPT {}\r\n", username, id));
+
+		Ok(())
+	}
+
+	// Relay one chunk of an accepted transfer's encrypted stream (FILEDATA id :base64).
+	// A sender that goes past what the declared size allows has the transfer cancelled.
+	fn handle_filedata(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let (id, data) = match (parts.get(1), parts.get(2)) {
+			(Some(id), Some(data)) => (*id, data.trim_start_matches(':').trim()),
+			_ => return self.send_numeric(461, "FILEDATA", "Not enough parameters"),
+		};
+
+		let len = match base64::decode(data) {
+			Ok(bytes) => bytes.len() as u64,
+			Err(_) => return self.send_fail("FILEDATA", "INVALID_DATA", "File data must be base64"),
+		};
+
+		let mut server = lock_or_recover(&self.server);
+
+		let (recipient_id, over_limit) = match server.transfers.get_mut(id) {
+			Some(transfer) if transfer.sender == self.user_id && transfer.accepted => {
+				transfer.relayed += len;
+				(transfer.recipient.clone(), transfer.relayed > max_file_transfer_len(transfer.size))
+			}
+			_ => {
+				drop(server);
+				return self.send_fail("FILEDATA", "UNKNOWN_TRANSFER", "No accepted file transfer with that ID");
+			}
+		};
+
+		if over_limit {
+			server.transfers.remove(id);
+			self.send_to(&server, &recipient_id, &format!(":{} FILE CANCEL {}\r\n", "server", id));
+			drop(server);
+			return self.send_fail("FILEDATA", "TOO_LARGE", "File data exceeds the declared size");
+		}
+
+		let username = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		self.send_to(
+			&server,
+			&recipient_id,
+			&format!(":{} FILE DATA {} :{}\r\n", username, id, data),
+		);
+
+		Ok(())
+	}
+
+	// Finish a transfer once the sender has relayed its whole stream
+	fn handle_filedone(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let id = match parts.get(1) {
+			Some(id) => id.trim(),
+			None => return self.send_numeric(461, "FILEDONE", "Not enough parameters"),
+		};
+
+		let mut server = lock_or_recover(&self.server);
+
+		let recipient_id = match server.transfers.get(id) {
+			Some(transfer) if transfer.sender == self.user_id && transfer.accepted => transfer.recipient.clone(),
+			_ => {
+				drop(server);
+				return self.send_fail("FILEDONE", "UNKNOWN_TRANSFER", "No accepted file transfer with that ID");
+			}
+		};
+		server.transfers.remove(id);
+
+		let username = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		self.send_to(&server, &recipient_id, &format!(":{} FILE DONE {}\r\n", username, id));
+
+		Ok(())
+	}
+
+	// Cancel or decline a transfer; either side may do so at any time
+	fn handle_cancelfile(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let id = match parts.get(1) {
+			Some(id) => id.trim(),
+			None => return self.send_numeric(461, "CANCELFILE", "Not enough parameters"),
+		};
+
+		let mut server = lock_or_recover(&self.server);
+
+		let other_id = match server.transfers.get(id) {
+			Some(transfer) if transfer.sender == self.user_id => transfer.recipient.clone(),
+			Some(transfer) if transfer.recipient == self.user_id => transfer.sender.clone(),
+			_ => {
+				drop(server);
+				return self.send_fail("CANCELFILE", "UNKNOWN_TRANSFER", "No file transfer with that ID");
+			}
+		};
+		server.transfers.remove(id);
+
+		let username = server.users.get(&self.user_id).map(|u| u.username.clone()).unwrap_or_default();
+		self.send_to(&server, &other_id, &format!(":{} FILE CANCEL {}\r\n", username, id));
+
+		Ok(())
+	}
+
+	fn handle_away(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		// Everything after AWAY is the message; none clears the away status
+		let message = parts[1..].join(" ");
+		let message = message.trim_start_matches(':').trim();
+
+		let away = if message.is_empty() {
+			None
+		} else {
+			Some(strip_line_breaks(message))
+		};
+
+		let (numeric, reply) = if away.is_some() {
+			(306, "You have been marked as being away")
+		} else {
+			(305, "You are no longer marked as being away")
+		};
+
+		let mut server = lock_or_recover(&self.server);
+		let username = match server.users.get_mut(&self.user_id) {
+			Some(user) => {
+				user.away = away.clone();
+				user.username.clone()
+			}
+			None => return Err("User not found".to_string()),
+		};
+
+		// Peers that negotiated away-notify see the change without polling WHOIS
+		let away_line = match &away {
+			Some(message) => format!(":{} AWAY :{}\r\n", username, message),
+			None => format!(":{} AWAY\r\n", username),
+		};
+		server.send_to_channel_peers(&self.user_id, &away_line, Some("away-notify"));
+		drop(server);
+
+		self.send_numeric(numeric, "", reply)
+	}
+
+	fn handle_pong(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		// The nonce is the last parameter ("PONG :nonce" or "PONG server :nonce")
+		let nonce = parts.last().copied().unwrap_or("").trim_start_matches(':');
+
+		let mut server = lock_or_recover(&self.server);
+		if let Some(user) = server.users.get_mut(&self.user_id) {
+			// A PONG answers the PING sent to this connection, not the user's other devices
+			if let Some(session) = user.session_for(&self.stream) {
+				if !session.record_pong(nonce) {
+					debug!("{} Ignoring unexpected PONG", self.log_prefix);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handle_cap(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		if parts.len() < 2 {
+			return self.send_numeric(461, "CAP", "Not enough parameters");
+		}
+
+		let mut server = lock_or_recover(&self.server);
+		if let Some(user) = server.users.get_mut(&self.user_id) {
+			let reply = negotiate_cap(
+				&user.username,
+				parts[1],
+				parts.get(2).copied().unwrap_or(""),
+				&mut user.caps,
+			);
+
+			if let (Some(reply), Some(stream)) = (reply, &user.stream) {
+				if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+					return Err(format!("Failed to send CAP reply: {}", e));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// Admin-only explicit channel creation: CREATE #channel
+	fn handle_create(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let is_admin = lock_or_recover(&self.server).admins.contains(&self.user_id);
+		if !is_admin {
+			return self.send_numeric(481, "", "Permission Denied- You're not an IRC operator");
+		}
+
+		if parts.len() < 2 {
+			return self.send_numeric(461, "CREATE", "Not enough parameters");
+		}
+
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+
+		let created = {
+			let mut server = lock_or_recover(&self.server);
+			match server.channels.get_mut(&channel) {
+				Some(existing) => {
+					existing.persistent = true;
+					false
+				}
+				None => {
+					server
+						.channels
+						.insert(channel.clone(), Self::new_channel(&channel, true));
+					server.invalidate_channel_list();
+					true
+				}
+			}
+		};
+
+		if created {
+			info!("{} Created channel {}", self.log_prefix, channel);
+			self.send_notice(&format!("Channel {} created", channel))
+		} else {
+			self.send_notice(&format!("Channel {} already exists", channel))
+		}
+	}
+
+	// Close a channel: DRAIN #channel [+cooldown=<seconds>] [:reason]. Every member is
+	// told why and parted, the channel and its history are removed, and with a cooldown
+	// JOIN refuses to recreate it until the cooldown is over.
+	fn handle_drain(&mut self, parts: Vec<&str>) -> Result<(), String> {
 		if parts.len() < 2 {
-			return self.send_error("Not enough parameters for JOIN");
+			return self.send_numeric(461, "DRAIN", "Not enough parameters");
 		}
 
-		let channel = parts[1];
-
-		let mut server = self.server.lock().unwrap();
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
 
-		// Create channel if it doesn't exist
-		if !server.channels.contains_key(channel) {
-			server.channels.insert(
-				channel.to_string(),
-				Channel {
-					name: channel.to_string(),
-					topic: String::new(),
-					users: std::collections::HashSet::new(),
-					messages: std::collections::VecDeque::new(),
-					created_at: Instant::now(),
-					last_activity: Instant::now(),
-				},
-			);
+		// Server admins may drain any channel, channel operators only their own
+		let allowed = {
+			let server = lock_or_recover(&self.server);
+			server.admins.contains(&self.user_id)
+				|| server
+					.channels
+					.get(&channel)
+					.is_some_and(|ch| ch.operators.contains(&self.user_id))
+		};
+		if !allowed {
+			return self.send_numeric(482, &channel, "You're not channel operator");
 		}
 
-		// Add user to channel
-		if let Some(ch) = server.channels.get_mut(channel) {
-			ch.users.insert(self.user_id.clone());
-			ch.last_activity = Instant::now();
-		}
+		let rest = parts.get(2).copied().unwrap_or("");
+		let (cooldown_until, reason) = match rest.strip_prefix("+cooldown=") {
+			Some(option) => {
+				let (seconds, reason) = option.split_once(' ').unwrap_or((option, ""));
+				let seconds = match seconds.parse::<u64>() {
+					Ok(seconds) => seconds,
+					Err(_) => {
+						return self.send_fail("DRAIN", "INVALID_COOLDOWN", "Cooldown must be a number of seconds")
+					}
+				};
+				if seconds == 0 {
+					(None, reason)
+				} else {
+					// Refuse a cooldown so long the deadline can't be represented
+					match Instant::now().checked_add(Duration::from_secs(seconds)) {
+						Some(until) => (Some(until), reason),
+						None => return self.send_fail("DRAIN", "INVALID_COOLDOWN", "Cooldown is too long"),
+					}
+				}
+			}
+			None => (None, rest),
+		};
+		let reason = strip_line_breaks(reason.trim_start_matches(':').trim());
+		let reason = if reason.is_empty() { "Channel closed".to_string() } else { reason };
 
-		// Add channel to user's channels
-		if let Some(user) = server.users.get_mut(&self.user_id) {
-			user.channels.insert(channel.to_string());
+		let mut server = lock_or_recover(&self.server);
 
-			// Send join confirmation to user
-			if let Some(stream) = &user.stream {
-				let _ = stream
-					.lock()
-					.unwrap()
-					.write_all(format!(":{} JOIN {}\r\n", self.user_id, channel).as_bytes());
+		// Wipe the history first; the members' leave notices are wiped with the channel below
+		let member_ids: Vec<String> = match server.channels.get_mut(&channel) {
+			Some(drained) => {
+				for mut message in drained.messages.drain(..) {
+					secure_delete_message(&mut message);
+				}
+				drained.users.iter().cloned().collect()
+			}
+			None => {
+				drop(server);
+				return self.send_numeric(403, &channel, "No such channel");
 			}
+		};
+		let mut members: Vec<(String, String)> = member_ids
+			.into_iter()
+			.filter_map(|id| server.users.get(&id).map(|user| (user.username.clone(), id.clone())))
+			.collect();
+		members.sort();
+
+		// Part members one at a time so everyone still in the channel sees each PART
+		let notice = format!("{} is being closed: {}", channel, reason);
+		for (username, user_id) in &members {
+			self.send_to(&server, user_id, &format!(":{} NOTICE {} :{}\r\n", "server", username, notice));
+			Self::part_channel(&mut server, user_id, &channel, Some(&reason));
 		}
 
-		// Get username for broadcast
-		let username = match server.users.get(&self.user_id) {
-			Some(user) => user.username.clone(),
-			None => return Err("User not found".to_string()),
-		};
+		// A channel an admin created outlives its last member, so remove it here
+		if let Some(mut drained) = server.channels.remove(&channel) {
+			for message in drained.messages.iter_mut() {
+				secure_delete_message(message);
+			}
+			server.invalidate_channel_list();
+		}
 
-		// Broadcast join message to channel
-		let join_message = format!("* {} has joined {}", username, channel);
-		Self::broadcast_to_channel(&mut server, channel, &join_message, Some(&self.user_id));
+		if let Some(until) = cooldown_until {
+			server.drained_channels.insert(channel.clone(), until);
+		}
+		drop(server);
 
-		// Store join message in channel history
-		Self::store_channel_message(&mut server, channel, "SYSTEM", &join_message);
+		info!("{} Drained channel {} ({} members)", self.log_prefix, channel, members.len());
+		self.send_notice(&format!("Channel {} drained ({} members parted)", channel, members.len()))
+	}
 
-		Ok(())
+	// Toggle the automated message-expiry notices: SILENCE on|off
+	fn handle_silence(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let setting = parts.get(1).map(|p| p.trim_start_matches(':').to_lowercase());
+
+		let silenced = {
+			let mut server = lock_or_recover(&self.server);
+			let user = match server.users.get_mut(&self.user_id) {
+				Some(user) => user,
+				None => return Err("User not found".to_string()),
+			};
+
+			match setting.as_deref() {
+				Some("on") => user.silenced = true,
+				Some("off") => user.silenced = false,
+				None => {}
+				Some(_) => {
+					drop(server);
+					return self.send_numeric(461, "SILENCE", "Usage: SILENCE on|off");
+				}
+			}
+			user.silenced
+		};
+
+		if silenced {
+			self.send_notice("SILENCE: message expiry notices are off")
+		} else {
+			self.send_notice("SILENCE: message expiry notices are on")
+		}
 	}
 
-	fn handle_leave(&mut self, parts: Vec<&str>) -> Result<(), String> {
+	// Channel modes; only the ban list (+b) is supported.
+	// Listing bans is open to everyone, changing them requires operator privileges.
+	fn handle_mode(&mut self, parts: Vec<&str>) -> Result<(), String> {
 		if parts.len() < 2 {
-			return self.send_error("Not enough parameters for PART");
+			return self.send_numeric(461, "MODE", "Not enough parameters");
 		}
 
-		let channel = parts[1];
+		// Standard clients query their own user modes after registering; there are none
+		if !parts[1].starts_with('#') {
+			return self.send_numeric(221, "", "+");
+		}
 
-		let mut server = self.server.lock().unwrap();
+		let channel = match normalize_channel_name(parts[1]) {
+			Ok(name) => name,
+			Err((numeric, reason)) => return self.send_numeric(numeric, parts[1], reason),
+		};
+		let channel = channel.as_str();
 
-		// Get username for broadcast
-		let username = match server.users.get(&self.user_id) {
-			Some(user) => user.username.clone(),
-			None => return Err("User not found".to_string()),
+		let args: Vec<&str> = parts.get(2).map(|a| a.split_whitespace().collect()).unwrap_or_default();
+		let modes = args.first().copied().unwrap_or("");
+		let mask = args.get(1).copied();
+
+		let mut server = lock_or_recover(&self.server);
+
+		let (bans, flags) = match server.channels.get(channel) {
+			Some(ch) => (ch.bans.clone(), ch.modes()),
+			None => {
+				drop(server);
+				return self.send_numeric(403, channel, "No such channel");
+			}
 		};
 
-		// Remove user from channel
-		if let Some(ch) = server.channels.get_mut(channel) {
-			ch.users.remove(&self.user_id);
-			ch.last_activity = Instant::now();
+		match (modes, mask) {
+			// Report channel flags; bans are listed separately
+			("", _) => {
+				drop(server);
+				self.send_numeric(324, channel, &flags)
+			}
+			("+s", None) | ("-s", None) | ("+t", None) | ("-t", None) => {
+				if !server.admins.contains(&self.user_id) {
+					drop(server);
+					return self.send_numeric(482, channel, "You're not channel operator");
+				}
 
-			// Remove empty channels
-			if ch.users.is_empty() {
-				server.channels.remove(channel);
-			} else {
-				// Broadcast leave message to remaining users
-				let leave_message = format!("* {} has left {}", username, channel);
-				Self::broadcast_to_channel(&mut server, channel, &leave_message, None);
+				if let Some(ch) = server.channels.get_mut(channel) {
+					let enable = modes.starts_with('+');
+					if modes.ends_with('s') {
+						ch.secret = enable;
+					} else {
+						ch.topic_locked = enable;
+					}
+				}
+				server.invalidate_channel_list();
 
-				// Store leave message in channel history
-				Self::store_channel_message(&mut server, channel, "SYSTEM", &leave_message);
+				info!("{} MODE {} {}", self.log_prefix, channel, modes);
+
+				// Confirm the change to the operator
+				if let Some(user) = server.users.get(&self.user_id) {
+					if let Some(stream) = &user.stream {
+						let reply = format!(":{} MODE {} {}\r\n", user.username, channel, modes);
+						if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+							return Err(format!("Failed to send MODE reply: {}", e));
+						}
+					}
+				}
+
+				Ok(())
 			}
-		}
+			// "MODE #chan b" or "MODE #chan +b" lists the bans
+			("b", _) | ("+b", None) => {
+				drop(server);
+
+				let mut bans: Vec<String> = bans.into_iter().collect();
+				bans.sort();
+				for ban in &bans {
+					self.send_numeric(367, &format!("{} {}", channel, ban), "Ban")?;
+				}
+				self.send_numeric(368, channel, "End of channel ban list")
+			}
+			("+b", Some(mask)) | ("-b", Some(mask)) => {
+				if !server.admins.contains(&self.user_id) {
+					drop(server);
+					return self.send_numeric(482, channel, "You're not channel operator");
+				}
 
-		// Remove channel from user's list
-		if let Some(user) = server.users.get_mut(&self.user_id) {
-			user.channels.remove(channel);
+				let mask = mask.to_lowercase();
+				if let Some(ch) = server.channels.get_mut(channel) {
+					if modes == "+b" {
+						ch.bans.insert(mask.clone());
+					} else {
+						ch.bans.remove(&mask);
+					}
+				}
 
-			// Send part confirmation to user
-			if let Some(stream) = &user.stream {
-				let _ = stream
-					.lock()
-					.unwrap()
-					.write_all(format!(":{} PART {}\r\n", self.user_id, channel).as_bytes());
+				info!("{} MODE {} {} {}", self.log_prefix, channel, modes, mask);
+
+				// Confirm the change to the operator
+				if let Some(user) = server.users.get(&self.user_id) {
+					if let Some(stream) = &user.stream {
+						let reply = format!(":{} MODE {} {} {}\r\n", user.username, channel, modes, mask);
+						if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+							return Err(format!("Failed to send MODE reply: {}", e));
+						}
+					}
+				}
+
+				Ok(())
+			}
+			_ => {
+				drop(server);
+				self.send_numeric(472, modes, "is unknown mode char to me")
 			}
 		}
-
-		Ok(())
 	}
 
-	fn handle_privmsg(&mut self, parts: Vec<&str>) -> Result<(), String> {
-		if parts.len() < 3 {
-			return self.send_error("Not enough parameters for PRIVMSG");
+	// Admin-only live configuration: CONFIG SET <key> <seconds>
+	fn handle_config(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let is_admin = lock_or_recover(&self.server).admins.contains(&self.user_id);
+		if !is_admin {
+			return self.send_numeric(481, "", "Permission Denied- You're not an IRC operator");
 		}
 
-		let target = parts[1];
-		let message = parts[2];
-
-		let mut server = self.server.lock().unwrap();
+		let args: Vec<&str> = parts.get(2).map(|a| a.split_whitespace().collect()).unwrap_or_default();
+		if parts.len() < 3 || !parts[1].eq_ignore_ascii_case("SET") || args.len() != 2 {
+			return self.send_numeric(461, "CONFIG", "Usage: CONFIG SET <key> <seconds>");
+		}
 
-		// Get sender info
-		let sender = match server.users.get(&self.user_id) {
-			Some(user) => user,
-			None => return Err("Sender not found".to_string()),
+		let key = args[0].to_lowercase();
+		let seconds = match args[1].parse::<u64>() {
+			Ok(seconds) if seconds > 0 => seconds,
+			_ => return self.send_notice(&format!("CONFIG: {} must be a positive number of seconds", key)),
 		};
+		let value = Duration::from_secs(seconds);
 
-		// Update sender's session activity
-		if let Some(user) = server.users.get_mut(&self.user_id) {
-			if let Some(session) = &mut user.session {
-				session.update_activity();
+		{
+			let mut server = lock_or_recover(&self.server);
+			match key.as_str() {
+				"message_ttl" => server.message_ttl = value,
+				"session_timeout" => server.session_timeout = value,
+				"inactivity_warning" => server.inactivity_warning = value,
+				"ping_timeout" => server.ping_timeout = value,
+				_ => {
+					drop(server);
+					return self.send_notice(&format!(
+						"CONFIG: unknown key {} (expected message_ttl, session_timeout, inactivity_warning or ping_timeout)",
+						key
+					));
+				}
 			}
 		}
 
-		// Check if target is a channel or user
-		if target.starts_with('#') {
-			// Channel message
-			// Check if user is in channel
-			if !sender.channels.contains(target) {
-				return self.send_error(&format!("You are not in channel {}", target));
-			}
+		info!("{} CONFIG {} set to {} seconds", self.log_prefix, key, seconds);
+		self.send_notice(&format!("CONFIG: {} set to {} seconds", key, seconds))
+	}
 
-			// Format the message
-			let formatted_message = format!("<{}> {}", sender.username, message);
+	// Relay a transient "is typing" notification to a channel's other members or to a user.
+	// Nothing is stored, and notifications beyond one per TYPING_INTERVAL are dropped.
+	fn handle_typing(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let target = match parts.get(1) {
+			Some(target) => target.trim_start_matches(':'),
+			None => return self.send_numeric(461, "TYPING", "Not enough parameters"),
+		};
 
-			// Store in channel history
-			Self::store_channel_message(&mut server, target, &sender.username, message);
+		let mut server = lock_or_recover(&self.server);
+		let now = Instant::now();
+
+		let (sender_name, sender_channels) = match server.users.get_mut(&self.user_id) {
+			Some(user) => {
+				if let Some(session) = user.session_for(&self.stream) {
+					if session
+						.last_typing
+						.map_or(false, |last| now.duration_since(last) < TYPING_INTERVAL)
+					{
+						return Ok(());
+					}
+					session.last_typing = Some(now);
+				}
+				(user.username.clone(), user.channels.clone())
+			}
+			None => return Err("User not found".to_string()),
+		};
 
-			// Broadcast message
-			Self::broadcast_to_channel(
-				&mut server,
-				target,
-				&formatted_message,
-				Some(&self.user_id),
-			);
+		// Unknown targets are ignored: the notification is transient either way
+		let (target, recipients): (String, Vec<String>) = if target.starts_with('#') {
+			let channel = match normalize_channel_name(target) {
+				Ok(name) if sender_channels.contains(&name) => name,
+				_ => return Ok(()),
+			};
+			let members = server
+				.channels
+				.get(&channel)
+				.map(|ch| ch.users.iter().filter(|id| **id != self.user_id).cloned().collect())
+				.unwrap_or_default();
+			(channel, members)
 		} else {
-			// Private message
-			// Find recipient by username
-			let recipient_id = Self::find_user_by_username(&server, target)
-				.ok_or_else(|| format!("User {} not found", target))?;
-
-			// Store message in sender's and recipient's history
-			Self::store_private_message(&mut server, &self.user_id, &recipient_id, message);
+			(
+				target.to_string(),
+				Self::find_user_by_username(&server, target).into_iter().collect(),
+			)
+		};
 
-			// Send message to recipient
-			if let Some(recipient) = server.users.get(&recipient_id) {
-				if let Some(stream) = &recipient.stream {
-					let pm_message = format!("PRIVMSG {} :{}\r\n", sender.username, message);
-					if let Err(e) = stream.lock().unwrap().write_all(pm_message.as_bytes()) {
-						return Err(format!("Failed to send message: {}", e));
-					}
+		for recipient_id in &recipients {
+			if let Some(user) = server.users.get(recipient_id) {
+				if let Some(stream) = &user.stream {
+					// Clients with message-tags get the IRCv3 typing tag
+					let line = if user.caps.contains("message-tags") {
+						format!("@+typing=active :{} TAGMSG {}\r\n", sender_name, target)
+					} else {
+						format!(":{} TYPING {}\r\n", sender_name, target)
+					};
+					let _ = lock_or_recover(stream).write_all(line.as_bytes());
 				}
 			}
 		}
@@ -238,152 +2569,169 @@ impl MessageHandler {
 		Ok(())
 	}
 
-	fn handle_list(&mut self) -> Result<(), String> {
-		let server = self.server.lock().unwrap();
-
-		// Send list of channels
-		let mut channel_list = String::new();
-		for (name, channel) in &server.channels {
-			channel_list.push_str(&format!(
-				":{} 322 {} {} {} :{}\r\n",
-				"server",
-				self.user_id,
-				name,
-				channel.users.len(),
-				channel.topic
-			));
+	// Send a NOTICE to every connected user (admins only)
+	fn handle_announce(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let is_admin = lock_or_recover(&self.server).admins.contains(&self.user_id);
+		if !is_admin {
+			return self.send_numeric(481, "", "Permission Denied- You're not an IRC operator");
 		}
 
-		channel_list.push_str(&format!(
-			":{} 323 {} :End of LIST\r\n",
-			"server", self.user_id
-		));
-
-		if let Some(user) = server.users.get(&self.user_id) {
-			if let Some(stream) = &user.stream {
-				if let Err(e) = stream.lock().unwrap().write_all(channel_list.as_bytes()) {
-					return Err(format!("Failed to send channel list: {}", e));
-				}
-			}
+		let message = parts[1..].join(" ");
+		let message = message.trim_start_matches(':').trim();
+		if message.is_empty() {
+			return self.send_numeric(461, parts[0], "Not enough parameters");
 		}
 
+		let reached = broadcast_announcement(&self.server, message);
+		info!("{} Sent announcement to {} users", self.log_prefix, reached);
+
 		Ok(())
 	}
 
-	fn handle_who(&mut self, parts: Vec<&str>) -> Result<(), String> {
-		if parts.len() < 2 {
-			return self.send_error("Not enough parameters for WHO");
+	fn handle_kill(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let is_admin = lock_or_recover(&self.server).admins.contains(&self.user_id);
+		if !is_admin {
+			return self.send_numeric(481, "", "Permission Denied- You're not an IRC operator");
 		}
 
-		let channel = parts[1];
-		let server = self.server.lock().unwrap();
+		if parts.len() < 2 {
+			return self.send_numeric(461, "KILL", "Not enough parameters");
+		}
 
-		// Check if channel exists
-		let ch = match server.channels.get(channel) {
-			Some(c) => c,
-			None => return self.send_error(&format!("Channel {} not found", channel)),
+		let target = parts[1];
+		let reason = parts
+			.get(2)
+			.map(|r| r.trim_start_matches(':'))
+			.filter(|r| !r.is_empty())
+			.unwrap_or("No reason given");
+
+		let mut server = lock_or_recover(&self.server);
+
+		let target_id = match Self::find_user_by_username(&server, target) {
+			Some(id) => id,
+			None => {
+				drop(server);
+				return self.send_numeric(401, target, "No such nick");
+			}
 		};
 
-		// Send list of users in channel
-		let mut who_list = String::new();
-		for user_id in &ch.users {
-			if let Some(user) = server.users.get(user_id) {
-				who_list.push_str(&format!(
-					":{} 352 {} {} {} {} {} {} H :0 {}\r\n",
-					"server",
-					self.user_id,
-					channel,
-					user.username,
-					"hostname",
-					"server",
-					user.username,
-					user.username
-				));
-			}
-		}
+		let killer = server
+			.users
+			.get(&self.user_id)
+			.map(|u| u.username.clone())
+			.unwrap_or_else(|| "Unknown".to_string());
 
-		who_list.push_str(&format!(
-			":{} 315 {} {} :End of WHO list\r\n",
-			"server", self.user_id, channel
-		));
+		info!("{} Killed user {}: {}", self.log_prefix, target, reason);
+		server.audit(AuditEvent::Kill {
+			user_id: &target_id,
+			by: &self.user_id,
+			reason,
+		});
 
-		if let Some(user) = server.users.get(&self.user_id) {
+		// Tell the target why, then close their connection so their connection thread exits
+		if let Some(user) = server.users.get(&target_id) {
 			if let Some(stream) = &user.stream {
-				if let Err(e) = stream.lock().unwrap().write_all(who_list.as_bytes()) {
-					return Err(format!("Failed to send WHO list: {}", e));
-				}
+				let mut stream = lock_or_recover(stream);
+				let _ = stream.write_all(
+					format!("ERROR :Killed by {} ({})\r\n", killer, reason).as_bytes(),
+				);
+				let _ = stream.shutdown();
 			}
 		}
+		server.disconnect_user(&target_id, &format!("Killed by {} ({})", killer, reason));
 
 		Ok(())
 	}
 
-	fn handle_quit(&mut self, parts: Vec<&str>) -> Result<(), String> {
-		let secure_delete = parts.len() > 1 && parts[1].contains("SECURE_DELETE");
+	// Disconnect everyone whose session was authenticated by the token with this jti,
+	// as IRCServerFacade::disconnect_by_jti does, and report how many were dropped
+	fn handle_killtoken(&mut self, parts: Vec<&str>) -> Result<(), String> {
+		let is_admin = lock_or_recover(&self.server).admins.contains(&self.user_id);
+		if !is_admin {
+			return self.send_numeric(481, "", "Permission Denied- You're not an IRC operator");
+		}
 
-		let mut server = self.server.lock().unwrap();
+		if parts.len() < 2 {
+			return self.send_numeric(461, "KILLTOKEN", "Not enough parameters");
+		}
 
-		if secure_delete {
-			info!("Secure deletion requested for user: {}", self.user_id);
+		let jti = parts[1];
+		let reason = parts
+			.get(2)
+			.map(|r| strip_line_breaks(r.trim_start_matches(':')))
+			.filter(|r| !r.is_empty())
+			.unwrap_or_else(|| "Token revoked".to_string());
 
-			// Perform secure deletion
-			if let Some(user) = server.users.get_mut(&self.user_id) {
-				// Clear and securely delete all messages
-				for mut msg in user.messages.drain(..) {
-					Self::secure_delete_message(&mut msg);
-				}
+		let disconnected = lock_or_recover(&self.server).revoke_token(jti, &self.user_id, &reason);
+		info!("{} Killed {} users using token {}: {}", self.log_prefix, disconnected, jti, reason);
 
-				// Remove from all channels and clear their messages
-				for channel_name in &user.channels.clone() {
-					if let Some(channel) = server.channels.get_mut(channel_name) {
-						channel.messages.retain(|msg| msg.sender != user.username);
-					}
+		self.send_notice(&format!("Disconnected {} users using token {}", disconnected, jti))
+	}
+
+	fn handle_unknown(&mut self, command: &str) -> Result<(), String> {
+		self.send_numeric(421, command, "Unknown command")
+	}
+
+	fn send_notice(&self, message: &str) -> Result<(), String> {
+		let server = lock_or_recover(&self.server);
+
+		if let Some(user) = server.users.get(&self.user_id) {
+			if let Some(stream) = &user.stream {
+				let notice = tag_line(
+					&user.caps,
+					&new_message_id(),
+					SystemTime::now(),
+					&format!(":{} NOTICE {} :{}\r\n", "server", user.username, message),
+				);
+				if let Err(e) = lock_or_recover(stream).write_all(notice.as_bytes()) {
+					return Err(format!("Failed to send notice: {}", e));
 				}
 			}
 		}
 
-		// Disconnect user
-		Self::disconnect_user(&mut server, &self.user_id);
-
 		Ok(())
 	}
 
-	fn handle_secure_clear(&mut self) -> Result<(), String> {
-		let mut server = self.server.lock().unwrap();
+	// The user's current nickname, which numeric replies are addressed to
+	fn nick(&self, server: &ServerState) -> String {
+		server
+			.users
+			.get(&self.user_id)
+			.map_or_else(|| self.user_id.clone(), |user| user.username.clone())
+	}
 
-		// Clear all messages for this user
-		if let Some(user) = server.users.get_mut(&self.user_id) {
-			for mut msg in user.messages.drain(..) {
-				Self::secure_delete_message(&mut msg);
-			}
+	fn send_numeric(&self, numeric: u16, target: &str, message: &str) -> Result<(), String> {
+		let server = lock_or_recover(&self.server);
 
-			// Notify the user
+		if let Some(user) = server.users.get(&self.user_id) {
 			if let Some(stream) = &user.stream {
-				let _ = stream
-					.lock()
-					.unwrap()
-					.write_all(b"NOTICE :All your messages have been securely deleted\r\n");
+				// Some numerics (e.g. 481) have no target parameter
+				let reply = if target.is_empty() {
+					format!(":{} {:03} {} :{}\r\n", "server", numeric, user.username, message)
+				} else {
+					format!(
+						":{} {:03} {} {} :{}\r\n",
+						"server", numeric, user.username, target, message
+					)
+				};
+				if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+					return Err(format!("Failed to send numeric reply: {}", e));
+				}
 			}
 		}
 
 		Ok(())
 	}
 
-	fn handle_unknown(&mut self, command: &str) -> Result<(), String> {
-		self.send_error(&format!("Unknown command: {}", command))
-	}
-
-	fn send_error(&self, message: &str) -> Result<(), String> {
-		let server = self.server.lock().unwrap();
+	// Send an IRCv3 standard FAIL reply for a command that could not be carried out
+	fn send_fail(&self, command: &str, code: &str, message: &str) -> Result<(), String> {
+		let server = lock_or_recover(&self.server);
 
 		if let Some(user) = server.users.get(&self.user_id) {
 			if let Some(stream) = &user.stream {
-				if let Err(e) = stream
-					.lock()
-					.unwrap()
-					.write_all(format!("ERROR :{}\r\n", message).as_bytes())
-				{
-					return Err(format!("Failed to send error message: {}", e));
+				let reply = format!(":{} FAIL {} {} :{}\r\n", "server", command, code, message);
+				if let Err(e) = lock_or_recover(stream).write_all(reply.as_bytes()) {
+					return Err(format!("Failed to send FAIL reply: {}", e));
 				}
 			}
 		}
@@ -393,33 +2741,53 @@ impl MessageHandler {
 
 	// Helper methods
 
+	// Write a line to a user's connection, if they have one; delivery failures are ignored
+	fn send_to(&self, server: &ServerState, user_id: &str, line: &str) {
+		if let Some(stream) = server.users.get(user_id).and_then(|u| u.stream.as_ref()) {
+			let _ = lock_or_recover(stream).write_all(line.as_bytes());
+		}
+	}
+
+	// Send ":sender PRIVMSG #channel :message" to every member. The line is the
+	// same for everyone, so it is formatted once, plus at most once more for
+	// each combination of IRCv3 tags the members negotiated.
 	fn broadcast_to_channel(
 		server: &mut ServerState,
 		channel_name: &str,
+		sender: &str,
 		message: &str,
 		exclude_user: Option<&str>,
+		msgid: &str,
+		sent_at: SystemTime,
 	) {
 		let channel = match server.channels.get(channel_name) {
 			Some(c) => c,
 			None => return,
 		};
 
+		let line = format!(
+			":{} PRIVMSG {} :{}\r\n",
+			sender,
+			channel_name,
+			strip_line_breaks(message)
+		);
+		let mut tagged: [Option<String>; 4] = Default::default(); // Indexed by tag_variant
+
 		for user_id in &channel.users {
-			if let Some(excluded) = exclude_user {
-				if user_id == excluded {
-					continue;
-				}
+			if exclude_user == Some(user_id.as_str()) {
+				continue;
 			}
 
 			if let Some(user) = server.users.get(user_id) {
 				if let Some(stream) = &user.stream {
-					let message = format!(
-						":{} PRIVMSG {} :{}\r\n",
-						channel_name, user.username, message
-					);
-					if let Ok(mut stream) = stream.lock() {
-						let _ = stream.write_all(message.as_bytes());
-					}
+					let variant = tag_variant(&user.caps);
+					let bytes = match variant {
+						0 => line.as_bytes(),
+						_ => tagged[variant]
+							.get_or_insert_with(|| tag_line(&user.caps, msgid, sent_at, &line))
+							.as_bytes(),
+					};
+					let _ = lock_or_recover(stream).write_all(bytes);
 				}
 			}
 		}
@@ -430,33 +2798,63 @@ impl MessageHandler {
 		channel_name: &str,
 		sender: &str,
 		content: &str,
+		msgid: &str,
+		sent_at: SystemTime,
+		ttl: Option<Duration>,
 	) {
-		if let Some(channel) = server.channels.get_mut(channel_name) {
-			// Encrypt the message content
-			let encrypted = Vec::new(); // In a real implementation, this would be encrypted
-
-			let message = ChatMessage {
-				sender: sender.to_string(),
-				content: content.to_string(),
-				timestamp: Instant::now(),
-				encrypted,
-			};
+		// Nothing is stored or counted for a channel that no longer exists
+		let channel = match server.channels.get_mut(channel_name) {
+			Some(channel) => channel,
+			None => return,
+		};
+
+		// Encrypt the message content
+		let encrypted = Vec::new(); // In a real implementation, this would be encrypted
+
+		let message = ChatMessage {
+			id: msgid.to_string(),
+			sender: sender.to_string(),
+			content: content.to_string(),
+			timestamp: Instant::now(),
+			sent_at,
+			encrypted,
+			ttl,
+		};
+
+		channel.messages.push_back(message);
+		channel.last_activity = Instant::now();
+		if sender != "SYSTEM" {
+			channel.message_count += 1;
+		}
 
-			channel.messages.push_back(message);
-			channel.last_activity = Instant::now();
+		// Limit message history
+		while channel.messages.len() > 100 {
+			channel.messages.pop_front();
+		}
 
-			// Limit message history
-			while channel.messages.len() > 100 {
-				channel.messages.pop_front();
+		// Join and part notices are stored as SYSTEM and don't count as anyone's message
+		if sender != "SYSTEM" {
+			if let Some(sender_id) = server.username_to_id.get(sender).cloned() {
+				Self::count_sent_message(server, &sender_id);
 			}
 		}
 	}
 
+	// Advance a user's per-session sent message counter
+	fn count_sent_message(server: &mut ServerState, user_id: &str) {
+		if let Some(session) = server.users.get_mut(user_id).and_then(|u| u.session.as_mut()) {
+			session.messages_sent += 1;
+		}
+	}
+
 	fn store_private_message(
 		server: &mut ServerState,
 		sender_id: &str,
 		recipient_id: &str,
 		content: &str,
+		msgid: &str,
+		sent_at: SystemTime,
+		ttl: Option<Duration>,
 	) {
 		// Encrypt the message
 		let encrypted = Vec::new(); // In a real implementation, this would be encrypted
@@ -470,12 +2868,17 @@ impl MessageHandler {
 
 		// Create message records
 		let msg = ChatMessage {
+			id: msgid.to_string(),
 			sender: sender_username.clone(),
 			content: content.to_string(),
 			timestamp,
+			sent_at,
 			encrypted: encrypted.clone(),
+			ttl,
 		};
 
+		Self::count_sent_message(server, sender_id);
+
 		// Store in sender's history
 		if let Some(user) = server.users.get_mut(sender_id) {
 			user.messages.push_back(msg.clone());
@@ -497,66 +2900,34 @@ impl MessageHandler {
 		}
 	}
 
-	fn secure_delete_message(message: &mut ChatMessage) {
-		// Overwrite content with zeros using safe code
-		let zeros = vec![0u8; message.content.len()];
-		unsafe {
-			// This is unsafe because we're manipulating the string's bytes directly
-			std::ptr::copy(
-				zeros.as_ptr(),
-				message.content.as_bytes_mut().as_mut_ptr(),
-				message.content.len(),
-			);
-		}
-
-		// Overwrite encrypted content with zeros
-		for byte in &mut message.encrypted {
-			*byte = 0;
+	fn new_channel(name: &str, persistent: bool) -> Channel {
+		Channel {
+			name: name.to_string(),
+			topic: String::new(),
+			users: HashSet::new(),
+			operators: HashSet::new(),
+			messages: std::collections::VecDeque::new(),
+			created_at: Instant::now(),
+			last_activity: Instant::now(),
+			bans: HashSet::new(),
+			persistent,
+			secret: false,
+			topic_locked: false,
+			message_count: 0,
 		}
 	}
 
-	fn disconnect_user(server: &mut ServerState, user_id: &str) {
-		// Get username for logging
-		let username = server
-			.users
-			.get(user_id)
-			.map(|u| u.username.clone())
-			.unwrap_or_else(|| "Unknown".to_string());
-
-		info!("Disconnecting user: {} ({})", username, user_id);
-
-		if let Some(user) = server.users.get(user_id) {
-			// Leave all channels and notify others
-			for channel in user.channels.clone() {
-				// Remove user from channel
-				if let Some(ch) = server.channels.get_mut(&channel) {
-					ch.users.remove(user_id);
-
-					// Broadcast leave message
-					let leave_message = format!("* {} has disconnected", username);
-					Self::broadcast_to_channel(server, &channel, &leave_message, None);
-				}
-			}
-
-			// Clear all private messages
-			// In a secure system, we want to completely remove messages when a user disconnects
-			if let Some(user) = server.users.get_mut(user_id) {
-				user.messages.clear();
-			}
-		}
-
-		// Remove user completely
-		server.users.remove(user_id);
-
-		info!("User disconnected and messages cleared: {}", username);
+	// Whether a user is in a secret channel the requesting user isn't in
+	fn in_hidden_channel(&self, server: &ServerState, user: &User) -> bool {
+		user.channels.iter().any(|name| {
+			server
+				.channels
+				.get(name)
+				.map_or(false, |ch| ch.secret && !ch.users.contains(&self.user_id))
+		})
 	}
 
 	fn find_user_by_username(server: &ServerState, username: &str) -> Option<String> {
-		for (id, user) in &server.users {
-			if user.username == username {
-				return Some(id.clone());
-			}
-		}
-		None
+		server.user_id_by_username(username).cloned()
 	}
 }
@@ -0,0 +1,141 @@
+use serde_json::{json, Map, Value};
+
+// JSON line protocol: an alternative to IRC text for programmatic clients, served
+// by IRCServerFacade::start_json. Each line in either direction is one JSON object.
+//
+// Client commands carry "cmd" plus named fields for the core commands:
+//   {"cmd":"AUTH","token":"<jwt>"}                         first line; the token
+//   {"cmd":"RESUME","token":"<resume token>"}              first line; RESUME <token>
+//   {"cmd":"JOIN","channel":"#rust"}                       JOIN #rust
+//   {"cmd":"PART","channel":"#rust"}                       PART #rust
+//   {"cmd":"PRIVMSG","target":"#rust","body":"hi","ttl":30} PRIVMSG #rust +ttl=30 :hi (ttl optional)
+//   {"cmd":"TOPIC","channel":"#rust","topic":"..."}        TOPIC #rust :... (topic optional)
+//   {"cmd":"AWAY","message":"lunch"}                       AWAY :lunch (message optional)
+//   {"cmd":"QUIT","reason":"bye"}                          QUIT :bye (reason optional)
+// Any other command is written generically as {"cmd":"WHOIS","params":["bob"],"text":"..."},
+// where "params" and "text" are both optional; PING and PONG use "text".
+//
+// Server events are every IRC line the server would have sent, split into
+// {"source":"alice","command":"PRIVMSG","params":["#rust"],"text":"<alice> hi","tags":{...}},
+// with "source" and "text" null when the line has none and "tags" holding any
+// IRCv3 message tags. Numeric replies keep their number as the command ("001").
+
+// Translate one JSON command into the IRC line MessageHandler dispatches
+pub fn command_from_json(line: &str) -> Result<String, String> {
+	let value: Value = serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {}", e))?;
+	let object = value.as_object().ok_or("A command must be a JSON object")?;
+
+	let cmd = match object.get("cmd").and_then(Value::as_str) {
+		Some(cmd) if !cmd.is_empty() && cmd.chars().all(|c| c.is_ascii_alphanumeric()) => {
+			cmd.to_ascii_uppercase()
+		}
+		_ => return Err("\"cmd\" must be a command name".to_string()),
+	};
+
+	let field = |name: &str| string_field(object, name);
+	let required = |name: &str| string_field(object, name)?.ok_or_else(|| format!("{} requires \"{}\"", cmd, name));
+
+	match cmd.as_str() {
+		"AUTH" => Ok(check_param(required("token")?)?.to_string()),
+		"RESUME" => Ok(format!("RESUME {}", check_param(required("token")?)?)),
+		"JOIN" | "PART" => irc_line(&cmd, &[required("channel")?], None),
+		"PRIVMSG" => {
+			let ttl = match object.get("ttl") {
+				None | Some(Value::Null) => None,
+				Some(ttl) => Some(ttl.as_u64().ok_or("\"ttl\" must be a whole number of seconds")?),
+			};
+			let ttl = ttl.map(|ttl| format!("+ttl={}", ttl));
+			let mut params = vec![required("target")?];
+			params.extend(ttl.as_deref());
+			irc_line(&cmd, &params, Some(required("body")?))
+		}
+		"TOPIC" => irc_line(&cmd, &[required("channel")?], field("topic")?),
+		"AWAY" => irc_line(&cmd, &[], field("message")?),
+		"QUIT" => irc_line(&cmd, &[], field("reason")?),
+		_ => {
+			let params = match object.get("params") {
+				None | Some(Value::Null) => Vec::new(),
+				Some(Value::Array(params)) => params
+					.iter()
+					.map(|param| param.as_str().ok_or("\"params\" must hold strings"))
+					.collect::<Result<Vec<&str>, &str>>()?,
+				Some(_) => return Err("\"params\" must be an array".to_string()),
+			};
+			irc_line(&cmd, &params, field("text")?)
+		}
+	}
+}
+
+// An optional string field of a command object
+fn string_field<'a>(object: &'a Map<String, Value>, name: &str) -> Result<Option<&'a str>, String> {
+	match object.get(name) {
+		None | Some(Value::Null) => Ok(None),
+		Some(Value::String(text)) => Ok(Some(text.as_str())),
+		Some(_) => Err(format!("\"{}\" must be a string", name)),
+	}
+}
+
+// A middle parameter must be one non-empty word that isn't mistaken for the trailing text
+fn check_param(param: &str) -> Result<&str, String> {
+	if param.is_empty() || param.starts_with(':') || param.contains(|c: char| c.is_whitespace() || c == '\0') {
+		return Err(format!("Invalid parameter: {:?}", param));
+	}
+	Ok(param)
+}
+
+// Build "CMD param... :text", refusing anything that would split into extra lines
+fn irc_line(cmd: &str, params: &[&str], text: Option<&str>) -> Result<String, String> {
+	let mut line = cmd.to_string();
+	for param in params {
+		line.push(' ');
+		line.push_str(check_param(param)?);
+	}
+
+	if let Some(text) = text {
+		if text.contains(['\r', '\n', '\0']) {
+			return Err("Text must not contain line breaks".to_string());
+		}
+		line.push_str(" :");
+		line.push_str(text);
+	}
+
+	Ok(line)
+}
+
+// Split one IRC line from the server into its JSON event
+pub fn event_to_json(line: &str) -> Value {
+	let mut rest = line.trim_end_matches(['\r', '\n']);
+
+	let mut tags = Map::new();
+	if let Some(tagged) = rest.strip_prefix('@') {
+		let (tag_text, after) = tagged.split_once(' ').unwrap_or((tagged, ""));
+		for tag in tag_text.split(';').filter(|tag| !tag.is_empty()) {
+			let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+			tags.insert(key.to_string(), Value::String(value.to_string()));
+		}
+		rest = after;
+	}
+
+	let mut source = None;
+	if let Some(prefixed) = rest.strip_prefix(':') {
+		let (prefix, after) = prefixed.split_once(' ').unwrap_or((prefixed, ""));
+		source = Some(prefix);
+		rest = after;
+	}
+
+	let (head, text) = match rest.split_once(" :") {
+		Some((head, text)) => (head, Some(text)),
+		None => (rest, None),
+	};
+	let mut words = head.split_whitespace();
+	let command = words.next().unwrap_or("");
+	let params: Vec<&str> = words.collect();
+
+	json!({
+		"source": source,
+		"command": command,
+		"params": params,
+		"text": text,
+		"tags": tags,
+	})
+}
@@ -1,11 +1,19 @@
 // Export server modules
+pub mod audit;
 pub mod crypto;
 pub mod facade;
 pub mod handler;
+pub mod json_protocol;
 pub mod models;
 pub mod session;
+pub mod transport;
 
 // Re-export main types
 pub use facade::IRCServerFacade;
-pub use models::{Channel, ChatMessage, Message, MessageType, User};
+pub use handler::decode_token;
+pub use models::{
+	Channel, ChannelCreationPolicy, ChannelInfo, ChannelStats, ChatMessage, Device, Message, MessageType, ServerStats,
+	User, UserInfo,
+};
 pub use session::Session;
+pub use transport::{ClientSink, FanOutSink, JsonLineReader, JsonSink, MemorySink, SharedSink};
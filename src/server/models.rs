@@ -1,19 +1,30 @@
 use std::collections::{HashSet, VecDeque};
-use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
 use crate::server::session::Session;
+use crate::server::transport::{FanOutSink, SharedSink};
+use crate::utils::sync::lock_or_recover;
 
 // Chat message with expiration
 #[derive(Clone)]
 pub struct ChatMessage {
+	pub id: String, // Message ID, sent to clients as the IRCv3 msgid tag
 	pub sender: String,
 	pub content: String,
 	pub timestamp: Instant,
+	pub sent_at: SystemTime, // Wall-clock send time, for display and export
 	pub encrypted: Vec<u8>,
+	pub ttl: Option<Duration>, // Sender-chosen lifetime, shorter than the server's message TTL
+}
+
+impl ChatMessage {
+	// Whether the message has outlived its own TTL, or `default_ttl` when it has none
+	pub fn is_expired(&self, now: Instant, default_ttl: Duration) -> bool {
+		now.duration_since(self.timestamp) >= self.ttl.unwrap_or(default_ttl)
+	}
 }
 
 // Message types
@@ -38,11 +49,114 @@ pub struct Message {
 pub struct User {
 	pub id: String,
 	pub username: String,
-	pub profile_pic: Vec<u8>, // Raw 8-bit profile picture data
+	pub profile_pic: Vec<u8>, // Raw 8-bit profile picture data (empty if none)
+	pub profile_pic_ref: Option<String>, // Key of the avatar in the server's avatar store, instead of `profile_pic`
 	pub channels: HashSet<String>,
-	pub stream: Option<Arc<Mutex<TcpStream>>>,
-	pub session: Option<Session>,
+	pub stream: Option<SharedSink>, // TCP or WebSocket connection; `fan_out` once a second device connects
+	pub session: Option<Session>,   // Started by the first connection and shared by later devices
+	pub devices: Vec<Device>,       // Connections from the user's other devices, each with its own session
+	pub fan_out: Option<Arc<Mutex<FanOutSink>>>, // Every connection of a multi-device user
 	pub messages: VecDeque<ChatMessage>, // Store recent messages
+	pub away: Option<String>,            // Away message, if the user is marked away
+	pub caps: HashSet<String>,           // IRCv3 capabilities negotiated with CAP
+	pub host: String,                    // Client IP address, matched by ban masks
+	pub silenced: bool,                  // Suppress automated message-expiry notices (SILENCE on)
+}
+
+// A further connection of a user already signed in from another device
+pub struct Device {
+	pub sink: SharedSink,
+	pub session: Session,
+}
+
+impl User {
+	// Add a connection from another device; everything sent to the user then reaches both
+	pub fn attach_device(&mut self, sink: SharedSink, session: Session) {
+		let stream = &self.stream;
+		let fan_out = self
+			.fan_out
+			.get_or_insert_with(|| Arc::new(Mutex::new(FanOutSink::new(stream.iter().cloned().collect()))))
+			.clone();
+		lock_or_recover(&fan_out).add(sink.clone());
+
+		let stream: SharedSink = fan_out;
+		self.stream = Some(stream);
+		self.devices.push(Device { sink, session });
+	}
+
+	// Whether `sink` is still one of the user's connections. A connection whose user
+	// has since quit and signed in again on a new connection is not, so closing it
+	// leaves the new connection alone.
+	pub fn is_attached(&self, sink: &SharedSink) -> bool {
+		match &self.fan_out {
+			Some(fan_out) => lock_or_recover(fan_out).contains(sink),
+			None => self.stream.as_ref().is_some_and(|stream| Arc::ptr_eq(stream, sink)),
+		}
+	}
+
+	// Detach one connection of a multi-device user, returning whether others remain.
+	// When it is the last one nothing changes, and the caller ends the user instead.
+	// Detaching the first connection promotes the next device's session to `session`,
+	// and the replaced session zeroes its secrets as it is dropped.
+	pub fn detach_device(&mut self, sink: &SharedSink) -> bool {
+		let fan_out = match &self.fan_out {
+			Some(fan_out) => fan_out,
+			None => return false,
+		};
+
+		let mut fan_out = lock_or_recover(fan_out);
+		if fan_out.len() <= 1 || !fan_out.contains(sink) {
+			return false;
+		}
+		fan_out.remove(sink);
+		drop(fan_out);
+
+		match self.devices.iter().position(|device| Arc::ptr_eq(&device.sink, sink)) {
+			Some(index) => {
+				self.devices.remove(index);
+			}
+			None if !self.devices.is_empty() => {
+				self.session = Some(self.devices.remove(0).session);
+			}
+			None => {}
+		}
+		true
+	}
+
+	// The session of the connection writing to `sink`: a second device's own session,
+	// otherwise the first connection's
+	pub fn session_for(&mut self, sink: &SharedSink) -> Option<&mut Session> {
+		match self.devices.iter_mut().find(|device| Arc::ptr_eq(&device.sink, sink)) {
+			Some(device) => Some(&mut device.session),
+			None => self.session.as_mut(),
+		}
+	}
+
+	// Every connection's session with the sink reaching that connection alone. The
+	// first connection's sink is None while its session waits for RESUME.
+	pub fn connections_mut(&mut self) -> Vec<(Option<SharedSink>, &mut Session)> {
+		let first_sink = self.first_sink();
+		let mut connections = Vec::new();
+		if let Some(session) = &mut self.session {
+			connections.push((first_sink, session));
+		}
+		for device in &mut self.devices {
+			connections.push((Some(device.sink.clone()), &mut device.session));
+		}
+		connections
+	}
+
+	// The sink of the first connection, which is the one not listed in `devices`
+	fn first_sink(&self) -> Option<SharedSink> {
+		match &self.fan_out {
+			Some(fan_out) => lock_or_recover(fan_out)
+				.sinks()
+				.iter()
+				.find(|sink| !self.devices.iter().any(|device| Arc::ptr_eq(&device.sink, sink)))
+				.cloned(),
+			None => self.stream.clone(),
+		}
+	}
 }
 
 // Channel representation
@@ -50,9 +164,95 @@ pub struct Channel {
 	pub name: String,
 	pub topic: String,
 	pub users: HashSet<String>,
+	pub operators: HashSet<String>, // Members who may DRAIN the channel: whoever created it by joining
 	pub messages: VecDeque<ChatMessage>, // History with expiration
 	pub created_at: Instant,
 	pub last_activity: Instant,
+	pub bans: HashSet<String>, // Ban masks (nick!user@host globs) set with MODE +b
+	pub persistent: bool,      // Created by an admin with CREATE; kept while empty
+	pub secret: bool,          // MODE +s: hidden from LIST and WHO for non-members
+	pub topic_locked: bool,    // MODE +t: only operators may change the topic
+	pub message_count: u64,    // Messages sent to the channel since it was created
+}
+
+impl Channel {
+	// Channel flags as reported by MODE, e.g. "+st" ("+" when none are set)
+	pub fn modes(&self) -> String {
+		format!(
+			"+{}{}",
+			if self.secret { "s" } else { "" },
+			if self.topic_locked { "t" } else { "" }
+		)
+	}
+}
+
+// Runtime statistics returned by IRCServerFacade::stats
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+	pub users: usize,               // Connected users, including sessions awaiting RESUME
+	pub channels: Vec<ChannelStats>, // Sorted by channel name
+}
+
+// Per-channel totals within ServerStats
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+	pub name: String,
+	pub users: usize,
+	pub message_count: u64,
+}
+
+// Snapshot of one channel returned by IRCServerFacade::channels
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelInfo {
+	pub name: String,
+	pub topic: String,
+	pub users: usize,           // Member count
+	pub created_at: SystemTime,
+	pub modes: String,          // As reported by MODE, e.g. "+st"
+}
+
+// Snapshot of one connected user returned by IRCServerFacade::users; never carries
+// the avatar or message contents
+#[derive(Debug, Clone, Serialize)]
+pub struct UserInfo {
+	pub id: String,
+	pub username: String,
+	pub channels: usize,      // Channels joined
+	pub idle: Duration,       // Time since the user's last command
+	pub away: Option<String>, // Away message, if marked away
+}
+
+// One channel as listed by LIST, from ServerState's cached channel list
+#[derive(Debug, Clone)]
+pub struct ChannelListing {
+	pub name: String,
+	pub users: usize, // Member count
+	pub topic: String,
+	pub secret: bool, // Listed only to members
+}
+
+// File transfer relayed between two users; only its byte count is kept, never the data
+pub struct FileTransfer {
+	pub sender: String,    // User ID offering the file
+	pub recipient: String, // User ID it is offered to
+	pub name: String,
+	pub size: u64,      // Declared plaintext size, in bytes
+	pub accepted: bool, // Set by ACCEPTFILE; FILEDATA is refused until then
+	pub relayed: u64,   // Encrypted bytes relayed so far
+}
+
+// Recent authentication failures from one source IP
+pub struct AuthFailures {
+	pub recent: VecDeque<Instant>,      // Failures within the current window
+	pub cooldown: Duration,             // Length of the last lockout (zero if never locked out)
+	pub blocked_until: Option<Instant>, // New connections are refused until then
+}
+
+// Whether JOIN may create channels that don't exist yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelCreationPolicy {
+	AllowAutoCreate,        // Any JOIN creates the channel (default)
+	RequirePreRegistration, // Only admins create channels, with CREATE
 }
 
 // Token Claims Structure
@@ -60,7 +260,10 @@ pub struct Channel {
 pub struct TokenClaims {
 	pub sub: String,                 // User ID
 	pub username: String,            // Username
-	pub profile_pic: String,         // Base64 encoded 8-bit profile picture
+	#[serde(default)]
+	pub profile_pic: String,         // Base64 encoded 8-bit profile picture (empty if none)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub profile_pic_ref: Option<String>, // Avatar kept in the server's avatar store rather than in the token
 	pub exp: usize,                  // Expiration timestamp
 	pub iat: usize,                  // Issued at timestamp
 	pub nbf: Option<usize>,          // Not valid before timestamp
@@ -1,4 +1,8 @@
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::server::crypto::Encryptor;
 
 // Session information
 pub struct Session {
@@ -8,6 +12,16 @@ pub struct Session {
 	pub last_activity: Instant,
 	pub encryption_key: [u8; 32], // AES-256 key
 	pub nonce_counter: u64,
+	pub inactivity_warned: bool, // Whether the idle warning was sent since the last activity
+	pub last_pong: Instant,                      // When the client last answered a PING
+	pub pending_ping: Option<(String, Instant)>, // Nonce and send time of an unanswered PING
+	pub last_typing: Option<Instant>,            // When a typing notification was last relayed
+	pub resume_token: String,                    // Secret a reconnecting client presents with RESUME
+	pub disconnected_at: Option<Instant>,        // When the connection dropped, while awaiting RESUME
+	pub messages_sent: u64,                      // Channel and private messages sent this session
+	pub token_expires_at: Option<SystemTime>,    // The authenticating token's exp; the session ends then
+	pub jti: Option<String>,                     // The authenticating token's ID, matched by disconnect_by_jti
+	last_sealed: u64,                            // Highest nonce counter handed out under the current key
 }
 
 impl Session {
@@ -20,27 +34,103 @@ impl Session {
 			last_activity: now,
 			encryption_key,
 			nonce_counter: 0,
+			inactivity_warned: false,
+			last_pong: now,
+			pending_ping: None,
+			last_typing: None,
+			resume_token: String::new(),
+			disconnected_at: None,
+			messages_sent: 0,
+			token_expires_at: None,
+			jti: None,
+			last_sealed: 0,
 		}
 	}
 
 	pub fn update_activity(&mut self) {
 		self.last_activity = Instant::now();
+		self.inactivity_warned = false;
+	}
+
+	// Record a PONG; only a reply carrying the outstanding nonce counts
+	pub fn record_pong(&mut self, nonce: &str) -> bool {
+		match &self.pending_ping {
+			Some((expected, _)) if expected == nonce => {
+				self.pending_ping = None;
+				self.last_pong = Instant::now();
+				true
+			}
+			_ => false,
+		}
+	}
+
+	// Whether, at `now`, a PING has gone unanswered for longer than the timeout
+	pub fn ping_timed_out(&self, now: Instant, timeout: std::time::Duration) -> bool {
+		match &self.pending_ping {
+			Some((_, sent_at)) => now.duration_since(*sent_at) > timeout,
+			None => false,
+		}
 	}
 
 	pub fn is_expired(&self, timeout_duration: std::time::Duration) -> bool {
 		Instant::now().duration_since(self.last_activity) > timeout_duration
 	}
 
+	// Hand out the next nonce counter. Every counter handed out may seal a message,
+	// so one at or below the last handed out means the counter was reset under the same key.
 	pub fn increment_nonce(&mut self) -> u64 {
 		self.nonce_counter += 1;
+		debug_assert!(
+			self.nonce_counter > self.last_sealed,
+			"nonce counter {} reused under the same session key",
+			self.nonce_counter
+		);
+		self.last_sealed = self.nonce_counter;
 		self.nonce_counter
 	}
 
+	// Encrypt under the session key with the next nonce counter, returning the counter
+	// with the ciphertext. All encryption for a session must go through here (or
+	// increment_nonce), so no counter is ever used twice with the same key.
+	pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(u64, Vec<u8>), String> {
+		let counter = self.increment_nonce();
+		let ciphertext = Encryptor::new(self.encryption_key).encrypt(counter, plaintext)?;
+		Ok((counter, ciphertext))
+	}
+
+	// Switch to a new key; only then may the nonce counter start again from zero
+	pub fn rekey(&mut self, key: [u8; 32]) {
+		self.encryption_key.zeroize();
+		self.encryption_key = key;
+		self.nonce_counter = 0;
+		self.last_sealed = 0;
+	}
+
+	// Whether the token the session authenticated with has passed its exp
+	pub fn token_expired(&self, now: SystemTime) -> bool {
+		self.token_expires_at.map_or(false, |expires_at| now >= expires_at)
+	}
+
 	pub fn duration(&self) -> std::time::Duration {
 		Instant::now().duration_since(self.started_at)
 	}
 
+	// Overwrite the encryption key and resume token; runs automatically on drop
+	pub fn zeroize_secrets(&mut self) {
+		self.encryption_key.zeroize();
+		self.resume_token.zeroize();
+	}
+
 	pub fn idle_time(&self) -> std::time::Duration {
 		Instant::now().duration_since(self.last_activity)
 	}
 }
+
+// Keys and tokens must not linger in freed memory, whichever path drops the session
+impl Drop for Session {
+	fn drop(&mut self) {
+		self.zeroize_secrets();
+	}
+}
+
+impl ZeroizeOnDrop for Session {}
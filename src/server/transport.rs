@@ -0,0 +1,522 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::warn;
+
+use tungstenite::protocol::Role;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+use crate::server::json_protocol::{command_from_json, event_to_json};
+use crate::utils::sync::lock_or_recover;
+
+// Outgoing half of a client connection. Everything written is IRC protocol
+// text made of "\r\n"-terminated lines.
+pub trait ClientSink: Write + Send {
+	// Close the connection, unblocking any thread reading from it
+	fn shutdown(&mut self) -> io::Result<()>;
+}
+
+// Sink shared between the connection thread and everyone who sends to the user
+pub type SharedSink = Arc<Mutex<dyn ClientSink>>;
+
+// Incoming half of a client connection, yielding one IRC command line at a time
+pub trait LineReader: Send {
+	// Read the next line without its line ending.
+	// Returns None once the client has closed the connection. A line longer than
+	// `max_len` is dropped and reported with `line_too_long`; reading can go on
+	// with the line after it.
+	fn read_line(&mut self, max_len: usize) -> io::Result<Option<String>>;
+}
+
+// The error every LineReader returns for a line over `max_len` (kind InvalidData)
+pub fn line_too_long(max_len: usize) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!("Line exceeds maximum length of {} bytes", max_len),
+	)
+}
+
+impl ClientSink for TcpStream {
+	fn shutdown(&mut self) -> io::Result<()> {
+		TcpStream::shutdown(self, Shutdown::Both)
+	}
+}
+
+// Collects everything written to it in memory instead of sending it anywhere.
+// Clones share the same buffer, so a test can hand one clone to the server as a
+// user's stream and read what the handlers wrote through another.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+	buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemorySink {
+	pub fn new() -> Self {
+		MemorySink::default()
+	}
+
+	// Everything written since the last call, as text
+	pub fn take(&self) -> String {
+		let data = std::mem::take(&mut *lock_or_recover(&self.buffer));
+		String::from_utf8_lossy(&data).to_string()
+	}
+
+	// Wrap a clone as a user's stream
+	pub fn shared(&self) -> SharedSink {
+		Arc::new(Mutex::new(self.clone()))
+	}
+}
+
+impl Write for MemorySink {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		lock_or_recover(&self.buffer).extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl ClientSink for MemorySink {
+	fn shutdown(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+// Reads newline-terminated lines from a raw TCP connection
+pub struct TcpLineReader {
+	stream: TcpStream,
+	carry: Vec<u8>,   // Bytes received after the current line, kept for the next read
+	discarding: bool, // Skipping the rest of an over-long line, up to its newline
+}
+
+impl TcpLineReader {
+	pub fn new(stream: TcpStream) -> Self {
+		TcpLineReader {
+			stream,
+			carry: Vec::new(),
+			discarding: false,
+		}
+	}
+}
+
+impl LineReader for TcpLineReader {
+	// Grows the buffer until a newline arrives, so lines may span several reads
+	fn read_line(&mut self, max_len: usize) -> io::Result<Option<String>> {
+		let mut chunk = [0; 4096];
+
+		loop {
+			if let Some(pos) = self.carry.iter().position(|&b| b == b'\n') {
+				let line: Vec<u8> = self.carry.drain(..=pos).collect();
+				if std::mem::take(&mut self.discarding) {
+					continue;
+				}
+
+				let line = String::from_utf8_lossy(&line).trim().to_string();
+				if line.len() > max_len {
+					return Err(line_too_long(max_len));
+				}
+				return Ok(Some(line));
+			}
+
+			// Don't buffer an over-long line; drop what has arrived and skip the rest
+			if self.discarding {
+				self.carry.clear();
+			} else if self.carry.len() > max_len {
+				self.carry.clear();
+				self.discarding = true;
+				return Err(line_too_long(max_len));
+			}
+
+			// A large token may arrive in several segments; a signal mid-line is not an error
+			let size = match self.stream.read(&mut chunk) {
+				Ok(size) => size,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			};
+
+			if size == 0 {
+				// Treat unterminated data before the connection closed as a final line
+				if self.carry.is_empty() || self.discarding {
+					return Ok(None);
+				}
+				let line = String::from_utf8_lossy(&self.carry).trim().to_string();
+				self.carry.clear();
+				return Ok(Some(line));
+			}
+
+			self.carry.extend_from_slice(&chunk[0..size]);
+		}
+	}
+}
+
+// Convert a WebSocket error into an I/O error for the transport traits
+fn ws_error(e: tungstenite::Error) -> io::Error {
+	match e {
+		tungstenite::Error::Io(e) => e,
+		tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+			io::Error::new(io::ErrorKind::NotConnected, "WebSocket closed")
+		}
+		other => io::Error::new(io::ErrorKind::Other, other.to_string()),
+	}
+}
+
+// The sending side of an upgraded WebSocket connection. Every frame the server sends,
+// including the replies to a client's pings, goes through this one socket.
+pub type WebSocketWriter = Arc<Mutex<WebSocket<TcpStream>>>;
+
+// Create the sending side for an already-upgraded WebSocket connection
+pub fn websocket_writer(stream: TcpStream) -> WebSocketWriter {
+	Arc::new(Mutex::new(WebSocket::from_raw_socket(stream, Role::Server, None)))
+}
+
+// The receiving side's view of the TCP stream. It writes normally until the handshake
+// is done; after that writes are dropped, so control frames tungstenite would answer on
+// its own can't interleave with frames sent through the WebSocketWriter.
+pub struct WebSocketReadStream {
+	stream: TcpStream,
+	handshake_done: bool,
+}
+
+impl WebSocketReadStream {
+	pub fn new(stream: TcpStream) -> Self {
+		WebSocketReadStream {
+			stream,
+			handshake_done: false,
+		}
+	}
+}
+
+impl Read for WebSocketReadStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.stream.read(buf)
+	}
+}
+
+impl Write for WebSocketReadStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.handshake_done {
+			return Ok(buf.len());
+		}
+		self.stream.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.stream.flush()
+	}
+}
+
+// Sends each IRC line written to it as one WebSocket text frame
+pub struct WebSocketSink {
+	socket: WebSocketWriter,
+	pending: Vec<u8>, // Written bytes not yet terminated by a newline
+}
+
+impl WebSocketSink {
+	// Create a sink writing to an already-upgraded WebSocket connection
+	pub fn new(socket: WebSocketWriter) -> Self {
+		WebSocketSink {
+			socket,
+			pending: Vec::new(),
+		}
+	}
+}
+
+impl Write for WebSocketSink {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.pending.extend_from_slice(buf);
+
+		while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = self.pending.drain(..=pos).collect();
+			let text = String::from_utf8_lossy(&line)
+				.trim_end_matches(|c| c == '\r' || c == '\n')
+				.to_string();
+			lock_or_recover(&self.socket).send(WsMessage::Text(text)).map_err(ws_error)?;
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		lock_or_recover(&self.socket).flush().map_err(ws_error)
+	}
+}
+
+impl ClientSink for WebSocketSink {
+	fn shutdown(&mut self) -> io::Result<()> {
+		let mut socket = lock_or_recover(&self.socket);
+		let _ = socket.close(None);
+		let _ = socket.flush();
+		TcpStream::shutdown(socket.get_mut(), Shutdown::Both)
+	}
+}
+
+// Reads one IRC command line from each WebSocket text frame
+pub struct WebSocketLineReader {
+	socket: WebSocket<WebSocketReadStream>,
+	writer: WebSocketWriter, // Where pongs are sent
+}
+
+impl WebSocketLineReader {
+	// `socket` has just completed the handshake; `writer` must send on the same connection
+	pub fn new(mut socket: WebSocket<WebSocketReadStream>, writer: WebSocketWriter) -> Self {
+		socket.get_mut().handshake_done = true;
+		WebSocketLineReader { socket, writer }
+	}
+}
+
+impl LineReader for WebSocketLineReader {
+	fn read_line(&mut self, max_len: usize) -> io::Result<Option<String>> {
+		loop {
+			let text = match self.socket.read() {
+				Ok(WsMessage::Text(text)) => text,
+				Ok(WsMessage::Binary(data)) => String::from_utf8_lossy(&data).to_string(),
+				Ok(WsMessage::Close(_)) => return Ok(None),
+				Ok(WsMessage::Ping(data)) => {
+					let mut writer = lock_or_recover(&self.writer);
+					writer.send(WsMessage::Pong(data)).map_err(ws_error)?;
+					continue;
+				}
+				Ok(_) => continue,
+				Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+					return Ok(None)
+				}
+				Err(e) => return Err(ws_error(e)),
+			};
+
+			if text.len() > max_len {
+				return Err(line_too_long(max_len));
+			}
+
+			return Ok(Some(text.trim().to_string()));
+		}
+	}
+}
+
+// Sends each IRC line written to it as one JSON event per line (see json_protocol)
+pub struct JsonSink<S: ClientSink> {
+	inner: S,
+	pending: Vec<u8>, // Written bytes not yet terminated by a newline
+}
+
+impl<S: ClientSink> JsonSink<S> {
+	pub fn new(inner: S) -> Self {
+		JsonSink {
+			inner,
+			pending: Vec::new(),
+		}
+	}
+}
+
+impl<S: ClientSink> Write for JsonSink<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.pending.extend_from_slice(buf);
+
+		while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = self.pending.drain(..=pos).collect();
+			let event = event_to_json(&String::from_utf8_lossy(&line));
+			self.inner.write_all(format!("{}\n", event).as_bytes())?;
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<S: ClientSink> ClientSink for JsonSink<S> {
+	fn shutdown(&mut self) -> io::Result<()> {
+		self.inner.shutdown()
+	}
+}
+
+// Translates each JSON command line from `inner` into the IRC line it stands for.
+// Commands that don't fit the schema are answered with `FAIL JSON INVALID_COMMAND`
+// on `sink` and skipped, so a malformed object never reaches the handlers.
+pub struct JsonLineReader<R: LineReader> {
+	inner: R,
+	sink: SharedSink,
+}
+
+impl<R: LineReader> JsonLineReader<R> {
+	pub fn new(inner: R, sink: SharedSink) -> Self {
+		JsonLineReader { inner, sink }
+	}
+}
+
+impl<R: LineReader> LineReader for JsonLineReader<R> {
+	fn read_line(&mut self, max_len: usize) -> io::Result<Option<String>> {
+		loop {
+			let line = match self.inner.read_line(max_len)? {
+				Some(line) if line.is_empty() => continue,
+				Some(line) => line,
+				None => return Ok(None),
+			};
+
+			match command_from_json(&line) {
+				Ok(command) => return Ok(Some(command)),
+				Err(reason) => {
+					let fail = format!(":{} FAIL JSON INVALID_COMMAND :{}\r\n", "server", reason.replace(['\r', '\n'], " "));
+					lock_or_recover(&self.sink).write_all(fail.as_bytes())?;
+				}
+			}
+		}
+	}
+}
+
+// Writes queued for a client before it is considered too slow and disconnected
+pub const SEND_QUEUE_CAPACITY: usize = 256;
+
+enum Outgoing {
+	Data(Vec<u8>),
+	Close,
+}
+
+// Queues writes for a dedicated writer thread so a slow client never blocks
+// the thread sending to it (or anyone waiting on the server lock meanwhile).
+// A client whose queue fills up is disconnected.
+pub struct QueuedSink {
+	sender: SyncSender<Outgoing>,
+	closer: TcpStream, // Handle on the underlying socket for closing it immediately
+}
+
+impl QueuedSink {
+	// Start a writer thread that owns `inner`; `closer` must refer to the same socket
+	pub fn spawn(inner: Box<dyn ClientSink>, closer: TcpStream) -> Self {
+		let (sender, receiver) = mpsc::sync_channel(SEND_QUEUE_CAPACITY);
+
+		thread::spawn(move || {
+			Self::run_writer(inner, receiver);
+		});
+
+		QueuedSink { sender, closer }
+	}
+
+	// Write queued data until the queue is closed or the client stops accepting it
+	fn run_writer(mut inner: Box<dyn ClientSink>, receiver: Receiver<Outgoing>) {
+		for outgoing in receiver {
+			match outgoing {
+				Outgoing::Data(data) => {
+					if inner.write_all(&data).and_then(|_| inner.flush()).is_err() {
+						break;
+					}
+				}
+				Outgoing::Close => break,
+			}
+		}
+
+		let _ = inner.shutdown();
+	}
+}
+
+impl Write for QueuedSink {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self.sender.try_send(Outgoing::Data(buf.to_vec())) {
+			Ok(()) => Ok(buf.len()),
+			Err(TrySendError::Full(_)) => {
+				// Drop the client rather than block; its reader then sees the close and cleans up
+				warn!("Send queue full, disconnecting slow client");
+				let _ = self.closer.shutdown(Shutdown::Both);
+				Err(io::Error::new(io::ErrorKind::Other, "send queue full"))
+			}
+			Err(TrySendError::Disconnected(_)) => {
+				Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+			}
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl ClientSink for QueuedSink {
+	// Close after everything already queued (e.g. a KILL message) has been written
+	fn shutdown(&mut self) -> io::Result<()> {
+		if self.sender.try_send(Outgoing::Close).is_err() {
+			self.closer.shutdown(Shutdown::Both)?;
+		}
+		Ok(())
+	}
+}
+
+// Writes to every connection of a user signed in from several devices at once.
+// A connection that fails a write is skipped; its own thread notices the close
+// and detaches it.
+pub struct FanOutSink {
+	sinks: Vec<SharedSink>,
+}
+
+impl FanOutSink {
+	pub fn new(sinks: Vec<SharedSink>) -> Self {
+		FanOutSink { sinks }
+	}
+
+	pub fn add(&mut self, sink: SharedSink) {
+		self.sinks.push(sink);
+	}
+
+	// Stop writing to `sink`, returning whether it was one of the connections
+	pub fn remove(&mut self, sink: &SharedSink) -> bool {
+		let before = self.sinks.len();
+		self.sinks.retain(|s| !Arc::ptr_eq(s, sink));
+		self.sinks.len() != before
+	}
+
+	pub fn contains(&self, sink: &SharedSink) -> bool {
+		self.sinks.iter().any(|s| Arc::ptr_eq(s, sink))
+	}
+
+	pub fn sinks(&self) -> &[SharedSink] {
+		&self.sinks
+	}
+
+	pub fn len(&self) -> usize {
+		self.sinks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.sinks.is_empty()
+	}
+}
+
+impl Write for FanOutSink {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Succeed as long as one connection took the data
+		let mut delivered = self.sinks.is_empty();
+		let mut last_error = None;
+		for sink in &self.sinks {
+			match lock_or_recover(sink).write_all(buf) {
+				Ok(()) => delivered = true,
+				Err(e) => last_error = Some(e),
+			}
+		}
+
+		match last_error {
+			Some(e) if !delivered => Err(e),
+			_ => Ok(buf.len()),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		for sink in &self.sinks {
+			let _ = lock_or_recover(sink).flush();
+		}
+		Ok(())
+	}
+}
+
+impl ClientSink for FanOutSink {
+	fn shutdown(&mut self) -> io::Result<()> {
+		for sink in &self.sinks {
+			let _ = lock_or_recover(sink).shutdown();
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,55 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::utils::sync::lock_or_recover;
+
+/// Source of the current instant for the server's expiry decisions
+///
+/// The cleanup pass reads the time through `ServerState::clock`, so tests can swap
+/// in a `ManualClock` and move time forward instead of sleeping through a TTL.
+pub trait Clock: Send + Sync {
+	fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock, used unless another one is installed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// A clock that starts at the real time it was created and only moves when advanced
+#[derive(Debug)]
+pub struct ManualClock {
+	start: Instant,
+	elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+	pub fn new() -> Self {
+		ManualClock {
+			start: Instant::now(),
+			elapsed: Mutex::new(Duration::ZERO),
+		}
+	}
+
+	/// Move the clock forward by `duration`
+	pub fn advance(&self, duration: Duration) {
+		*lock_or_recover(&self.elapsed) += duration;
+	}
+}
+
+impl Default for ManualClock {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Clock for ManualClock {
+	fn now(&self) -> Instant {
+		self.start + *lock_or_recover(&self.elapsed)
+	}
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+/// Format a duration in a human-readable way, such as `1h 0m 0s`, `5m 30s` or `30s`
+///
+/// Leading zero units are left out; sub-second parts are dropped.
+pub fn format_duration(duration: Duration) -> String {
+	let total_seconds = duration.as_secs();
+	let hours = total_seconds / 3600;
+	let minutes = (total_seconds % 3600) / 60;
+	let seconds = total_seconds % 60;
+
+	if hours > 0 {
+		format!("{}h {}m {}s", hours, minutes, seconds)
+	} else if minutes > 0 {
+		format!("{}m {}s", minutes, seconds)
+	} else {
+		format!("{}s", seconds)
+	}
+}
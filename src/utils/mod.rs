@@ -1,5 +1,11 @@
 // Export utility modules
+pub mod clock;
+pub mod duration;
+pub mod sync;
 pub mod token;
 
 // Re-export main types
-pub use token::TokenGenerator;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use duration::format_duration;
+pub use sync::lock_or_recover;
+pub use token::{AvatarFormat, AvatarOptions, TokenGenerator};
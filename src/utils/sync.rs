@@ -0,0 +1,14 @@
+use std::sync::{Mutex, MutexGuard};
+
+use log::warn;
+
+/// Lock a mutex, recovering the guard if another thread panicked while holding it
+///
+/// A panic in one connection thread must not take the whole server down, so a
+/// poisoned lock is logged and its data used as-is instead of propagating the panic.
+pub fn lock_or_recover<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|poisoned| {
+		warn!("Recovering from poisoned lock after a thread panicked");
+		poisoned.into_inner()
+	})
+}
@@ -1,36 +1,172 @@
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use image::{GenericImageView, ImageBuffer, Rgba};
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageBuffer, ImageOutputFormat, Rgba};
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use log::debug;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
+use crate::error::IrcError;
+use crate::server::handler::valid_avatar_ref;
 use crate::server::models::TokenClaims;
 
+/// Longest lifetime a token may be issued for (one year)
+pub const MAX_TOKEN_VALIDITY: Duration = Duration::from_secs(365 * 86400);
+
+/// Largest avatar edge length accepted, to keep tokens from bloating
+pub const MAX_AVATAR_SIZE: u32 = 512;
+
+/// Output encoding for converted profile pictures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AvatarFormat {
+	Png,
+	Jpeg(u8), // Quality, 1-100
+	WebP,
+}
+
+/// Options controlling how profile pictures are resized and encoded
+#[derive(Debug, Clone, Copy)]
+pub struct AvatarOptions {
+	pub size: u32, // Width and height in pixels
+	pub format: AvatarFormat,
+	pub colors: u16, // Palette size, 2-256
+	pub filter: FilterType,
+}
+
+impl Default for AvatarOptions {
+	fn default() -> Self {
+		AvatarOptions {
+			size: 64,
+			format: AvatarFormat::Png,
+			colors: 256,
+			filter: FilterType::Nearest,
+		}
+	}
+}
+
 /// Token generator for creating secure authentication tokens
 pub struct TokenGenerator {
-	jwt_secret: String,
+	encoding_key: EncodingKey,
+	algorithm: Algorithm,
 }
 
 impl TokenGenerator {
-	/// Create a new token generator
+	/// Create a new token generator signing with a shared HS256 secret
 	pub fn new(jwt_secret: &str) -> Self {
 		TokenGenerator {
-			jwt_secret: jwt_secret.to_string(),
+			encoding_key: EncodingKey::from_secret(jwt_secret.as_bytes()),
+			algorithm: Algorithm::HS256,
 		}
 	}
 
+	/// Create a new token generator signing with an RS256 private key (PEM)
+	///
+	/// The server only needs the matching public key, so it can verify tokens
+	/// without being able to mint them.
+	pub fn new_rsa(private_key_pem: &str) -> Result<Self, IrcError> {
+		let encoding_key = EncodingKey::from_rsa_pem(private_key_pem.as_bytes())
+			.map_err(|e| IrcError::InvalidInput(format!("Invalid RSA private key: {}", e)))?;
+
+		Ok(TokenGenerator {
+			encoding_key,
+			algorithm: Algorithm::RS256,
+		})
+	}
+
 	/// Generate a token for a user
+	///
+	/// Pass `None` for `profile_pic_data` to mint a token for a user without an avatar.
 	pub fn generate_token(
 		&self,
 		user_id: &str,
 		username: &str,
-		profile_pic_data: &[u8],
+		profile_pic_data: Option<&[u8]>,
 		days_valid: u64,
 		additional_claims: Option<HashMap<String, String>>,
-	) -> Result<String, String> {
+	) -> Result<String, IrcError> {
+		let valid_for = Duration::from_secs(days_valid.saturating_mul(86400)); // 86400 seconds in a day
+		self.generate_token_with_duration(
+			user_id,
+			username,
+			profile_pic_data,
+			valid_for,
+			additional_claims,
+		)
+	}
+
+	/// Generate a token valid for an arbitrary duration (e.g. a few hours)
+	pub fn generate_token_with_duration(
+		&self,
+		user_id: &str,
+		username: &str,
+		profile_pic_data: Option<&[u8]>,
+		valid_for: Duration,
+		additional_claims: Option<HashMap<String, String>>,
+	) -> Result<String, IrcError> {
+		// Base64 encode the profile picture (empty when the user has no avatar)
+		let profile_pic_base64 = profile_pic_data.map(base64::encode).unwrap_or_default();
+
+		self.sign_claims(user_id, username, profile_pic_base64, None, valid_for, additional_claims)
+	}
+
+	/// Generate a token that refers to an avatar in the server's avatar store
+	/// instead of carrying the image, keeping the token small
+	///
+	/// The server serves the avatar stored under `avatar_ref` (see
+	/// `IRCServerFacade::store_avatar`) in reply to PROFILE. The reference must be
+	/// printable, without whitespace and at most `MAX_AVATAR_REF_LEN` bytes long.
+	pub fn generate_token_with_avatar_ref(
+		&self,
+		user_id: &str,
+		username: &str,
+		avatar_ref: &str,
+		valid_for: Duration,
+		additional_claims: Option<HashMap<String, String>>,
+	) -> Result<String, IrcError> {
+		if !valid_avatar_ref(avatar_ref) {
+			return Err(IrcError::InvalidInput(format!(
+				"Invalid avatar reference: {:?}",
+				avatar_ref
+			)));
+		}
+
+		self.sign_claims(
+			user_id,
+			username,
+			String::new(),
+			Some(avatar_ref.to_string()),
+			valid_for,
+			additional_claims,
+		)
+	}
+
+	/// Build and sign the claims shared by every kind of token
+	fn sign_claims(
+		&self,
+		user_id: &str,
+		username: &str,
+		profile_pic_base64: String,
+		profile_pic_ref: Option<String>,
+		valid_for: Duration,
+		additional_claims: Option<HashMap<String, String>>,
+	) -> Result<String, IrcError> {
+		// Validate the requested lifetime
+		if valid_for.as_secs() == 0 {
+			return Err(IrcError::InvalidInput(
+				"Token validity must be at least one second".to_string(),
+			));
+		}
+
+		if valid_for > MAX_TOKEN_VALIDITY {
+			return Err(IrcError::InvalidInput(format!(
+				"Token validity of {} seconds exceeds the maximum of {} seconds",
+				valid_for.as_secs(),
+				MAX_TOKEN_VALIDITY.as_secs()
+			)));
+		}
+
 		// Get current timestamp
 		let now = SystemTime::now()
 			.duration_since(UNIX_EPOCH)
@@ -38,10 +174,7 @@ impl TokenGenerator {
 			.as_secs();
 
 		// Calculate expiration timestamp
-		let expiration = now + (days_valid * 86400); // 86400 seconds in a day
-
-		// Base64 encode the profile picture
-		let profile_pic_base64 = base64::encode(profile_pic_data);
+		let expiration = now + valid_for.as_secs();
 
 		// Generate a unique token ID
 		let token_id = format!("{:x}-{:x}", thread_rng().gen::<u64>(), now);
@@ -51,6 +184,7 @@ impl TokenGenerator {
 			sub: user_id.to_string(),
 			username: username.to_string(),
 			profile_pic: profile_pic_base64,
+			profile_pic_ref,
 			iat: now as usize,
 			exp: expiration as usize,
 			nbf: Some(now as usize), // Token valid immediately
@@ -71,40 +205,79 @@ impl TokenGenerator {
 		}
 
 		// Generate the token
-		let header = Header::new(Algorithm::HS256);
-		let encoding_key = EncodingKey::from_secret(self.jwt_secret.as_bytes());
+		let header = Header::new(self.algorithm);
 
-		let token = jsonwebtoken::encode(&header, &claims, &encoding_key)
-			.map_err(|e| format!("Failed to generate token: {}", e))?;
+		let token = jsonwebtoken::encode(&header, &claims, &self.encoding_key)?;
 
 		debug!("Generated token for user {} with ID {}", username, user_id);
 		Ok(token)
 	}
 
 	/// Load an image and convert it to 8-bit format for profile pictures
-	pub fn load_and_convert_image(&self, image_path: &str) -> Result<Vec<u8>, String> {
+	pub fn load_and_convert_image(&self, image_path: &str) -> Result<Vec<u8>, IrcError> {
+		self.load_and_convert_image_with_options(image_path, &AvatarOptions::default())
+	}
+
+	/// Load an image and convert it using custom size, format and palette options
+	pub fn load_and_convert_image_with_options(
+		&self,
+		image_path: &str,
+		options: &AvatarOptions,
+	) -> Result<Vec<u8>, IrcError> {
+		// Validate options before doing any work
+		if options.size == 0 || options.size > MAX_AVATAR_SIZE {
+			return Err(IrcError::InvalidInput(format!(
+				"Avatar size must be between 1 and {} pixels, got {}",
+				MAX_AVATAR_SIZE, options.size
+			)));
+		}
+
+		if options.colors < 2 || options.colors > 256 {
+			return Err(IrcError::InvalidInput(format!(
+				"Avatar color count must be between 2 and 256, got {}",
+				options.colors
+			)));
+		}
+
+		let output_format = match options.format {
+			AvatarFormat::Png => ImageOutputFormat::Png,
+			AvatarFormat::Jpeg(quality) if (1..=100).contains(&quality) => {
+				ImageOutputFormat::Jpeg(quality)
+			}
+			AvatarFormat::Jpeg(quality) => {
+				return Err(IrcError::InvalidInput(format!(
+					"JPEG quality must be between 1 and 100, got {}",
+					quality
+				)))
+			}
+			AvatarFormat::WebP => ImageOutputFormat::WebP,
+		};
+
 		// Check if file exists
 		if !std::path::Path::new(image_path).exists() {
-			return Err(format!("Image file not found: {}", image_path));
+			return Err(IrcError::Io(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				format!("Image file not found: {}", image_path),
+			)));
 		}
 
 		// Load the image
 		let img = image::open(image_path).map_err(|e| format!("Failed to open image: {}", e))?;
 
-		// Resize to 64x64 for profile picture
-		let resized = img.resize_exact(64, 64, image::imageops::FilterType::Nearest);
+		// Resize to the requested square size
+		let resized = img.resize_exact(options.size, options.size, options.filter);
 
 		// Convert to 8-bit format
 		let quantized = resized.to_rgba8();
 
 		// Convert to indexed color (8-bit palette)
-		let palette = image::imageops::colorops::quantize(&quantized, 256);
+		let palette = image::imageops::colorops::quantize(&quantized, options.colors);
 
-		// Encode as PNG
+		// Encode in the requested format
 		let mut buffer = Vec::new();
 		let mut cursor = std::io::Cursor::new(&mut buffer);
 		palette
-			.write_to(&mut cursor, image::ImageOutputFormat::Png)
+			.write_to(&mut cursor, output_format)
 			.map_err(|e| format!("Failed to encode image: {}", e))?;
 
 		debug!(
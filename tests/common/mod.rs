@@ -0,0 +1,157 @@
+// Shared helpers for the integration tests: boot a server on an ephemeral port
+// and connect authenticated raw TCP clients to it.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use irc_server::{IRCServerFacade, TokenGenerator};
+
+pub const JWT_SECRET: &str = "integration-test-secret";
+
+// How long to wait for an expected line before failing the test
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+	pub facade: IRCServerFacade,
+	pub addr: SocketAddr,
+}
+
+impl TestServer {
+	// Start a server on 127.0.0.1 with a free port
+	pub fn start() -> Self {
+		let facade = IRCServerFacade::new(JWT_SECRET);
+		let addr = facade
+			.start_background("127.0.0.1:0")
+			.expect("failed to start test server");
+
+		TestServer { facade, addr }
+	}
+
+	// Mint a valid token for a user, without an avatar
+	pub fn token(user_id: &str, username: &str) -> String {
+		TokenGenerator::new(JWT_SECRET)
+			.generate_token(user_id, username, None, 1, None)
+			.expect("failed to generate token")
+	}
+
+	// Open a connection that has not sent anything yet
+	pub fn connect_raw(&self) -> TestClient {
+		TestClient::connect(self.addr)
+	}
+
+	// Connect and authenticate, returning once the welcome line has arrived
+	pub fn connect(&self, user_id: &str, username: &str) -> TestClient {
+		let mut client = self.connect_raw();
+		client.send(&Self::token(user_id, username));
+		client.expect(" 001 ");
+		client
+	}
+}
+
+pub struct TestClient {
+	reader: BufReader<TcpStream>,
+	writer: TcpStream,
+}
+
+impl TestClient {
+	pub fn connect(addr: SocketAddr) -> Self {
+		let stream = TcpStream::connect(addr).expect("failed to connect to test server");
+		stream
+			.set_read_timeout(Some(Duration::from_millis(100)))
+			.expect("failed to set read timeout");
+
+		TestClient {
+			reader: BufReader::new(stream.try_clone().expect("failed to clone stream")),
+			writer: stream,
+		}
+	}
+
+	// Send one line, adding the CRLF terminator
+	pub fn send(&mut self, line: &str) {
+		self.writer
+			.write_all(format!("{}\r\n", line).as_bytes())
+			.expect("failed to send line");
+	}
+
+	// Send bytes exactly as given, without adding a terminator
+	pub fn send_bytes(&mut self, bytes: &[u8]) {
+		self.writer.write_all(bytes).expect("failed to send bytes");
+		self.writer.flush().expect("failed to flush");
+	}
+
+	// Read the next raw line (including its terminator), waiting up to `timeout`.
+	// Returns None on timeout or once the server has closed the connection.
+	pub fn read_line_within(&mut self, timeout: Duration) -> Option<String> {
+		let deadline = Instant::now() + timeout;
+		let mut line = String::new();
+
+		loop {
+			match self.reader.read_line(&mut line) {
+				Ok(0) => return None,
+				Ok(_) if line.ends_with('\n') => return Some(line),
+				Ok(_) => {}
+				Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+				Err(_) => return None,
+			}
+
+			if Instant::now() >= deadline {
+				return None;
+			}
+		}
+	}
+
+	// Read lines until one contains `needle`, failing the test if none arrives in time
+	pub fn expect(&mut self, needle: &str) -> String {
+		let deadline = Instant::now() + READ_TIMEOUT;
+		let mut seen = Vec::new();
+
+		while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+			match self.read_line_within(remaining) {
+				Some(line) if line.contains(needle) => return line,
+				Some(line) => seen.push(line),
+				None => break,
+			}
+		}
+
+		panic!("expected a line containing {:?}, got {:?}", needle, seen);
+	}
+
+	// Fail the test if a line containing `needle` arrives within `wait`
+	pub fn expect_none(&mut self, needle: &str, wait: Duration) {
+		let deadline = Instant::now() + wait;
+
+		while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+			match self.read_line_within(remaining) {
+				Some(line) if line.contains(needle) => {
+					panic!("unexpected line containing {:?}: {:?}", needle, line)
+				}
+				Some(_) => {}
+				None => break,
+			}
+		}
+	}
+
+	// Fail the test unless the server closes the connection (a read returning 0) in time
+	pub fn expect_closed(&mut self) {
+		let deadline = Instant::now() + READ_TIMEOUT;
+		let mut buf = [0; 512];
+
+		loop {
+			match self.reader.read(&mut buf) {
+				Ok(0) => return,
+				Ok(size) => panic!(
+					"expected the connection to close, got {:?}",
+					String::from_utf8_lossy(&buf[..size])
+				),
+				Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+				Err(e) => panic!("expected the connection to close cleanly, got {}", e),
+			}
+
+			if Instant::now() >= deadline {
+				panic!("connection still open after {:?}", READ_TIMEOUT);
+			}
+		}
+	}
+}
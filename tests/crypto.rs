@@ -0,0 +1,68 @@
+use std::io::Cursor;
+
+use irc_server::server::crypto::{Encryptor, FRAME_SIZE};
+
+const KEY: [u8; 32] = [42; 32];
+
+// Plaintext spanning several frames, with a partial last frame
+fn payload() -> Vec<u8> {
+	(0..FRAME_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect()
+}
+
+fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+	let mut framed = Vec::new();
+	let written = Encryptor::new(KEY)
+		.encrypt_stream(7, Cursor::new(plaintext), &mut framed)
+		.unwrap();
+	assert_eq!(written, plaintext.len() as u64);
+	framed
+}
+
+#[test]
+fn multi_frame_stream_round_trips() {
+	let plaintext = payload();
+	let framed = encrypt(&plaintext);
+
+	let mut decrypted = Vec::new();
+	let read = Encryptor::new(KEY)
+		.decrypt_stream(7, Cursor::new(&framed), &mut decrypted)
+		.unwrap();
+
+	assert_eq!(read, plaintext.len() as u64);
+	assert_eq!(decrypted, plaintext);
+}
+
+#[test]
+fn empty_stream_round_trips() {
+	let framed = encrypt(&[]);
+
+	let mut decrypted = Vec::new();
+	Encryptor::new(KEY)
+		.decrypt_stream(7, Cursor::new(&framed), &mut decrypted)
+		.unwrap();
+
+	assert!(decrypted.is_empty());
+}
+
+#[test]
+fn truncated_stream_is_rejected() {
+	let framed = encrypt(&payload());
+
+	// Drop the closing frame and part of the last data frame
+	let truncated = &framed[..framed.len() - 40];
+	let result = Encryptor::new(KEY).decrypt_stream(7, Cursor::new(truncated), &mut Vec::new());
+
+	assert!(result.is_err());
+}
+
+#[test]
+fn stream_with_wrong_counter_or_key_is_rejected() {
+	let framed = encrypt(&payload());
+
+	assert!(Encryptor::new(KEY)
+		.decrypt_stream(8, Cursor::new(&framed), &mut Vec::new())
+		.is_err());
+	assert!(Encryptor::new([1; 32])
+		.decrypt_stream(7, Cursor::new(&framed), &mut Vec::new())
+		.is_err());
+}
@@ -0,0 +1,703 @@
+// Command handler tests that run MessageHandler directly against in-memory
+// sinks, without sockets or a running server.
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use irc_server::server::crypto::Encryptor;
+use irc_server::server::handler::{constant_time_eq, MessageHandler, ServerState};
+use irc_server::server::models::TokenClaims;
+use irc_server::server::{MemorySink, Session, User};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+fn new_server() -> Arc<Mutex<ServerState>> {
+	Arc::new(Mutex::new(ServerState::new("handler-test-secret")))
+}
+
+// Register a user whose output goes to a MemorySink, returning their handler and sink
+fn add_user(server: &Arc<Mutex<ServerState>>, name: &str) -> (MessageHandler, MemorySink) {
+	add_user_as(server, name, name)
+}
+
+// Like add_user, but with a user id that differs from the username
+fn add_user_as(server: &Arc<Mutex<ServerState>>, id: &str, name: &str) -> (MessageHandler, MemorySink) {
+	let sink = MemorySink::new();
+
+	server.lock().unwrap().add_user(User {
+		id: id.to_string(),
+		username: name.to_string(),
+		profile_pic: Vec::new(),
+		profile_pic_ref: None,
+		channels: HashSet::new(),
+		stream: Some(sink.shared()),
+		session: None,
+		devices: Vec::new(),
+		fan_out: None,
+		messages: VecDeque::new(),
+		away: None,
+		caps: HashSet::new(),
+		host: "127.0.0.1".to_string(),
+		silenced: false,
+	});
+
+	let handler = MessageHandler::new(id.to_string(), sink.shared(), server.clone(), name.to_string());
+	(handler, sink)
+}
+
+#[test]
+fn join_is_confirmed_and_announced_to_members() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	assert!(alice_out.take().contains(":alice JOIN #rust\r\n"));
+
+	bob.handle_message("JOIN #rust").unwrap();
+	assert!(bob_out.take().contains(":bob JOIN #rust\r\n"));
+	let alice_lines = alice_out.take();
+	assert!(alice_lines.contains(":bob JOIN #rust\r\n"));
+	assert!(alice_lines.contains(":bob PRIVMSG #rust :* bob has joined #rust\r\n"));
+}
+
+#[test]
+fn malformed_channel_names_are_refused() {
+	let server = new_server();
+	// Numerics are addressed to the nickname, not the user ID
+	let (mut alice, alice_out) = add_user_as(&server, "u-alice", "alice");
+
+	let cases = [
+		("JOIN rust", ":server 403 alice rust :No such channel\r\n"),
+		("JOIN #", ":server 403 alice # :No such channel\r\n"),
+		("JOIN #a,b", ":server 479 alice #a,b :Illegal channel name\r\n"),
+		("JOIN #bell\x07", ":server 479 alice #bell\x07 :Illegal channel name\r\n"),
+		("PART rust", ":server 403 alice rust :No such channel\r\n"),
+		("TOPIC #a,b :hello", ":server 479 alice #a,b :Illegal channel name\r\n"),
+		("MODE #a,b +s", ":server 479 alice #a,b :Illegal channel name\r\n"),
+		("WHO rust", ":server 403 alice rust :No such channel\r\n"),
+	];
+	for (command, reply) in cases {
+		alice.handle_message(command).unwrap();
+		assert_eq!(alice_out.take(), reply, "{}", command);
+	}
+
+	let long = format!("#{}", "a".repeat(60));
+	alice.handle_message(&format!("JOIN {}", long)).unwrap();
+	assert!(alice_out.take().starts_with(&format!(":server 479 alice {} :", long)));
+	assert!(server.lock().unwrap().channels.is_empty());
+}
+
+#[test]
+fn away_changes_reach_only_peers_with_away_notify() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	carol.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("CAP REQ :away-notify").unwrap();
+	alice_out.take();
+	bob_out.take();
+	carol_out.take();
+
+	alice.handle_message("AWAY :lunch").unwrap();
+	assert_eq!(bob_out.take(), ":alice AWAY :lunch\r\n");
+	assert_eq!(carol_out.take(), "");
+
+	alice.handle_message("AWAY").unwrap();
+	assert_eq!(bob_out.take(), ":alice AWAY\r\n");
+}
+
+#[test]
+fn negotiated_caps_tag_messages_with_time_and_msgid() {
+	let server = new_server();
+	let (mut alice, _alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+
+	bob.handle_message("CAP LS 302").unwrap();
+	let offered = bob_out.take();
+	assert!(offered.starts_with(":server CAP bob LS :"));
+	assert!(offered.contains("server-time") && offered.contains("message-tags"));
+	bob.handle_message("CAP REQ :server-time message-tags").unwrap();
+	assert_eq!(bob_out.take(), ":server CAP bob ACK :server-time message-tags\r\n");
+
+	for handler in [&mut alice, &mut bob, &mut carol] {
+		handler.handle_message("JOIN #rust").unwrap();
+	}
+	bob_out.take();
+	carol_out.take();
+
+	alice.handle_message("PRIVMSG #rust :hello").unwrap();
+	let tagged = bob_out.take();
+	let (tags, line) = tagged.split_once(' ').unwrap();
+	assert_eq!(line, ":alice PRIVMSG #rust :<alice> hello\r\n");
+	let tags: Vec<&str> = tags.trim_start_matches('@').split(';').collect();
+	assert_eq!(tags.len(), 2);
+	assert!(tags[0].starts_with("time=") && tags[0].ends_with('Z'));
+	assert!(tags[1].starts_with("msgid=") && tags[1].len() > "msgid=".len());
+
+	// Clients that never negotiated get the plain line
+	assert_eq!(carol_out.take(), ":alice PRIVMSG #rust :<alice> hello\r\n");
+}
+
+#[test]
+fn join_beyond_the_channel_limit_is_refused() {
+	let server = new_server();
+	server.lock().unwrap().max_channels_per_user = 3;
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, _bob_out) = add_user(&server, "bob");
+
+	// One existing channel and two new ones both count
+	bob.handle_message("JOIN #existing").unwrap();
+	for channel in ["#existing", "#new1", "#new2"] {
+		alice.handle_message(&format!("JOIN {}", channel)).unwrap();
+	}
+	alice_out.take();
+
+	alice.handle_message("JOIN #new3").unwrap();
+	assert_eq!(alice_out.take(), ":server 405 alice #new3 :You have joined too many channels\r\n");
+	assert!(!server.lock().unwrap().channels.contains_key("#new3"));
+
+	// Rejoining a channel already joined is not a new membership
+	alice.handle_message("JOIN #new1").unwrap();
+	assert!(!alice_out.take().contains(" 405 "));
+}
+
+#[test]
+fn banned_users_cannot_join_until_unbanned() {
+	let server = new_server();
+	let (mut admin, admin_out) = add_user(&server, "admin");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+	server.lock().unwrap().admins.insert("admin".to_string());
+	admin.handle_message("JOIN #rust").unwrap();
+	admin_out.take();
+
+	bob.handle_message("MODE #rust +b carol").unwrap();
+	assert_eq!(bob_out.take(), ":server 482 bob #rust :You're not channel operator\r\n");
+
+	admin.handle_message("MODE #rust +b bob").unwrap();
+	admin.handle_message("MODE #rust +b carol!*@127.*").unwrap();
+	assert_eq!(admin_out.take(), ":admin MODE #rust +b bob\r\n:admin MODE #rust +b carol!*@127.*\r\n");
+
+	bob.handle_message("JOIN #rust").unwrap();
+	assert_eq!(bob_out.take(), ":server 474 bob #rust :Cannot join channel (+b)\r\n");
+	carol.handle_message("JOIN #rust").unwrap();
+	assert_eq!(carol_out.take(), ":server 474 carol #rust :Cannot join channel (+b)\r\n");
+
+	admin.handle_message("MODE #rust b").unwrap();
+	assert_eq!(
+		admin_out.take(),
+		":server 367 admin #rust bob :Ban\r\n:server 367 admin #rust carol!*@127.* :Ban\r\n:server 368 admin #rust :End of channel ban list\r\n"
+	);
+
+	admin.handle_message("MODE #rust -b bob").unwrap();
+	admin_out.take();
+	bob.handle_message("JOIN #rust").unwrap();
+	assert!(bob_out.take().contains(":bob JOIN #rust\r\n"));
+	assert!(server.lock().unwrap().channels["#rust"].users.contains("bob"));
+}
+
+#[test]
+fn channel_privmsg_reaches_other_members_only() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (_carol, carol_out) = add_user(&server, "carol");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	alice_out.take();
+	bob_out.take();
+
+	alice.handle_message("PRIVMSG #rust :hello").unwrap();
+
+	assert_eq!(bob_out.take(), ":alice PRIVMSG #rust :<alice> hello\r\n");
+	assert_eq!(alice_out.take(), "");
+	assert_eq!(carol_out.take(), "");
+}
+
+#[test]
+fn line_breaks_in_a_message_cannot_smuggle_a_command() {
+	let server = new_server();
+	let (mut alice, _alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	bob_out.take();
+
+	alice.handle_message("PRIVMSG #rust :hi\r\nQUIT :bye").unwrap();
+	let received = bob_out.take();
+	assert_eq!(received.matches("\r\n").count(), 1);
+	assert!(received.ends_with(" PRIVMSG #rust :<alice> hi  QUIT :bye\r\n"));
+	assert!(server.lock().unwrap().users.contains_key("alice"));
+
+	alice.handle_message("PRIVMSG bob :hi\nQUIT").unwrap();
+	assert_eq!(bob_out.take(), "PRIVMSG alice :hi QUIT\r\n");
+}
+
+#[test]
+fn action_round_trips_and_is_stored_without_ctcp_framing() {
+	let server = new_server();
+	let (mut alice, _alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	bob_out.take();
+
+	alice.handle_message("PRIVMSG #rust :\x01ACTION waves\x01").unwrap();
+	assert!(bob_out.take().ends_with(":alice PRIVMSG #rust :* alice waves\r\n"));
+
+	// Private actions keep their CTCP framing so the recipient's client renders them
+	alice.handle_message("PRIVMSG bob :\x01ACTION nods\x01").unwrap();
+	assert_eq!(bob_out.take(), "PRIVMSG alice :\x01ACTION nods\x01\r\n");
+
+	// Other CTCP requests to a channel are ignored
+	alice.handle_message("PRIVMSG #rust :\x01VERSION\x01").unwrap();
+	assert_eq!(bob_out.take(), "");
+
+	let state = server.lock().unwrap();
+	let history: Vec<&str> = state.channels["#rust"].messages.iter().map(|m| m.content.as_str()).collect();
+	assert!(history.contains(&"* alice waves"));
+	assert!(history.iter().all(|content| !content.contains('\x01')));
+	assert_eq!(state.users["bob"].messages.back().unwrap().content, "* alice nods");
+}
+
+#[test]
+fn message_to_a_removed_channel_gets_403_and_is_not_stored() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	alice_out.take();
+
+	// The channel disappears while alice still lists it, as if it emptied with her message in flight
+	server.lock().unwrap().channels.remove("#rust");
+
+	alice.handle_message("PRIVMSG #rust :hello?").unwrap();
+
+	assert_eq!(alice_out.take(), ":server 403 alice #rust :No such channel\r\n");
+	let state = server.lock().unwrap();
+	assert!(!state.channels.contains_key("#rust"));
+	assert!(!state.users["alice"].channels.contains("#rust"));
+}
+
+#[test]
+fn message_to_an_unknown_nick_gets_401() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+
+	alice.handle_message("PRIVMSG nobody :hello?").unwrap();
+
+	assert_eq!(alice_out.take(), ":server 401 alice nobody :No such nick\r\n");
+	assert!(server.lock().unwrap().users["alice"].messages.is_empty());
+}
+
+#[test]
+fn large_channel_gets_one_identical_line_per_member() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	alice.handle_message("JOIN #big").unwrap();
+
+	// Seat the members directly rather than through JOIN, which would announce each one
+	let members: Vec<MemorySink> = (0..1000)
+		.map(|i| {
+			let name = format!("user{}", i);
+			let (_, sink) = add_user(&server, &name);
+			let mut state = server.lock().unwrap();
+			state.channels.get_mut("#big").unwrap().users.insert(name.clone());
+			state.users.get_mut(&name).unwrap().channels.insert("#big".to_string());
+			sink
+		})
+		.collect();
+	alice_out.take();
+
+	alice.handle_message("PRIVMSG #big :hello everyone").unwrap();
+
+	for sink in &members {
+		assert_eq!(sink.take(), ":alice PRIVMSG #big :<alice> hello everyone\r\n");
+	}
+	assert_eq!(alice_out.take(), "");
+}
+
+#[test]
+fn part_is_confirmed_and_announced_to_remaining_members() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	alice_out.take();
+	bob_out.take();
+
+	bob.handle_message("PART #rust").unwrap();
+
+	assert!(bob_out.take().contains(":bob PART #rust\r\n"));
+	let alice_lines = alice_out.take();
+	assert!(alice_lines.contains(":bob PART #rust\r\n"));
+	assert!(alice_lines.contains(":bob PRIVMSG #rust :* bob has left #rust\r\n"));
+	assert!(!server.lock().unwrap().channels["#rust"].users.contains("bob"));
+}
+
+#[test]
+fn search_returns_unexpired_matches_to_members_only() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, _bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+
+	alice.handle_message("JOIN #rust").unwrap();
+	bob.handle_message("JOIN #rust").unwrap();
+	alice.handle_message("PRIVMSG #rust :Borrow checker question").unwrap();
+	bob.handle_message("PRIVMSG #rust :lifetimes again").unwrap();
+	bob.handle_message("PRIVMSG #rust :the BORROW was fine").unwrap();
+	alice.handle_message("PRIVMSG #rust :an old borrow").unwrap();
+
+	// Age the last message past the TTL
+	server.lock().unwrap().channels.get_mut("#rust").unwrap().messages.back_mut().unwrap().timestamp =
+		Instant::now() - Duration::from_secs(7200);
+	alice_out.take();
+
+	alice.handle_message("SEARCH #rust :borrow").unwrap();
+	let lines: Vec<String> = alice_out.take().lines().map(str::to_string).collect();
+	assert_eq!(lines.len(), 3);
+	assert!(lines[0].starts_with(":server 920 alice #rust alice "));
+	assert!(lines[0].ends_with(" :Borrow checker question"));
+	assert!(lines[1].starts_with(":server 920 alice #rust bob "));
+	assert!(lines[1].ends_with(" :the BORROW was fine"));
+	assert_eq!(lines[2], ":server 921 alice #rust :End of search results");
+
+	carol.handle_message("SEARCH #rust :borrow").unwrap();
+	assert_eq!(carol_out.take(), ":server 442 carol #rust :You're not on that channel\r\n");
+}
+
+#[test]
+fn who_lists_channel_members_in_order() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, _bob_out) = add_user(&server, "bob");
+
+	bob.handle_message("JOIN #rust").unwrap();
+	alice.handle_message("JOIN #rust").unwrap();
+	alice_out.take();
+
+	alice.handle_message("WHO #rust").unwrap();
+
+	let lines: Vec<String> = alice_out.take().lines().map(str::to_string).collect();
+	assert_eq!(lines.len(), 3);
+	assert!(lines[0].starts_with(":server 352 alice #rust alice "));
+	assert!(lines[1].starts_with(":server 352 alice #rust bob "));
+	assert_eq!(lines[2], ":server 315 alice #rust :End of WHO list");
+}
+
+#[test]
+fn who_reports_idle_time_and_away_status() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (mut bob, _bob_out) = add_user(&server, "bob");
+	{
+		let mut session = Session::new("s1".to_string(), "bob".to_string(), [1; 32]);
+		session.last_activity = Instant::now() - Duration::from_secs(300);
+		server.lock().unwrap().users.get_mut("bob").unwrap().session = Some(session);
+	}
+	bob.handle_message("PONG :server").unwrap();
+
+	alice.handle_message("WHO *").unwrap();
+	let listing = alice_out.take();
+	let bob_line = listing.lines().find(|line| line.contains(" 352 alice * bob ")).unwrap();
+	let idle: u64 = bob_line
+		.split("(idle ")
+		.nth(1)
+		.and_then(|rest| rest.split('s').next())
+		.and_then(|secs| secs.parse().ok())
+		.unwrap();
+	assert!((300..310).contains(&idle), "{}", bob_line);
+	assert!(bob_line.contains(" bob H :0 bob "));
+
+	server.lock().unwrap().users.get_mut("bob").unwrap().away = Some("lunch".to_string());
+	alice.handle_message("WHO *").unwrap();
+	assert!(alice_out.take().contains(" bob G :0 bob "));
+}
+
+#[test]
+fn list_filters_channels_by_pattern() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	for channel in ["#rust", "#rust-beginners", "#go"] {
+		alice.handle_message(&format!("JOIN {}", channel)).unwrap();
+	}
+	alice_out.take();
+
+	alice.handle_message("LIST #rust*").unwrap();
+	assert_eq!(
+		alice_out.take(),
+		":server 322 alice #rust 1 :\r\n:server 322 alice #rust-beginners 1 :\r\n:server 323 alice :End of LIST\r\n"
+	);
+
+	alice.handle_message("LIST #python*").unwrap();
+	assert_eq!(alice_out.take(), ":server 323 alice :End of LIST\r\n");
+
+	alice.handle_message("LIST").unwrap();
+	let listing = alice_out.take();
+	assert_eq!(listing.matches(" 322 ").count(), 3);
+	assert!(listing.ends_with(":server 323 alice :End of LIST\r\n"));
+}
+
+#[test]
+fn list_cache_is_rebuilt_after_a_channel_is_created() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+
+	alice.handle_message("JOIN #one").unwrap();
+	alice_out.take();
+	alice.handle_message("LIST").unwrap();
+	assert!(alice_out.take().contains(":server 322 alice #one 1 :\r\n"));
+
+	// Repeated LISTs reuse the same snapshot
+	let cached = server.lock().unwrap().channel_listing();
+	alice.handle_message("LIST").unwrap();
+	assert!(Arc::ptr_eq(&cached, &server.lock().unwrap().channel_listing()));
+	alice_out.take();
+
+	alice.handle_message("JOIN #two").unwrap();
+	assert!(server.lock().unwrap().channel_list.is_none());
+	alice_out.take();
+
+	alice.handle_message("LIST").unwrap();
+	let listing = alice_out.take();
+	assert!(listing.contains(":server 322 alice #one 1 :\r\n"));
+	assert!(listing.contains(":server 322 alice #two 1 :\r\n"));
+}
+
+#[test]
+fn drain_parts_every_member_and_removes_the_channel() {
+	let server = new_server();
+	let (mut admin, admin_out) = add_user(&server, "admin");
+	let (mut bob, bob_out) = add_user_as(&server, "u-bob", "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+	server.lock().unwrap().admins.insert("admin".to_string());
+
+	bob.handle_message("JOIN #old").unwrap();
+	carol.handle_message("JOIN #old").unwrap();
+	bob.handle_message("JOIN #keep").unwrap();
+	bob_out.take();
+	carol_out.take();
+
+	// Only server admins and the channel's operators may drain
+	carol.handle_message("DRAIN #old :mine now").unwrap();
+	assert!(carol_out.take().contains(":server 482 carol #old :You're not channel operator"));
+
+	admin.handle_message("DRAIN #old +cooldown=60 :Moving to #new").unwrap();
+	assert!(admin_out.take().contains("Channel #old drained (2 members parted)"));
+
+	let bob_lines = bob_out.take();
+	assert!(bob_lines.contains(":server NOTICE bob :#old is being closed: Moving to #new\r\n"));
+	assert!(bob_lines.contains(":bob PART #old :Moving to #new\r\n"));
+	assert!(!bob_lines.contains("u-bob"));
+	let carol_lines = carol_out.take();
+	assert!(carol_lines.contains(":bob PART #old :Moving to #new\r\n"));
+	assert!(carol_lines.contains(":carol PART #old :Moving to #new\r\n"));
+
+	{
+		let state = server.lock().unwrap();
+		assert!(!state.channels.contains_key("#old"));
+		assert!(state.channels.contains_key("#keep"));
+		assert!(state.users.values().all(|user| !user.channels.contains("#old")));
+	}
+
+	// The cooldown keeps the channel from being recreated straight away
+	carol.handle_message("JOIN #old").unwrap();
+	assert!(carol_out.take().contains(":server 437 carol #old :Channel is temporarily unavailable"));
+}
+
+#[test]
+fn channel_operator_may_drain_their_own_channel() {
+	let server = new_server();
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+
+	// Creating a channel by joining it makes bob its operator
+	bob.handle_message("JOIN #mine").unwrap();
+	carol.handle_message("JOIN #mine").unwrap();
+	carol.handle_message("JOIN #theirs").unwrap();
+	bob_out.take();
+	carol_out.take();
+
+	bob.handle_message("DRAIN #theirs").unwrap();
+	assert!(bob_out.take().contains(" 482 bob #theirs "));
+
+	// A cooldown too long to represent is refused before anyone is parted
+	bob.handle_message("DRAIN #mine +cooldown=18446744073709551615").unwrap();
+	assert!(bob_out.take().contains(":server FAIL DRAIN INVALID_COOLDOWN "));
+	assert!(server.lock().unwrap().channels.contains_key("#mine"));
+
+	bob.handle_message("DRAIN #mine :done").unwrap();
+	assert!(bob_out.take().contains("Channel #mine drained (2 members parted)"));
+	assert!(carol_out.take().contains(":carol PART #mine :done\r\n"));
+	assert!(!server.lock().unwrap().channels.contains_key("#mine"));
+}
+
+#[test]
+fn kill_is_reserved_for_operators() {
+	let server = new_server();
+	let (mut admin, admin_out) = add_user(&server, "admin");
+	let (mut bob, bob_out) = add_user(&server, "bob");
+	let (mut carol, carol_out) = add_user(&server, "carol");
+	server.lock().unwrap().admins.insert("admin".to_string());
+	bob.handle_message("JOIN #rust").unwrap();
+	carol.handle_message("JOIN #rust").unwrap();
+	bob_out.take();
+	carol_out.take();
+
+	bob.handle_message("KILL carol :annoying").unwrap();
+	assert_eq!(bob_out.take(), ":server 481 bob :Permission Denied- You're not an IRC operator\r\n");
+	assert!(server.lock().unwrap().users.contains_key("carol"));
+
+	admin.handle_message("KILL carol :spamming").unwrap();
+	assert!(admin_out.take().is_empty());
+	assert_eq!(carol_out.take(), "ERROR :Killed by admin (spamming)\r\n");
+	assert!(bob_out.take().contains(":carol QUIT :Killed by admin (spamming)\r\n"));
+	assert!(!server.lock().unwrap().users.contains_key("carol"));
+}
+
+#[test]
+fn each_device_keeps_its_own_session() {
+	let server = new_server();
+	let (_alice, _alice_out) = add_user(&server, "alice");
+	let laptop_out = MemorySink::new();
+	let laptop_sink = laptop_out.shared();
+	let idle_since = Instant::now() - Duration::from_secs(600);
+	let phone_sink = {
+		let mut state = server.lock().unwrap();
+		let user = state.users.get_mut("alice").unwrap();
+		let phone_sink = user.stream.clone().unwrap();
+
+		let mut phone_session = Session::new("phone".to_string(), "alice".to_string(), [1; 32]);
+		phone_session.last_activity = idle_since;
+		user.session = Some(phone_session);
+		let mut laptop_session = Session::new("laptop".to_string(), "alice".to_string(), [2; 32]);
+		laptop_session.last_activity = idle_since;
+		user.attach_device(laptop_sink.clone(), laptop_session);
+		phone_sink
+	};
+	let mut phone = MessageHandler::new("alice".to_string(), phone_sink, server.clone(), "alice".to_string());
+	let mut laptop = MessageHandler::new("alice".to_string(), laptop_sink, server.clone(), "alice".to_string());
+
+	// Activity on the laptop keeps only the laptop's session alive
+	laptop.handle_message("LIST").unwrap();
+	{
+		let state = server.lock().unwrap();
+		let user = &state.users["alice"];
+		assert_eq!(user.session.as_ref().unwrap().last_activity, idle_since);
+		assert!(user.devices[0].session.last_activity > idle_since);
+	}
+
+	// Once the first connection quits, the laptop's session takes its place
+	phone.handle_message("QUIT :switching").unwrap();
+	let state = server.lock().unwrap();
+	let user = &state.users["alice"];
+	assert_eq!(user.session.as_ref().unwrap().id, "laptop");
+	assert!(user.devices.is_empty());
+}
+
+#[test]
+fn rekey_rotates_the_session_key_and_discards_history() {
+	let server = new_server();
+	let (mut alice, alice_out) = add_user(&server, "alice");
+	let (_bob, _bob_out) = add_user(&server, "bob");
+	let old_key = [7u8; 32];
+	{
+		let mut state = server.lock().unwrap();
+		let mut session = Session::new("s1".to_string(), "alice".to_string(), old_key);
+		session.increment_nonce();
+		state.users.get_mut("alice").unwrap().session = Some(session);
+	}
+
+	alice.handle_message("PRIVMSG bob :before the rekey").unwrap();
+	alice_out.take();
+
+	alice.handle_message("REKEY").unwrap();
+	assert_eq!(alice_out.take(), ":server NOTICE alice :REKEYED 1\r\n");
+
+	let mut state = server.lock().unwrap();
+	let user = state.users.get_mut("alice").unwrap();
+	assert!(user.messages.is_empty());
+
+	let session = user.session.as_mut().unwrap();
+	assert_ne!(session.encryption_key, old_key);
+	assert_eq!(session.nonce_counter, 0);
+
+	// The new key encrypts and decrypts from a fresh counter
+	let encryptor = Encryptor::new(session.encryption_key);
+	let counter = session.increment_nonce();
+	let ciphertext = encryptor.encrypt(counter, b"after the rekey").unwrap();
+	assert_eq!(encryptor.decrypt(counter, &ciphertext).unwrap(), b"after the rekey");
+	assert!(Encryptor::new(old_key).decrypt(counter, &ciphertext).is_err());
+}
+
+// Claims for a token that would be valid for the next hour under any accepted algorithm
+fn hour_claims() -> TokenClaims {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as usize;
+	TokenClaims {
+		sub: "u1".to_string(),
+		username: "alice".to_string(),
+		profile_pic: String::new(),
+		profile_pic_ref: None,
+		exp: now + 3600,
+		iat: now,
+		nbf: None,
+		jti: None,
+		device_id: None,
+		allowed_ips: None,
+	}
+}
+
+fn sign(algorithm: Algorithm, claims: &TokenClaims) -> String {
+	encode(&Header::new(algorithm), claims, &EncodingKey::from_secret(b"handler-test-secret")).unwrap()
+}
+
+#[test]
+fn tokens_signed_with_other_algorithms_are_rejected() {
+	let server = new_server();
+	let claims = hour_claims();
+
+	assert_eq!(server.lock().unwrap().decode_token(&sign(Algorithm::HS256, &claims)).unwrap().sub, "u1");
+
+	// Same secret, well-formed, but an algorithm the server wasn't told to accept
+	let hs384 = sign(Algorithm::HS384, &claims);
+	let err = server.lock().unwrap().decode_token(&hs384).unwrap_err();
+	assert!(err.contains("HS384 is not accepted"), "{}", err);
+
+	// An unsigned token naming alg "none"
+	let encode_part = |value: serde_json::Value| URL_SAFE_NO_PAD.encode(value.to_string());
+	let unsigned = format!(
+		"{}.{}.",
+		encode_part(serde_json::json!({"alg": "none", "typ": "JWT"})),
+		encode_part(serde_json::to_value(&claims).unwrap()),
+	);
+	assert!(server.lock().unwrap().decode_token(&unsigned).is_err());
+
+	// Relabelling a signed token's header as RS256 doesn't switch verification to RSA
+	let (_, signed_rest) = hs384.split_once('.').unwrap();
+	let rs256 = format!("{}.{}", encode_part(serde_json::json!({"alg": "RS256", "typ": "JWT"})), signed_rest);
+	assert!(server.lock().unwrap().decode_token(&rs256).is_err());
+
+	// Widening the accepted set lets HS384 through
+	server.lock().unwrap().jwt_algorithms = vec![Algorithm::HS256, Algorithm::HS384];
+	assert_eq!(server.lock().unwrap().decode_token(&hs384).unwrap().username, "alice");
+}
+
+#[test]
+fn constant_time_eq_compares_whole_secrets() {
+	assert!(constant_time_eq(b"resume-token", b"resume-token"));
+	assert!(!constant_time_eq(b"resume-token", b"resume-tokeN"));
+	assert!(!constant_time_eq(b"Resume-token", b"resume-token"));
+	assert!(!constant_time_eq(b"resume-token", b"resume-token-longer"));
+	assert!(constant_time_eq(b"", b""));
+}
@@ -0,0 +1,117 @@
+// JSON line protocol tests: translation in both directions, and a JSON client
+// talking to an IRC client through the same server.
+mod common;
+
+use common::{TestClient, TestServer};
+use irc_server::server::json_protocol::{command_from_json, event_to_json};
+use serde_json::{json, Value};
+
+#[test]
+fn core_commands_translate_to_irc_lines() {
+	let cases = [
+		(json!({"cmd": "AUTH", "token": "abc.def.ghi"}), "abc.def.ghi"),
+		(json!({"cmd": "RESUME", "token": "r3sume"}), "RESUME r3sume"),
+		(json!({"cmd": "JOIN", "channel": "#rust"}), "JOIN #rust"),
+		(json!({"cmd": "part", "channel": "#rust"}), "PART #rust"),
+		(json!({"cmd": "PRIVMSG", "target": "#rust", "body": "hi there"}), "PRIVMSG #rust :hi there"),
+		(
+			json!({"cmd": "PRIVMSG", "target": "bob", "body": "soon gone", "ttl": 30}),
+			"PRIVMSG bob +ttl=30 :soon gone",
+		),
+		(json!({"cmd": "TOPIC", "channel": "#rust", "topic": "Ownership"}), "TOPIC #rust :Ownership"),
+		(json!({"cmd": "TOPIC", "channel": "#rust"}), "TOPIC #rust"),
+		(json!({"cmd": "AWAY", "message": "lunch"}), "AWAY :lunch"),
+		(json!({"cmd": "AWAY"}), "AWAY"),
+		(json!({"cmd": "QUIT", "reason": "bye"}), "QUIT :bye"),
+		(json!({"cmd": "WHOIS", "params": ["bob"]}), "WHOIS bob"),
+		(json!({"cmd": "PONG", "text": "n0nce"}), "PONG :n0nce"),
+	];
+
+	for (command, expected) in cases {
+		assert_eq!(command_from_json(&command.to_string()).unwrap(), expected);
+	}
+}
+
+#[test]
+fn malformed_commands_are_rejected() {
+	let bad = [
+		"not json",
+		"[1, 2]",
+		r##"{"channel": "#rust"}"##,
+		r##"{"cmd": "JOIN"}"##,
+		r##"{"cmd": "JOIN", "channel": "#a b"}"##,
+		r##"{"cmd": "PRIVMSG", "target": "bob", "body": "line\r\nQUIT"}"##,
+		r##"{"cmd": "PRIVMSG", "target": "bob", "body": "hi", "ttl": "soon"}"##,
+		r##"{"cmd": "WHOIS", "params": [":bob"]}"##,
+		r##"{"cmd": "AUTH", "token": "two words"}"##,
+	];
+
+	for line in bad {
+		assert!(command_from_json(line).is_err(), "accepted {:?}", line);
+	}
+}
+
+#[test]
+fn server_lines_translate_to_events() {
+	assert_eq!(
+		event_to_json("@time=2024-01-01T00:00:00.000Z;msgid=abc :alice PRIVMSG #rust :<alice> hi\r\n"),
+		json!({
+			"source": "alice",
+			"command": "PRIVMSG",
+			"params": ["#rust"],
+			"text": "<alice> hi",
+			"tags": {"time": "2024-01-01T00:00:00.000Z", "msgid": "abc"},
+		})
+	);
+	assert_eq!(
+		event_to_json(":server 322 u1 #rust 2 :Topic here\r\n"),
+		json!({"source": "server", "command": "322", "params": ["u1", "#rust", "2"], "text": "Topic here", "tags": {}})
+	);
+	assert_eq!(
+		event_to_json("PING :n0nce\r\n"),
+		json!({"source": null, "command": "PING", "params": [], "text": "n0nce", "tags": {}})
+	);
+}
+
+// Read JSON events until one has `command`, failing the test if none arrives in time
+fn expect_event(client: &mut TestClient, command: &str) -> Value {
+	loop {
+		let line = client.expect("\"command\"");
+		let event: Value = serde_json::from_str(&line).expect("server sent invalid JSON");
+		if event["command"] == command {
+			return event;
+		}
+	}
+}
+
+#[test]
+fn json_client_talks_to_irc_client() {
+	let server = TestServer::start();
+	let json_addr = server.facade.start_json("127.0.0.1:0").unwrap();
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #rust");
+	bob.expect(" JOIN #rust");
+
+	let mut alice = TestClient::connect(json_addr);
+	alice.send(&json!({"cmd": "AUTH", "token": TestServer::token("u1", "alice")}).to_string());
+	expect_event(&mut alice, "001");
+
+	alice.send(r##"{"cmd": "JOIN", "channel": "#rust"}"##);
+	let join = expect_event(&mut alice, "JOIN");
+	assert_eq!(join["params"], json!(["#rust"]));
+
+	alice.send(r##"{"cmd": "PRIVMSG", "target": "#rust", "body": "hello from json"}"##);
+	bob.expect(":alice PRIVMSG #rust :<alice> hello from json");
+
+	bob.send("PRIVMSG #rust :hello back");
+	let message = expect_event(&mut alice, "PRIVMSG");
+	assert_eq!(message["source"], "bob");
+	assert_eq!(message["text"], "<bob> hello back");
+
+	// Malformed commands are answered and skipped without dropping the connection
+	alice.send(r##"{"cmd": "JOIN"}"##);
+	let fail = expect_event(&mut alice, "FAIL");
+	assert_eq!(fail["params"], json!(["JSON", "INVALID_COMMAND"]));
+	alice.send(r##"{"cmd": "WHOIS", "params": ["bob"]}"##);
+	expect_event(&mut alice, "318");
+}
@@ -0,0 +1,1956 @@
+mod common;
+
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use common::{TestClient, TestServer, JWT_SECRET, READ_TIMEOUT};
+use irc_server::client::{
+	ConnectionState, FileEvent, IRCClientBuilder, SecurityNoticeKind, ServerMessage, SessionStatus,
+};
+use irc_server::server::transport::{QueuedSink, SEND_QUEUE_CAPACITY};
+use irc_server::server::{ChannelCreationPolicy, ClientSink};
+use irc_server::utils::ManualClock;
+use irc_server::{IRCClient, IrcError, TokenGenerator};
+
+#[test]
+fn authenticated_client_is_welcomed() {
+	let server = TestServer::start();
+	let mut client = server.connect_raw();
+
+	client.send(&TestServer::token("u1", "alice"));
+
+	let welcome = client.expect(" 001 ");
+	assert!(welcome.contains("Welcome to the Secure IRC Server, alice"));
+	client.expect("NOTICE");
+}
+
+#[test]
+fn welcome_and_security_notice_use_custom_templates() {
+	let server = TestServer::start();
+	server.facade.set_welcome_template("Hi {username}, welcome to Acme Chat").unwrap();
+	server
+		.facade
+		.set_security_notice_template("Messages vanish after {ttl_minutes} min")
+		.unwrap();
+	assert!(server.facade.set_welcome_template("two\r\nlines").is_err());
+
+	let mut client = server.connect_raw();
+	client.send(&TestServer::token("u1", "alice"));
+
+	client.expect(":server 001 alice :Hi alice, welcome to Acme Chat");
+	client.expect(":server NOTICE alice :Messages vanish after 60 min");
+}
+
+#[test]
+fn security_notice_shows_sub_minute_ttl_in_seconds() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut admin = server.connect("u1", "admin");
+	admin.send("CONFIG SET message_ttl 30");
+	admin.expect("message_ttl");
+
+	let mut client = server.connect_raw();
+	client.send(&TestServer::token("u2", "bob"));
+
+	client.expect(":server NOTICE bob :SECURITY: All messages will be deleted after 30s");
+}
+
+#[test]
+fn invalid_token_is_rejected() {
+	let server = TestServer::start();
+	let mut client = server.connect_raw();
+
+	client.send("not-a-token");
+
+	client.expect("ERROR :Authentication failed");
+}
+
+#[test]
+fn join_is_confirmed_and_announced() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(":alice JOIN #test");
+
+	bob.send("JOIN #test");
+	bob.expect(":bob JOIN #test");
+	alice.expect("* bob has joined #test");
+}
+
+#[test]
+fn channel_message_reaches_other_members_only() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	alice.send("PRIVMSG #test :hello everyone");
+
+	let line = bob.expect("hello everyone");
+	assert_eq!(line, ":alice PRIVMSG #test :<alice> hello everyone\r\n");
+	alice.expect_none("hello everyone", Duration::from_millis(300));
+}
+
+#[test]
+fn channel_message_errors_use_standard_numerics() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+
+	bob.send("PRIVMSG #test :let me in");
+	assert_eq!(bob.expect(" 404 "), ":server 404 bob #test :Cannot send to channel\r\n");
+	alice.expect_none("let me in", Duration::from_millis(300));
+
+	bob.send("PRIVMSG #nowhere :hello?");
+	assert_eq!(bob.expect(" 403 "), ":server 403 bob #nowhere :No such channel\r\n");
+
+	bob.send("PRIVMSG #test");
+	assert_eq!(bob.expect(" 461 "), ":server 461 bob PRIVMSG :Not enough parameters\r\n");
+
+	bob.send("FROBNICATE now");
+	assert_eq!(bob.expect(" 421 "), ":server 421 bob FROBNICATE :Unknown command\r\n");
+}
+
+#[test]
+fn private_message_is_delivered() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("PRIVMSG bob :hi bob");
+
+	let line = bob.expect("hi bob");
+	assert_eq!(line, "PRIVMSG alice :hi bob\r\n");
+	alice.expect_none("hi bob", Duration::from_millis(300));
+}
+
+#[test]
+fn part_is_announced_to_remaining_members() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	bob.send("PART #test");
+	alice.expect("* bob has left #test");
+
+	// Bob no longer receives channel traffic
+	alice.send("PRIVMSG #test :after part");
+	bob.expect_none("after part", Duration::from_millis(300));
+}
+
+#[test]
+fn partall_leaves_every_channel() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	for channel in ["#one", "#two"] {
+		alice.send(&format!("JOIN {}", channel));
+		alice.expect(&format!(" JOIN {}", channel));
+	}
+	bob.send("JOIN #one");
+	bob.expect(" JOIN #one");
+
+	alice.send("PARTALL");
+	alice.expect(":alice PART #one");
+	alice.expect(":alice PART #two");
+	bob.expect("* alice has left #one");
+
+	// #two was left empty and removed
+	alice.send("LIST");
+	alice.expect_none(" 322 alice #two ", Duration::from_millis(300));
+}
+
+#[test]
+fn duplicate_nickname_is_refused() {
+	let server = TestServer::start();
+	let _alice = server.connect("u1", "alice");
+
+	let mut imposter = TestClient::connect(server.addr);
+	imposter.send(&TestServer::token("u2", "alice"));
+
+	imposter.expect("ERROR :Nickname in use");
+}
+
+#[test]
+fn connections_beyond_the_per_ip_limit_are_refused() {
+	let server = TestServer::start();
+	server.facade.set_max_connections_per_ip(1).unwrap();
+
+	let _alice = server.connect("u1", "alice");
+
+	let mut second = server.connect_raw();
+	second.expect("ERROR :Too many connections from your IP");
+}
+
+#[test]
+fn repeated_auth_failures_lock_out_the_ip() {
+	let server = TestServer::start();
+	server.facade.set_auth_failure_limit(3, 60).unwrap();
+
+	for _ in 0..3 {
+		let mut client = server.connect_raw();
+		client.send("forged-token");
+		client.expect("ERROR :Authentication failed");
+	}
+
+	// Even a valid token is refused while the IP is locked out
+	let mut locked_out = server.connect_raw();
+	locked_out.send(&TestServer::token("u1", "alice"));
+	locked_out.expect("ERROR :Too many failed attempts");
+}
+
+#[test]
+fn announcement_reaches_every_user_and_requires_admin() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	bob.send("ANNOUNCE :not allowed");
+	bob.expect(":server 481 bob :Permission Denied");
+
+	alice.send("WALLOPS :Maintenance at 22:00");
+	alice.expect(":server NOTICE alice :ANNOUNCEMENT: Maintenance at 22:00");
+	bob.expect(":server NOTICE bob :ANNOUNCEMENT: Maintenance at 22:00");
+
+	server.facade.announce("Restarting now").unwrap();
+	bob.expect(":server NOTICE bob :ANNOUNCEMENT: Restarting now");
+}
+
+#[test]
+fn typing_is_relayed_and_rate_limited() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	alice.send("TYPING #test");
+	assert_eq!(bob.expect("TYPING"), ":alice TYPING #test\r\n");
+
+	// A second notification right away is dropped
+	alice.send("TYPING #test");
+	bob.expect_none("TYPING", Duration::from_millis(300));
+}
+
+#[test]
+fn quit_reason_is_broadcast_to_shared_channels() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	alice.send("QUIT :see you later");
+
+	assert_eq!(bob.expect(" QUIT "), ":alice QUIT :see you later\r\n");
+}
+
+#[test]
+fn quit_is_acknowledged_before_the_connection_closes() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+
+	alice.send("QUIT :done");
+
+	assert_eq!(alice.expect("ERROR"), "ERROR :Closing Link: alice (done)\r\n");
+	alice.expect_closed();
+
+	// Commands are case-insensitive, and so is closing after QUIT
+	let mut bob = server.connect("u2", "bob");
+	bob.send("quit :later");
+	assert_eq!(bob.expect("ERROR"), "ERROR :Closing Link: bob (later)\r\n");
+	bob.expect_closed();
+}
+
+#[test]
+fn signing_in_again_right_after_quit_keeps_the_new_connection() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+
+	// The old connection's thread may still be finishing when the new one registers
+	for _ in 0..10 {
+		let mut alice = server.connect("u1", "alice");
+		alice.send("QUIT");
+		alice.expect("ERROR");
+		let _alice = server.connect("u1", "alice");
+		thread::sleep(Duration::from_millis(20));
+
+		bob.send("WHOIS alice");
+		bob.expect(":server 311 bob alice ");
+	}
+}
+
+#[test]
+fn token_split_across_writes_is_assembled_before_decoding() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let (head, tail) = token.split_at(token.len() / 2);
+
+	let mut client = server.connect_raw();
+	client.send_bytes(head.as_bytes());
+	thread::sleep(Duration::from_millis(300));
+	client.send_bytes(format!("{}\r\n", tail).as_bytes());
+
+	client.expect(" 001 ");
+}
+
+#[test]
+fn health_probe_is_answered_without_authenticating() {
+	let server = TestServer::start();
+
+	let mut probe = server.connect_raw();
+	probe.send("PING :lb-check");
+	assert_eq!(probe.expect("PONG"), ":server PONG server :lb-check\r\n");
+	assert_eq!(probe.read_line_within(Duration::from_secs(2)), None);
+
+	let mut http = server.connect_raw();
+	http.send("GET /health HTTP/1.1");
+	assert_eq!(http.expect("HTTP/"), "HTTP/1.0 200 OK\r\n");
+	http.expect("OK");
+
+	assert_eq!(server.facade.stats().users, 0);
+}
+
+#[test]
+fn same_user_on_two_devices_receives_on_both() {
+	let server = TestServer::start();
+	let mut phone = server.connect("u1", "alice");
+	phone.send("JOIN #dev");
+	phone.expect(" JOIN #dev");
+
+	let mut laptop = server.connect("u1", "alice");
+	laptop.expect(":alice JOIN #dev");
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #dev");
+	bob.expect(" JOIN #dev");
+
+	bob.send("PRIVMSG alice :hello both");
+	phone.expect("PRIVMSG bob :hello both");
+	laptop.expect("PRIVMSG bob :hello both");
+	assert_eq!(server.facade.users().len(), 2);
+
+	// Leaving from one device keeps the user on the other
+	phone.send("QUIT :switching");
+	phone.expect("ERROR :Closing Link: alice (switching)");
+	bob.send("PRIVMSG #dev :still there?");
+	laptop.expect(":bob PRIVMSG #dev :<bob> still there?");
+	bob.expect_none(" QUIT ", Duration::from_millis(300));
+	assert_eq!(server.facade.users().len(), 2);
+}
+
+#[test]
+fn each_device_must_answer_its_own_ping() {
+	let server = TestServer::start();
+	let clock = Arc::new(ManualClock::new());
+	server.facade.set_clock(clock.clone());
+	server.facade.set_ping_timeout(60).unwrap();
+	let mut phone = server.connect("u1", "alice");
+	let mut laptop = server.connect("u1", "alice");
+
+	server.facade.run_cleanup();
+	phone.expect("PING :");
+	let ping = laptop.expect("PING :");
+	let nonce = ping.trim_end().trim_start_matches("PING :").to_string();
+
+	// Only the laptop answers; the reply to a later command shows the PONG was read
+	laptop.send(&format!("PONG :{}", nonce));
+	laptop.send("LIST");
+	laptop.expect(" 323 ");
+
+	clock.advance(Duration::from_secs(61));
+	server.facade.run_cleanup();
+	while phone.read_line_within(Duration::from_millis(500)).is_some() {}
+	phone.expect_closed();
+
+	laptop.send("LIST");
+	laptop.expect(" 323 ");
+	assert_eq!(server.facade.users().len(), 1);
+}
+
+#[test]
+fn dropped_connection_is_announced_as_quit() {
+	let server = TestServer::start();
+	server.facade.set_resume_grace(0).unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+	assert_eq!(alice.expect(":bob JOIN"), ":bob JOIN #test\r\n");
+
+	drop(alice);
+
+	assert_eq!(bob.expect(" QUIT "), ":alice QUIT :Connection closed\r\n");
+}
+
+#[test]
+fn join_creates_missing_channels_by_default() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+
+	alice.send("JOIN #fresh");
+
+	alice.expect(":alice JOIN #fresh");
+}
+
+#[test]
+fn pre_registration_policy_requires_admin_create() {
+	let server = TestServer::start();
+	server
+		.facade
+		.set_channel_creation_policy(ChannelCreationPolicy::RequirePreRegistration)
+		.unwrap();
+	server.facade.add_admin("u1").unwrap();
+	let mut admin = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	bob.send("JOIN #locked");
+	bob.expect(" 403 bob #locked :No such channel");
+
+	bob.send("CREATE #locked");
+	bob.expect(" 481 ");
+
+	admin.send("CREATE #locked");
+	admin.expect("Channel #locked created");
+
+	bob.send("JOIN #locked");
+	bob.expect(":bob JOIN #locked");
+}
+
+#[test]
+fn over_long_message_is_truncated_on_a_character_boundary() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	// 300 two-byte characters, well past the 512-byte line limit
+	let text = "é".repeat(300);
+	alice.send(&format!("PRIVMSG bob :{}", text));
+
+	alice.expect("NOTICE alice :Message truncated to");
+	let line = bob.expect("PRIVMSG alice :");
+	let body = line
+		.trim_end()
+		.strip_prefix("PRIVMSG alice :")
+		.unwrap();
+
+	// "PRIVMSG bob :" plus CRLF leaves 497 bytes, so 248 whole characters fit
+	assert_eq!(body, "é".repeat(248));
+	assert!(!body.contains('\u{FFFD}'));
+}
+
+#[test]
+fn rs256_token_is_verified_with_the_public_key() {
+	let server = TestServer::start();
+	server
+		.facade
+		.set_rsa_public_key(include_str!("fixtures/rsa_public.pem"))
+		.unwrap();
+	let token = TokenGenerator::new_rsa(include_str!("fixtures/rsa_private.pem"))
+		.unwrap()
+		.generate_token("u1", "alice", None, 1, None)
+		.unwrap();
+	let header: serde_json::Value =
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(token.split('.').next().unwrap()).unwrap()).unwrap();
+	assert_eq!(header["alg"], "RS256");
+
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect(" 001 ");
+
+	// The shared secret no longer signs valid tokens
+	let mut bob = server.connect_raw();
+	bob.send(&TestServer::token("u2", "bob"));
+	bob.expect("ERROR :Authentication failed");
+}
+
+#[test]
+fn token_without_an_avatar_authenticates() {
+	let server = TestServer::start();
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token("u1", "alice", None, 1, None)
+		.unwrap();
+	let payload = token.split('.').nth(1).unwrap();
+	let claims: serde_json::Value =
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+	assert_eq!(claims["profile_pic"], "");
+
+	let mut alice = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	alice.connect().unwrap();
+	assert!(alice.request_profile("alice").unwrap().is_empty());
+}
+
+#[test]
+fn client_reassembles_chunked_profile_picture() {
+	let server = TestServer::start();
+
+	// Large enough to need several 910 chunks
+	let picture: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token("u1", "alice", Some(&picture), 1, None)
+		.unwrap();
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect(" 001 ");
+
+	let bob_token = TestServer::token("u2", "bob");
+	let mut bob = IRCClient::new("127.0.0.1", server.addr.port(), &bob_token);
+	bob.connect().unwrap();
+
+	assert_eq!(bob.request_profile("alice").unwrap(), picture);
+	assert!(matches!(bob.request_profile("nobody"), Err(IrcError::NickNotFound(_))));
+}
+
+#[test]
+fn token_with_a_large_avatar_is_read_whole() {
+	let server = TestServer::start();
+
+	// Several times the old fixed 4096-byte token buffer
+	let picture: Vec<u8> = (0..20_000u32).map(|i| (i % 253) as u8).collect();
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token("u1", "alice", Some(&picture), 1, None)
+		.unwrap();
+	assert!(token.len() > 4 * 4096);
+
+	let mut alice = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	alice.connect().unwrap();
+	assert_eq!(alice.request_profile("alice").unwrap(), picture);
+}
+
+#[test]
+fn oversized_profile_picture_is_rejected() {
+	let server = TestServer::start();
+	server.facade.set_max_avatar_size(512).unwrap();
+
+	let picture = vec![7u8; 1000];
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token("u1", "alice", Some(&picture), 1, None)
+		.unwrap();
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect("ERROR :Profile picture too large");
+
+	// A picture within the limit is still accepted
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token("u2", "bob", Some(&picture[..512]), 1, None)
+		.unwrap();
+	let mut bob = server.connect_raw();
+	bob.send(&token);
+	bob.expect(" 001 ");
+}
+
+#[test]
+fn referenced_profile_picture_is_served_from_avatar_store() {
+	let server = TestServer::start();
+	let picture: Vec<u8> = (0..1000u32).map(|i| (i % 241) as u8).collect();
+	server.facade.store_avatar("avatars/alice", picture.clone()).unwrap();
+	server
+		.facade
+		.set_avatar_fetcher(|reference| (reference == "avatars/carol").then(|| vec![3u8; 10]));
+
+	let generator = TokenGenerator::new(JWT_SECRET);
+	let valid_for = Duration::from_secs(3600);
+	let mut clients = Vec::new();
+	for (id, name, reference) in [
+		("u1", "alice", "avatars/alice"),
+		("u3", "carol", "avatars/carol"),
+		("u4", "dave", "avatars/missing"),
+	] {
+		let token = generator
+			.generate_token_with_avatar_ref(id, name, reference, valid_for, None)
+			.unwrap();
+		let mut client = server.connect_raw();
+		client.send(&token);
+		client.expect(" 001 ");
+		clients.push(client);
+	}
+
+	let bob_token = TestServer::token("u2", "bob");
+	let mut bob = IRCClient::new("127.0.0.1", server.addr.port(), &bob_token);
+	bob.connect().unwrap();
+
+	assert_eq!(bob.request_profile("alice").unwrap(), picture);
+	assert_eq!(bob.request_profile("carol").unwrap(), vec![3u8; 10]);
+	assert!(bob.request_profile("dave").unwrap().is_empty());
+
+	// References the server would refuse can't be minted either
+	let too_long = "a".repeat(257);
+	for reference in ["", "has space", too_long.as_str()] {
+		assert!(matches!(
+			generator.generate_token_with_avatar_ref("u5", "erin", reference, valid_for, None),
+			Err(IrcError::InvalidInput(_))
+		));
+	}
+}
+
+#[test]
+fn fetched_avatars_are_reused_until_no_one_refers_to_them() {
+	let server = TestServer::start();
+	let fetches = Arc::new(AtomicUsize::new(0));
+	let counter = fetches.clone();
+	server.facade.set_avatar_fetcher(move |reference| {
+		counter.fetch_add(1, Ordering::SeqCst);
+		(reference == "avatars/carol").then(|| vec![3u8; 10])
+	});
+
+	let generator = TokenGenerator::new(JWT_SECRET);
+	let valid_for = Duration::from_secs(3600);
+	let connect_with_ref = |id: &str, name: &str, reference: &str| {
+		let token = generator
+			.generate_token_with_avatar_ref(id, name, reference, valid_for, None)
+			.unwrap();
+		let mut client = server.connect_raw();
+		client.send(&token);
+		client.expect(" 001 ");
+		client
+	};
+	let mut carol = connect_with_ref("u3", "carol", "avatars/carol");
+	let _dave = connect_with_ref("u4", "dave", "avatars/missing");
+
+	let bob_token = TestServer::token("u2", "bob");
+	let mut bob = IRCClient::new("127.0.0.1", server.addr.port(), &bob_token);
+	bob.connect().unwrap();
+
+	// Both the found and the missing avatar are fetched once
+	for _ in 0..2 {
+		assert_eq!(bob.request_profile("carol").unwrap(), vec![3u8; 10]);
+		assert!(bob.request_profile("dave").unwrap().is_empty());
+	}
+	assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+	// Once carol is gone her avatar is dropped and fetched afresh when she returns
+	carol.send("QUIT");
+	carol.expect("ERROR");
+	server.facade.run_cleanup();
+	let _carol = connect_with_ref("u3", "carol", "avatars/carol");
+	assert_eq!(bob.request_profile("carol").unwrap(), vec![3u8; 10]);
+	assert_eq!(fetches.load(Ordering::SeqCst), 3);
+
+	// A stored avatar takes over from a fetched one, and can be removed again
+	server.facade.store_avatar("avatars/carol", vec![4u8; 10]).unwrap();
+	assert_eq!(bob.request_profile("carol").unwrap(), vec![4u8; 10]);
+	assert!(server.facade.remove_avatar("avatars/carol"));
+	assert!(!server.facade.remove_avatar("avatars/carol"));
+	assert_eq!(bob.request_profile("carol").unwrap(), vec![3u8; 10]);
+	assert_eq!(fetches.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn dropped_connection_can_resume_its_session() {
+	let server = TestServer::start();
+	let mut alice = server.connect_raw();
+	alice.send(&TestServer::token("u1", "alice"));
+	let resume_token = alice
+		.expect(":server NOTICE alice :RESUME_TOKEN ")
+		.trim_end()
+		.rsplit(' ')
+		.next()
+		.unwrap()
+		.to_string();
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	// Let the server notice the dropped connection
+	drop(alice);
+	std::thread::sleep(Duration::from_millis(300));
+
+	let mut alice = server.connect_raw();
+	alice.send(&format!("RESUME {}", resume_token));
+	alice.expect(" 001 ");
+	alice.expect(":alice JOIN #test");
+
+	bob.send("PRIVMSG #test :welcome back");
+	alice.expect("welcome back");
+
+	// The token is replaced once used
+	let mut other = server.connect_raw();
+	other.send(&format!("RESUME {}", resume_token));
+	other.expect("ERROR :Invalid or expired resume token");
+}
+
+#[test]
+fn bare_who_lists_every_connected_user() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let _bob = server.connect("u2", "bob");
+	let mut carol = server.connect("u3", "carol");
+
+	carol.send("AWAY :lunch");
+	carol.expect(" 306 ");
+
+	alice.send("WHO");
+
+	// Sorted by nick, with "*" in place of a channel
+	assert!(alice.expect(" 352 ").starts_with(":server 352 alice * alice hostname server alice H "));
+	assert!(alice.expect(" 352 ").starts_with(":server 352 alice * bob hostname server bob H "));
+	assert!(alice.expect(" 352 ").starts_with(":server 352 alice * carol hostname server carol G "));
+	alice.expect(":server 315 alice * :End of WHO list");
+
+	alice.send("WHO *");
+	alice.expect(" 352 alice * alice ");
+}
+
+#[test]
+fn secret_channel_is_listed_only_to_members() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #hidden");
+	alice.expect(" JOIN #hidden");
+	alice.send("MODE #hidden +s");
+	alice.expect(":alice MODE #hidden +s");
+
+	bob.send("LIST");
+	bob.expect_none("#hidden", Duration::from_millis(300));
+
+	alice.send("LIST");
+	alice.expect(" 322 alice #hidden 1 ");
+}
+
+#[test]
+fn any_member_may_set_the_topic_of_an_unlocked_channel() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #chat");
+	alice.expect(" JOIN #chat");
+	bob.send("JOIN #chat");
+	bob.expect(" JOIN #chat");
+
+	bob.send("TOPIC #chat :Weekly sync");
+	alice.expect(":bob TOPIC #chat :Weekly sync");
+
+	alice.send("TOPIC #chat");
+	alice.expect(":server 332 alice #chat :Weekly sync");
+}
+
+#[test]
+fn locked_topic_can_only_be_changed_by_operators() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #chat");
+	alice.expect(" JOIN #chat");
+	bob.send("JOIN #chat");
+	bob.expect(" JOIN #chat");
+
+	alice.send("MODE #chat +t");
+	alice.expect(":alice MODE #chat +t");
+
+	bob.send("TOPIC #chat :Hijacked");
+	bob.expect(":server 482 bob #chat :You're not channel operator");
+
+	// Reading the topic is still allowed
+	bob.send("TOPIC #chat");
+	bob.expect(":server 331 bob #chat :No topic is set");
+
+	alice.send("TOPIC #chat :Official");
+	bob.expect(":alice TOPIC #chat :Official");
+
+	alice.send("MODE #chat");
+	alice.expect(":server 324 alice #chat :+t");
+}
+
+#[test]
+fn isupport_advertises_the_configured_limits() {
+	let server = TestServer::start();
+	server.facade.set_max_nick_length(8).unwrap();
+
+	let mut alice = server.connect("u1", "alice");
+	let line = alice.expect(" 005 ");
+	assert!(line.contains(" NICKLEN=8 "));
+	assert!(line.contains(" CHANMODES=b,,,st "));
+
+	let mut long = server.connect_raw();
+	long.send(&TestServer::token("u2", "longername"));
+	long.expect("ERROR :Invalid username: Username too long");
+}
+
+#[test]
+fn lowered_name_and_topic_limits_are_enforced() {
+	let server = TestServer::start();
+	assert!(server.facade.set_max_topic_length(0).is_err());
+	assert!(server.facade.set_max_channel_name_length(1).is_err());
+	assert!(server.facade.set_max_nick_length(1000).is_err());
+	server.facade.set_max_topic_length(10).unwrap();
+	server.facade.set_max_channel_name_length(6).unwrap();
+
+	let mut alice = server.connect("u1", "alice");
+	let line = alice.expect(" 005 ");
+	assert!(line.contains(" CHANNELLEN=6 "));
+	assert!(line.contains(" TOPICLEN=10 "));
+
+	alice.send("JOIN #toolong");
+	alice.expect(":server 479 alice #toolong :Channel name too long");
+	alice.send("JOIN #short");
+	alice.expect(" JOIN #short");
+
+	alice.send("TOPIC #short :This topic is too long");
+	alice.expect("FAIL TOPIC TOPIC_TOO_LONG :Topic exceeds 10 bytes");
+	alice.send("TOPIC #short :Fits fine");
+	alice.expect(":alice TOPIC #short :Fits fine");
+}
+
+#[test]
+fn successful_auth_is_written_to_the_audit_log() {
+	let server = TestServer::start();
+	let path = std::env::temp_dir().join(format!("irc-audit-{}.log", std::process::id()));
+	let _ = fs::remove_file(&path);
+	server.facade.set_audit_log(&path).unwrap();
+
+	let _alice = server.connect("u1", "alice");
+
+	// Lines are written by the audit log's own thread, so wait for it
+	let deadline = Instant::now() + Duration::from_secs(5);
+	let line = loop {
+		let contents = fs::read_to_string(&path).unwrap_or_default();
+		if let Some(line) = contents.lines().find(|line| line.contains("\"connect\"")) {
+			break line.to_string();
+		}
+		assert!(Instant::now() < deadline, "no connect event in the audit log");
+		thread::sleep(Duration::from_millis(20));
+	};
+	fs::remove_file(&path).unwrap();
+
+	let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+	assert_eq!(event["user_id"], "u1");
+	assert_eq!(event["username"], "alice");
+	assert_eq!(event["ip"], "127.0.0.1");
+	assert!(event["jti"].is_string());
+	assert_eq!(event["resumed"], false);
+}
+
+#[test]
+fn resumed_session_is_audited_with_its_token_jti() {
+	let server = TestServer::start();
+	let path = std::env::temp_dir().join(format!("irc-audit-resume-{}.log", std::process::id()));
+	let _ = fs::remove_file(&path);
+	server.facade.set_audit_log(&path).unwrap();
+
+	let mut alice = server.connect_raw();
+	alice.send(&TestServer::token("u1", "alice"));
+	let resume_token = alice
+		.expect(":server NOTICE alice :RESUME_TOKEN ")
+		.trim_end()
+		.rsplit(' ')
+		.next()
+		.unwrap()
+		.to_string();
+
+	// Let the server notice the dropped connection
+	drop(alice);
+	std::thread::sleep(Duration::from_millis(300));
+
+	let mut alice = server.connect_raw();
+	alice.send(&format!("RESUME {}", resume_token));
+	alice.expect(" 001 ");
+
+	// Lines are written by the audit log's own thread, so wait for both connects
+	let deadline = Instant::now() + Duration::from_secs(5);
+	let events: Vec<serde_json::Value> = loop {
+		let contents = fs::read_to_string(&path).unwrap_or_default();
+		let events: Vec<serde_json::Value> = contents
+			.lines()
+			.filter(|line| line.contains("\"connect\""))
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		if events.len() == 2 {
+			break events;
+		}
+		assert!(Instant::now() < deadline, "expected two connect events in the audit log");
+		thread::sleep(Duration::from_millis(20));
+	};
+	fs::remove_file(&path).unwrap();
+
+	assert_eq!(events[0]["resumed"], false);
+	assert_eq!(events[1]["resumed"], true);
+	assert!(events[1]["jti"].is_string());
+	assert_eq!(events[1]["jti"], events[0]["jti"]);
+}
+
+#[test]
+fn message_counters_advance_as_messages_are_sent() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #count");
+	alice.expect(" JOIN #count");
+	bob.send("JOIN #count");
+	bob.expect(" JOIN #count");
+
+	alice.send("PRIVMSG #count :one");
+	bob.expect("<alice> one");
+	alice.send("PRIVMSG #count :two");
+	bob.expect("<alice> two");
+	alice.send("PRIVMSG bob :three");
+	bob.expect("PRIVMSG alice :three");
+
+	let stats = server.facade.stats();
+	assert_eq!(stats.users, 2);
+	let channel = stats.channels.iter().find(|c| c.name == "#count").unwrap();
+	assert_eq!((channel.users, channel.message_count), (2, 2));
+
+	bob.send("WHOIS alice");
+	bob.expect(":server 320 bob alice :has sent 3 messages this session");
+}
+
+#[test]
+fn channels_snapshot_reports_joined_channel() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #snap");
+	alice.expect(" JOIN #snap");
+	bob.send("JOIN #snap");
+	bob.expect(" JOIN #snap");
+	bob.send("TOPIC #snap :Snapshots");
+	bob.expect(" TOPIC #snap ");
+
+	let channels = server.facade.channels();
+	let channel = channels.iter().find(|c| c.name == "#snap").unwrap();
+	assert_eq!(channel.users, 2);
+	assert_eq!(channel.topic, "Snapshots");
+	assert_eq!(channel.modes, "+");
+	assert!(channel.created_at <= SystemTime::now());
+
+	let json = serde_json::to_value(channel).unwrap();
+	assert_eq!(json["users"], 2);
+}
+
+#[test]
+fn users_snapshot_reports_connected_users() {
+	let server = TestServer::start();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #one");
+	alice.expect(" JOIN #one");
+	alice.send("JOIN #two");
+	alice.expect(" JOIN #two");
+	bob.send("AWAY :lunch");
+	bob.expect(" 306 ");
+	thread::sleep(Duration::from_millis(300));
+
+	let users = server.facade.users();
+	assert_eq!(users.len(), 2);
+	let (alice_info, bob_info) = (&users[0], &users[1]);
+	assert_eq!((alice_info.id.as_str(), alice_info.username.as_str()), ("u1", "alice"));
+	assert_eq!((alice_info.channels, alice_info.away.as_deref()), (2, None));
+	assert_eq!((bob_info.channels, bob_info.away.as_deref()), (0, Some("lunch")));
+	assert!(alice_info.idle >= Duration::from_millis(300));
+	assert!(alice_info.idle < READ_TIMEOUT);
+
+	let json = serde_json::to_value(bob_info).unwrap();
+	assert!(json.get("profile_pic").is_none());
+	assert_eq!(json["away"], "lunch");
+}
+
+#[test]
+fn disconnecting_a_token_jti_drops_its_session() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let payload = token.split('.').nth(1).unwrap();
+	let claims: serde_json::Value =
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+	let jti = claims["jti"].as_str().unwrap().to_string();
+
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect(" 001 ");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	assert_eq!(server.facade.disconnect_by_jti("some-other-jti"), 0);
+	assert_eq!(server.facade.disconnect_by_jti(&jti), 1);
+
+	alice.expect("ERROR :Token revoked");
+	bob.expect(":alice QUIT :Token revoked");
+	let users = server.facade.users();
+	assert_eq!(users.len(), 1);
+	assert_eq!(users[0].username, "bob");
+	assert_eq!(server.facade.disconnect_by_jti(&jti), 0);
+
+	// The revoked token can't be used to sign in again
+	let mut again = server.connect_raw();
+	again.send(&token);
+	again.expect("ERROR :Authentication failed: Token revoked");
+}
+
+#[test]
+fn killtoken_disconnects_like_disconnect_by_jti() {
+	let server = TestServer::start();
+	server.facade.add_admin("u9").unwrap();
+	let mut admin = server.connect("u9", "admin");
+	let token = TestServer::token("u1", "alice");
+	let payload = token.split('.').nth(1).unwrap();
+	let claims: serde_json::Value =
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+	let jti = claims["jti"].as_str().unwrap().to_string();
+
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect(" 001 ");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #test");
+	alice.expect(" JOIN #test");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	admin.send(&format!("KILLTOKEN {} :Stolen token", jti));
+	admin.expect("Disconnected 1 users using token");
+	alice.expect("ERROR :Stolen token");
+	bob.expect(":alice QUIT :Stolen token");
+	assert_eq!(server.facade.users().len(), 2);
+
+	let mut again = server.connect_raw();
+	again.send(&token);
+	again.expect("ERROR :Authentication failed: Token revoked");
+}
+
+#[test]
+fn over_long_lines_are_answered_with_417_and_skipped() {
+	let server = TestServer::start();
+	server.facade.set_buffer_sizes(2048, 512).unwrap();
+
+	// During registration
+	let mut alice = server.connect_raw();
+	alice.send(&format!("PASS {}", TestServer::token("u1", "alice")));
+	alice.send(&format!("USER {} 0 * :Alice", "x".repeat(3000)));
+	alice.expect(":server 417 * :Input line was too long");
+	alice.send("NICK alice");
+	alice.send("USER alice 0 * :Alice");
+	alice.expect(" 001 ");
+
+	// Once registered, whether the line arrives in one piece or across several writes
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #long");
+	bob.expect(" JOIN #long");
+	alice.send("JOIN #long");
+	alice.expect(" JOIN #long");
+	bob.send(&format!("PRIVMSG #long :{}", "y".repeat(600)));
+	bob.expect(":server 417 bob :Input line was too long");
+	bob.send_bytes(format!("PRIVMSG #long :{}", "z".repeat(5000)).as_bytes());
+	bob.send_bytes(b"\r\nPRIVMSG #long :still here\r\n");
+	bob.expect(":server 417 bob :Input line was too long");
+	alice.expect(":bob PRIVMSG #long :<bob> still here");
+}
+
+#[test]
+fn nickname_in_use_can_be_retried_during_registration() {
+	let server = TestServer::start();
+	let _alice = server.connect("u1", "alice");
+
+	let mut client = server.connect_raw();
+	client.send(&format!("PASS {}", TestServer::token("u2", "bob")));
+	client.send("NICK alice");
+	client.send("USER bob 0 * :Bob");
+	client.expect(":server 433 * alice :Nickname is already in use");
+	client.expect_none(" 001 ", Duration::from_millis(300));
+
+	client.send("NICK alice2");
+	client.expect(":server 001 alice2 ");
+}
+
+#[test]
+fn nickname_in_use_is_suffixed_when_enabled() {
+	let server = TestServer::start();
+	server.facade.set_nick_auto_suffix(true);
+	let _alice = server.connect("u1", "alice");
+	let _alice_ = server.connect("u2", "alice_");
+
+	let mut client = server.connect_raw();
+	client.send(&format!("PASS {}", TestServer::token("u3", "carol")));
+	client.send("NICK alice");
+	client.send("USER carol 0 * :Carol");
+	client.expect(":server 001 alice__ ");
+}
+
+// Register with CAP/NICK/USER and send `token` as the password of a SASL PLAIN exchange
+fn start_sasl_plain(server: &TestServer, nick: &str, token: &str) -> TestClient {
+	let mut client = server.connect_raw();
+	client.send("CAP LS 302");
+	client.expect("CAP * LS :");
+	client.send("CAP REQ :sasl");
+	client.expect("CAP * ACK :sasl");
+	client.send(&format!("NICK {}", nick));
+	client.send(&format!("USER {} 0 * :{}", nick, nick));
+	client.send("AUTHENTICATE PLAIN");
+	client.expect("AUTHENTICATE +");
+
+	let payload = BASE64.encode(format!("\0{}\0{}", nick, token));
+	for chunk in payload.as_bytes().chunks(400) {
+		client.send(&format!("AUTHENTICATE {}", std::str::from_utf8(chunk).unwrap()));
+	}
+	if payload.len() % 400 == 0 {
+		client.send("AUTHENTICATE +");
+	}
+	client
+}
+
+#[test]
+fn sasl_plain_with_a_valid_token_registers() {
+	let server = TestServer::start();
+	let mut client = start_sasl_plain(&server, "alice", &TestServer::token("u1", "alice"));
+
+	client.expect(":server 900 alice alice!*@* alice :You are now logged in as alice");
+	client.expect(":server 903 alice :SASL authentication successful");
+	client.send("CAP END");
+	client.expect(":server 001 alice ");
+}
+
+#[test]
+fn sasl_plain_with_an_invalid_token_fails() {
+	let server = TestServer::start();
+	let forged = TokenGenerator::new("wrong-secret")
+		.generate_token("u1", "alice", None, 1, None)
+		.unwrap();
+	let mut client = start_sasl_plain(&server, "alice", &forged);
+
+	client.expect(":server 904 alice :SASL authentication failed");
+	client.send("CAP END");
+	client.expect(":server 464 * :Password required");
+}
+
+#[test]
+fn session_status_formats_duration_and_tracks_server_remaining() {
+	let status = SessionStatus::new(Duration::from_secs(3723), None);
+	assert_eq!(status.formatted, "1h 2m 3s");
+	assert_eq!(SessionStatus::new(Duration::from_secs(42), None).formatted, "42s");
+
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+	assert_eq!(client.session_status().remaining, None);
+
+	// Each cleanup pass sends SESSION_REMAINING to registered users
+	client.read_until(&[1], READ_TIMEOUT).unwrap();
+	server.facade.run_cleanup();
+	let deadline = Instant::now() + Duration::from_secs(5);
+	while client.session_status().remaining.is_none() {
+		assert!(Instant::now() < deadline, "no SESSION_REMAINING received");
+		let _ = client.read_parsed();
+	}
+
+	let remaining = client.session_status().remaining.unwrap();
+	assert!(remaining > Duration::from_secs(3500) && remaining <= Duration::from_secs(3600));
+}
+
+#[test]
+fn client_reports_its_connection_state() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	assert_eq!(client.state(), ConnectionState::NeverConnected);
+
+	client.connect().unwrap();
+	assert_eq!(client.state(), ConnectionState::Connected);
+
+	client.disconnect().unwrap();
+	assert_eq!(client.state(), ConnectionState::Disconnected);
+}
+
+#[test]
+fn client_read_timeout_applies_to_current_and_later_connections() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+
+	client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+	client.connect().unwrap();
+	let stream = client.stream.as_ref().unwrap();
+	assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_millis(200)));
+
+	// Reads give up after the timeout once the welcome lines are drained
+	while client.read_message().is_ok() {}
+	let started = Instant::now();
+	let err = client.read_message().unwrap_err();
+	assert_eq!(err.kind(), ErrorKind::WouldBlock);
+	assert!(started.elapsed() < Duration::from_secs(1));
+
+	client.set_read_timeout(None).unwrap();
+	assert_eq!(client.stream.as_ref().unwrap().read_timeout().unwrap(), None);
+
+	// Reconnecting keeps the configured timeout
+	client.disconnect().unwrap();
+	client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+	client.connect().unwrap();
+	let stream = client.stream.as_ref().unwrap();
+	assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_millis(300)));
+
+	assert!(matches!(
+		client.set_read_timeout(Some(Duration::ZERO)),
+		Err(IrcError::InvalidInput(_))
+	));
+}
+
+#[test]
+fn client_built_with_the_builder_connects_with_its_settings() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+
+	let mut client = IRCClientBuilder::new()
+		.server("127.0.0.1")
+		.port(server.addr.port())
+		.token(&token)
+		.read_timeout(Some(Duration::from_millis(200)))
+		.build()
+		.unwrap();
+	assert_eq!(client.read_timeout(), Some(Duration::from_millis(200)));
+
+	client.connect().unwrap();
+	assert_eq!(client.state(), ConnectionState::Connected);
+	let stream = client.stream.as_ref().unwrap();
+	assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_millis(200)));
+
+	// Server and token are required
+	assert!(matches!(
+		IRCClient::builder().token(&token).build(),
+		Err(IrcError::InvalidInput(_))
+	));
+	assert!(matches!(
+		IRCClient::builder().server("127.0.0.1").build(),
+		Err(IrcError::InvalidInput(_))
+	));
+}
+
+#[test]
+fn client_connect_reports_a_rejected_token() {
+	let server = TestServer::start();
+	let token = TokenGenerator::new("not-the-server-secret")
+		.generate_token("u1", "alice", None, 1, None)
+		.unwrap();
+
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	assert!(matches!(client.connect(), Err(IrcError::AuthFailed(_))));
+	assert_eq!(client.state(), ConnectionState::Lost);
+}
+
+#[test]
+fn client_read_loop_ends_when_the_server_closes_the_connection() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut admin = server.connect("u1", "alice");
+
+	let token = TestServer::token("u2", "bob");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+	// KILL needs bob registered, which the welcome confirms
+	client.read_until(&[1], READ_TIMEOUT).unwrap();
+
+	let reader = thread::spawn(move || {
+		while client.state() == ConnectionState::Connected {
+			let _ = client.read_message();
+		}
+		client
+	});
+
+	admin.send("KILL bob :testing");
+
+	let started = Instant::now();
+	while !reader.is_finished() {
+		assert!(started.elapsed() < Duration::from_secs(5), "reader did not exit");
+		thread::sleep(Duration::from_millis(20));
+	}
+
+	let client = reader.join().unwrap();
+	assert_eq!(client.state(), ConnectionState::Lost);
+	assert!(client.stream.is_none());
+}
+
+#[test]
+fn client_refreshes_channels_from_the_server() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+	client.join_channel("#one").unwrap();
+	client.join_channel("#two").unwrap();
+
+	// Pretend local state was lost
+	client.channels.clear();
+	client.refresh_channels().unwrap();
+
+	let mut channels: Vec<String> = client.channels.iter().cloned().collect();
+	channels.sort();
+	assert_eq!(channels, ["#one", "#two"]);
+}
+
+#[test]
+fn client_queries_list_who_and_whois() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #rust");
+	bob.expect(" JOIN #rust");
+	bob.send("AWAY :lunch");
+	bob.expect(" 306 ");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	let channels = client.list_channels(Some("#ru*")).unwrap();
+	assert_eq!(channels.len(), 1);
+	assert_eq!((channels[0].name.as_str(), channels[0].users), ("#rust", 1));
+
+	let users = client.who(Some("#rust")).unwrap();
+	assert_eq!(users.len(), 1);
+	assert_eq!(users[0].nick, "bob");
+	assert!(users[0].away);
+
+	let info = client.whois("bob").unwrap();
+	assert_eq!(info.channels, ["#rust"]);
+	assert_eq!(info.away.as_deref(), Some("lunch"));
+
+	assert!(matches!(client.whois("nobody"), Err(IrcError::NickNotFound(_))));
+}
+
+#[test]
+fn client_search_parses_matching_history() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+	bob.send("PRIVMSG #test :deploy at noon");
+	bob.send("PRIVMSG #test :lunch first");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	assert!(matches!(client.search("#test", "deploy"), Err(IrcError::InvalidInput(_))));
+
+	client.join_channel("#test").unwrap();
+	let results = client.search("#test", "DEPLOY").unwrap();
+	assert_eq!(results.len(), 1);
+	assert_eq!(results[0].sender, "bob");
+	assert_eq!(results[0].text, "deploy at noon");
+	assert!(!results[0].encrypted);
+}
+
+#[test]
+fn ephemeral_message_expires_before_a_normal_one() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+	bob.send("JOIN #test");
+	bob.expect(" JOIN #test");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+	client.join_channel("#test").unwrap();
+	client.send_ephemeral("#test", "self destructing note", Duration::from_secs(1)).unwrap();
+	client.send_message("#test", "ordinary note").unwrap();
+	bob.expect("ordinary note");
+
+	// Longer than the server's message TTL is refused
+	bob.send("PRIVMSG #test +ttl=999999 :too long");
+	bob.expect(":server FAIL PRIVMSG INVALID_TTL");
+
+	thread::sleep(Duration::from_millis(1100));
+	server.facade.run_cleanup();
+
+	bob.send("SEARCH #test :note");
+	assert!(bob.expect(" 920 ").ends_with(" :ordinary note\r\n"));
+	bob.expect(" 921 ");
+}
+
+#[test]
+fn client_rekey_reports_discarded_history() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	client.send_message("bob", "one").unwrap();
+	client.send_message("bob", "two").unwrap();
+	bob.expect(":two");
+
+	assert_eq!(client.rekey().unwrap(), 2);
+	assert_eq!(client.rekey().unwrap(), 0);
+}
+
+#[test]
+fn client_reads_the_server_time() {
+	let server = TestServer::start();
+	let mut raw = server.connect("u2", "bob");
+	raw.send("TIME");
+	let line = raw.expect(" 391 ");
+	assert!(line.starts_with(":server 391 bob server "), "{}", line);
+	assert!(line.trim_end().ends_with('Z'), "{}", line);
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	let before = SystemTime::now();
+	let time = client.server_time().unwrap();
+	let after = SystemTime::now();
+	// Same machine, so the clocks agree to within the round trip
+	assert!(time.time >= before - Duration::from_secs(1) && time.time <= after + Duration::from_secs(1));
+	assert!(time.offset_millis.abs() < 1000);
+	let local = SystemTime::now();
+	let shifted = time.to_server_clock(local);
+	assert!(shifted.duration_since(local).unwrap_or_default() < Duration::from_secs(1));
+}
+
+#[test]
+fn security_notices_are_parsed_by_kind() {
+	let cases = [
+		(":server NOTICE alice :SECURITY: All messages will be deleted after 1h 0m 0s", SecurityNoticeKind::Retention),
+		(
+			":server NOTICE alice :SECURITY: 3 messages have been automatically deleted from #rust",
+			SecurityNoticeKind::MessageExpiry,
+		),
+		(
+			":server NOTICE alice :SECURITY: 2 private messages have been automatically deleted",
+			SecurityNoticeKind::MessageExpiry,
+		),
+		(
+			":server NOTICE alice :SECURITY: You will be disconnected for inactivity in 5m 0s",
+			SecurityNoticeKind::InactivityWarning,
+		),
+		(
+			"NOTICE :SECURITY: You have been disconnected due to inactivity. All messages have been deleted.",
+			SecurityNoticeKind::SessionExpired,
+		),
+		(":server NOTICE alice :SECURITY: Logged by the compliance team", SecurityNoticeKind::Other),
+	];
+	for (line, expected) in cases {
+		match ServerMessage::parse(line) {
+			ServerMessage::SecurityNotice { kind, detail } => {
+				assert_eq!(kind, expected, "{}", line);
+				assert!(line.ends_with(&detail), "{}", line);
+			}
+			other => panic!("{} parsed as {:?}", line, other),
+		}
+	}
+
+	assert!(matches!(
+		ServerMessage::parse(":server NOTICE alice :Message truncated to 10 bytes"),
+		ServerMessage::Notice { .. }
+	));
+}
+
+#[test]
+fn client_collects_security_notices() {
+	let server = TestServer::start();
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	// The connect notice reports the message TTL
+	let deadline = Instant::now() + READ_TIMEOUT;
+	while client.security_notices().next().is_none() && Instant::now() < deadline {
+		let _ = client.read_parsed();
+	}
+	let notices: Vec<_> = client.security_notices().collect();
+	assert_eq!(notices.len(), 1);
+	assert_eq!(notices[0].0, SecurityNoticeKind::Retention);
+	assert!(notices[0].1.starts_with("All messages will be deleted after"));
+}
+
+#[test]
+fn client_sets_and_clears_away() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	client.set_away(Some("lunch")).unwrap();
+	assert_eq!(client.away_message(), Some("lunch"));
+	bob.send("WHOIS alice");
+	bob.expect(":server 301 bob alice :lunch");
+
+	client.set_away(None).unwrap();
+	assert_eq!(client.away_message(), None);
+	bob.send("WHOIS alice");
+	bob.expect_none(" 301 ", Duration::from_millis(300));
+}
+
+#[test]
+fn client_refuses_a_message_with_line_breaks() {
+	let server = TestServer::start();
+	let mut bob = server.connect("u2", "bob");
+
+	let token = TestServer::token("u1", "alice");
+	let mut client = IRCClient::new("127.0.0.1", server.addr.port(), &token);
+	client.connect().unwrap();
+
+	assert!(matches!(client.send_message("bob", "hi\r\nQUIT :bye"), Err(IrcError::InvalidInput(_))));
+	bob.expect_none("PRIVMSG", Duration::from_millis(300));
+	assert_eq!(client.state(), ConnectionState::Connected);
+}
+
+#[test]
+fn file_is_relayed_encrypted_to_the_accepting_user() {
+	let server = TestServer::start();
+	let key = [9u8; 32];
+	let dir = std::env::temp_dir().join(format!("irc-file-transfer-{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+
+	// Several encryption frames' worth of data
+	let contents: Vec<u8> = (0..40_000u32).map(|i| (i % 253) as u8).collect();
+	let source = dir.join("notes.bin");
+	fs::write(&source, &contents).unwrap();
+
+	let bob_token = TestServer::token("u2", "bob");
+	let mut bob = IRCClient::new("127.0.0.1", server.addr.port(), &bob_token);
+	bob.connect().unwrap();
+	bob.read_until(&[1], Duration::from_secs(5)).unwrap();
+	bob.set_channel_key("alice", key);
+	let destination = dir.join("received.bin");
+	let save_to = destination.clone();
+	bob.on_file_offer(move |sender, name, size| {
+		assert_eq!((sender, name, size), ("alice", "notes.bin", 40_000));
+		Some(save_to.clone())
+	});
+
+	let alice_token = TestServer::token("u1", "alice");
+	let mut alice = IRCClient::new("127.0.0.1", server.addr.port(), &alice_token);
+	alice.connect().unwrap();
+	alice.set_channel_key("bob", key);
+
+	// send_file blocks until Bob accepts, so Alice sends from another thread
+	let sender = thread::spawn(move || alice.send_file(&source, "bob"));
+
+	let deadline = Instant::now() + Duration::from_secs(10);
+	let saved = loop {
+		assert!(Instant::now() < deadline, "file was not received");
+		let messages = match bob.read_parsed() {
+			Ok(messages) => messages,
+			Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+			Err(e) => panic!("read failed: {}", e),
+		};
+		let saved = messages.into_iter().find_map(|message| match message {
+			ServerMessage::File {
+				event: FileEvent::Saved { path, .. },
+				..
+			} => Some(path),
+			_ => None,
+		});
+		if let Some(path) = saved {
+			break path;
+		}
+	};
+
+	sender.join().unwrap().unwrap();
+	assert_eq!(saved, destination);
+	assert_eq!(fs::read(&saved).unwrap(), contents);
+	let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn file_transfers_are_limited_and_end_with_the_connection() {
+	let server = TestServer::start();
+	server.facade.set_max_file_size(1000).unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("SENDFILE bob big.bin 5000");
+	alice.expect(":server FAIL SENDFILE TOO_LARGE");
+	bob.expect_none("FILE OFFER", Duration::from_millis(300));
+
+	alice.send("SENDFILE bob small.bin 100");
+	let line = alice.expect(":server FILE PENDING ");
+	let id = line.split_whitespace().nth(3).unwrap().to_string();
+	bob.expect(&format!(":alice FILE OFFER {} small.bin 100", id));
+
+	// Nothing is relayed before the recipient accepts
+	alice.send(&format!("FILEDATA {} :AAAA", id));
+	alice.expect(":server FAIL FILEDATA UNKNOWN_TRANSFER");
+
+	// The recipient leaving cancels the transfer
+	drop(bob);
+	alice.expect(&format!(":server FILE CANCEL {}", id));
+}
+
+#[test]
+fn killed_and_quitting_users_are_scrubbed_and_their_transfers_cancelled() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let scrubbed = Arc::new(Mutex::new(Vec::new()));
+	let record = scrubbed.clone();
+	server
+		.facade
+		.on_session_scrubbed(move |user| record.lock().unwrap().push(user.id.clone()));
+
+	let mut admin = server.connect("u1", "admin");
+	let mut alice = server.connect("u2", "alice");
+	let mut bob = server.connect("u3", "bob");
+
+	alice.send("SENDFILE bob notes.txt 100");
+	let line = alice.expect(":server FILE PENDING ");
+	let id = line.split_whitespace().nth(3).unwrap().to_string();
+	bob.expect(&format!(":alice FILE OFFER {} notes.txt 100", id));
+
+	admin.send("KILL alice :testing");
+	bob.expect(&format!(":server FILE CANCEL {}", id));
+	// The hook runs with the server locked, so once the lock is free it has run
+	server.facade.stats();
+	assert!(scrubbed.lock().unwrap().contains(&"u2".to_string()));
+
+	bob.send("QUIT :bye");
+	bob.expect("ERROR :Closing Link: bob (bye)");
+	bob.expect_closed();
+	assert!(scrubbed.lock().unwrap().contains(&"u3".to_string()));
+}
+
+#[test]
+fn closed_connection_is_scrubbed_like_any_other_disconnect() {
+	let server = TestServer::start();
+	// Without RESUME the session ends as soon as its connection closes
+	server.facade.set_resume_grace(0).unwrap();
+	let scrubbed = Arc::new(Mutex::new(Vec::new()));
+	let record = scrubbed.clone();
+	server
+		.facade
+		.on_session_scrubbed(move |user| record.lock().unwrap().push(user.id.clone()));
+
+	let mut alice = server.connect("u1", "alice");
+	let bob = server.connect("u2", "bob");
+	alice.send("JOIN #room");
+	alice.expect(" JOIN #room");
+	drop(bob);
+
+	let started = Instant::now();
+	while !scrubbed.lock().unwrap().contains(&"u2".to_string()) {
+		assert!(started.elapsed() < READ_TIMEOUT, "bob was never scrubbed");
+		thread::sleep(Duration::from_millis(20));
+	}
+}
+
+#[test]
+fn channel_is_told_the_actual_reason_for_a_disconnect() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+	let mut admin = server.connect("u1", "admin");
+	let mut alice = server.connect("u2", "alice");
+	let mut bob = server.connect("u3", "bob");
+	alice.send("JOIN #room");
+	alice.expect(" JOIN #room");
+	bob.send("JOIN #room");
+	bob.expect(" JOIN #room");
+
+	admin.send("KILL alice :spamming");
+	let line = bob.expect("* alice has disconnected");
+	assert!(line.contains("* alice has disconnected (Killed by admin (spamming))"), "{}", line);
+	assert!(!line.contains("inactivity"));
+}
+
+#[test]
+fn empty_channel_is_removed_after_its_retention() {
+	let server = TestServer::start();
+	server
+		.facade
+		.set_empty_channel_ttl(Duration::from_secs(2))
+		.unwrap();
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+
+	alice.send("JOIN #temp");
+	alice.expect(" JOIN #temp");
+	alice.send("QUIT :bye");
+	std::thread::sleep(Duration::from_millis(300));
+
+	// Still within the retention period
+	server.facade.run_cleanup();
+	bob.send("LIST");
+	bob.expect(" 322 bob #temp 0 ");
+
+	std::thread::sleep(Duration::from_secs(2));
+	server.facade.run_cleanup();
+	bob.send("LIST");
+	bob.expect_none("#temp", Duration::from_millis(300));
+}
+
+#[test]
+fn messages_and_idle_sessions_expire_on_a_manual_clock() {
+	let server = TestServer::start();
+	let clock = Arc::new(ManualClock::new());
+	server.facade.set_clock(clock.clone());
+	server.facade.set_session_timeout(2).unwrap();
+	// Unanswered PINGs would otherwise end the sessions before they go idle
+	server.facade.set_ping_timeout(3 * 3600).unwrap();
+
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	alice.send("JOIN #ttl");
+	alice.expect(" JOIN #ttl");
+	bob.send("JOIN #ttl");
+	bob.expect(" JOIN #ttl");
+	alice.send("PRIVMSG #ttl :gone within the hour");
+	bob.expect("gone within the hour");
+
+	server.facade.run_cleanup();
+	assert!(server.facade.export_history().contains("gone within the hour"));
+
+	// Past the one-hour message TTL, but within the two-hour session timeout
+	clock.advance(Duration::from_secs(3600 + 1));
+	server.facade.run_cleanup();
+	bob.expect("messages have been automatically deleted from #ttl");
+	assert!(!server.facade.export_history().contains("gone within the hour"));
+	assert_eq!(server.facade.users().len(), 2);
+
+	// Within five minutes of the session timeout, each user is warned by nickname
+	clock.advance(Duration::from_secs(3300));
+	server.facade.run_cleanup();
+	alice.expect(":server NOTICE alice :SECURITY: You will be disconnected for inactivity in ");
+	bob.expect(":server NOTICE bob :SECURITY: You will be disconnected for inactivity in ");
+
+	// Past the session timeout: both users are idle and disconnected
+	clock.advance(Duration::from_secs(300));
+	server.facade.run_cleanup();
+	alice.expect("You have been disconnected due to inactivity");
+	assert!(server.facade.users().is_empty());
+}
+
+#[test]
+fn silenced_user_gets_no_expiry_notices() {
+	let server = TestServer::start();
+	let clock = Arc::new(ManualClock::new());
+	server.facade.set_clock(clock.clone());
+	server.facade.set_ping_timeout(3 * 3600).unwrap();
+
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	let mut carol = server.connect("u3", "carol");
+	carol.send("SILENCE on");
+	carol.expect("SILENCE: message expiry notices are off");
+	for client in [&mut alice, &mut bob, &mut carol] {
+		client.send("JOIN #ttl");
+		client.expect(" JOIN #ttl");
+	}
+	alice.send("PRIVMSG #ttl :soon gone");
+	alice.send("PRIVMSG carol :also soon gone");
+	carol.expect("also soon gone");
+
+	clock.advance(Duration::from_secs(3600 + 1));
+	server.facade.run_cleanup();
+	bob.expect("messages have been automatically deleted from #ttl");
+	carol.expect_none("automatically deleted", Duration::from_millis(300));
+	assert!(!server.facade.export_history().contains("soon gone"));
+}
+
+#[test]
+fn every_server_line_including_expiry_notices_ends_in_crlf() {
+	let server = TestServer::start();
+	let clock = Arc::new(ManualClock::new());
+	server.facade.set_clock(clock.clone());
+	server.facade.set_session_timeout(3).unwrap();
+	server.facade.set_ping_timeout(3 * 3600).unwrap();
+
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect_raw();
+	bob.send(&TestServer::token("u2", "bob"));
+	alice.send("JOIN #ttl");
+	alice.expect(" JOIN #ttl");
+	bob.send("JOIN #ttl");
+	bob.send("PRIVMSG alice :private and soon gone");
+	alice.expect("private and soon gone");
+	alice.send("PRIVMSG #ttl :soon gone");
+	alice.send("PRIVMSG bob :also soon gone");
+	bob.expect("also soon gone");
+
+	clock.advance(Duration::from_secs(3600 + 1));
+	server.facade.run_cleanup();
+
+	let mut lines = Vec::new();
+	while let Some(line) = bob.read_line_within(Duration::from_millis(300)) {
+		lines.push(line);
+	}
+	assert!(lines.iter().all(|line| line.ends_with("\r\n") && line.matches('\n').count() == 1), "{:?}", lines);
+	let expiry_notices: Vec<&String> = lines.iter().filter(|line| line.contains("automatically deleted")).collect();
+	assert_eq!(expiry_notices.len(), 2, "{:?}", lines);
+	assert!(expiry_notices.iter().all(|line| line.starts_with(":server NOTICE bob :SECURITY: ")));
+}
+
+#[test]
+fn idle_session_is_scrubbed_before_removal() {
+	let server = TestServer::start();
+	server.facade.add_admin("u1").unwrap();
+
+	// Record, for each scrubbed user, whether its messages and key were zeroed
+	let scrubbed = Arc::new(Mutex::new(Vec::new()));
+	let record = scrubbed.clone();
+	server.facade.on_session_scrubbed(move |user| {
+		let messages_zeroed = !user.messages.is_empty()
+			&& user
+				.messages
+				.iter()
+				.all(|msg| msg.content.bytes().all(|b| b == 0));
+		let key_zeroed = user
+			.session
+			.as_ref()
+			.map_or(false, |session| session.encryption_key == [0; 32]);
+		record
+			.lock()
+			.unwrap()
+			.push((user.id.clone(), messages_zeroed, key_zeroed));
+	});
+
+	let mut alice = server.connect("u1", "alice");
+	let mut bob = server.connect("u2", "bob");
+	bob.send("PRIVMSG alice :secret plan");
+	alice.expect("secret plan");
+
+	alice.send("CONFIG SET session_timeout 1");
+	alice.expect("CONFIG: session_timeout set to 1 seconds");
+
+	std::thread::sleep(Duration::from_millis(1500));
+	server.facade.run_cleanup();
+
+	let scrubbed = scrubbed.lock().unwrap();
+	assert!(scrubbed.contains(&("u2".to_string(), true, true)));
+}
+
+#[test]
+fn session_ends_when_its_token_expires() {
+	let server = TestServer::start();
+	let token = TokenGenerator::new(JWT_SECRET)
+		.generate_token_with_duration("u1", "alice", None, Duration::from_secs(2), None)
+		.unwrap();
+	let mut alice = server.connect_raw();
+	alice.send(&token);
+	alice.expect(" 001 ");
+
+	// Not expired yet
+	server.facade.run_cleanup();
+	alice.send("LIST");
+	alice.expect(" 323 ");
+
+	thread::sleep(Duration::from_millis(3100));
+	server.facade.run_cleanup();
+	alice.expect("ERROR :Token expired, please re-authenticate");
+	assert!(alice.read_line_within(Duration::from_secs(1)).is_none());
+}
+
+#[test]
+fn websocket_pings_are_answered_alongside_irc_lines() {
+	use tungstenite::Message;
+
+	// Reserve a free port, then serve WebSocket clients on it
+	let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	thread::spawn(move || irc_server::IRCServerFacade::new(JWT_SECRET).start_websocket(&addr.to_string()));
+
+	let deadline = Instant::now() + READ_TIMEOUT;
+	let mut socket = loop {
+		match tungstenite::connect(format!("ws://{}", addr)) {
+			Ok((socket, _)) => break socket,
+			Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+			Err(e) => panic!("failed to connect: {}", e),
+		}
+	};
+	// Skip frames until one matches
+	fn read_until<S: std::io::Read + std::io::Write>(
+		socket: &mut tungstenite::WebSocket<S>,
+		wanted: &dyn Fn(&Message) -> bool,
+	) {
+		while !wanted(&socket.read().expect("connection closed early")) {}
+	}
+
+	socket.send(Message::Text(TestServer::token("u1", "alice"))).unwrap();
+	read_until(&mut socket, &|m| matches!(m, Message::Text(text) if text.contains(" 001 ")));
+
+	socket.send(Message::Ping(b"are you there".to_vec())).unwrap();
+	socket.send(Message::Text("JOIN #ws".to_string())).unwrap();
+	read_until(&mut socket, &|m| matches!(m, Message::Pong(data) if data == b"are you there"));
+	read_until(&mut socket, &|m| matches!(m, Message::Text(text) if text.contains(" JOIN #ws")));
+}
+
+// A client that never reads: every write blocks until the test drops the sender
+struct StalledSink(Receiver<()>);
+
+impl Write for StalledSink {
+	fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+		let _ = self.0.recv();
+		Err(io::Error::new(ErrorKind::BrokenPipe, "stalled reader gone"))
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl ClientSink for StalledSink {
+	fn shutdown(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn stalled_reader_is_disconnected_when_its_send_queue_fills() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	let (closer, _) = listener.accept().unwrap();
+	let (unstall, stalled) = mpsc::channel();
+
+	let mut sink = QueuedSink::spawn(Box::new(StalledSink(stalled)), closer);
+
+	// Writes are queued without blocking until the queue is full
+	let started = Instant::now();
+	let mut queued = 0;
+	let error = loop {
+		match sink.write(b":alice PRIVMSG #rust :flood\r\n") {
+			Ok(_) => queued += 1,
+			Err(e) => break e,
+		}
+		assert!(queued <= SEND_QUEUE_CAPACITY + 1, "queue never filled");
+	};
+	assert!(started.elapsed() < Duration::from_secs(1));
+	assert!(queued >= SEND_QUEUE_CAPACITY);
+	assert_eq!(error.to_string(), "send queue full");
+
+	// The connection is closed so the client's reader thread cleans up
+	peer.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+	assert_eq!(peer.read(&mut [0; 16]).unwrap(), 0);
+	drop(unstall);
+}
@@ -0,0 +1,64 @@
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use irc_server::server::crypto::Encryptor;
+use irc_server::server::Session;
+use zeroize::ZeroizeOnDrop;
+
+// Compiles only for types whose Drop wipes their secrets
+fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+
+#[test]
+fn session_wipes_its_secrets_on_drop() {
+	assert_zeroize_on_drop::<Session>();
+
+	// Drop the session in place, then look at the key left in its memory
+	let mut slot = MaybeUninit::new(Session::new("s1".to_string(), "u1".to_string(), [7; 32]));
+	let key = unsafe {
+		ptr::drop_in_place(slot.as_mut_ptr());
+		ptr::addr_of!((*slot.as_ptr()).encryption_key).read()
+	};
+
+	assert_eq!(key, [0; 32]);
+}
+
+#[test]
+fn encryptions_in_a_session_get_distinct_nonces() {
+	let mut session = Session::new("s1".to_string(), "u1".to_string(), [7; 32]);
+
+	let (first, first_ciphertext) = session.encrypt(b"same text").unwrap();
+	let (second, second_ciphertext) = session.encrypt(b"same text").unwrap();
+
+	assert_ne!(first, second);
+	assert_ne!(first_ciphertext, second_ciphertext);
+	let encryptor = Encryptor::new([7; 32]);
+	assert_eq!(encryptor.decrypt(second, &second_ciphertext).unwrap(), b"same text");
+
+	// A new key lets the counter start over
+	session.rekey([9; 32]);
+	let (counter, _) = session.encrypt(b"after rekey").unwrap();
+	assert_eq!(counter, 1);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "reused under the same session key")]
+fn resetting_the_counter_without_a_new_key_is_caught() {
+	let mut session = Session::new("s1".to_string(), "u1".to_string(), [7; 32]);
+	session.encrypt(b"first").unwrap();
+
+	session.nonce_counter = 0;
+	let _ = session.encrypt(b"second");
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "reused under the same session key")]
+fn counters_from_increment_nonce_are_tracked_too() {
+	let mut session = Session::new("s1".to_string(), "u1".to_string(), [7; 32]);
+	session.increment_nonce();
+	session.increment_nonce();
+
+	session.nonce_counter = 1;
+	session.increment_nonce();
+}
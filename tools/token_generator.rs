@@ -13,14 +13,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 	if args.len() < 5 {
 		println!(
-			"Usage: {} <user_id> <username> <image_path> <jwt_secret> [days_valid]",
+			"Usage: {} <user_id> <username> <image_path> <jwt_secret> [validity]",
 			args[0]
 		);
 		println!("  user_id: Unique identifier for the user");
 		println!("  username: Display name for the user");
-		println!("  image_path: Path to the user's 8-bit profile image");
+		println!("  image_path: Path to the user's 8-bit profile image (use - for no avatar)");
 		println!("  jwt_secret: Secret key used to sign the token");
-		println!("  days_valid: (Optional) Number of days the token is valid for (default: 1)");
+		println!("  validity: (Optional) Days the token is valid for, or hours with an h suffix (e.g. 12h) (default: 1)");
 		return Ok(());
 	}
 
@@ -29,12 +29,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let image_path = &args[3];
 	let jwt_secret = &args[4];
 
-	// Default to 1 day if not specified (for security)
-	let days_valid = if args.len() > 5 {
-		args[5].parse::<u64>().unwrap_or(1)
-	} else {
-		1
+	// Default to 1 day if not specified (for security); a trailing "h" means hours
+	let valid_for = match args.get(5) {
+		Some(arg) => match parse_validity(arg) {
+			Ok(valid_for) => valid_for,
+			Err(e) => {
+				error!("{}", e);
+				eprintln!("{}", e);
+				std::process::exit(1);
+			}
+		},
+		None => Duration::from_secs(86400),
 	};
+	let hours_valid = valid_for.as_secs() / 3600;
 
 	// Add additional security options
 	let include_security_fields = true;
@@ -56,31 +63,57 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	}
 
 	info!("Generating token for user {} ({})", username, user_id);
-	info!("Token will be valid for {} days", days_valid);
+	info!("Token will be valid for {} hours", hours_valid);
 
 	// Create token generator
 	let token_generator = TokenGenerator::new(jwt_secret);
 
-	// Load and convert image
-	let img_data = token_generator.load_and_convert_image(image_path)?;
-	info!(
-		"Loaded profile image: {} ({} bytes converted to 8-bit format)",
-		image_path,
-		img_data.len()
-	);
+	// Load and convert image ("-" means the user has no avatar)
+	let img_data = if image_path.as_str() == "-" {
+		info!("No profile image supplied, generating token without avatar");
+		None
+	} else {
+		let data = token_generator.load_and_convert_image(image_path)?;
+		info!(
+			"Loaded profile image: {} ({} bytes converted to 8-bit format)",
+			image_path,
+			data.len()
+		);
+		Some(data)
+	};
 
 	// Generate token
-	let token = token_generator.generate_token(
+	let token = token_generator.generate_token_with_duration(
 		user_id,
 		username,
-		&img_data,
-		days_valid,
+		img_data.as_deref(),
+		valid_for,
 		Some(additional_claims),
 	)?;
 
 	println!("Token generated successfully:");
 	println!("{}", token);
-	println!("\nThis token will expire in {} days", days_valid);
+	println!("\nThis token will expire in {} hours", hours_valid);
 
 	Ok(())
 }
+
+// Parse a validity given in days, or in hours with an "h" suffix
+fn parse_validity(arg: &str) -> Result<Duration, String> {
+	let (count, unit_secs) = match arg.strip_suffix('h') {
+		Some(hours) => (hours, 3600),
+		None => (arg, 86400),
+	};
+
+	let count = count.parse::<u64>().map_err(|_| {
+		format!(
+			"Invalid validity {:?}: expected a number of days, or hours with an h suffix",
+			arg
+		)
+	})?;
+
+	count
+		.checked_mul(unit_secs)
+		.map(Duration::from_secs)
+		.ok_or_else(|| format!("Validity {:?} is too long", arg))
+}